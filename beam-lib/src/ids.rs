@@ -1,5 +1,7 @@
 
 use std::fmt::Display;
+#[cfg(feature = "strict-ids")]
+use std::str::FromStr;
 
 use serde::{Deserialize, Serialize};
 
@@ -53,7 +55,7 @@ impl Display for AppOrProxyId {
 #[cfg(feature = "strict-ids")]
 impl AppOrProxyId {
     pub fn new(id: &str) -> Result<Self, BeamIdError> {
-        match get_id_type(id)? {
+        match id.parse::<BeamId>()?.kind {
             BeamIdType::AppId => Ok(Self::App(AppId(id.to_owned()))),
             BeamIdType::ProxyId => Ok(Self::Proxy(ProxyId(id.to_owned()))),
             BeamIdType::BrokerId => Err(BeamIdError::InvalidIdKind),
@@ -72,18 +74,7 @@ impl AppOrProxyId {
     }
 
     pub fn hide_broker(&self) -> String {
-        match self {
-            AppOrProxyId::App(app) => {
-                let without_broker = strip_broker_id(&app.0).expect("Is valid id");
-                without_broker[..without_broker.len() - 1].to_owned()
-            }
-            AppOrProxyId::Proxy(proxy) => proxy
-                .0
-                .split_once('.')
-                .map(|(proxy, _broker)| proxy)
-                .unwrap_or_default()
-                .to_string(),
-        }
+        BeamId::from(self.clone()).components().collect::<Vec<_>>().join(".")
     }
 }
 
@@ -130,25 +121,35 @@ fn strip_broker_id(id: &str) -> Result<&str, BeamIdError> {
 }
 
 #[cfg(feature = "strict-ids")]
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
 pub(crate) enum BeamIdType {
     AppId,
     ProxyId,
     BrokerId,
 }
 
+#[cfg(feature = "strict-ids")]
+impl BeamIdType {
+    /// How many components precede the broker's ID
+    fn component_count(self) -> usize {
+        match self {
+            BeamIdType::AppId => 2,
+            BeamIdType::ProxyId => 1,
+            BeamIdType::BrokerId => 0,
+        }
+    }
+}
+
 macro_rules! impl_new {
     ($id:ident) => {
         impl $id {
             #[cfg(feature = "strict-ids")]
             pub fn new(id: impl Into<String>) -> Result<Self, BeamIdError> {
-                {
-                    let id = id.into();
-                    if get_id_type(&id)? == BeamIdType::$id {
-                        Ok(Self(id))
-                    } else {
-                        Err(BeamIdError::InvalidIdKind)
-                    }
+                let id = BeamId::new(id)?;
+                if id.kind == BeamIdType::$id {
+                    Ok(Self(id.id))
+                } else {
+                    Err(BeamIdError::InvalidIdKind)
                 }
             }
             
@@ -169,19 +170,33 @@ impl_new!(ProxyId);
 
 #[cfg(feature = "strict-ids")]
 fn get_id_type(id: &str) -> Result<BeamIdType, BeamIdError> {
+    parse_id(id, false)
+}
+
+/// With `wildcards`, the app and proxy may be `*`, but only from the left, e.g. `*.*.broker` but not `app.*.broker`
+#[cfg(feature = "strict-ids")]
+fn parse_id(id: &str, wildcards: bool) -> Result<BeamIdType, BeamIdError> {
+    let check_part = |part: &str| match part {
+        WILDCARD if wildcards => Ok(()),
+        part => check_valid_id_part(part),
+    };
     let rest = strip_broker_id(id)?;
     let Some(rest) = rest.strip_suffix('.') else {
-        return Ok(BeamIdType::BrokerId);
+        // Otherwise the broker ID is only the end of another domain
+        return if rest.is_empty() { Ok(BeamIdType::BrokerId) } else { Err(BeamIdError::WrongBrokerId) };
     };
     let mut split = rest.split('.');
     let ret = match (split.next(), split.next()) {
         (Some(proxy), None) => {
-            check_valid_id_part(proxy)?;
+            check_part(proxy)?;
             Ok(BeamIdType::ProxyId)
         }
         (Some(app), Some(proxy)) => {
-            check_valid_id_part(app)?;
-            check_valid_id_part(proxy)?;
+            check_part(app)?;
+            check_part(proxy)?;
+            if proxy == WILDCARD && app != WILDCARD {
+                return Err(BeamIdError::MisplacedWildcard);
+            }
             Ok(BeamIdType::AppId)
         }
         (None, _) => unreachable!(),
@@ -193,6 +208,152 @@ fn get_id_type(id: &str) -> Result<BeamIdType, BeamIdError> {
     }
 }
 
+/// Any Beam ID, i.e. `<app>.<proxy>.<broker>`, `<proxy>.<broker>` or the broker's own ID,
+/// parsed into its components. The broker's ID is the one set via [`set_broker_id`].
+#[cfg(feature = "strict-ids")]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BeamId {
+    id: String,
+    kind: BeamIdType,
+}
+
+#[cfg(feature = "strict-ids")]
+impl BeamId {
+    pub fn new(id: impl Into<String>) -> Result<Self, BeamIdError> {
+        let id = id.into();
+        let kind = get_id_type(&id)?;
+        Ok(Self { id, kind })
+    }
+
+    /// The app's name, e.g. `app1` of `app1.proxy23.broker.samply.de`, if this is an app's ID
+    pub fn app_name(&self) -> Option<&str> {
+        self.is_app().then(|| self.components().next()).flatten()
+    }
+
+    /// The proxy's ID for apps and proxies, e.g. `proxy23.broker.samply.de` of `app1.proxy23.broker.samply.de`
+    pub fn proxy_id(&self) -> Option<ProxyId> {
+        match self.kind {
+            BeamIdType::AppId => self.id.split_once('.').map(|(_app, proxy)| ProxyId(proxy.to_owned())),
+            BeamIdType::ProxyId => Some(ProxyId(self.id.clone())),
+            BeamIdType::BrokerId => None,
+        }
+    }
+
+    pub fn broker_id(&self) -> &str {
+        &self.id[self.broker_start()..]
+    }
+
+    pub fn is_app(&self) -> bool {
+        self.kind == BeamIdType::AppId
+    }
+
+    pub fn is_proxy(&self) -> bool {
+        self.kind == BeamIdType::ProxyId
+    }
+
+    pub fn is_broker(&self) -> bool {
+        self.kind == BeamIdType::BrokerId
+    }
+
+    /// Parses a pattern for [`BeamId::matches`], which may have `*` instead of the app or proxy, e.g.
+    /// `*.proxy23.broker.samply.de` for any app at proxy23 or `*.*.broker.samply.de` for any app.
+    /// Only whole components from the left may be `*`, so neither `app1.*.broker.samply.de` nor `app*.proxy23.broker.samply.de` are patterns.
+    pub fn pattern(pattern: impl Into<String>) -> Result<Self, BeamIdError> {
+        let id = pattern.into();
+        let kind = parse_id(&id, true)?;
+        Ok(Self { id, kind })
+    }
+
+    /// Whether this ID is matched by `pattern`, see [`BeamId::pattern`]. IDs only match patterns of the same kind,
+    /// so `*.proxy23.broker.samply.de` matches the apps at proxy23 but not proxy23 itself.
+    pub fn matches(&self, pattern: &BeamId) -> bool {
+        self.kind == pattern.kind
+            && self
+                .components()
+                .zip(pattern.components())
+                .all(|(component, pattern)| pattern == WILDCARD || component == pattern)
+    }
+
+    /// The app and proxy, if any, without the broker
+    fn components(&self) -> impl Iterator<Item = &str> {
+        self.id[..self.broker_start()].split_terminator('.')
+    }
+
+    /// Where the broker's ID starts, i.e. after as many components as there are for this kind of ID
+    fn broker_start(&self) -> usize {
+        match self.kind.component_count() {
+            0 => 0,
+            count => self.id.match_indices('.').nth(count - 1).map_or(self.id.len(), |(dot, _)| dot + 1),
+        }
+    }
+}
+
+#[cfg(feature = "strict-ids")]
+impl FromStr for BeamId {
+    type Err = BeamIdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::new(s)
+    }
+}
+
+#[cfg(feature = "strict-ids")]
+impl TryFrom<&str> for BeamId {
+    type Error = BeamIdError;
+
+    fn try_from(id: &str) -> Result<Self, Self::Error> {
+        Self::new(id)
+    }
+}
+
+#[cfg(feature = "strict-ids")]
+impl AsRef<str> for BeamId {
+    fn as_ref(&self) -> &str {
+        &self.id
+    }
+}
+
+#[cfg(feature = "strict-ids")]
+impl Display for BeamId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.id)
+    }
+}
+
+#[cfg(feature = "strict-ids")]
+impl Serialize for BeamId {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.id)
+    }
+}
+
+#[cfg(feature = "strict-ids")]
+impl From<AppId> for BeamId {
+    fn from(app: AppId) -> Self {
+        Self { id: app.0, kind: BeamIdType::AppId }
+    }
+}
+
+#[cfg(feature = "strict-ids")]
+impl From<ProxyId> for BeamId {
+    fn from(proxy: ProxyId) -> Self {
+        Self { id: proxy.0, kind: BeamIdType::ProxyId }
+    }
+}
+
+#[cfg(feature = "strict-ids")]
+impl From<AppOrProxyId> for BeamId {
+    fn from(id: AppOrProxyId) -> Self {
+        match id {
+            AppOrProxyId::App(app) => app.into(),
+            AppOrProxyId::Proxy(proxy) => proxy.into(),
+        }
+    }
+}
+
+#[cfg(feature = "strict-ids")]
+const WILDCARD: &str = "*";
+
 #[derive(Debug, Clone, Serialize, PartialEq, Eq, Hash)]
 pub struct AppId(String);
 
@@ -244,6 +405,8 @@ pub enum BeamIdError {
     InvalidNumberOfIdFragments,
     InvalidIdKind,
     InvalidIdFragment,
+    EmptyIdFragment,
+    MisplacedWildcard,
     #[cfg(feature = "strict-ids")]
     WrongBrokerId,
 }
@@ -255,7 +418,9 @@ impl std::error::Error for BeamIdError {}
 impl Display for BeamIdError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let text = match self {
-            BeamIdError::InvalidIdFragment => "Id fragment may only contain alphanumeric values.",
+            BeamIdError::InvalidIdFragment => "Id fragment may only contain alphanumeric values and dashes.",
+            BeamIdError::EmptyIdFragment => "Id fragment may not be empty.",
+            BeamIdError::MisplacedWildcard => "Only the leftmost id fragments may be wildcards.",
             BeamIdError::InvalidNumberOfIdFragments => "Id had an unexpected amount of fragments.",
             BeamIdError::InvalidIdKind => "Id parsed as a different kind of id then specified.",
             #[cfg(feature = "strict-ids")]
@@ -269,6 +434,9 @@ impl Display for BeamIdError {
 
 #[cfg(feature = "strict-ids")]
 fn check_valid_id_part(id: &str) -> Result<(), BeamIdError> {
+    if id.is_empty() {
+        return Err(BeamIdError::EmptyIdFragment);
+    }
     for char in id.chars() {
         if !(char.is_alphanumeric() || char == '-') {
             return Err(BeamIdError::InvalidIdFragment);
@@ -295,6 +463,8 @@ impl_deserialize!(AppId);
 impl_deserialize!(ProxyId);
 #[cfg(feature = "strict-ids")]
 impl_deserialize!(AppOrProxyId);
+#[cfg(feature = "strict-ids")]
+impl_deserialize!(BeamId);
 
 #[cfg(all(test, feature = "strict-ids"))]
 mod tests {
@@ -331,6 +501,84 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_beam_id_components() {
+        set_broker_id("broker.samply.de".to_string());
+        let app: BeamId = "app1.proxy23.broker.samply.de".parse().unwrap();
+        assert!(app.is_app());
+        assert_eq!(app.app_name(), Some("app1"));
+        assert_eq!(app.proxy_id(), Some(ProxyId::new("proxy23.broker.samply.de").unwrap()));
+        assert_eq!(app.broker_id(), "broker.samply.de");
+        assert_eq!(app.to_string(), "app1.proxy23.broker.samply.de");
+
+        let proxy = BeamId::try_from("proxy23.broker.samply.de").unwrap();
+        assert!(proxy.is_proxy());
+        assert_eq!(proxy.app_name(), None);
+        assert_eq!(proxy.proxy_id().unwrap().to_string(), "proxy23.broker.samply.de");
+        assert_eq!(proxy, BeamId::from(ProxyId::new("proxy23.broker.samply.de").unwrap()));
+
+        let broker: BeamId = "broker.samply.de".parse().unwrap();
+        assert!(broker.is_broker());
+        assert_eq!(broker.proxy_id(), None);
+        assert_eq!(broker.broker_id(), "broker.samply.de");
+
+        let json = serde_json::to_string(&app).unwrap();
+        assert_eq!(json, r#""app1.proxy23.broker.samply.de""#);
+        assert_eq!(serde_json::from_str::<BeamId>(&json).unwrap(), app);
+
+        // The components do not depend on the length of the global broker ID
+        let foreign = BeamId::from(AppId::new_unchecked("app1.proxy2.broker.example.com"));
+        assert_eq!(foreign.app_name(), Some("app1"));
+        assert_eq!(foreign.proxy_id().unwrap().to_string(), "proxy2.broker.example.com");
+        assert_eq!(foreign.broker_id(), "broker.example.com");
+
+        assert_eq!(AppOrProxyId::new("app1.proxy23.broker.samply.de").unwrap().hide_broker(), "app1.proxy23");
+        assert_eq!(AppOrProxyId::new("proxy23.broker.samply.de").unwrap().hide_broker(), "proxy23");
+    }
+
+    #[test]
+    fn test_invalid_beam_ids() {
+        set_broker_id("broker.samply.de".to_string());
+        assert_eq!(BeamId::new("a.app1.proxy23.broker.samply.de"), Err(BeamIdError::InvalidNumberOfIdFragments));
+        assert_eq!(BeamId::new(".proxy23.broker.samply.de"), Err(BeamIdError::EmptyIdFragment));
+        assert_eq!(BeamId::new("app1..broker.samply.de"), Err(BeamIdError::EmptyIdFragment));
+        assert_eq!(BeamId::new("app_1.proxy23.broker.samply.de"), Err(BeamIdError::InvalidIdFragment));
+        assert_eq!(BeamId::new("proxy23.broker.example.com"), Err(BeamIdError::WrongBrokerId));
+        assert_eq!(BeamId::new("otherbroker.samply.de"), Err(BeamIdError::WrongBrokerId));
+        assert_eq!(AppId::new("proxy23.broker.samply.de"), Err(BeamIdError::InvalidIdKind));
+    }
+
+    #[test]
+    fn test_beam_id_patterns() {
+        set_broker_id("broker.samply.de".to_string());
+        let cases = [
+            ("app1.proxy23.broker.samply.de", "app1.proxy23.broker.samply.de", true),
+            ("app1.proxy23.broker.samply.de", "*.proxy23.broker.samply.de", true),
+            ("app1.proxy23.broker.samply.de", "*.*.broker.samply.de", true),
+            ("app1.proxy23.broker.samply.de", "app2.proxy23.broker.samply.de", false),
+            ("app1.proxy23.broker.samply.de", "*.proxy42.broker.samply.de", false),
+            ("app1.proxy23.broker.samply.de", "*.broker.samply.de", false),
+            ("app1.proxy23.broker.samply.de", "broker.samply.de", false),
+            ("proxy23.broker.samply.de", "proxy23.broker.samply.de", true),
+            ("proxy23.broker.samply.de", "*.broker.samply.de", true),
+            ("proxy23.broker.samply.de", "proxy42.broker.samply.de", false),
+            ("proxy23.broker.samply.de", "*.proxy23.broker.samply.de", false),
+            ("proxy23.broker.samply.de", "*.*.broker.samply.de", false),
+            ("broker.samply.de", "broker.samply.de", true),
+            ("broker.samply.de", "*.broker.samply.de", false),
+        ];
+        for (id, pattern, matches) in cases {
+            let id = BeamId::new(id).unwrap();
+            assert_eq!(id.matches(&BeamId::pattern(pattern).unwrap()), matches, "{id} matching {pattern}");
+        }
+
+        assert_eq!(BeamId::pattern("app1.*.broker.samply.de"), Err(BeamIdError::MisplacedWildcard));
+        assert_eq!(BeamId::pattern("app*.proxy23.broker.samply.de"), Err(BeamIdError::InvalidIdFragment));
+        assert_eq!(BeamId::pattern("*.*.*.broker.samply.de"), Err(BeamIdError::InvalidNumberOfIdFragments));
+        assert_eq!(BeamId::pattern("*.proxy23.*.samply.de"), Err(BeamIdError::WrongBrokerId));
+        assert_eq!(BeamId::new("*.proxy23.broker.samply.de"), Err(BeamIdError::InvalidIdFragment), "Only patterns have wildcards");
+    }
+
     #[test]
     fn test_app_or_proxy_id() {
         let app_id_str = "app.proxy1.broker.samply.de";
@@ -3,7 +3,7 @@ use serde_json::Value;
 use uuid::Uuid;
 use crate::AddressingId;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize)]
 pub struct MsgId(Uuid);
 
 impl MsgId {
@@ -31,6 +31,12 @@ pub struct TaskRequest<T> {
     pub ttl: String,
     pub failure_strategy: FailureStrategy,
     pub metadata: Value,
+    /// Opt into ordered delivery: A recipient only gets this task once it has fetched all tasks from the same sender with a lower sequence number
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sequence: Option<u64>,
+    /// Connectivity probe: The broker keeps this task for at most a minute, counts it towards its storage cap but not the task summary, and drops it as soon as its results have been delivered
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub probe: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -144,6 +150,8 @@ mod tests {
             ttl: "10s".to_string(),
             failure_strategy: FailureStrategy::Discard,
             metadata: Value::Null,
+            sequence: None,
+            probe: false,
         };
         assert_eq!(serde_json::from_str::<TaskRequest<T>>(&serde_json::to_string(&task).unwrap()).unwrap().body, task.body);
     }
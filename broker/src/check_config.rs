@@ -0,0 +1,67 @@
+use std::fmt;
+
+use shared::{config::CONFIG_CENTRAL, config_broker::CertSource, errors::SamplyBeamError};
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+
+#[cfg(feature = "vault")]
+use crate::crypto;
+use crate::{crypto_dir, health::VaultStatus};
+
+/// One check of the configuration, which is skipped if a check it depends on has failed
+pub(crate) struct ConfigCheck {
+    pub(crate) name: String,
+    pub(crate) result: Option<Result<(), SamplyBeamError>>,
+}
+
+impl ConfigCheck {
+    pub(crate) fn new(name: impl Into<String>, result: Result<(), SamplyBeamError>) -> Self {
+        Self { name: name.into(), result: Some(result) }
+    }
+
+    #[cfg(feature = "vault")]
+    pub(crate) fn skipped(name: impl Into<String>) -> Self {
+        Self { name: name.into(), result: None }
+    }
+
+    pub(crate) fn passed(&self) -> bool {
+        matches!(self.result, Some(Ok(())))
+    }
+}
+
+impl fmt::Display for ConfigCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.result {
+            Some(Ok(())) => write!(f, "[PASS] {}", self.name),
+            Some(Err(e)) => write!(f, "[FAIL] {}: {e}", self.name),
+            None => write!(f, "[SKIP] {}", self.name),
+        }
+    }
+}
+
+/// Checks the configured source of the proxies' certificates without serving anything,
+/// prints the result of every check and returns whether all of them passed
+pub(crate) async fn check_config(
+    vault_status_sender: watch::Sender<VaultStatus>,
+    clock_skew_sender: watch::Sender<Option<i64>>,
+    shutdown: CancellationToken,
+) -> Result<bool, SamplyBeamError> {
+    #[cfg(not(feature = "vault"))]
+    drop((vault_status_sender, clock_skew_sender, shutdown));
+    let checks = match CONFIG_CENTRAL.cert_source.clone() {
+        #[cfg(feature = "vault")]
+        CertSource::Vault { addresses, auth } => {
+            crypto::GetCertsFromPki::new(addresses, auth, vault_status_sender, clock_skew_sender, shutdown)
+                .await?
+                .validate()
+                .await
+        }
+        CertSource::Dir(dir) => {
+            vec![ConfigCheck::new(format!("Certificates in {}", dir.display()), crypto_dir::build_cert_getter(dir).map(drop))]
+        }
+    };
+    for check in &checks {
+        println!("{check}");
+    }
+    Ok(checks.iter().all(ConfigCheck::passed))
+}
@@ -0,0 +1,142 @@
+//! Fails requests to Vault immediately while it is down instead of letting every caller wait for its retries
+
+use std::{sync::Mutex, time::Duration};
+
+use shared::config_broker::CircuitBreakerSettings;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+pub(crate) struct CircuitBreaker {
+    settings: CircuitBreakerSettings,
+    state: Mutex<CircuitState>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CircuitState {
+    /// Requests are sent, counting the consecutive failures since the first of them
+    Closed { failures: u32, since: Option<Instant> },
+    /// Requests fail immediately until the cooldown has passed
+    Open { until: Instant },
+    /// A single probe request has been let through. Should it not report back, e.g. because its caller
+    /// was cancelled, another probe is let through after the cooldown.
+    HalfOpen { probe_sent: Instant },
+}
+
+impl CircuitBreaker {
+    pub(crate) fn new(settings: CircuitBreakerSettings) -> Self {
+        Self {
+            settings,
+            state: Mutex::new(CircuitState::Closed { failures: 0, since: None }),
+        }
+    }
+
+    /// Whether a request may be sent. If not, returns how long the circuit is expected to stay open.
+    pub(crate) fn allow_request(&self) -> Result<(), Duration> {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        match *state {
+            CircuitState::Closed { .. } => Ok(()),
+            CircuitState::Open { until } if now < until => Err(until - now),
+            CircuitState::HalfOpen { probe_sent } if now < probe_sent + self.settings.cooldown => {
+                Err(probe_sent + self.settings.cooldown - now)
+            }
+            CircuitState::Open { .. } | CircuitState::HalfOpen { .. } => {
+                info!("Samply.PKI: Probing whether Vault is available again");
+                *state = CircuitState::HalfOpen { probe_sent: now };
+                Ok(())
+            }
+        }
+    }
+
+    pub(crate) fn record_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        if !matches!(*state, CircuitState::Closed { .. }) {
+            info!("Samply.PKI: Vault is available again; closing the circuit breaker");
+        }
+        *state = CircuitState::Closed { failures: 0, since: None };
+    }
+
+    pub(crate) fn record_failure(&self) {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let (failures, since) = match *state {
+            CircuitState::Closed { failures, since: Some(since) } if now - since <= self.settings.window => (failures + 1, since),
+            CircuitState::Closed { .. } => (1, now),
+            CircuitState::HalfOpen { .. } => (self.settings.threshold, now),
+            CircuitState::Open { .. } => return,
+        };
+        if failures >= self.settings.threshold {
+            warn!(
+                "Samply.PKI: {failures} consecutive requests to Vault failed; failing requests immediately for {:?}",
+                self.settings.cooldown
+            );
+            *state = CircuitState::Open { until: now + self.settings.cooldown };
+        } else {
+            *state = CircuitState::Closed { failures, since: Some(since) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker() -> CircuitBreaker {
+        CircuitBreaker::new(CircuitBreakerSettings {
+            threshold: 3,
+            window: Duration::from_secs(10),
+            cooldown: Duration::from_secs(30),
+        })
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_circuit_opens_and_probes() {
+        let breaker = breaker();
+        breaker.record_failure();
+        breaker.record_failure();
+        breaker.record_success();
+        breaker.record_failure();
+        breaker.record_failure();
+        assert!(breaker.allow_request().is_ok(), "A success resets the failures");
+        breaker.record_failure();
+        assert_eq!(breaker.allow_request(), Err(Duration::from_secs(30)));
+
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert!(breaker.allow_request().is_ok(), "A probe must be let through after the cooldown");
+        assert!(breaker.allow_request().is_err(), "Only a single probe at a time");
+        breaker.record_failure();
+        assert_eq!(breaker.allow_request(), Err(Duration::from_secs(30)), "A failed probe opens the circuit again");
+
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert!(breaker.allow_request().is_ok());
+        breaker.record_success();
+        assert!(breaker.allow_request().is_ok());
+        assert!(breaker.allow_request().is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_failures_outside_the_window_are_not_consecutive() {
+        let breaker = breaker();
+        breaker.record_failure();
+        breaker.record_failure();
+        tokio::time::advance(Duration::from_secs(11)).await;
+        breaker.record_failure();
+        breaker.record_failure();
+        assert!(breaker.allow_request().is_ok());
+        breaker.record_failure();
+        assert!(breaker.allow_request().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_lost_probe_is_replaced() {
+        let breaker = breaker();
+        for _ in 0..3 {
+            breaker.record_failure();
+        }
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert!(breaker.allow_request().is_ok());
+        // The probe never reports back
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert!(breaker.allow_request().is_ok());
+    }
+}
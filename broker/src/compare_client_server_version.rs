@@ -9,9 +9,13 @@ enum Verdict {
 }
 
 fn compare_version(their_version_header: &HeaderValue) -> Verdict {
+    // Proxies may append a suffix such as a site identifier, separated by a space
     let mut version = their_version_header
         .to_str()
         .unwrap_or("GARBLED_CLIENT_VERSION")
+        .split(' ')
+        .next()
+        .unwrap_or_default()
         .split('/');
 
     let Some(val) = version.next() else {
@@ -52,3 +56,19 @@ pub(crate) async fn log_version_mismatch(
     }
     next.run(req).await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compare_version_ignores_suffix() {
+        let ours = format!("Samply.Beam.Proxy/{}", env!("CARGO_PKG_VERSION"));
+        for user_agent in [ours.clone(), format!("{ours} site-a"), format!("{ours}-abc123 site-a")] {
+            let verdict = compare_version(&HeaderValue::from_str(&user_agent).unwrap());
+            assert!(matches!(verdict, Verdict::BeamWithMatchingVersion), "{user_agent}");
+        }
+        assert!(matches!(compare_version(&HeaderValue::from_static("Samply.Beam.Proxy/0.0.1 site-a")), Verdict::BeamWithMismatchingVersion(v) if v == "0.0.1"));
+        assert!(matches!(compare_version(&HeaderValue::from_static("curl/8.0")), Verdict::NotBeam));
+    }
+}
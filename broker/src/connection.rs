@@ -0,0 +1,240 @@
+//! Accept loop for features that need control over individual connections
+//! (PROXY protocol headers, reaping idle connections) which [`axum::serve`] does not offer.
+
+use std::{
+    convert::Infallible,
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use axum::{body::Body, extract::ConnectInfo, Router};
+use http_body_util::BodyExt;
+use hyper::{body::Incoming, Request};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::{conn::auto, graceful::GracefulShutdown},
+};
+use shared::middleware::SourceFromProxyProtocol;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpListener,
+};
+use tower::ServiceExt;
+use tracing::{debug, warn};
+
+use crate::proxy_protocol;
+
+pub(crate) struct ServeOptions {
+    /// Peers whose connections start with a PROXY protocol header
+    pub proxy_protocol_from: Vec<IpAddr>,
+    /// Close connections without traffic and without pending requests after this long
+    pub idle_timeout: Option<Duration>,
+}
+
+/// Serves `app` like [`axum::serve`] with the given per-connection options.
+/// For connections with a PROXY protocol header, the source address from the header is what handlers see as [`ConnectInfo`].
+pub(crate) async fn serve(
+    listener: TcpListener,
+    app: Router,
+    options: ServeOptions,
+    shutdown: impl Future<Output = ()>,
+) {
+    let graceful = GracefulShutdown::new();
+    tokio::pin!(shutdown);
+    loop {
+        let (mut stream, peer) = tokio::select! {
+            conn = listener.accept() => match conn {
+                Ok(conn) => conn,
+                Err(e) => {
+                    warn!("Unable to accept connection: {e}");
+                    continue;
+                }
+            },
+            _ = &mut shutdown => break,
+        };
+        let app = app.clone();
+        let trusted = options.proxy_protocol_from.contains(&peer.ip());
+        let idle_timeout = options.idle_timeout;
+        let watcher = graceful.watcher();
+        tokio::spawn(async move {
+            let source = if trusted {
+                match tokio::time::timeout(proxy_protocol::HEADER_TIMEOUT, proxy_protocol::read_header(&mut stream)).await {
+                    Ok(Ok(source)) => source.unwrap_or(peer),
+                    Ok(Err(e)) => {
+                        warn!("Dropping connection from {peer}: {e}");
+                        return;
+                    },
+                    Err(_) => {
+                        warn!("Dropping connection from {peer}: Timed out waiting for PROXY protocol header");
+                        return;
+                    },
+                }
+            } else {
+                peer
+            };
+            debug!("Accepted connection from {source} (via {peer})");
+            let activity = Arc::new(Activity::new());
+            let service = hyper::service::service_fn({
+                let activity = activity.clone();
+                move |mut req: Request<Incoming>| {
+                    req.extensions_mut().insert(ConnectInfo(source));
+                    if trusted {
+                        req.extensions_mut().insert(SourceFromProxyProtocol);
+                    }
+                    let app = app.clone();
+                    let in_flight = InFlight::new(activity.clone());
+                    async move {
+                        let resp = app.oneshot(req).await?;
+                        // The request counts as pending until its response has been sent completely
+                        Ok::<_, Infallible>(resp.map(|body| Body::new(body.map_frame(move |frame| {
+                            let _ = &in_flight;
+                            frame
+                        }))))
+                    }
+                }
+            });
+            let io = ActivityIo { inner: stream, activity: activity.clone() };
+            let builder = auto::Builder::new(TokioExecutor::new());
+            let conn = watcher.watch(builder.serve_connection_with_upgrades(TokioIo::new(io), service).into_owned());
+            let res = match idle_timeout {
+                Some(timeout) => tokio::select! {
+                    res = conn => res,
+                    _ = activity.idle(timeout) => {
+                        debug!("Closing idle connection from {source}");
+                        return;
+                    }
+                },
+                None => conn.await,
+            };
+            if let Err(e) = res {
+                debug!("Connection from {source} closed with error: {e}");
+            }
+        });
+    }
+    graceful.shutdown().await;
+}
+
+/// Tracks when a connection last transferred data and how many of its requests are pending
+struct Activity {
+    created: Instant,
+    /// Milliseconds since `created`
+    last_io: AtomicU64,
+    in_flight: AtomicUsize,
+}
+
+impl Activity {
+    fn new() -> Self {
+        Self { created: Instant::now(), last_io: AtomicU64::new(0), in_flight: AtomicUsize::new(0) }
+    }
+
+    fn touch(&self) {
+        self.last_io.store(self.created.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    fn idle_for(&self) -> Duration {
+        self.created.elapsed().saturating_sub(Duration::from_millis(self.last_io.load(Ordering::Relaxed)))
+    }
+
+    /// Resolves once the connection has been idle for `timeout`.
+    /// Connections with pending requests (e.g. long polls) are never idle.
+    async fn idle(&self, timeout: Duration) {
+        loop {
+            let idle_for = self.idle_for();
+            let pending = self.in_flight.load(Ordering::Relaxed) > 0;
+            if !pending && idle_for >= timeout {
+                return;
+            }
+            let wait = if pending { timeout } else { timeout - idle_for };
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+struct InFlight(Arc<Activity>);
+
+impl InFlight {
+    fn new(activity: Arc<Activity>) -> Self {
+        activity.in_flight.fetch_add(1, Ordering::Relaxed);
+        Self(activity)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.touch();
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Records every successful read or write in an [`Activity`]
+struct ActivityIo<S> {
+    inner: S,
+    activity: Arc<Activity>,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ActivityIo<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if matches!(res, Poll::Ready(Ok(()))) && buf.filled().len() > filled {
+            self.activity.touch();
+        }
+        res
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ActivityIo<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let res = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if matches!(res, Poll::Ready(Ok(n)) if n > 0) {
+            self.activity.touch();
+        }
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::routing::get;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_idle_connections_are_closed_but_long_polls_survive() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().route("/poll", get(|| async {
+            tokio::time::sleep(Duration::from_millis(800)).await;
+            "done"
+        }));
+        let options = ServeOptions { proxy_protocol_from: Vec::new(), idle_timeout: Some(Duration::from_millis(200)) };
+        tokio::spawn(serve(listener, app, options, std::future::pending()));
+
+        let mut idle = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let closed = tokio::time::timeout(Duration::from_secs(2), idle.read(&mut [0; 16])).await;
+        assert_eq!(closed.expect("Idle connection must be closed").unwrap(), 0);
+
+        let mut polling = tokio::net::TcpStream::connect(addr).await.unwrap();
+        polling.write_all(b"GET /poll HTTP/1.1\r\nHost: broker\r\nConnection: close\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        polling.read_to_string(&mut response).await.unwrap();
+        assert!(response.ends_with("done"), "Long poll must not be reaped: {response:?}");
+    }
+}
@@ -1,26 +1,350 @@
-use std::{future::Future, mem::discriminant};
+use std::{collections::{HashMap, HashSet}, future::Future, mem::discriminant, path::{Path, PathBuf}, sync::{atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering}, Arc, Mutex}};
 
 use axum::{
     async_trait,
     http::{header, method, uri::Scheme, Method, Request, StatusCode, Uri},
 };
+use arc_swap::{ArcSwap, ArcSwapOption};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 use shared::{
-    config,
-    crypto::{parse_crl, CertificateCache, CertificateCacheUpdate, GetCerts},
+    config, config_broker::{CacheTtlBounds, CircuitBreakerSettings, CrlSettings, RetryBackoff, RetryableStatusCodes, VaultAuth, VaultResponseLimits, VaultRetryBudgets},
+    crypto::{crl_revokes, parse_crl, normalize_fingerprint, parse_single_certificate, sha256_fingerprint, CertificateCache, CertificateCacheUpdate, CertificateStatus, GetCerts, MaybeStale},
     errors::SamplyBeamError,
-    http_client::{self, SamplyHttpClient}, openssl::x509::X509Crl, reqwest::{self, Url},
+    http_client::{self, ClientIdentity, ConnectionSettings, HttpErrorKind, SamplyHttpClient}, openssl::{asn1::Asn1Time, x509::{X509, X509Crl, X509CrlRef}}, reqwest::{self, ResponseBuilderExt, Url},
 };
-use std::time::Duration;
-use tokio::time::timeout;
+use std::time::{Duration, SystemTime};
+use tokio::{sync::OnceCell, task::JoinHandle, time::{error::Elapsed, timeout, Instant}};
+use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, warn, info};
 
-use crate::health::{self, VaultStatus};
+use crate::{check_config::ConfigCheck, circuit_breaker::CircuitBreaker, health::{self, VaultStatus}, ocsp::OcspChecker};
+
+const DEFAULT_PKI_USER_AGENT: &str = concat!(env!("SAMPLY_USER_AGENT"), "+pki");
+/// Time without further changes to the CA certificates after which they are reloaded
+const CA_RELOAD_DEBOUNCE: Duration = Duration::from_millis(500);
+/// Vault's `LIST` method, which is not among the standard methods
+static METHOD_LIST: once_cell::sync::Lazy<Method> =
+    once_cell::sync::Lazy::new(|| Method::from_bytes(b"LIST").expect("LIST is a valid method name"));
+
+/// Reads the body of a response from Vault, giving up once it exceeds `limit` bytes instead of buffering whatever is sent
+pub(crate) async fn read_limited_body(mut resp: reqwest::Response, limit: usize) -> Result<Vec<u8>, SamplyBeamError> {
+    let too_large = |resp: &reqwest::Response| {
+        SamplyBeamError::VaultOtherError(format!("Vault's response from {} exceeds the limit of {limit} bytes", resp.url().path()))
+    };
+    if resp.content_length().is_some_and(|len| len > limit as u64) {
+        return Err(too_large(&resp));
+    }
+    let mut body = Vec::new();
+    while let Some(chunk) = resp.chunk().await? {
+        if body.len() + chunk.len() > limit {
+            return Err(too_large(&resp));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body)
+}
+
+/// Reads the whole body like [`read_limited_body`] and returns the response with the body in memory
+async fn buffer_response(resp: reqwest::Response, limit: usize) -> Result<reqwest::Response, SamplyBeamError> {
+    let mut buffered = axum::http::Response::builder().status(resp.status()).version(resp.version()).url(resp.url().clone());
+    if let Some(headers) = buffered.headers_mut() {
+        *headers = resp.headers().clone();
+    }
+    let body = read_limited_body(resp, limit).await?;
+    buffered
+        .body(body)
+        .map(reqwest::Response::from)
+        .map_err(|e| SamplyBeamError::InternalSynchronizationError(format!("Unable to buffer Vault's response: {e}")))
+}
+
+async fn read_limited_text(resp: reqwest::Response, limit: usize) -> Result<String, SamplyBeamError> {
+    read_limited_body(resp, limit).await.map(|body| String::from_utf8_lossy(&body).into_owned())
+}
+
+/// Limits the number of concurrent requests to Vault. Further requests queue until a permit is free.
+struct VaultRequestLimit {
+    permits: tokio::sync::Semaphore,
+    max: usize,
+}
+
+/// Allows sending one request to Vault while held
+struct VaultRequestPermit<'a> {
+    permit: Option<tokio::sync::SemaphorePermit<'a>>,
+    limit: &'a VaultRequestLimit,
+}
+
+impl VaultRequestLimit {
+    fn new(max: usize) -> Self {
+        Self { permits: tokio::sync::Semaphore::new(max), max }
+    }
+
+    async fn acquire(&self) -> VaultRequestPermit<'_> {
+        if self.permits.available_permits() == 0 {
+            debug!("Samply.PKI: {} requests to Vault are in flight; waiting for one to finish", self.max);
+        }
+        let permit = self.permits.acquire().await.expect("The semaphore is never closed");
+        self.report_in_flight();
+        VaultRequestPermit { permit: Some(permit), limit: self }
+    }
+
+    fn report_in_flight(&self) {
+        metrics::gauge!("beam_vault_requests_in_flight").set((self.max - self.permits.available_permits()) as f64);
+    }
+}
+
+impl Drop for VaultRequestPermit<'_> {
+    fn drop(&mut self) {
+        drop(self.permit.take());
+        self.limit.report_in_flight();
+    }
+}
+
+/// Authenticated access to Vault, shared with the task keeping the token alive
+struct VaultClient {
+    /// Where Vault's API is found at each address from PKI_ADDRESS, see [`vault_api_base`]
+    api_bases: Vec<Url>,
+    /// Index of the address requests are sent to until it fails
+    current: AtomicUsize,
+    /// Whether each address was fine when it was last used
+    healthy: Vec<AtomicBool>,
+    pki_auth: VaultAuth,
+    /// The static token or the one obtained by the last login (empty before the first login)
+    pki_token: ArcSwap<String>,
+    /// Seconds for which the token is valid after it has been obtained or renewed, 0 if unknown or it does not expire
+    token_lease: AtomicU64,
+    /// The login currently in progress, which requests whose token has been rejected wait for instead of logging in themselves
+    pending_login: Mutex<Option<Arc<PendingLogin>>>,
+    user_agent: header::HeaderValue,
+    /// Vault Enterprise namespace of the PKI mount and the auth method
+    namespace: Option<header::HeaderValue>,
+    /// Replaced when the CA certificates in TLS_CA_CERTIFICATES_DIR change
+    hyper_client: ArcSwap<SamplyHttpClient>,
+    /// Aborts pending retries and the token renewal when the broker shuts down
+    shutdown: CancellationToken,
+}
 
 pub struct GetCertsFromPki {
+    vault: Arc<VaultClient>,
     pki_realm: String,
-    hyper_client: SamplyHttpClient,
     health_report_sender: tokio::sync::watch::Sender<health::VaultStatus>,
+    clock_skew_sender: tokio::sync::watch::Sender<Option<i64>>,
+    retry_budgets: VaultRetryBudgets,
+    retry_backoff: RetryBackoff,
+    /// Time after which a failing request is given up even if attempts are left
+    retry_deadline: Duration,
+    /// Time after which a single attempt, including reading the response, is given up and retried
+    attempt_timeout: Duration,
+    retry_status_codes: RetryableStatusCodes,
+    circuit_breaker: CircuitBreaker,
+    /// Bounds the requests in flight to Vault so that bursts do not trip Vault's rate limits or connection caps
+    request_limit: VaultRequestLimit,
+    response_limits: VaultResponseLimits,
+    /// Where Vault's health is checked, relative to Vault's API
+    health_path: String,
+    /// How long the result of a health check is shared by failing requests
+    health_cache_ttl: Duration,
+    last_health_check: tokio::sync::Mutex<Option<CachedHealth>>,
+    /// Whether certificates outside their validity period are reported as [`SamplyBeamError::CertificateExpired`]
+    reject_expired_certs: bool,
+    /// Whether the last certificate list and certificates fetched are served while Vault is unavailable
+    serve_stale_on_error: bool,
+    fetch_concurrency: usize,
+    cache_ttl_bounds: CacheTtlBounds,
+    max_clock_skew: Duration,
+    /// How often the certificate list is fetched in the background, if not whenever the cached list expires
+    refresh_interval: Option<Duration>,
+    /// Seconds until the certificate list should be fetched again as derived from Vault's lease duration
+    cache_ttl: AtomicU64,
+    certificate_list: ArcSwapOption<CachedCertificateList>,
+    /// Fetches of certificates by serial which are in flight, shared by all concurrent callers
+    pending_certificates: Mutex<HashMap<String, Arc<PendingCertificate>>>,
+    /// Certificates fetched by [`GetCertsFromPki::warm_cache`] which are handed out once instead of asking Vault
+    prefetched_certificates: Mutex<HashMap<String, String>>,
+    /// The last certificate fetched for each listed serial, only kept if `serve_stale_on_error` is set
+    known_good_certificates: Mutex<HashMap<String, String>>,
+    /// When Vault last said that it does not know a serial, e.g. of a replayed message's sender
+    missing_certificates: Mutex<HashMap<String, Instant>>,
+    /// Serials by SHA-256 fingerprint of the certificates fetched by [`GetCertsFromPki::warm_cache`] or looked up by fingerprint
+    fingerprints: Mutex<HashMap<String, String>>,
+    /// When a fingerprint was last not found on the certificate list, so that it is not searched for again right away
+    missing_fingerprints: Mutex<HashMap<String, Instant>>,
+    /// How long a serial in `missing_certificates` or a fingerprint in `missing_fingerprints` is not asked for again
+    not_found_cache_ttl: Duration,
+    crl_settings: CrlSettings,
+    /// The last CRL fetched, shared by concurrent callers while it is being refreshed
+    crl: tokio::sync::Mutex<Option<CachedCrl>>,
+    /// Only set if certificates are checked via OCSP
+    ocsp: Option<OcspChecker>,
+    /// Loops such as renewing the token, which run until [`GetCertsFromPki::shutdown`]
+    background_tasks: Mutex<Vec<JoinHandle<()>>>,
+}
+
+struct CachedCrl {
+    /// `None` if the PKI has no CRL as nothing has been revoked
+    crl: Option<Arc<X509Crl>>,
+    /// When the CRL was last fetched or, if that failed, when refreshing it was last tried
+    fetched_at: Instant,
+}
+
+/// How long checking a message waits for the CRL to be refreshed while a stale one can be used instead
+const CRL_STALE_REFRESH_TIMEOUT: Duration = Duration::from_secs(2);
+
+type PendingCertificate = OnceCell<Result<String, Arc<SamplyBeamError>>>;
+
+type PendingLogin = OnceCell<Result<(), Arc<SamplyBeamError>>>;
+
+/// The result of the last health check of Vault
+struct CachedHealth {
+    /// Index of the checked address
+    address: usize,
+    checked_at: Instant,
+    result: Result<(), Arc<SamplyBeamError>>,
+}
+
+/// Vault being sealed is not remembered for longer so that the broker notices soon when it is unsealed
+const SEALED_HEALTH_CACHE_TTL: Duration = Duration::from_millis(500);
+
+struct CachedCertificateList {
+    serials: Vec<String>,
+    fetched_at: Instant,
+    ttl: Duration,
+}
+
+/// The caches of [`GetCertsFromPki`] as distinguished by the `cache` label of their metrics
+#[derive(Debug, Clone, Copy)]
+enum CertCache {
+    /// The certificate list
+    List,
+    /// The certificates fetched ahead of time, which are taken out when first needed
+    Prefetched,
+    /// The certificates by serial which messages are verified with, i.e. [`shared::crypto::CertificateCache`]
+    Serial,
+}
+
+impl CertCache {
+    fn label(self) -> &'static str {
+        match self {
+            CertCache::List => "list",
+            CertCache::Prefetched => "prefetched",
+            CertCache::Serial => "serial",
+        }
+    }
+
+    fn hit(self) {
+        metrics::counter!("beam_cert_cache_hits_total", "cache" => self.label()).increment(1);
+    }
+
+    fn miss(self) {
+        metrics::counter!("beam_cert_cache_misses_total", "cache" => self.label()).increment(1);
+    }
+
+    fn evicted(self, entries: usize) {
+        metrics::counter!("beam_cert_cache_evictions_total", "cache" => self.label()).increment(entries as u64);
+    }
+
+    fn set_size(self, entries: usize) {
+        metrics::gauge!("beam_cert_cache_size", "cache" => self.label()).set(entries as f64);
+    }
+}
+
+/// Recreates an error which another caller waiting for the same request also got
+pub(crate) fn shared_error(e: &SamplyBeamError) -> SamplyBeamError {
+    match e {
+        SamplyBeamError::VaultSealed => SamplyBeamError::VaultSealed,
+        SamplyBeamError::VaultNotInitialized => SamplyBeamError::VaultNotInitialized,
+        SamplyBeamError::VaultCircuitOpen(retry_in) => SamplyBeamError::VaultCircuitOpen(*retry_in),
+        SamplyBeamError::VaultGaveUp { attempts, elapsed } => SamplyBeamError::VaultGaveUp { attempts: *attempts, elapsed: *elapsed },
+        SamplyBeamError::VaultRequestCancelled => SamplyBeamError::VaultRequestCancelled,
+        SamplyBeamError::VaultAuthError(e) => SamplyBeamError::VaultAuthError(e.clone()),
+        SamplyBeamError::VaultRedirectError(code, location) => SamplyBeamError::VaultRedirectError(*code, location.clone()),
+        SamplyBeamError::VaultOtherError(e) => SamplyBeamError::VaultOtherError(e.clone()),
+        SamplyBeamError::CertificateNotFound(serial) => SamplyBeamError::CertificateNotFound(serial.clone()),
+        SamplyBeamError::CertificateFingerprintNotFound(fingerprint) => SamplyBeamError::CertificateFingerprintNotFound(fingerprint.clone()),
+        other => SamplyBeamError::VaultOtherError(other.to_string()),
+    }
+}
+
+/// Whether the error means that Vault could not be asked, as opposed to Vault rejecting the request
+fn is_vault_unavailable(e: &SamplyBeamError) -> bool {
+    matches!(
+        e,
+        SamplyBeamError::VaultSealed
+            | SamplyBeamError::VaultUnreachable(_)
+            | SamplyBeamError::VaultNotInitialized
+            | SamplyBeamError::VaultCircuitOpen(_)
+            | SamplyBeamError::VaultGaveUp { .. }
+    )
+}
+
+/// How [`GetCertsFromPki::resilient_vault_request`] handles a response from Vault
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ResponseAction {
+    /// Up to the caller
+    Return,
+    Retry,
+    Fail,
+}
+
+fn response_action(code: StatusCode, operation: VaultOperation, retryable: &RetryableStatusCodes) -> ResponseAction {
+    // A certificate which does not exist is up to the caller as well
+    if code.is_success() || (code == StatusCode::NOT_FOUND && operation == VaultOperation::Fetch) {
+        ResponseAction::Return
+    } else if retryable.contains(code) {
+        ResponseAction::Retry
+    } else {
+        ResponseAction::Fail
+    }
+}
+
+/// The kinds of requests we send to Vault, each with its own retry budget
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum VaultOperation {
+    List,
+    Fetch,
+    Health,
+    Ca,
+}
+
+impl VaultOperation {
+    fn max_tries(self, budgets: &VaultRetryBudgets) -> u32 {
+        let budget = match self {
+            VaultOperation::List => budgets.list,
+            VaultOperation::Fetch => budgets.fetch,
+            VaultOperation::Health => budgets.health,
+            VaultOperation::Ca => budgets.ca,
+        };
+        budget.min(budgets.max_retries.saturating_add(1))
+    }
+
+    /// Whether the request fails immediately while Vault is considered down. Fetching the CA certificate
+    /// is exempt as the broker waits for it at startup.
+    fn uses_circuit_breaker(self) -> bool {
+        matches!(self, VaultOperation::List | VaultOperation::Fetch)
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            VaultOperation::List => "list",
+            VaultOperation::Fetch => "fetch",
+            VaultOperation::Health => "health",
+            VaultOperation::Ca => "ca",
+        }
+    }
+
+    /// Records a single attempt in the `beam_vault_requests_total` counter and the
+    /// `beam_vault_request_duration_seconds` histogram
+    fn record_attempt(self, outcome: &'static str, duration: Duration) {
+        metrics::counter!("beam_vault_requests_total", "operation" => self.label(), "outcome" => outcome).increment(1);
+        metrics::histogram!("beam_vault_request_duration_seconds", "operation" => self.label()).record(duration);
+    }
+
+    /// Records an attempt which failed without a response, also in `beam_vault_connection_errors_total` by its kind
+    fn record_connection_error(self, kind: HttpErrorKind, duration: Duration) {
+        self.record_attempt("unreachable", duration);
+        metrics::counter!("beam_vault_connection_errors_total", "operation" => self.label(), "kind" => kind.label()).increment(1);
+    }
 }
 
 #[derive(Debug, Deserialize, Clone, Hash)]
@@ -32,16 +356,358 @@ struct PkiListResponse {
     request_id: String,
     lease_id: String,
     renewable: bool,
-    lease_duration: u32,
+    lease_duration: u64,
     data: KeyHolder,
     wrap_info: Option<String>,
-    warnings: Option<String>,
+    warnings: Option<Vec<String>>,
     auth: Option<String>,
 }
 
+/// Vault's response to logins and token renewals
+#[derive(Debug, Deserialize)]
+struct LoginResponse {
+    auth: LoginToken,
+    warnings: Option<Vec<String>>,
+}
+
+/// Passes on warnings Vault attached to its response, e.g. about deprecations or policies
+fn log_vault_warnings(api_path: &str, warnings: Option<&[String]>) {
+    for warning in warnings.unwrap_or_default() {
+        warn!("Samply.PKI: Vault warned about {api_path}: {warning}");
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct LoginToken {
+    client_token: String,
+    lease_duration: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenLookupResponse {
+    data: TokenLookup,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenLookup {
+    /// Remaining seconds until the token expires, 0 if it does not expire
+    ttl: u64,
+    renewable: bool,
+}
+
+impl VaultClient {
+    /// How often to check whether there is a token to renew if its lease is unknown
+    const NO_LEASE_RECHECK: Duration = Duration::from_secs(60);
+
+    fn new_addresses(api_bases: Vec<Url>) -> (Vec<Url>, AtomicUsize, Vec<AtomicBool>) {
+        let healthy = api_bases.iter().map(|_| AtomicBool::new(true)).collect();
+        (api_bases, AtomicUsize::new(0), healthy)
+    }
+
+    fn current_address(&self) -> usize {
+        self.current.load(Ordering::Relaxed)
+    }
+
+    fn pki_url(&self, location: &str) -> Result<Url, SamplyBeamError> {
+        self.api_url(self.current_address(), location)
+    }
+
+    fn api_url(&self, address: usize, location: &str) -> Result<Url, SamplyBeamError> {
+        self.api_bases[address]
+            .join(location)
+            .map_err(|e| SamplyBeamError::ConfigurationFailed(format!("Unable to build the URL of Vault's {location}: {e}")))
+    }
+
+    fn mark_healthy(&self, address: usize) {
+        self.healthy[address].store(true, Ordering::Relaxed);
+    }
+
+    /// Sends further requests to the next address which has not failed yet (or simply the next one if all have),
+    /// unless a concurrent request has already failed over
+    fn fail_over(&self, failed: usize) {
+        self.healthy[failed].store(false, Ordering::Relaxed);
+        let n = self.api_bases.len();
+        if n == 1 {
+            return;
+        }
+        let next = (1..n)
+            .map(|i| (failed + i) % n)
+            .find(|&i| self.healthy[i].load(Ordering::Relaxed))
+            .unwrap_or((failed + 1) % n);
+        if self.current.compare_exchange(failed, next, Ordering::Relaxed, Ordering::Relaxed).is_ok() {
+            warn!("Samply.PKI: Failing over from Vault at {} to {}", self.api_bases[failed], self.api_bases[next]);
+        }
+    }
+
+    /// Runs `fut` unless the broker is shutting down first
+    async fn unless_shutdown<F: Future>(&self, fut: F) -> Result<F::Output, SamplyBeamError> {
+        tokio::select! {
+            biased;
+            _ = self.shutdown.cancelled() => Err(SamplyBeamError::VaultRequestCancelled),
+            out = fut => Ok(out),
+        }
+    }
+
+    /// Returns false if a static token is used
+    fn logs_in(&self) -> bool {
+        !matches!(self.pki_auth, VaultAuth::Token(_))
+    }
+
+    /// Obtains a new token which is then shared by all requests
+    async fn login(&self) -> Result<(), SamplyBeamError> {
+        let (method, role, credentials) = match self.pki_auth {
+            VaultAuth::Token(_) => return Ok(()),
+            VaultAuth::AppRole { ref role_id, ref secret_id } => ("approle", role_id, json!({ "role_id": role_id, "secret_id": secret_id })),
+            VaultAuth::Kubernetes { ref role, ref token_file } => {
+                let jwt = tokio::fs::read_to_string(token_file).await.map_err(|e| {
+                    SamplyBeamError::VaultAuthError(format!("Unable to read the service account token at {}: {e}", token_file.display()))
+                })?;
+                ("kubernetes", role, json!({ "role": role, "jwt": jwt.trim() }))
+            },
+        };
+        debug!("Samply.PKI: Logging in to Vault with {method} role {role}");
+        let resp = self.unless_shutdown(self
+            .request(Method::POST, self.pki_url(&format!("auth/{method}/login"))?)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(credentials.to_string())
+            .send())
+            .await?
+            .map_err(|e| SamplyBeamError::VaultAuthError(format!("Unable to reach Vault: {e}")))?;
+        let status = resp.status();
+        if !status.is_success() {
+            return Err(SamplyBeamError::VaultAuthError(format!("Vault rejected the {method} login with code {status}")));
+        }
+        let body = resp.bytes().await?;
+        let login: LoginResponse = serde_json::from_slice(&body)
+            .map_err(|e| SamplyBeamError::VaultAuthError(format!("Cannot deserialize Vault's login response: {e}")))?;
+        log_vault_warnings(&format!("auth/{method}/login"), login.warnings.as_deref());
+        token_header(&login.auth.client_token)
+            .map_err(|_| SamplyBeamError::VaultAuthError(format!("Vault returned a token which is not a valid header value after the {method} login")))?;
+        info!("Samply.PKI: Logged in to Vault with {method} role {role}; the token is valid for {} seconds", login.auth.lease_duration);
+        self.pki_token.store(Arc::new(login.auth.client_token));
+        self.token_lease.store(login.auth.lease_duration, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Logs in again as `rejected` has been rejected or is missing (i.e. empty), unless it has been replaced by a login in the meantime.
+    /// Concurrent callers share a single login, so that a burst of rejected requests does not log in once for each of them.
+    async fn login_once(&self, rejected: &str) -> Result<(), SamplyBeamError> {
+        let pending = {
+            let mut in_flight = self.pending_login.lock().unwrap();
+            if self.pki_token.load().as_str() != rejected {
+                return Ok(());
+            }
+            in_flight.get_or_insert_with(Default::default).clone()
+        };
+        // Should the caller logging in be cancelled, one of the waiting callers takes over
+        let result = pending
+            .get_or_init(|| async { self.login().await.map_err(Arc::new) })
+            .await
+            .clone();
+        let mut in_flight = self.pending_login.lock().unwrap();
+        // A later rejection must lead to a new login rather than this result
+        if in_flight.as_ref().is_some_and(|login| Arc::ptr_eq(login, &pending)) {
+            in_flight.take();
+        }
+        drop(in_flight);
+        drop(pending);
+        result.map_err(|e| Arc::try_unwrap(e).unwrap_or_else(|e| shared_error(&e)))
+    }
+
+    async fn send_with_token(&self, method: &Method, uri: &Url) -> Result<Result<reqwest::Response, reqwest::Error>, SamplyBeamError> {
+        // Tokens are checked when they are obtained, so this only fails if that has been missed
+        let token = token_header(&self.pki_token.load())
+            .map_err(|e| SamplyBeamError::HttpRequestBuildError(format!("Vault token is not a valid header value: {e}")))?;
+        self.unless_shutdown(self
+            .request(method.clone(), uri.clone())
+            .header("X-Vault-Token", token)
+            .send())
+            .await
+    }
+
+    /// Starts a request to the PKI mount or an auth method with the headers all of them need
+    fn request(&self, method: Method, uri: Url) -> reqwest::RequestBuilder {
+        let request = self.hyper_client.load().request(method, uri).header(header::USER_AGENT, &self.user_agent);
+        match &self.namespace {
+            Some(namespace) => request.header("X-Vault-Namespace", namespace),
+            None => request,
+        }
+    }
+
+    /// Sends the request with the current token. Unless a static token is used, we log in first
+    /// if we have no token yet and log in again once if Vault rejects the token, e.g. as it has expired.
+    async fn send_authenticated(&self, method: &Method, uri: &Url) -> Result<Result<reqwest::Response, reqwest::Error>, SamplyBeamError> {
+        if !self.logs_in() {
+            return self.send_with_token(method, uri).await;
+        }
+        if self.pki_token.load().is_empty() {
+            self.login_once("").await?;
+        }
+        let token = self.pki_token.load_full();
+        let resp = self.send_with_token(method, uri).await?;
+        if !matches!(resp, Ok(ref resp) if resp.status() == StatusCode::FORBIDDEN) {
+            return Ok(resp);
+        }
+        info!("Samply.PKI: Vault rejected our token; logging in again");
+        self.login_once(&token).await?;
+        self.send_with_token(method, uri).await
+    }
+
+    /// Extends the token's lease via `auth/token/renew-self` and returns the new lease duration
+    async fn renew(&self) -> Result<u64, SamplyBeamError> {
+        let resp = self.send_with_token(&Method::POST, &self.pki_url("auth/token/renew-self")?).await??;
+        let status = resp.status();
+        if !status.is_success() {
+            return Err(SamplyBeamError::VaultAuthError(format!("Vault refused to renew the token with code {status}")));
+        }
+        let body = resp.bytes().await?;
+        let renewed: LoginResponse = serde_json::from_slice(&body)
+            .map_err(|e| SamplyBeamError::VaultAuthError(format!("Cannot deserialize Vault's renewal response: {e}")))?;
+        log_vault_warnings("auth/token/renew-self", renewed.warnings.as_deref());
+        self.token_lease.store(renewed.auth.lease_duration, Ordering::Relaxed);
+        Ok(renewed.auth.lease_duration)
+    }
+
+    /// Finds out whether the static token expires and can be renewed via `auth/token/lookup-self`
+    async fn look_up_static_token(&self) -> Result<(), SamplyBeamError> {
+        let resp = self.send_with_token(&Method::GET, &self.pki_url("auth/token/lookup-self")?).await??;
+        let status = resp.status();
+        if !status.is_success() {
+            return Err(SamplyBeamError::VaultAuthError(format!("Vault refused to look up the token with code {status}")));
+        }
+        let body = resp.bytes().await?;
+        let lookup: TokenLookupResponse = serde_json::from_slice(&body)
+            .map_err(|e| SamplyBeamError::VaultAuthError(format!("Cannot deserialize Vault's token lookup: {e}")))?;
+        if lookup.data.renewable {
+            self.token_lease.store(lookup.data.ttl, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    fn build_http_client(ca_certificates: &Vec<reqwest::Certificate>) -> Result<SamplyHttpClient, SamplyBeamError> {
+        Self::build_http_client_with(
+            ca_certificates,
+            config::CONFIG_SHARED.tls_client_identity.as_ref(),
+            &ConnectionSettings { http2: config::CONFIG_CENTRAL.pki_http2, ..config::CONFIG_SHARED.http_connection.clone() },
+            config::CONFIG_CENTRAL.pki_pool_idle_timeout,
+            config::CONFIG_CENTRAL.pki_pool_max_idle_per_host,
+        )
+    }
+
+    /// Builds the client for Vault from the shared `connection` settings and the pool settings for Vault (`PKI_POOL_*`)
+    fn build_http_client_with(
+        ca_certificates: &Vec<reqwest::Certificate>,
+        client_identity: Option<&ClientIdentity>,
+        connection: &ConnectionSettings,
+        pool_idle_timeout: Option<Duration>,
+        pool_max_idle_per_host: Option<usize>,
+    ) -> Result<SamplyHttpClient, SamplyBeamError> {
+        http_client::build(
+            ca_certificates,
+            client_identity,
+            &connection
+                .clone()
+                .with_default_connect_timeout(Duration::from_secs(30))
+                .with_pool(pool_idle_timeout, pool_max_idle_per_host)
+                .with_user_agent(env!("SAMPLY_USER_AGENT")),
+            Some(Duration::from_secs(20)),
+            &[],
+        )
+    }
+
+    /// Rebuilds the HTTP client with the new CA certificates whenever files in `ca_dir` or the `ca_bundle` change,
+    /// e.g. when cert-manager rotates them. Runs until the broker shuts down.
+    async fn reload_ca_certificates_on_change(
+        self: Arc<Self>,
+        ca_dir: Option<PathBuf>,
+        ca_bundle: Option<PathBuf>,
+        strict: bool,
+        build: impl Fn(&Vec<reqwest::Certificate>) -> Result<SamplyHttpClient, SamplyBeamError>,
+    ) {
+        let (_watcher, mut changes) = match watch_ca_certificates(ca_dir.as_deref(), ca_bundle.as_deref()) {
+            Ok(watching) => watching,
+            Err(e) => {
+                warn!("Unable to watch the CA certificates for changes, they are only loaded at startup: {e}");
+                return;
+            }
+        };
+        loop {
+            tokio::select! {
+                _ = self.shutdown.cancelled() => return,
+                change = next_ca_change(&mut changes) => if change.is_none() { return },
+            }
+            match self.reload_ca_certificates(ca_dir.clone(), ca_bundle.as_deref(), strict, &build) {
+                Ok(count) => info!("Reloaded {count} CA certificates"),
+                Err(e) => warn!("Keeping the previous CA certificates as the changed ones cannot be used: {e}"),
+            }
+        }
+    }
+
+    /// Replaces the HTTP client with one trusting the CA certificates currently in `ca_dir` and `ca_bundle`,
+    /// returning their number. The previous client is kept if they cannot be loaded.
+    fn reload_ca_certificates(
+        &self,
+        ca_dir: Option<PathBuf>,
+        ca_bundle: Option<&Path>,
+        strict: bool,
+        build: &impl Fn(&Vec<reqwest::Certificate>) -> Result<SamplyHttpClient, SamplyBeamError>,
+    ) -> Result<usize, SamplyBeamError> {
+        let certs = shared::crypto::load_tls_ca_certificates(ca_dir, ca_bundle, strict)?;
+        self.hyper_client.store(Arc::new(build(&certs)?));
+        Ok(certs.len())
+    }
+
+    /// Renews the token after about two thirds of its lease so that requests do not run into an expired token.
+    /// If renewing fails, we log in again unless a static token is used. Runs until the broker shuts down.
+    async fn keep_token_alive(self: Arc<Self>) {
+        if !self.logs_in() {
+            if let Err(e) = self.look_up_static_token().await {
+                warn!("Samply.PKI: Unable to find out whether the Vault token expires, not renewing it: {e}");
+                return;
+            }
+            if self.token_lease.load(Ordering::Relaxed) == 0 {
+                debug!("Samply.PKI: The Vault token does not expire or cannot be renewed");
+                return;
+            }
+        }
+        loop {
+            let lease = self.token_lease.load(Ordering::Relaxed);
+            // Without a known lease (e.g. as logging in has failed so far) we check again later
+            let wait = if lease == 0 { Self::NO_LEASE_RECHECK } else { Duration::from_secs(lease) * 2 / 3 };
+            if self.unless_shutdown(tokio::time::sleep(wait)).await.is_err() {
+                return;
+            }
+            if self.token_lease.load(Ordering::Relaxed) == 0 {
+                continue;
+            }
+            match self.renew().await {
+                Ok(lease) => debug!("Samply.PKI: Renewed the Vault token for {lease} seconds"),
+                Err(SamplyBeamError::VaultRequestCancelled) => return,
+                Err(e) if self.logs_in() => {
+                    warn!("Samply.PKI: Unable to renew the Vault token: {e}; logging in again");
+                    if let Err(e) = self.login_once(&self.pki_token.load_full()).await {
+                        warn!("{e}; retrying with the next request to Vault");
+                        self.token_lease.store(0, Ordering::Relaxed);
+                    }
+                },
+                Err(e) => {
+                    error!("Samply.PKI: Unable to renew the Vault token, requests will fail once it expires: {e}");
+                    return;
+                },
+            }
+        }
+    }
+}
+
 impl GetCertsFromPki {
-    pub(crate) fn new(
+    /// Logs in right away unless a static token is configured.
+    /// If that fails, logging in is retried with the first request to Vault.
+    pub(crate) async fn new(
+        pki_addresses: Vec<Url>,
+        pki_auth: VaultAuth,
         health_report_sender: tokio::sync::watch::Sender<health::VaultStatus>,
+        clock_skew_sender: tokio::sync::watch::Sender<Option<i64>>,
+        shutdown: CancellationToken,
     ) -> Result<Self, SamplyBeamError> {
         let mut certs: Vec<String> = Vec::new();
         if let Some(dir) = &config::CONFIG_CENTRAL.tls_ca_certificates_dir {
@@ -55,19 +721,84 @@ impl GetCertsFromPki {
                     certs.push(file.path().to_str().unwrap().into());
                 }
             }
+        }
+        if let Some(bundle) = &config::CONFIG_CENTRAL.tls_ca_certificates_file {
+            certs.push(bundle.to_string_lossy().into());
+        }
+        if !certs.is_empty() {
             debug!("Loaded local certificates: {}", certs.join(" "));
         }
-        let hyper_client = http_client::build(
-            &config::CONFIG_SHARED.tls_ca_certificates,
-            Some(Duration::from_secs(30)),
-            Some(Duration::from_secs(20)),
-        )?;
+        let hyper_client = VaultClient::build_http_client(&config::CONFIG_SHARED.tls_ca_certificates)?;
         let pki_realm = config::CONFIG_CENTRAL.pki_realm.clone();
+        let pki_token = match pki_auth {
+            VaultAuth::Token(ref token) => {
+                token_header(token).map_err(|_| {
+                    SamplyBeamError::ConfigurationFailed("The PKI API key contains characters which cannot be sent in an HTTP header".into())
+                })?;
+                token.clone()
+            }
+            VaultAuth::AppRole { .. } | VaultAuth::Kubernetes { .. } => String::new(),
+        };
+
+        let (api_bases, current, healthy) = VaultClient::new_addresses(pki_addresses.iter().map(vault_api_base).collect::<Result<_, _>>()?);
+        let vault = Arc::new(VaultClient {
+            api_bases,
+            current,
+            healthy,
+            pki_auth,
+            pki_token: ArcSwap::from_pointee(pki_token),
+            token_lease: AtomicU64::new(0),
+            pending_login: Default::default(),
+            user_agent: config::CONFIG_CENTRAL.pki_user_agent.clone().unwrap_or_else(|| {
+                http_client::user_agent_with_suffix(DEFAULT_PKI_USER_AGENT, config::CONFIG_SHARED.http_connection.user_agent_suffix.as_ref())
+            }),
+            namespace: config::CONFIG_CENTRAL.pki_namespace.clone(),
+            hyper_client: ArcSwap::from_pointee(hyper_client),
+            shutdown,
+        });
+        // Fails now rather than with the first health check
+        vault.pki_url(&config::CONFIG_CENTRAL.pki_health_path)?;
+        if vault.logs_in() {
+            if let Err(e) = vault.login().await {
+                warn!("{e}; retrying with the first request to Vault");
+            }
+        }
 
         Ok(Self {
+            vault,
             pki_realm,
-            hyper_client,
             health_report_sender,
+            clock_skew_sender,
+            retry_budgets: config::CONFIG_CENTRAL.pki_retry_budgets,
+            retry_backoff: config::CONFIG_CENTRAL.pki_retry_backoff,
+            retry_deadline: config::CONFIG_CENTRAL.pki_retry_deadline,
+            attempt_timeout: config::CONFIG_CENTRAL.pki_attempt_timeout,
+            retry_status_codes: config::CONFIG_CENTRAL.pki_retry_status_codes.clone(),
+            circuit_breaker: CircuitBreaker::new(config::CONFIG_CENTRAL.pki_circuit_breaker),
+            request_limit: VaultRequestLimit::new(config::CONFIG_CENTRAL.pki_max_concurrent_requests),
+            response_limits: config::CONFIG_CENTRAL.pki_response_limits,
+            not_found_cache_ttl: config::CONFIG_CENTRAL.pki_not_found_cache_ttl,
+            missing_certificates: Default::default(),
+            fingerprints: Default::default(),
+            missing_fingerprints: Default::default(),
+            health_path: config::CONFIG_CENTRAL.pki_health_path.clone(),
+            health_cache_ttl: config::CONFIG_CENTRAL.pki_health_cache_ttl,
+            last_health_check: Default::default(),
+            reject_expired_certs: config::CONFIG_CENTRAL.pki_reject_expired_certs,
+            serve_stale_on_error: config::CONFIG_CENTRAL.pki_serve_stale_on_error,
+            fetch_concurrency: config::CONFIG_CENTRAL.pki_fetch_concurrency,
+            cache_ttl_bounds: config::CONFIG_CENTRAL.pki_cache_ttl,
+            max_clock_skew: config::CONFIG_CENTRAL.pki_max_clock_skew,
+            refresh_interval: config::CONFIG_CENTRAL.pki_refresh_interval,
+            cache_ttl: AtomicU64::new(config::CONFIG_CENTRAL.pki_cache_ttl.default.as_secs()),
+            certificate_list: ArcSwapOption::empty(),
+            pending_certificates: Default::default(),
+            prefetched_certificates: Default::default(),
+            known_good_certificates: Default::default(),
+            crl_settings: config::CONFIG_CENTRAL.pki_crl.clone(),
+            crl: Default::default(),
+            ocsp: config::CONFIG_CENTRAL.pki_ocsp.clone().map(OcspChecker::new),
+            background_tasks: Default::default(),
         })
     }
 
@@ -82,8 +813,33 @@ impl GetCertsFromPki {
         });
     }
 
+    /// Compares our clock with the Date header of a Vault response and warns once the deviation becomes too large
+    fn check_clock_skew(&self, resp: &reqwest::Response) {
+        let Some(skew) = resp.headers().get(header::DATE).and_then(|date| clock_skew(date, SystemTime::now())) else {
+            return;
+        };
+        let previous = self.clock_skew_sender.send_replace(Some(skew));
+        log_clock_skew_change(previous, skew, self.max_clock_skew);
+    }
+
+    /// Concurrent callers share a single health check whose result is reused for a short while.
+    /// If the current address is not healthy, e.g. because it is a sealed or standby node, further requests go to the next one.
     pub(crate) async fn check_vault_health(&self) -> Result<(), SamplyBeamError> {
-        let state = self.check_vault_health_helper().await;
+        let mut last_check = self.last_health_check.lock().await;
+        let address = self.vault.current_address();
+        if let Some(cached) = last_check.as_ref().filter(|cached| cached.address == address) {
+            let ttl = match cached.result {
+                Err(ref e) if matches!(**e, SamplyBeamError::VaultSealed | SamplyBeamError::VaultNotInitialized) => {
+                    self.health_cache_ttl.min(SEALED_HEALTH_CACHE_TTL)
+                }
+                _ => self.health_cache_ttl,
+            };
+            if cached.checked_at.elapsed() < ttl {
+                debug!("Using the result of Vault's health check from {:.1?} ago", cached.checked_at.elapsed());
+                return cached.result.clone().map_err(|e| shared_error(&e));
+            }
+        }
+        let state = self.check_vault_health_helper(address).await;
         let monitoring_status = match state {
             Ok(_) => VaultStatus::Ok,
             Err(ref e) => match e {
@@ -95,15 +851,40 @@ impl GetCertsFromPki {
             },
         };
         self.report_vault_health(monitoring_status).await;
+        if matches!(state, Err(SamplyBeamError::VaultRequestCancelled)) {
+            return state;
+        }
+        match state {
+            Ok(()) => self.vault.mark_healthy(address),
+            Err(_) => self.vault.fail_over(address),
+        }
+        let result = state.as_ref().map_err(|e| Arc::new(shared_error(e))).copied();
+        *last_check = Some(CachedHealth { address, checked_at: Instant::now(), result });
         state
     }
 
-    async fn check_vault_health_helper(&self) -> Result<(), SamplyBeamError> {
-        let url = pki_url_builder("sys/health");
+    /// Interprets the status codes of Vault's `sys/health`: 2xx is healthy, 501 means that Vault is not initialized
+    /// and 503 that it is sealed. Standby nodes answer 429 (473 for performance standbys) and are therefore
+    /// considered faulty unless the `standbyok` (`perfstandbyok`) query parameter is part of PKI_HEALTH_PATH.
+    async fn check_vault_health_helper(&self, address: usize) -> Result<(), SamplyBeamError> {
+        // Vault's health is only reported in the root namespace, so this request is sent without one
+        let url = self.vault.api_url(address, &self.health_path)?;
         debug!("Checking Vault's health at URL {url}");
-        let health = self.hyper_client.get(url).send().await;
-        let Ok(resp) = health else {
-            return Err(SamplyBeamError::VaultUnreachable(health.unwrap_err()));
+        let max_tries = VaultOperation::Health.max_tries(&self.retry_budgets);
+        let mut tries = 0;
+        let resp = loop {
+            tries += 1;
+            match self.vault.unless_shutdown(self.vault.hyper_client.load().get(url.clone()).header(header::USER_AGENT, &self.vault.user_agent).send()).await? {
+                Ok(resp) => {
+                    self.check_clock_skew(&resp);
+                    break resp;
+                },
+                Err(e) if tries >= max_tries => return Err(SamplyBeamError::VaultUnreachable(e)),
+                Err(e) => {
+                    warn!("Samply.PKI: Unable to check Vault's health: {e}; retrying (failed attempt #{tries})");
+                    self.vault.unless_shutdown(tokio::time::sleep(self.retry_backoff.delay_before_retry(tries))).await?;
+                }
+            }
         };
         match resp.status() {
             code if code.is_success() => Ok(()),
@@ -124,128 +905,625 @@ impl GetCertsFromPki {
         }
     }
 
-    async fn resilient_vault_request(
-        &self,
-        method: &Method,
-        api_path: &str,
-        max_tries: Option<u32>,
-    ) -> Result<reqwest::Response, SamplyBeamError> {
-        let uri = pki_url_builder(api_path);
-        debug!("Samply.PKI: Vault request to {uri}");
-        let max_tries = max_tries.unwrap_or(u32::MAX);
-        for tries in 0..max_tries {
-            if tries > 0 {
-                tokio::time::sleep(Duration::from_secs(3)).await;
-            }
-            let resp = self.hyper_client
-                .request(method.clone(), uri.clone())
-                .header("X-Vault-Token", &config::CONFIG_CENTRAL.pki_token)
-                .header("User-Agent", env!("SAMPLY_USER_AGENT"))
-                .send()
-                .await;
-            let Ok(resp) = resp else {
-                warn!("Samply.PKI: Unable to communicate to vault: {}; retrying (failed attempt #{})", resp.unwrap_err(), tries+2);
-                self.report_vault_health(VaultStatus::Unreachable).await;
-                continue;
-            };
-            match resp.status() {
-                code if code.is_success() => {
-                    self.report_vault_health(VaultStatus::Ok).await;
-                    return Ok(resp);
-                }
-                code if code.is_client_error() || code.is_redirection() => {
-                    error!(
-                        "Samply.PKI: Vault reported client-side Error (code {}), not retrying. Response was {}",
-                        code, resp.text().await.unwrap_or_else(|e| format!("Failed to decode failed response: {e}"))
-                    );
-                    self.report_vault_health(VaultStatus::OtherError).await;
-                    return Err(SamplyBeamError::VaultOtherError(format!(
-                        "Samply.PKI: Vault reported client-side error (code {})",
-                        code
-                    )));
-                }
-                code => {
-                    match self.check_vault_health().await {
-                        Err(SamplyBeamError::VaultSealed) => {
-                            warn!(
-                                "Samply.PKI: Vault is still sealed; retrying (failed attempt {})",
-                                tries
-                            );
-                            continue;
-                        }
-                        Err(SamplyBeamError::VaultRedirectError(code, location)) => {
-                            let err = SamplyBeamError::VaultRedirectError(code, location);
-                            error!("Samply.PKI asked to redirect; aborting: {err}");
-                            return Err(err);
-                        }
-                        Err(e) => {
-                            warn!("Samply.PKI: Got error from Vault: {}; status code {}; retrying (failed attempt #{})", e, code, tries);
-                            continue;
-                        }
-                        Ok(()) => {
-                            debug!("Got code {} communicating with Samply.PKI fetching URL {api_path}.", code);
-                            continue;
-                        }
-                    }
-                }
-            }
-        }
-        let err = format!(
-            "Samply.PKI: Unable to communicate after {} attempts. Giving up.",
-            max_tries
-        );
-        error!(err);
-        Err(SamplyBeamError::VaultOtherError(err))
-    }
-}
-
-#[async_trait]
-impl GetCerts for GetCertsFromPki {
-    async fn certificate_list_via_network(&self) -> Result<Vec<String>, SamplyBeamError> {
-        debug!("Getting Cert List via network");
-        let resp = self
-            .resilient_vault_request(
-                &Method::from_bytes("LIST".as_bytes()).unwrap(),
-                &format!("{}/certs", &config::CONFIG_CENTRAL.pki_realm),
-                Some(100),
-            )
-            .await?;
-        let body: PkiListResponse = serde_json::from_slice(&resp.bytes().await?).map_err(|e| {
-            SamplyBeamError::VaultOtherError(format!(
-                "Cannot deserialize vault certificate list: {}",
-                e
-            ))
-        })?;
-        debug!("Got cert list with {} elements", body.data.keys.len());
-        return Ok(body.data.keys);
-    }
-
-    async fn certificate_by_serial_as_pem(&self, serial: &str) -> Result<String, SamplyBeamError> {
+    async fn fetch_certificate_by_serial(&self, serial: &str) -> Result<String, SamplyBeamError> {
         debug!("Getting Cert with serial {}", serial);
         let resp = self
             .resilient_vault_request(
                 &Method::GET,
                 &format!("{}/cert/{}/raw/pem", &self.pki_realm, serial),
-                Some(100),
+                VaultOperation::Fetch,
+                self.response_limits.single,
             )
             .await?;
-        Ok(resp.text().await?)
+        // Vault answers unknown serials with 204, older versions with 404
+        if matches!(resp.status(), StatusCode::NOT_FOUND | StatusCode::NO_CONTENT) {
+            return Err(SamplyBeamError::CertificateNotFound(serial.to_owned()));
+        }
+        read_limited_text(resp, self.response_limits.single).await
     }
 
-    async fn im_certificate_as_pem(&self) -> Result<String, SamplyBeamError> {
-        debug!("Getting IM CA Cert");
-        let resp = self
-            .resilient_vault_request(
-                &Method::GET,
-                &format!("{}/ca/pem", self.pki_realm),
-                Some(100),
-            )
-            .await?;
-        Ok(resp.text().await?)
+    /// Runs a background task which must return once the broker shuts down, e.g. via [`VaultClient::unless_shutdown`]
+    fn spawn(&self, task: impl Future<Output = ()> + Send + 'static) {
+        let mut tasks = self.background_tasks.lock().unwrap();
+        tasks.retain(|task| !task.is_finished());
+        tasks.push(tokio::spawn(task));
     }
 
-    async fn on_timer(&self, cache: &mut CertificateCache) -> CertificateCacheUpdate {
-        let result = cache.update_certificates_mut().await;
+    /// Fetches the certificate list and all certificates on it so that the certificate cache can be filled
+    /// without waiting for Vault. Failures are only logged as the certificates are fetched again when needed.
+    pub(crate) async fn warm_cache(&self) {
+        let started = Instant::now();
+        let serials = match self.certificate_list_via_network().await {
+            Ok(serials) => serials,
+            Err(e) => {
+                warn!("Unable to prefetch the certificate list: {e}");
+                return;
+            }
+        };
+        let fetched = self.certificates_by_serials(&serials).await;
+        let mut failed = 0;
+        let mut prefetched = self.prefetched_certificates.lock().unwrap();
+        for (serial, pem) in fetched {
+            match pem {
+                Ok(pem) => {
+                    self.index_fingerprint(&serial, &pem);
+                    prefetched.insert(serial, pem);
+                }
+                Err(e) => {
+                    debug!("Unable to prefetch certificate {serial}: {e}");
+                    failed += 1;
+                }
+            }
+        }
+        CertCache::Prefetched.set_size(prefetched.len());
+        info!("Prefetched {} certificates ({failed} failed) in {:.1?}", prefetched.len(), started.elapsed());
+    }
+
+    /// Polls Vault's health with backoff while it is sealed or not initialized, so that the broker does not fail at startup
+    /// because Vault has not been unsealed yet. Gives up with the last error once `timeout` has passed.
+    /// Other failures are left to the requests that follow.
+    pub(crate) async fn wait_for_vault(&self, timeout: Duration) -> Result<(), SamplyBeamError> {
+        let started = Instant::now();
+        let mut tries = 0;
+        loop {
+            match self.check_vault_health().await {
+                Err(e @ (SamplyBeamError::VaultSealed | SamplyBeamError::VaultNotInitialized)) => {
+                    if started.elapsed() >= timeout {
+                        error!("{e} Gave up waiting for Vault after {:.1?}", started.elapsed());
+                        return Err(e);
+                    }
+                    tries += 1;
+                    let delay = self.retry_backoff.delay_before_retry(tries).min(timeout.saturating_sub(started.elapsed()));
+                    info!("{e} Waiting for it to be unsealed ({:.0?} so far)", started.elapsed());
+                    self.vault.unless_shutdown(tokio::time::sleep(delay)).await?;
+                }
+                Err(SamplyBeamError::VaultRequestCancelled) => return Err(SamplyBeamError::VaultRequestCancelled),
+                _ => {
+                    if tries > 0 {
+                        info!("Vault is available after {:.1?}", started.elapsed());
+                    }
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Checks the PKI settings against Vault for `--check-config`: that the addresses form valid URLs, Vault is healthy,
+    /// the credentials are accepted and the realm's certificates can be listed. Checks are skipped once one they rely on has failed.
+    pub(crate) async fn validate(&self) -> Vec<ConfigCheck> {
+        let mut checks = Vec::new();
+        for (address, base) in self.vault.api_bases.iter().enumerate() {
+            let result = self.vault.api_url(address, &self.health_path)
+                .and_then(|_| self.vault.api_url(address, &format!("{}/certs", self.pki_realm)))
+                .map(drop);
+            checks.push(ConfigCheck::new(format!("Vault address {base}"), result));
+            if checks.last().is_some_and(ConfigCheck::passed) {
+                checks.push(ConfigCheck::new(format!("Vault health at {base}"), self.check_vault_health_helper(address).await));
+            }
+        }
+        let (auth, realm) = ("Vault authentication", format!("Certificate list of realm {}", self.pki_realm));
+        if !checks.iter().all(ConfigCheck::passed) {
+            checks.extend([ConfigCheck::skipped(auth), ConfigCheck::skipped(realm)]);
+            return checks;
+        }
+        let authenticated = if self.vault.logs_in() { self.vault.login().await } else { self.vault.look_up_static_token().await };
+        let authenticated = ConfigCheck::new(auth, authenticated);
+        let listed = if authenticated.passed() {
+            ConfigCheck::new(realm, self.refresh_certificate_list().await.map(drop))
+        } else {
+            ConfigCheck::skipped(realm)
+        };
+        checks.extend([authenticated, listed]);
+        checks
+    }
+
+    /// Remembers the serial of the certificate by its fingerprint and returns the fingerprint, or `None` if the certificate is garbled
+    fn index_fingerprint(&self, serial: &str, pem: &str) -> Option<(String, X509)> {
+        let cert = parse_single_certificate(pem).ok()?;
+        let fingerprint = sha256_fingerprint(&cert).ok()?;
+        self.fingerprints.lock().unwrap().insert(fingerprint.clone(), serial.to_owned());
+        Some((fingerprint, cert))
+    }
+
+    /// Fetches the certificate list from Vault, bypassing the cache, and caches it for the list's lease duration
+    pub(crate) async fn refresh_certificate_list(&self) -> Result<Vec<String>, SamplyBeamError> {
+        debug!("Getting Cert List via network");
+        let fetched_at = Instant::now();
+        let endpoint = format!("{}/certs", &self.pki_realm);
+        let resp = self
+            .resilient_vault_request(
+                &METHOD_LIST,
+                &endpoint,
+                VaultOperation::List,
+                self.response_limits.list,
+            )
+            .await?;
+        let body: PkiListResponse = serde_json::from_slice(&read_limited_body(resp, self.response_limits.list).await?)
+            .map_err(|source| SamplyBeamError::VaultDeserializationError { endpoint: endpoint.clone(), source })?;
+        log_vault_warnings(&endpoint, body.warnings.as_deref());
+        let ttl = self.cache_ttl_bounds.ttl_for_lease(body.lease_duration);
+        self.cache_ttl.store(ttl.as_secs(), Ordering::Relaxed);
+        debug!("Got cert list with {} elements, caching it for {} seconds", body.data.keys.len(), ttl.as_secs());
+        let previous = self.certificate_list.swap(Some(Arc::new(CachedCertificateList {
+            serials: body.data.keys.clone(),
+            fetched_at,
+            ttl,
+        })));
+        CertCache::List.set_size(body.data.keys.len());
+        // Prefetched certificates which are no longer listed will not be asked for
+        let mut prefetched = self.prefetched_certificates.lock().unwrap();
+        let before = prefetched.len();
+        prefetched.retain(|serial, _| body.data.keys.contains(serial));
+        if prefetched.len() < before {
+            CertCache::Prefetched.evicted(before - prefetched.len());
+            CertCache::Prefetched.set_size(prefetched.len());
+        }
+        drop(prefetched);
+        self.known_good_certificates.lock().unwrap().retain(|serial, _| body.data.keys.contains(serial));
+        // Newly issued certificates are fetched right away
+        self.missing_certificates.lock().unwrap().retain(|serial, _| !body.data.keys.contains(serial));
+        if previous.is_none_or(|previous| body.data.keys.iter().any(|serial| !previous.serials.contains(serial))) {
+            // Any of them might have a fingerprint which was not found before
+            self.missing_fingerprints.lock().unwrap().clear();
+        }
+        Ok(body.data.keys)
+    }
+
+    /// The cached CRL, fetched again once it is older than the refresh interval. While there is a CRL to fall back on,
+    /// refreshing it gets [`CRL_STALE_REFRESH_TIMEOUT`]; should it fail, the last CRL is used until the next refresh interval.
+    /// Without any CRL, certificates are considered not revoked if `fail_open` is set and the error is returned otherwise.
+    async fn current_crl(&self) -> Result<Option<Arc<X509Crl>>, SamplyBeamError> {
+        let mut cached = self.crl.lock().await;
+        if let Some(fresh) = cached.as_ref().filter(|c| c.fetched_at.elapsed() < self.crl_settings.refresh_interval) {
+            return Ok(fresh.crl.clone());
+        }
+        let fetched = if cached.is_some() {
+            tokio::time::timeout(CRL_STALE_REFRESH_TIMEOUT, self.fetch_crl())
+                .await
+                .unwrap_or_else(|elapsed| Err(SamplyBeamError::HttpTimeoutError(elapsed)))
+        } else {
+            self.fetch_crl().await
+        };
+        match fetched {
+            Ok(crl) => {
+                let crl = crl.map(Arc::new);
+                *cached = Some(CachedCrl { crl: crl.clone(), fetched_at: Instant::now() });
+                Ok(crl)
+            }
+            Err(SamplyBeamError::VaultRequestCancelled) => Err(SamplyBeamError::VaultRequestCancelled),
+            Err(e) => match cached.as_mut() {
+                Some(stale) => {
+                    warn!("Unable to refresh the CRL, using the last one for another {:.0?}: {e}", self.crl_settings.refresh_interval);
+                    stale.fetched_at = Instant::now();
+                    Ok(stale.crl.clone())
+                }
+                None if self.crl_settings.fail_open => {
+                    warn!("Unable to fetch the CRL, so revoked certificates are not detected: {e}");
+                    Ok(None)
+                }
+                None => Err(e),
+            },
+        }
+    }
+
+    /// The PKI's CRL or `None` if Vault has none, which it answers with `404 Not Found` as long as nothing has been revoked
+    async fn fetch_crl(&self) -> Result<Option<X509Crl>, SamplyBeamError> {
+        let Some(ref url) = self.crl_settings.url else {
+            debug!("Getting crl");
+            let resp = self.resilient_vault_request(
+                &Method::GET,
+                &format!("{}/crl", self.pki_realm),
+                VaultOperation::Fetch,
+                self.response_limits.list,
+            )
+            .await?;
+            if resp.status() == StatusCode::NOT_FOUND {
+                debug!("Vault has no CRL, so no certificate is revoked");
+                return Ok(None);
+            }
+            return parse_crl(&read_limited_body(resp, self.response_limits.list).await?).map(Some);
+        };
+        debug!("Getting crl from {url}");
+        let resp = self.vault
+            .unless_shutdown(self.vault.hyper_client.load().get(url.clone()).header(header::USER_AGENT, &self.vault.user_agent).send())
+            .await?
+            .map_err(|e| SamplyBeamError::VaultOtherError(format!("Unable to fetch the CRL from {url}: {e}")))?;
+        if !resp.status().is_success() {
+            return Err(SamplyBeamError::VaultOtherError(format!("Unable to fetch the CRL from {url}: code {}", resp.status())));
+        }
+        let crl = parse_crl(&read_limited_body(resp, self.response_limits.list).await?)?;
+        let issuer = parse_single_certificate(&self.im_certificate_as_pem().await?)?;
+        verify_crl(&crl, &issuer, url)?;
+        Ok(Some(crl))
+    }
+
+    /// Whether `key` was put into `missing` by [`Self::remember_missing`] less than `not_found_cache_ttl` ago
+    fn recently_missing(&self, missing: &Mutex<HashMap<String, Instant>>, key: &str) -> bool {
+        let mut missing = missing.lock().unwrap();
+        match missing.get(key) {
+            Some(since) if since.elapsed() < self.not_found_cache_ttl => true,
+            Some(_) => {
+                missing.remove(key);
+                false
+            }
+            None => false,
+        }
+    }
+
+    fn remember_missing(&self, missing: &Mutex<HashMap<String, Instant>>, key: &str) {
+        if self.not_found_cache_ttl.is_zero() {
+            return;
+        }
+        let mut missing = missing.lock().unwrap();
+        // Keys which are not asked for again would otherwise pile up
+        missing.retain(|_, since| since.elapsed() < self.not_found_cache_ttl);
+        missing.insert(key.to_owned(), Instant::now());
+    }
+
+    /// Hands out a prefetched certificate or fetches it, sharing the request to Vault with concurrent callers
+    async fn prefetched_or_shared_fetch(&self, serial: &str) -> Result<String, SamplyBeamError> {
+        let prefetched = {
+            let mut prefetched = self.prefetched_certificates.lock().unwrap();
+            let pem = prefetched.remove(serial);
+            if pem.is_some() {
+                CertCache::Prefetched.set_size(prefetched.len());
+            }
+            pem
+        };
+        if let Some(pem) = prefetched {
+            debug!("Using prefetched certificate {serial}");
+            CertCache::Prefetched.hit();
+            return Ok(pem);
+        }
+        CertCache::Prefetched.miss();
+        let pending = self.pending_certificates.lock().unwrap().entry(serial.to_owned()).or_default().clone();
+        // Should the caller fetching the certificate be cancelled, one of the waiting callers takes over
+        let result = pending
+            .get_or_init(|| async { self.fetch_certificate_by_serial(serial).await.map_err(Arc::new) })
+            .await
+            .clone();
+        let mut in_flight = self.pending_certificates.lock().unwrap();
+        // Later calls must not get this result but fetch the certificate anew
+        if in_flight.get(serial).is_some_and(|fetch| Arc::ptr_eq(fetch, &pending)) {
+            in_flight.remove(serial);
+        }
+        drop(in_flight);
+        drop(pending);
+        result.map_err(|e| Arc::try_unwrap(e).unwrap_or_else(|e| shared_error(&e)))
+    }
+
+    /// Counts an attempt which took longer than the attempt timeout as failed, like one which got no response at all
+    async fn attempt_timed_out(&self, operation: VaultOperation, address: usize, elapsed: Elapsed, tries: u32) {
+        operation.record_connection_error(HttpErrorKind::Timeout, self.attempt_timeout);
+        self.circuit_breaker.record_failure();
+        self.vault.fail_over(address);
+        let e = SamplyBeamError::HttpTimeoutError(elapsed);
+        warn!("Samply.PKI: {e} after {:.1?}; retrying (failed attempt #{})", self.attempt_timeout, tries + 1);
+        self.report_vault_health(VaultStatus::Unreachable).await;
+    }
+
+    /// Sends a request to Vault, retrying it as configured. The body of a response which is returned is read up to
+    /// `response_limit` bytes within the attempt, so that a connection breaking while it is downloaded is retried as well.
+    async fn resilient_vault_request(
+        &self,
+        method: &Method,
+        api_path: &str,
+        operation: VaultOperation,
+        response_limit: usize,
+    ) -> Result<reqwest::Response, SamplyBeamError> {
+        let max_tries = operation.max_tries(&self.retry_budgets);
+        let started = Instant::now();
+        // Set if Vault told us how long to wait before the next attempt
+        let mut retry_after = None;
+        let mut attempts = 0;
+        for tries in 0..max_tries {
+            if tries > 0 {
+                let delay = match retry_after.take() {
+                    // Capped so that a misbehaving Vault or proxy in front of it cannot stall requests for hours
+                    Some(retry_after) => Duration::min(retry_after, self.retry_backoff.max),
+                    None => self.retry_backoff.delay_before_retry(tries),
+                };
+                if started.elapsed() + delay > self.retry_deadline {
+                    break;
+                }
+                self.vault.unless_shutdown(tokio::time::sleep(delay)).await?;
+                metrics::counter!("beam_vault_retries_total", "operation" => operation.label()).increment(1);
+            }
+            if operation.uses_circuit_breaker() {
+                if let Err(retry_in) = self.circuit_breaker.allow_request() {
+                    debug!("Samply.PKI: Not requesting {api_path} as the circuit breaker is open");
+                    return Err(SamplyBeamError::VaultCircuitOpen(retry_in));
+                }
+            }
+            let address = self.vault.current_address();
+            let uri = self.vault.api_url(address, api_path)?;
+            debug!("Samply.PKI: Vault request to {uri}");
+            attempts += 1;
+            // Released before waiting for a retry so that other requests can go ahead
+            let _permit = self.vault.unless_shutdown(self.request_limit.acquire()).await?;
+            let attempt_started = Instant::now();
+            let attempt_deadline = attempt_started + self.attempt_timeout;
+            let resp = match tokio::time::timeout_at(attempt_deadline, self.vault.send_authenticated(method, &uri)).await {
+                Ok(resp) => resp,
+                Err(elapsed) => {
+                    self.attempt_timed_out(operation, address, elapsed, tries).await;
+                    continue;
+                }
+            };
+            let resp = match resp {
+                Ok(resp) => resp,
+                Err(SamplyBeamError::VaultRequestCancelled) => return Err(SamplyBeamError::VaultRequestCancelled),
+                // Retrying would only fail the same way
+                Err(e @ SamplyBeamError::HttpRequestBuildError(_)) => {
+                    error!("Samply.PKI: {e}");
+                    return Err(e);
+                }
+                Err(e) => {
+                    operation.record_attempt("unreachable", attempt_started.elapsed());
+                    self.circuit_breaker.record_failure();
+                    self.vault.fail_over(address);
+                    warn!("Samply.PKI: {e}; retrying (failed attempt #{})", tries + 1);
+                    self.report_vault_health(VaultStatus::OtherError).await;
+                    continue;
+                }
+            };
+            let resp = match resp {
+                Ok(resp) => resp,
+                Err(e) => {
+                    let kind = HttpErrorKind::of(&e);
+                    operation.record_connection_error(kind, attempt_started.elapsed());
+                    self.circuit_breaker.record_failure();
+                    self.vault.fail_over(address);
+                    warn!("Samply.PKI: Unable to communicate to vault ({}): {e}; retrying (failed attempt #{})", kind.label(), tries + 2);
+                    self.report_vault_health(VaultStatus::Unreachable).await;
+                    continue;
+                }
+            };
+            self.check_clock_skew(&resp);
+            let code = resp.status();
+            let action = response_action(code, operation, &self.retry_status_codes);
+            // Read as part of the attempt so that a connection which breaks while the body is downloaded is retried as well
+            let resp = match action {
+                ResponseAction::Return => match tokio::time::timeout_at(
+                    attempt_deadline,
+                    buffer_response(resp, response_limit),
+                ).await {
+                    Err(elapsed) => {
+                        self.attempt_timed_out(operation, address, elapsed, tries).await;
+                        continue;
+                    }
+                    Ok(Ok(resp)) => resp,
+                    Ok(Err(SamplyBeamError::HttpRequestError(e))) => {
+                        let kind = HttpErrorKind::of(&e);
+                        operation.record_connection_error(kind, attempt_started.elapsed());
+                        self.circuit_breaker.record_failure();
+                        self.vault.fail_over(address);
+                        warn!("Samply.PKI: Connection to vault broke while reading the response ({}): {e}; retrying (failed attempt #{})", kind.label(), tries + 1);
+                        self.report_vault_health(VaultStatus::Unreachable).await;
+                        continue;
+                    }
+                    Ok(Err(e)) => return Err(e),
+                },
+                ResponseAction::Retry | ResponseAction::Fail => resp,
+            };
+            let outcome = match resp.status() {
+                code if code.is_success() => "success",
+                code if code.is_server_error() => "server_error",
+                _ => "client_error",
+            };
+            operation.record_attempt(outcome, attempt_started.elapsed());
+            // Vault rejecting a request still shows that it is available, unless it is overloaded
+            if resp.status().is_server_error() || resp.status() == StatusCode::TOO_MANY_REQUESTS {
+                self.circuit_breaker.record_failure();
+            } else {
+                self.circuit_breaker.record_success();
+            }
+            match action {
+                ResponseAction::Return => {
+                    self.vault.mark_healthy(address);
+                    self.report_vault_health(VaultStatus::Ok).await;
+                    return Ok(resp);
+                }
+                ResponseAction::Fail => {
+                    error!(
+                        "Samply.PKI: Vault reported an error (code {}), which is not retried. Response was {}",
+                        code, read_limited_text(resp, self.response_limits.single).await.unwrap_or_else(|e| format!("Failed to decode failed response: {e}"))
+                    );
+                    self.report_vault_health(VaultStatus::OtherError).await;
+                    return Err(SamplyBeamError::VaultOtherError(format!(
+                        "Samply.PKI: Vault reported an error (code {})",
+                        code
+                    )));
+                }
+                ResponseAction::Retry if code == StatusCode::TOO_MANY_REQUESTS => {
+                    retry_after = retry_after_header(resp.headers(), SystemTime::now());
+                    warn!("Samply.PKI: Vault is rate limiting our requests; retrying after {retry_after:?} (failed attempt #{})", tries + 1);
+                    self.report_vault_health(VaultStatus::OtherError).await;
+                    continue;
+                }
+                ResponseAction::Retry => {
+                    if code == StatusCode::SERVICE_UNAVAILABLE {
+                        retry_after = retry_after_header(resp.headers(), SystemTime::now());
+                    }
+                    match self.check_vault_health().await {
+                        Err(SamplyBeamError::VaultSealed) => {
+                            warn!(
+                                "Samply.PKI: Vault is still sealed; retrying (failed attempt {})",
+                                tries
+                            );
+                            continue;
+                        }
+                        Err(SamplyBeamError::VaultRequestCancelled) => {
+                            return Err(SamplyBeamError::VaultRequestCancelled);
+                        }
+                        Err(SamplyBeamError::VaultRedirectError(code, location)) => {
+                            let err = SamplyBeamError::VaultRedirectError(code, location);
+                            error!("Samply.PKI asked to redirect; aborting: {err}");
+                            return Err(err);
+                        }
+                        Err(e) => {
+                            warn!("Samply.PKI: Got error from Vault: {}; status code {}; retrying (failed attempt #{})", e, code, tries);
+                            continue;
+                        }
+                        Ok(()) => {
+                            debug!("Got code {} communicating with Samply.PKI fetching URL {api_path}.", code);
+                            continue;
+                        }
+                    }
+                }
+            }
+        }
+        let err = SamplyBeamError::VaultGaveUp { attempts, elapsed: started.elapsed() };
+        error!("{err}");
+        Err(err)
+    }
+}
+
+#[async_trait]
+impl GetCerts for GetCertsFromPki {
+    /// Served from the cache until the lease of the last fetched list has expired
+    async fn certificate_list_via_network(&self) -> Result<Vec<String>, SamplyBeamError> {
+        self.certificate_list_or_stale().await.map(MaybeStale::into_inner)
+    }
+
+    async fn certificate_list_or_stale(&self) -> Result<MaybeStale<Vec<String>>, SamplyBeamError> {
+        let cached = self.certificate_list.load_full();
+        if let Some(ref cached) = cached {
+            if cached.fetched_at.elapsed() < cached.ttl {
+                debug!("Using cached cert list with {} elements", cached.serials.len());
+                CertCache::List.hit();
+                return Ok(MaybeStale::Fresh(cached.serials.clone()));
+            }
+            CertCache::List.evicted(1);
+        }
+        CertCache::List.miss();
+        match self.refresh_certificate_list().await {
+            Ok(serials) => Ok(MaybeStale::Fresh(serials)),
+            Err(e) if self.serve_stale_on_error && is_vault_unavailable(&e) => {
+                let Some(cached) = cached else { return Err(e) };
+                warn!("{e} Serving the certificate list fetched {:.0?} ago.", cached.fetched_at.elapsed());
+                Ok(MaybeStale::Stale(cached.serials.clone()))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Concurrent calls for the same serial share a single request to Vault
+    async fn certificate_by_serial_as_pem(&self, serial: &str) -> Result<String, SamplyBeamError> {
+        self.certificate_by_serial_or_stale(serial).await.map(MaybeStale::into_inner)
+    }
+
+    /// Looks the serial up in the index built while warming the cache and only falls back to fetching all
+    /// certificates on the list if the fingerprint is not indexed, e.g. for certificates issued since
+    async fn certificate_by_fingerprint(&self, sha256_hex: &str) -> Result<X509, SamplyBeamError> {
+        let fingerprint = normalize_fingerprint(sha256_hex);
+        let indexed = self.fingerprints.lock().unwrap().get(&fingerprint).cloned();
+        if let Some(serial) = indexed {
+            match self.certificate_by_serial(&serial).await {
+                Ok(cert) if sha256_fingerprint(&cert)? == fingerprint => return Ok(cert),
+                // The serial was reissued or is gone, so the index is outdated
+                Ok(_) | Err(SamplyBeamError::CertificateNotFound(_)) => _ = self.fingerprints.lock().unwrap().remove(&fingerprint),
+                Err(e) => return Err(e),
+            }
+        }
+        if self.recently_missing(&self.missing_fingerprints, &fingerprint) {
+            debug!("No certificate recently had the fingerprint {fingerprint}, not searching again");
+            return Err(SamplyBeamError::CertificateFingerprintNotFound(sha256_hex.to_owned()));
+        }
+        let serials = self.certificate_list_via_network().await?;
+        let mut found = None;
+        for (serial, pem) in self.certificates_by_serials(&serials).await {
+            let Ok(pem) = pem else {
+                continue;
+            };
+            if let Some((other, cert)) = self.index_fingerprint(&serial, &pem) {
+                if other == fingerprint {
+                    found = Some(cert);
+                }
+            }
+        }
+        found.ok_or_else(|| {
+            self.remember_missing(&self.missing_fingerprints, &fingerprint);
+            SamplyBeamError::CertificateFingerprintNotFound(sha256_hex.to_owned())
+        })
+    }
+
+    async fn certificate_by_serial_or_stale(&self, serial: &str) -> Result<MaybeStale<String>, SamplyBeamError> {
+        if self.recently_missing(&self.missing_certificates, serial) {
+            debug!("Vault recently did not know certificate {serial}, not asking again");
+            return Err(SamplyBeamError::CertificateNotFound(serial.to_owned()));
+        }
+        let pem = match self.prefetched_or_shared_fetch(serial).await {
+            Ok(pem) => {
+                if self.serve_stale_on_error {
+                    self.known_good_certificates.lock().unwrap().insert(serial.to_owned(), pem.clone());
+                }
+                MaybeStale::Fresh(pem)
+            }
+            Err(e) if self.serve_stale_on_error && is_vault_unavailable(&e) => {
+                let Some(pem) = self.known_good_certificates.lock().unwrap().get(serial).cloned() else {
+                    return Err(e);
+                };
+                warn!("{e} Serving the certificate {serial} fetched last.");
+                MaybeStale::Stale(pem)
+            }
+            Err(e @ SamplyBeamError::CertificateNotFound(_)) => {
+                self.remember_missing(&self.missing_certificates, serial);
+                return Err(e);
+            }
+            Err(e) => return Err(e),
+        };
+        if self.reject_expired_certs {
+            check_validity_period(serial, pem.as_ref())?;
+        }
+        Ok(pem)
+    }
+
+    async fn im_certificate_as_pem(&self) -> Result<String, SamplyBeamError> {
+        debug!("Getting IM CA Cert");
+        let resp = self
+            .resilient_vault_request(
+                &Method::GET,
+                &format!("{}/ca/pem", self.pki_realm),
+                VaultOperation::Ca,
+                self.response_limits.single,
+            )
+            .await?;
+        read_limited_text(resp, self.response_limits.single).await
+    }
+
+    async fn ca_chain_as_pem(&self) -> Result<String, SamplyBeamError> {
+        debug!("Getting CA chain");
+        let resp = self
+            .resilient_vault_request(
+                &Method::GET,
+                &format!("{}/ca_chain", self.pki_realm),
+                VaultOperation::Ca,
+                self.response_limits.single,
+            )
+            .await?;
+        read_limited_text(resp, self.response_limits.single).await
+    }
+
+    /// Fetches the certificate list and reconciles the cache with it: certificates which are no longer listed are
+    /// dropped and new ones fetched, at most [`GetCerts::fetch_concurrency`] at a time
+    async fn on_timer(&self, cache: &mut CertificateCache) -> CertificateCacheUpdate {
+        let previous = self.certificate_list.load_full();
+        // The timer fires once the list's lease has expired, so don't rely on the clock of the cached list
+        match self.refresh_certificate_list().await {
+            Ok(serials) => {
+                let listed: HashSet<&str> = serials.iter().map(String::as_str).collect();
+                let before: HashSet<&str> = previous.iter().flat_map(|previous| previous.serials.iter().map(String::as_str)).collect();
+                let added = listed.difference(&before).count();
+                let removed = before.difference(&listed).count();
+                let dropped = cache.retain_listed(&listed);
+                debug!("{added} certificates were added to the list and {removed} removed; dropped {dropped} unlisted certificates from the cache");
+                metrics::histogram!("beam_cert_list_changes", "change" => "added").record(added as f64);
+                metrics::histogram!("beam_cert_list_changes", "change" => "removed").record(removed as f64);
+            }
+            Err(e) => warn!("Unable to refresh the certificate list: {e}"),
+        }
+        let result = cache.update_certificates_mut().await;
         match result {
             Err(e) => {
                 warn!("Unable to update CertificateCache. Maybe it stopped? Reason: {e}.");
@@ -255,24 +1533,1774 @@ impl GetCerts for GetCertsFromPki {
         }
     }
 
-    async fn get_crl(&self) -> Result<Option<X509Crl>, SamplyBeamError> {
-        debug!("Getting crl");
-        let resp = self.resilient_vault_request(
-            &Method::GET,
-            &format!("{}/crl", self.pki_realm),
-            Some(100),
-        )
-        .await?;
-        parse_crl(&resp.bytes().await?).map(Some)
+    async fn get_crl(&self) -> Result<Option<Arc<X509Crl>>, SamplyBeamError> {
+        self.current_crl().await
+    }
+
+    async fn ocsp_status(&self, cert: &X509, issuer: &X509) -> Result<Option<CertificateStatus>, SamplyBeamError> {
+        let Some(ref ocsp) = self.ocsp else {
+            return Ok(None);
+        };
+        let client = self.vault.hyper_client.load_full();
+        self.vault.unless_shutdown(ocsp.status(&client, cert, issuer)).await?
+    }
+
+    fn fetch_concurrency(&self) -> usize {
+        self.fetch_concurrency
+    }
+
+    fn refresh_interval(&self) -> Duration {
+        self.refresh_interval.unwrap_or_else(|| Duration::from_secs(self.cache_ttl.load(Ordering::Relaxed)))
+    }
+
+    async fn check_health(&self) -> Result<(), SamplyBeamError> {
+        self.check_vault_health().await
+    }
+
+    async fn wait_until_ready(&self) -> Result<(), SamplyBeamError> {
+        if config::CONFIG_CENTRAL.pki_unseal_timeout.is_zero() {
+            return Ok(());
+        }
+        self.wait_for_vault(config::CONFIG_CENTRAL.pki_unseal_timeout).await
+    }
+
+    async fn prefetch(&self) {
+        self.warm_cache().await
+    }
+
+    fn on_cache_lookup(&self, hit: bool) {
+        if hit {
+            CertCache::Serial.hit();
+        } else {
+            CertCache::Serial.miss();
+        }
+    }
+
+    fn invalidate(&self, serial: &str) {
+        let mut prefetched = self.prefetched_certificates.lock().unwrap();
+        if prefetched.remove(serial).is_some() {
+            CertCache::Prefetched.evicted(1);
+            CertCache::Prefetched.set_size(prefetched.len());
+        }
+        drop(prefetched);
+        // A fetch in flight is answered as it is, but later calls fetch the certificate anew
+        self.pending_certificates.lock().unwrap().remove(serial);
+        self.known_good_certificates.lock().unwrap().remove(serial);
+        self.missing_certificates.lock().unwrap().remove(serial);
+        self.missing_fingerprints.lock().unwrap().clear();
+        self.fingerprints.lock().unwrap().retain(|_, indexed| indexed != serial);
+        info!("Invalidated the cached certificate {serial}");
+    }
+
+    fn invalidate_all(&self) {
+        if self.certificate_list.swap(None).is_some() {
+            CertCache::List.evicted(1);
+        }
+        CertCache::List.set_size(0);
+        let mut prefetched = self.prefetched_certificates.lock().unwrap();
+        CertCache::Prefetched.evicted(prefetched.len());
+        prefetched.clear();
+        CertCache::Prefetched.set_size(0);
+        drop(prefetched);
+        self.pending_certificates.lock().unwrap().clear();
+        self.known_good_certificates.lock().unwrap().clear();
+        self.missing_certificates.lock().unwrap().clear();
+        self.missing_fingerprints.lock().unwrap().clear();
+        self.fingerprints.lock().unwrap().clear();
+        // So that emergency revocations take effect; if the CRL is being fetched right now, it will be fresh anyway
+        if let Ok(mut crl) = self.crl.try_lock() {
+            *crl = None;
+        }
+        if let Some(ref ocsp) = self.ocsp {
+            ocsp.clear();
+        }
+        info!("Invalidated all cached certificates");
+    }
+
+    /// Cancels requests to Vault in flight as well as the background tasks and waits for the latter to finish
+    async fn shutdown(&self) {
+        self.vault.shutdown.cancel();
+        let tasks = std::mem::take(&mut *self.background_tasks.lock().unwrap());
+        let count = tasks.len();
+        for task in tasks {
+            if let Err(e) = task.await {
+                warn!("A background task of the PKI failed: {e}");
+            }
+        }
+        debug!("Stopped {count} background tasks of the PKI");
     }
 }
 
-pub(crate) fn build_cert_getter(
+pub(crate) async fn build_cert_getter(
+    pki_addresses: Vec<Url>,
+    pki_auth: VaultAuth,
     sender: tokio::sync::watch::Sender<VaultStatus>,
+    clock_skew_sender: tokio::sync::watch::Sender<Option<i64>>,
+    shutdown: CancellationToken,
 ) -> Result<GetCertsFromPki, SamplyBeamError> {
-    GetCertsFromPki::new(sender)
+    let getter = GetCertsFromPki::new(pki_addresses, pki_auth, sender, clock_skew_sender, shutdown).await?;
+    getter.spawn(getter.vault.clone().keep_token_alive());
+    if config::CONFIG_CENTRAL.tls_ca_certificates_dir.is_some() || config::CONFIG_CENTRAL.tls_ca_certificates_file.is_some() {
+        getter.spawn(getter.vault.clone().reload_ca_certificates_on_change(
+            config::CONFIG_CENTRAL.tls_ca_certificates_dir.clone(),
+            config::CONFIG_CENTRAL.tls_ca_certificates_file.clone(),
+            config::CONFIG_CENTRAL.tls_ca_certificates_strict,
+            VaultClient::build_http_client,
+        ));
+    }
+    Ok(getter)
+}
+
+/// Watches `ca_dir` and the directory of `ca_bundle`, as the bundle is usually replaced rather than written to.
+/// Every change to the CA certificates is sent to the returned receiver as long as the watcher lives.
+fn watch_ca_certificates(
+    ca_dir: Option<&Path>,
+    ca_bundle: Option<&Path>,
+) -> notify::Result<(RecommendedWatcher, tokio::sync::mpsc::UnboundedReceiver<()>)> {
+    let (changed, changes) = tokio::sync::mpsc::unbounded_channel();
+    let relevant = {
+        let ca_dir = ca_dir.map(Path::to_path_buf);
+        let bundle_name = ca_bundle.and_then(Path::file_name).map(ToOwned::to_owned);
+        move |path: &PathBuf| ca_dir.as_ref().is_some_and(|dir| path.starts_with(dir)) || path.file_name() == bundle_name.as_deref()
+    };
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+        // Loading the certificates reads them, which must not trigger another reload
+        Ok(event) if event.kind.is_access() => {},
+        Ok(event) if event.paths.iter().any(&relevant) => _ = changed.send(()),
+        Ok(_) => {},
+        Err(e) => warn!("Error watching the CA certificates: {e}"),
+    })?;
+    if let Some(ca_dir) = ca_dir {
+        watcher.watch(ca_dir, RecursiveMode::NonRecursive)?;
+    }
+    if let Some(bundle_dir) = ca_bundle.and_then(Path::parent) {
+        let bundle_dir = if bundle_dir.as_os_str().is_empty() { Path::new(".") } else { bundle_dir };
+        if ca_dir != Some(bundle_dir) {
+            watcher.watch(bundle_dir, RecursiveMode::NonRecursive)?;
+        }
+    }
+    Ok((watcher, changes))
+}
+
+/// Waits for the next change of the CA certificates. Files are usually replaced in several steps,
+/// so changes in quick succession are taken together, which results in a single reload.
+async fn next_ca_change(changes: &mut tokio::sync::mpsc::UnboundedReceiver<()>) -> Option<()> {
+    changes.recv().await?;
+    while let Ok(Some(())) = timeout(CA_RELOAD_DEBOUNCE, changes.recv()).await {}
+    Some(())
+}
+
+/// The token as sent in the `X-Vault-Token` header, marked as sensitive so that it is not logged
+fn token_header(token: &str) -> Result<header::HeaderValue, header::InvalidHeaderValue> {
+    let mut value = header::HeaderValue::from_str(token)?;
+    value.set_sensitive(true);
+    Ok(value)
+}
+
+/// Checks PKI_ADDRESS once at startup so that the URLs of Vault's endpoints can be built from the result
+fn vault_api_base(pki_address: &Url) -> Result<Url, SamplyBeamError> {
+    if !matches!(pki_address.scheme(), "http" | "https") || pki_address.cannot_be_a_base() {
+        return Err(SamplyBeamError::ConfigurationFailed(format!(
+            "PKI_ADDRESS ({pki_address}) must be an http or https URL"
+        )));
+    }
+    pki_address
+        .join("/v1/")
+        .map_err(|e| SamplyBeamError::ConfigurationFailed(format!("Invalid PKI_ADDRESS ({pki_address}): {e}")))
+}
+
+/// Fails unless the CRL is signed by the intermediate CA and not past its next update, as unlike Vault,
+/// a distribution point is not authenticated
+fn verify_crl(crl: &X509CrlRef, issuer: &X509, url: &Url) -> Result<(), SamplyBeamError> {
+    if !crl.verify(&*issuer.public_key()?)? {
+        return Err(SamplyBeamError::VaultOtherError(format!("The CRL from {url} is not signed by the intermediate CA")));
+    }
+    if let Some(next_update) = crl.next_update() {
+        if next_update < Asn1Time::days_from_now(0)? {
+            return Err(SamplyBeamError::VaultOtherError(format!("The CRL from {url} is outdated since {next_update}")));
+        }
+    }
+    Ok(())
+}
+
+/// Fails if the certificate is expired or not yet valid
+fn check_validity_period(serial: &str, pem: &str) -> Result<(), SamplyBeamError> {
+    let cert = parse_single_certificate(pem)?;
+    let now = Asn1Time::days_from_now(0)?;
+    if cert.not_after() < now || cert.not_before() > now {
+        warn!("Rejecting certificate {serial} which is only valid from {} until {}", cert.not_before(), cert.not_after());
+        return Err(SamplyBeamError::CertificateExpired { serial: serial.to_owned(), not_after: cert.not_after().to_string() });
+    }
+    Ok(())
+}
+
+/// How long to wait according to a `Retry-After` header, given either in seconds or as an HTTP date
+fn retry_after_header(headers: &header::HeaderMap, now: SystemTime) -> Option<Duration> {
+    let value = headers.get(header::RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(secs) = value.parse() {
+        return Some(Duration::from_secs(secs));
+    }
+    let at = httpdate::parse_http_date(value).ok()?;
+    // A date in the past means we may retry right away
+    Some(at.duration_since(now).unwrap_or_default())
+}
+
+/// Estimates by how many seconds our clock is ahead (positive) or behind (negative) the clock that produced the given `Date` header
+fn clock_skew(date: &header::HeaderValue, now: SystemTime) -> Option<i64> {
+    let remote = httpdate::parse_http_date(date.to_str().ok()?).ok()?;
+    Some(match now.duration_since(remote) {
+        Ok(ahead) => ahead.as_secs() as i64,
+        Err(behind) => -(behind.duration().as_secs() as i64),
+    })
+}
+
+/// Logs a warning if the skew exceeds the threshold. Returns whether it did.
+/// Logs when the clock skew starts or stops exceeding `max_skew` compared to the `previous` one.
+/// Returns whether anything was logged.
+fn log_clock_skew_change(previous: Option<i64>, skew: i64, max_skew: Duration) -> bool {
+    let exceeds = |skew: i64| skew.unsigned_abs() > max_skew.as_secs();
+    match (previous.is_some_and(exceeds), exceeds(skew)) {
+        (false, true) => {
+            let direction = if skew > 0 { "ahead of" } else { "behind" };
+            error!(
+                "Local clock is {}s {direction} Vault's clock (tolerated: {}s). Signatures and certificate validity checks may fail -- please check this host's time synchronization (NTP)!",
+                skew.unsigned_abs(), max_skew.as_secs()
+            );
+            true
+        }
+        (true, false) => {
+            info!("Local clock is in sync with Vault's clock again (off by {skew}s)");
+            true
+        }
+        _ => false,
+    }
 }
 
-pub(crate) fn pki_url_builder(location: &str) -> Url {
-    config::CONFIG_CENTRAL.pki_address.join(&format!("/v1/{location}")).unwrap()
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::config_broker::StatusCodeRange;
+
+    #[test]
+    fn test_operations_use_their_retry_budget() {
+        let budgets = VaultRetryBudgets {
+            list: 2,
+            fetch: 3,
+            health: 1,
+            ca: 50,
+            max_retries: 60,
+        };
+        assert_eq!(VaultOperation::List.max_tries(&budgets), 2);
+        assert_eq!(VaultOperation::Fetch.max_tries(&budgets), 3);
+        assert_eq!(VaultOperation::Health.max_tries(&budgets), 1);
+        assert_eq!(VaultOperation::Ca.max_tries(&budgets), 50);
+
+        // No request is retried more often than allowed for all of them
+        let capped = VaultRetryBudgets { max_retries: 9, ..budgets };
+        assert_eq!(VaultOperation::List.max_tries(&capped), 2);
+        assert_eq!(VaultOperation::Ca.max_tries(&capped), 10);
+        assert_eq!(VaultOperation::Ca.max_tries(&VaultRetryBudgets { max_retries: u32::MAX, ..budgets }), 50);
+    }
+
+    #[tokio::test]
+    async fn test_vault_requests_are_given_up_after_their_retry_budget() {
+        use axum::{extract::{Request, State}, routing::any, Router};
+
+        let requests = Arc::new(Mutex::new(HashMap::<String, u32>::new()));
+        let router = Router::new()
+            .route("/v1/sys/health", any(|| async { StatusCode::OK }))
+            .fallback(any(|State(requests): State<Arc<Mutex<HashMap<String, u32>>>>, req: Request| async move {
+                *requests.lock().unwrap().entry(req.uri().path().to_string()).or_default() += 1;
+                StatusCode::INTERNAL_SERVER_ERROR
+            }))
+            .with_state(requests.clone());
+        let mut getter = test_getter(&serve(router).await, CancellationToken::new());
+        getter.retry_budgets = VaultRetryBudgets { list: 2, fetch: 3, health: 1, ca: 4, max_retries: 60 };
+
+        fn gave_up_after<T: std::fmt::Debug>(res: Result<T, SamplyBeamError>) -> u32 {
+            match res {
+                Err(SamplyBeamError::VaultGaveUp { attempts, .. }) => attempts,
+                other => panic!("Unexpected result: {other:?}"),
+            }
+        }
+        assert_eq!(gave_up_after(getter.certificate_list_via_network().await), 2);
+        assert_eq!(gave_up_after(getter.certificate_by_serial_as_pem("12:34").await), 3);
+        assert_eq!(gave_up_after(getter.im_certificate_as_pem().await), 4);
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests["/v1/samply_pki/certs"], 2);
+        assert_eq!(requests["/v1/samply_pki/cert/12:34/raw/pem"], 3);
+        assert_eq!(requests["/v1/samply_pki/ca/pem"], 4);
+    }
+
+    #[test]
+    fn test_retry_backoff_grows_exponentially_with_jitter() {
+        let backoff = RetryBackoff {
+            base: Duration::from_millis(200),
+            max: Duration::from_secs(30),
+            multiplier: 2.0,
+        };
+        let within = |retry, full: Duration| {
+            let delay = backoff.delay_before_retry(retry);
+            assert!(delay <= full && delay >= full / 2, "Retry #{retry} waits {delay:?} instead of up to {full:?}");
+        };
+        within(1, Duration::from_millis(200));
+        within(2, Duration::from_millis(400));
+        within(5, Duration::from_millis(3200));
+        within(8, Duration::from_secs(25) + Duration::from_millis(600));
+        within(9, backoff.max);
+        within(u32::MAX, backoff.max);
+        let delays: std::collections::HashSet<_> = (0..20).map(|_| backoff.delay_before_retry(3)).collect();
+        assert!(delays.len() > 1, "Retries must be jittered");
+    }
+
+    #[test]
+    fn test_cache_ttl_follows_lease_duration() {
+        let bounds = CacheTtlBounds {
+            default: Duration::from_secs(60),
+            min: Duration::from_secs(10),
+            max: Duration::from_secs(3600),
+        };
+        let short = bounds.ttl_for_lease(30);
+        let long = bounds.ttl_for_lease(600);
+        assert!(short < long, "A short lease must be refetched earlier than a long one");
+        assert_eq!(short, Duration::from_secs(30));
+        assert_eq!(long, Duration::from_secs(600));
+        assert_eq!(bounds.ttl_for_lease(1), bounds.min);
+        assert_eq!(bounds.ttl_for_lease(u64::MAX), bounds.max);
+        assert_eq!(bounds.ttl_for_lease(0), bounds.default);
+    }
+
+    #[test]
+    fn test_vault_api_base() {
+        let base = vault_api_base(&"https://vault.example.org:8200".parse().unwrap()).unwrap();
+        assert_eq!(base.join("samply_pki/cert/0a:1b/raw/pem").unwrap().as_str(), "https://vault.example.org:8200/v1/samply_pki/cert/0a:1b/raw/pem");
+        for invalid in ["vault.example.org:8200", "mailto:vault@example.org", "file:///var/run/vault"] {
+            let res = vault_api_base(&invalid.parse().unwrap());
+            assert!(matches!(res, Err(SamplyBeamError::ConfigurationFailed(_))), "{invalid} must be rejected: {res:?}");
+        }
+    }
+
+    #[test]
+    fn test_retry_after_header() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_707_998_400);
+        let retry_after = |value: &str| retry_after_header(&header::HeaderMap::from_iter([(header::RETRY_AFTER, value.parse().unwrap())]), now);
+
+        assert_eq!(retry_after("120"), Some(Duration::from_secs(120)));
+        assert_eq!(retry_after("Thu, 15 Feb 2024 12:00:30 GMT"), Some(Duration::from_secs(30)));
+        assert_eq!(retry_after("Thu, 15 Feb 2024 11:59:00 GMT"), Some(Duration::ZERO));
+        assert_eq!(retry_after("soon"), None);
+        assert_eq!(retry_after("-5"), None);
+        assert_eq!(retry_after_header(&header::HeaderMap::new(), now), None);
+    }
+
+    #[test]
+    fn test_clock_skew_from_date_header() {
+        let now = SystemTime::now();
+        let max_skew = Duration::from_secs(30);
+        let date = |t: SystemTime| header::HeaderValue::from_str(&httpdate::fmt_http_date(t)).unwrap();
+
+        let in_sync = clock_skew(&date(now), now).unwrap();
+        assert!(in_sync.abs() <= 1);
+        assert!(!log_clock_skew_change(None, in_sync, max_skew));
+
+        let vault_behind = clock_skew(&date(now - Duration::from_secs(300)), now).unwrap();
+        assert!((299..=300).contains(&vault_behind));
+        assert!(log_clock_skew_change(Some(in_sync), vault_behind, max_skew));
+        assert!(!log_clock_skew_change(Some(vault_behind), vault_behind, max_skew), "Only changes are logged");
+
+        let vault_ahead = clock_skew(&date(now + Duration::from_secs(300)), now).unwrap();
+        assert!((-300..=-299).contains(&vault_ahead));
+        assert!(log_clock_skew_change(None, vault_ahead, max_skew));
+        assert!(!log_clock_skew_change(Some(vault_behind), vault_ahead, max_skew), "Still skewed");
+        assert!(log_clock_skew_change(Some(vault_ahead), in_sync, max_skew));
+
+        assert_eq!(clock_skew(&header::HeaderValue::from_static("not a date"), now), None);
+    }
+
+    /// `pki_address` may list several addresses like PKI_ADDRESS
+    fn test_vault(pki_address: &str, pki_auth: VaultAuth, shutdown: CancellationToken) -> VaultClient {
+        let pki_token = match pki_auth {
+            VaultAuth::Token(ref token) => token.clone(),
+            _ => String::new(),
+        };
+        let (api_bases, current, healthy) =
+            VaultClient::new_addresses(pki_address.split(',').map(|address| vault_api_base(&address.parse().unwrap()).unwrap()).collect());
+        VaultClient {
+            api_bases,
+            current,
+            healthy,
+            pki_auth,
+            pki_token: ArcSwap::from_pointee(pki_token),
+            token_lease: AtomicU64::new(0),
+            pending_login: Default::default(),
+            user_agent: header::HeaderValue::from_static(DEFAULT_PKI_USER_AGENT),
+            namespace: None,
+            hyper_client: ArcSwap::from_pointee(test_http_client(&Vec::new()).unwrap()),
+            shutdown,
+        }
+    }
+
+    fn test_http_client(ca_certificates: &Vec<reqwest::Certificate>) -> Result<SamplyHttpClient, SamplyBeamError> {
+        http_client::build(
+            ca_certificates,
+            None,
+            &ConnectionSettings { connect_timeout: Some(Duration::from_secs(1)), ..Default::default() },
+            Some(Duration::from_secs(1)),
+            &[],
+        )
+    }
+
+    /// Serves `router` on a free local port and returns its base URL
+    async fn serve(router: axum::Router) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+        url
+    }
+
+    fn test_getter(pki_address: &str, shutdown: CancellationToken) -> GetCertsFromPki {
+        GetCertsFromPki {
+            vault: Arc::new(test_vault(pki_address, VaultAuth::Token("token".into()), shutdown)),
+            pki_realm: "samply_pki".into(),
+            health_report_sender: tokio::sync::watch::channel(VaultStatus::default()).0,
+            clock_skew_sender: tokio::sync::watch::channel(None).0,
+            retry_budgets: VaultRetryBudgets { list: 100, fetch: 100, health: 100, ca: 100, max_retries: 100 },
+            retry_backoff: RetryBackoff { base: Duration::from_millis(10), max: Duration::from_millis(50), multiplier: 2.0 },
+            retry_deadline: Duration::from_secs(60),
+            attempt_timeout: Duration::from_secs(10),
+            retry_status_codes: RetryableStatusCodes::default(),
+            // Never opens so that tests can retry as often as they like
+            circuit_breaker: CircuitBreaker::new(CircuitBreakerSettings {
+                threshold: u32::MAX,
+                window: Duration::from_secs(60),
+                cooldown: Duration::from_secs(60),
+            }),
+            request_limit: VaultRequestLimit::new(16),
+            response_limits: VaultResponseLimits { list: 16 * 1024 * 1024, single: 256 * 1024 },
+            not_found_cache_ttl: Duration::from_secs(10),
+            missing_certificates: Default::default(),
+            fingerprints: Default::default(),
+            missing_fingerprints: Default::default(),
+            health_path: "sys/health".into(),
+            health_cache_ttl: Duration::from_secs(2),
+            last_health_check: Default::default(),
+            reject_expired_certs: false,
+            serve_stale_on_error: false,
+            fetch_concurrency: 8,
+            cache_ttl_bounds: CacheTtlBounds {
+                default: Duration::from_secs(60),
+                min: Duration::from_secs(10),
+                max: Duration::from_secs(3600),
+            },
+            max_clock_skew: Duration::from_secs(30),
+            refresh_interval: None,
+            cache_ttl: AtomicU64::new(60),
+            certificate_list: ArcSwapOption::empty(),
+            pending_certificates: Default::default(),
+            prefetched_certificates: Default::default(),
+            known_good_certificates: Default::default(),
+            crl_settings: CrlSettings { url: None, refresh_interval: Duration::from_secs(300), fail_open: false },
+            crl: Default::default(),
+            ocsp: None,
+            background_tasks: Default::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_cancels_retries() {
+        let shutdown = CancellationToken::new();
+        // Nothing listens here, so every attempt fails and is retried
+        let getter = test_getter("http://127.0.0.1:1", shutdown.clone());
+        tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                shutdown.cancel();
+            }
+        });
+        let res = timeout(
+            Duration::from_secs(2),
+            getter.resilient_vault_request(&Method::GET, "samply_pki/ca/pem", VaultOperation::Ca, getter.response_limits.single),
+        )
+        .await
+        .expect("Retries must stop promptly on shutdown");
+        assert!(matches!(res, Err(SamplyBeamError::VaultRequestCancelled)));
+    }
+
+    #[tokio::test]
+    async fn test_vault_requests_use_pki_user_agent() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (requests_tx, mut requests) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = vec![0; 4096];
+                let len = stream.read(&mut request).await.unwrap();
+                stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").await.unwrap();
+                requests_tx.send(String::from_utf8_lossy(&request[..len]).to_lowercase()).unwrap();
+            }
+        });
+
+        let mut getter = test_getter(&format!("http://{addr}"), CancellationToken::new());
+        Arc::get_mut(&mut getter.vault).unwrap().user_agent = header::HeaderValue::from_static("beam-pki-audit");
+        getter.resilient_vault_request(&Method::GET, "samply_pki/ca/pem", VaultOperation::Ca, getter.response_limits.single).await.unwrap();
+        let request = requests.recv().await.unwrap();
+        assert!(request.contains("\r\nuser-agent: beam-pki-audit\r\n"), "Unexpected request: {request}");
+        assert!(!request.contains("x-vault-namespace"), "No namespace unless configured: {request}");
+        assert!(DEFAULT_PKI_USER_AGENT.ends_with("+pki"));
+
+        Arc::get_mut(&mut getter.vault).unwrap().namespace = Some(header::HeaderValue::from_static("medic/pki"));
+        getter.resilient_vault_request(&Method::GET, "samply_pki/ca/pem", VaultOperation::Ca, getter.response_limits.single).await.unwrap();
+        let request = requests.recv().await.unwrap();
+        assert!(request.contains("\r\nx-vault-namespace: medic/pki\r\n"), "Unexpected request: {request}");
+    }
+
+    #[tokio::test]
+    async fn test_vault_requests_reuse_connections() {
+        use axum::{routing::get, Router};
+        use tokio::net::{TcpListener, TcpStream};
+
+        let server = serve(Router::new().route("/v1/samply_pki/ca/pem", get(|| async { "ca" }))).await;
+        let server_addr: std::net::SocketAddr = server.strip_prefix("http://").unwrap().parse().unwrap();
+        // Forwards to the server while counting the connections made by the client
+        let counter = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = counter.local_addr().unwrap();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let counted = connections.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut incoming, _) = counter.accept().await.unwrap();
+                counted.fetch_add(1, Ordering::Relaxed);
+                tokio::spawn(async move {
+                    let mut outgoing = TcpStream::connect(server_addr).await.unwrap();
+                    _ = tokio::io::copy_bidirectional(&mut incoming, &mut outgoing).await;
+                });
+            }
+        });
+
+        let requests_via = |connection: ConnectionSettings, pool: (Option<Duration>, Option<usize>)| {
+            let getter = test_getter(&format!("http://{addr}"), CancellationToken::new());
+            let connections = connections.clone();
+            async move {
+                connections.store(0, Ordering::Relaxed);
+                let client = VaultClient::build_http_client_with(&vec![], None, &connection, pool.0, pool.1).unwrap();
+                getter.vault.hyper_client.store(Arc::new(client));
+                for _ in 0..3 {
+                    let resp = getter.resilient_vault_request(&Method::GET, "samply_pki/ca/pem", VaultOperation::Ca, getter.response_limits.single).await.unwrap();
+                    assert_eq!(resp.text().await.unwrap(), "ca");
+                }
+                connections.load(Ordering::Relaxed)
+            }
+        };
+        let shared = ConnectionSettings { pool_max_idle_per_host: 0, ..Default::default() };
+        assert_eq!(requests_via(shared.clone(), (None, None)).await, 3);
+        assert_eq!(requests_via(shared, (Some(Duration::from_secs(60)), Some(1))).await, 1, "PKI_POOL_* must let requests to Vault reuse the connection");
+    }
+
+    #[tokio::test]
+    async fn test_connection_reset_while_reading_body_is_retried() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counted = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                _ = stream.read(&mut [0; 4096]).await.unwrap();
+                // The first response breaks off after part of the body
+                let response: &[u8] = match counted.fetch_add(1, Ordering::Relaxed) {
+                    0 => b"HTTP/1.1 200 OK\r\nContent-Length: 12\r\nConnection: close\r\n\r\n-----BEG",
+                    _ => b"HTTP/1.1 200 OK\r\nContent-Length: 12\r\nConnection: close\r\n\r\n-----BEGIN C",
+                };
+                stream.write_all(response).await.unwrap();
+            }
+        });
+
+        let getter = test_getter(&format!("http://{addr}"), CancellationToken::new());
+        let resp = getter.resilient_vault_request(&Method::GET, "samply_pki/ca/pem", VaultOperation::Ca, getter.response_limits.single).await.unwrap();
+        assert_eq!(resp.headers()[header::CONTENT_LENGTH], "12");
+        assert_eq!(resp.url().path(), "/v1/samply_pki/ca/pem");
+        assert_eq!(resp.text().await.unwrap(), "-----BEGIN C");
+        assert_eq!(requests.load(Ordering::Relaxed), 2);
+
+        // The limit is the one of the operation, and exceeding it is not retried
+        let res = getter.resilient_vault_request(&Method::GET, "samply_pki/ca/pem", VaultOperation::Ca, 8).await;
+        assert!(matches!(res, Err(SamplyBeamError::VaultOtherError(ref msg)) if msg.contains("exceeds the limit of 8 bytes")), "{res:?}");
+        assert_eq!(requests.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn test_hung_attempts_time_out_and_are_retried() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counted = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let attempt = counted.fetch_add(1, Ordering::Relaxed);
+                tokio::spawn(async move {
+                    _ = stream.read(&mut [0; 4096]).await.unwrap();
+                    // Hangs before the response, then in the middle of the body
+                    let response: &[u8] = match attempt {
+                        0 => b"",
+                        1 => b"HTTP/1.1 200 OK\r\nContent-Length: 12\r\nConnection: close\r\n\r\n-----BEG",
+                        _ => b"HTTP/1.1 200 OK\r\nContent-Length: 12\r\nConnection: close\r\n\r\n-----BEGIN C",
+                    };
+                    stream.write_all(response).await.unwrap();
+                    tokio::time::sleep(Duration::from_secs(60)).await;
+                });
+            }
+        });
+
+        let mut getter = test_getter(&format!("http://{addr}"), CancellationToken::new());
+        getter.attempt_timeout = Duration::from_millis(200);
+        let started = Instant::now();
+        let resp = getter.resilient_vault_request(&Method::GET, "samply_pki/ca/pem", VaultOperation::Ca, getter.response_limits.single).await.unwrap();
+        assert_eq!(resp.text().await.unwrap(), "-----BEGIN C");
+        assert_eq!(requests.load(Ordering::Relaxed), 3);
+        assert!(started.elapsed() < Duration::from_secs(2), "Hung attempts must not stall the request");
+    }
+
+    #[tokio::test]
+    async fn test_validate_reports_each_check() {
+        use axum::{http::HeaderMap, routing::{any, get}, Json, Router};
+
+        let router = Router::new()
+            .route("/v1/sys/health", get(|| async { "{}" }))
+            .route("/v1/auth/token/lookup-self", get(|headers: HeaderMap| async move {
+                match headers.get("X-Vault-Token") {
+                    Some(token) if token == "token" => Ok(Json(json!({ "data": { "ttl": 0, "renewable": false } }))),
+                    _ => Err(StatusCode::FORBIDDEN),
+                }
+            }))
+            .route("/v1/samply_pki/certs", any(|| async {
+                Json(json!({ "request_id": "", "lease_id": "", "renewable": false, "lease_duration": 600, "data": { "keys": ["0a:1b"] } }))
+            }));
+        let url = serve(router).await;
+        let outcomes = |checks: Vec<ConfigCheck>| checks.iter().map(|check| check.result.as_ref().map(Result::is_ok)).collect::<Vec<_>>();
+
+        let getter = test_getter(&url, CancellationToken::new());
+        assert_eq!(outcomes(getter.validate().await), [Some(true); 4]);
+
+        let mut wrong_realm = test_getter(&url, CancellationToken::new());
+        wrong_realm.pki_realm = "other_pki".into();
+        wrong_realm.retry_budgets.list = 1;
+        assert_eq!(outcomes(wrong_realm.validate().await), [Some(true), Some(true), Some(true), Some(false)]);
+
+        let wrong_token = GetCertsFromPki { vault: Arc::new(test_vault(&url, VaultAuth::Token("wrong".into()), CancellationToken::new())), ..test_getter(&url, CancellationToken::new()) };
+        assert_eq!(outcomes(wrong_token.validate().await), [Some(true), Some(true), Some(false), None]);
+
+        let mut unreachable = test_getter("http://127.0.0.1:1", CancellationToken::new());
+        unreachable.retry_budgets.health = 1;
+        assert_eq!(outcomes(unreachable.validate().await), [Some(true), Some(false), None, None]);
+    }
+
+    /// Accepts only the token of the most recent login until it is revoked
+    #[derive(Default)]
+    struct LoginVault {
+        logins: AtomicU64,
+        valid_token: std::sync::Mutex<Option<String>>,
+        /// Login request body accepted per auth method
+        credentials: std::sync::Mutex<std::collections::HashMap<String, serde_json::Value>>,
+        /// Lease duration of the tokens in seconds
+        lease: AtomicU64,
+        renewals: AtomicU64,
+        refuse_renewal: std::sync::atomic::AtomicBool,
+    }
+
+    impl LoginVault {
+        fn is_valid(&self, headers: &axum::http::HeaderMap) -> bool {
+            let token = headers.get("X-Vault-Token").and_then(|token| token.to_str().ok());
+            token.is_some() && token == self.valid_token.lock().unwrap().as_deref()
+        }
+    }
+
+    async fn serve_login_vault(vault: Arc<LoginVault>) -> String {
+        use axum::{extract::{Path, State}, http::HeaderMap, routing::{get, post}, Json, Router};
+
+        let router = Router::new()
+            .route("/v1/auth/:method/login", post(|State(vault): State<Arc<LoginVault>>, Path(method): Path<String>, Json(login): Json<serde_json::Value>| async move {
+                if vault.credentials.lock().unwrap().get(&method) != Some(&login) {
+                    return Err(StatusCode::BAD_REQUEST);
+                }
+                let token = format!("token{}", vault.logins.fetch_add(1, Ordering::Relaxed) + 1);
+                *vault.valid_token.lock().unwrap() = Some(token.clone());
+                Ok(Json(json!({ "auth": { "client_token": token, "lease_duration": vault.lease.load(Ordering::Relaxed) } })))
+            }))
+            .route("/v1/auth/token/renew-self", post(|State(vault): State<Arc<LoginVault>>, headers: HeaderMap| async move {
+                if !vault.is_valid(&headers) || vault.refuse_renewal.load(Ordering::Relaxed) {
+                    return Err(StatusCode::FORBIDDEN);
+                }
+                vault.renewals.fetch_add(1, Ordering::Relaxed);
+                let token = vault.valid_token.lock().unwrap().clone();
+                Ok(Json(json!({ "auth": { "client_token": token, "lease_duration": vault.lease.load(Ordering::Relaxed) } })))
+            }))
+            .route("/v1/samply_pki/ca/pem", get(|State(vault): State<Arc<LoginVault>>, headers: HeaderMap| async move {
+                if vault.is_valid(&headers) {
+                    Ok("pem")
+                } else {
+                    Err(StatusCode::FORBIDDEN)
+                }
+            }))
+            .with_state(vault);
+        serve(router).await
+    }
+
+    fn login_getter(pki_address: &str, auth: VaultAuth) -> GetCertsFromPki {
+        let mut getter = test_getter(pki_address, CancellationToken::new());
+        getter.vault = Arc::new(test_vault(pki_address, auth, CancellationToken::new()));
+        getter
+    }
+
+    fn approle(secret_id: &str) -> VaultAuth {
+        VaultAuth::AppRole { role_id: "beam-broker".into(), secret_id: secret_id.into() }
+    }
+
+    #[tokio::test]
+    async fn test_approle_login_and_reauthentication() {
+        let vault = Arc::new(LoginVault::default());
+        vault.credentials.lock().unwrap().insert("approle".into(), json!({ "role_id": "beam-broker", "secret_id": "secret" }));
+        let url = serve_login_vault(vault.clone()).await;
+        let getter = login_getter(&url, approle("secret"));
+
+        let resp = getter.resilient_vault_request(&Method::GET, "samply_pki/ca/pem", VaultOperation::Ca, getter.response_limits.single).await.unwrap();
+        assert_eq!(resp.text().await.unwrap(), "pem");
+        assert_eq!(vault.logins.load(Ordering::Relaxed), 1);
+        getter.resilient_vault_request(&Method::GET, "samply_pki/ca/pem", VaultOperation::Ca, getter.response_limits.single).await.unwrap();
+        assert_eq!(vault.logins.load(Ordering::Relaxed), 1, "The token must be reused");
+
+        // The token expires
+        vault.valid_token.lock().unwrap().take();
+        getter.resilient_vault_request(&Method::GET, "samply_pki/ca/pem", VaultOperation::Ca, getter.response_limits.single).await.unwrap();
+        assert_eq!(vault.logins.load(Ordering::Relaxed), 2);
+        assert_eq!(getter.vault.pki_token.load().as_str(), "token2");
+
+        let wrong_secret = login_getter(&url, approle("wrong"));
+        let res = wrong_secret.vault.send_authenticated(&Method::GET, &wrong_secret.vault.pki_url("samply_pki/ca/pem").unwrap()).await;
+        assert!(matches!(res, Err(SamplyBeamError::VaultAuthError(_))));
+    }
+
+    #[tokio::test]
+    async fn test_rejected_requests_share_one_login() {
+        let vault = Arc::new(LoginVault::default());
+        vault.credentials.lock().unwrap().insert("approle".into(), json!({ "role_id": "beam-broker", "secret_id": "secret" }));
+        let url = serve_login_vault(vault.clone()).await;
+        let client = Arc::new(test_vault(&url, approle("secret"), CancellationToken::new()));
+
+        async fn send_concurrently(client: &Arc<VaultClient>) {
+            let mut callers = tokio::task::JoinSet::new();
+            for _ in 0..20 {
+                let client = client.clone();
+                callers.spawn(async move { client.send_authenticated(&Method::GET, &client.pki_url("samply_pki/ca/pem").unwrap()).await });
+            }
+            while let Some(resp) = callers.join_next().await {
+                assert_eq!(resp.unwrap().unwrap().unwrap().status(), StatusCode::OK);
+            }
+        }
+        send_concurrently(&client).await;
+        assert_eq!(vault.logins.load(Ordering::Relaxed), 1, "Requests without a token must share the first login");
+
+        // The token expires
+        vault.valid_token.lock().unwrap().take();
+        send_concurrently(&client).await;
+        assert_eq!(vault.logins.load(Ordering::Relaxed), 2, "Requests whose token has been rejected must share one login");
+        assert!(client.pending_login.lock().unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_kubernetes_login_rereads_rotated_token() {
+        let token_file = std::env::temp_dir().join(format!("beam-k8s-token-{}", std::process::id()));
+        std::fs::write(&token_file, "jwt1\n").unwrap();
+        let vault = Arc::new(LoginVault::default());
+        let accept = |jwt: &str| vault.credentials.lock().unwrap().insert("kubernetes".into(), json!({ "role": "beam", "jwt": jwt }));
+        accept("jwt1");
+        let url = serve_login_vault(vault.clone()).await;
+        let getter = login_getter(&url, VaultAuth::Kubernetes { role: "beam".into(), token_file: token_file.clone() });
+
+        getter.resilient_vault_request(&Method::GET, "samply_pki/ca/pem", VaultOperation::Ca, getter.response_limits.single).await.unwrap();
+        assert_eq!(vault.logins.load(Ordering::Relaxed), 1);
+
+        // Kubernetes rotates the service account token while the Vault token expires
+        std::fs::write(&token_file, "jwt2").unwrap();
+        accept("jwt2");
+        vault.valid_token.lock().unwrap().take();
+        getter.resilient_vault_request(&Method::GET, "samply_pki/ca/pem", VaultOperation::Ca, getter.response_limits.single).await.unwrap();
+        assert_eq!(vault.logins.load(Ordering::Relaxed), 2);
+
+        std::fs::remove_file(&token_file).unwrap();
+        vault.valid_token.lock().unwrap().take();
+        let res = getter.vault.send_authenticated(&Method::GET, &getter.vault.pki_url("samply_pki/ca/pem").unwrap()).await;
+        assert!(matches!(res, Err(SamplyBeamError::VaultAuthError(_))), "A missing token file must fail the login");
+    }
+
+    /// Advances the paused clock in small steps until `done` holds, letting the other tasks run in between, and returns by how much
+    async fn advance_until(done: impl Fn() -> bool) -> Duration {
+        let start = tokio::time::Instant::now();
+        while !done() {
+            assert!(start.elapsed() < Duration::from_secs(60), "Gave up waiting for the other tasks");
+            tokio::time::advance(Duration::from_millis(10)).await;
+            for _ in 0..1000 {
+                tokio::task::yield_now().await;
+            }
+        }
+        start.elapsed()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_token_is_renewed_before_it_expires() {
+        let vault = Arc::new(LoginVault::default());
+        vault.credentials.lock().unwrap().insert("approle".into(), json!({ "role_id": "beam-broker", "secret_id": "secret" }));
+        vault.lease.store(1, Ordering::Relaxed);
+        let url = serve_login_vault(vault.clone()).await;
+        let shutdown = CancellationToken::new();
+        let client = Arc::new(test_vault(&url, approle("secret"), shutdown.clone()));
+        client.login().await.unwrap();
+        let renewal = tokio::spawn(client.clone().keep_token_alive());
+        // Lets the renewal start waiting before the clock is advanced
+        tokio::task::yield_now().await;
+
+        // Renewed after two thirds of the lease
+        let renewed_after = advance_until(|| vault.renewals.load(Ordering::Relaxed) == 1).await;
+        assert!((Duration::from_millis(660)..Duration::from_secs(1)).contains(&renewed_after), "{renewed_after:?}");
+        assert_eq!(vault.logins.load(Ordering::Relaxed), 1);
+
+        // Falls back to logging in again
+        vault.refuse_renewal.store(true, Ordering::Relaxed);
+        let logged_in_after = advance_until(|| vault.logins.load(Ordering::Relaxed) == 2).await;
+        assert!(logged_in_after < Duration::from_secs(1), "{logged_in_after:?}");
+        assert_eq!(client.pki_token.load().as_str(), "token2");
+
+        shutdown.cancel();
+        timeout(Duration::from_secs(1), renewal).await.expect("Renewal must stop on shutdown").unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_certificate_list_is_cached_for_its_lease() {
+        use axum::{extract::State, routing::any, Json, Router};
+
+        let lists = Arc::new(AtomicU64::new(0));
+        let router = Router::new()
+            .route("/v1/samply_pki/certs", any(|State(lists): State<Arc<AtomicU64>>| async move {
+                lists.fetch_add(1, Ordering::Relaxed);
+                Json(json!({ "request_id": "", "lease_id": "", "renewable": false, "lease_duration": 600, "data": { "keys": ["0a:1b"] } }))
+            }))
+            .with_state(lists.clone());
+        let url = serve(router).await;
+        let getter = test_getter(&url, CancellationToken::new());
+
+        let serials = vec!["0a:1b".to_string()];
+        assert_eq!(getter.certificate_list_via_network().await.unwrap(), serials);
+        assert_eq!(getter.certificate_list_via_network().await.unwrap(), serials);
+        assert_eq!(lists.load(Ordering::Relaxed), 1, "The list must be served from the cache");
+        assert_eq!(getter.refresh_interval(), Duration::from_secs(600));
+
+        assert_eq!(getter.refresh_certificate_list().await.unwrap(), serials);
+        assert_eq!(lists.load(Ordering::Relaxed), 2);
+
+        // Once the lease has expired the list is fetched again
+        let expired = getter.certificate_list.load_full().unwrap();
+        getter.certificate_list.store(Some(Arc::new(CachedCertificateList { serials: expired.serials.clone(), fetched_at: expired.fetched_at - expired.ttl, ttl: expired.ttl })));
+        getter.certificate_list_via_network().await.unwrap();
+        assert_eq!(lists.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn test_list_response_warnings() {
+        let list = json!({ "request_id": "", "lease_id": "", "renewable": false, "lease_duration": 0, "data": { "keys": [] } });
+        let body: PkiListResponse = serde_json::from_value(list.clone()).unwrap();
+        assert_eq!(body.warnings, None);
+
+        let mut warned = list;
+        warned["warnings"] = json!(["Endpoint ignored these unrecognized parameters: [foo]", "Deprecated"]);
+        let body: PkiListResponse = serde_json::from_value(warned).unwrap();
+        assert_eq!(body.warnings.unwrap().len(), 2, "Vault sends its warnings as an array");
+    }
+
+    #[tokio::test]
+    async fn test_unexpected_certificate_list_format_is_reported() {
+        use axum::{routing::any, Json, Router};
+
+        // E.g. after a Vault upgrade changed the schema
+        let router = Router::new().route("/v1/samply_pki/certs", any(|| async { Json(json!({ "data": { "serials": ["0a:1b"] } })) }));
+        let url = serve(router).await;
+        let getter = test_getter(&url, CancellationToken::new());
+
+        let res = getter.refresh_certificate_list().await;
+        let Err(SamplyBeamError::VaultDeserializationError { endpoint, source }) = res else {
+            panic!("Unexpected result: {res:?}");
+        };
+        assert_eq!(endpoint, "samply_pki/certs");
+        assert!(source.is_data());
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_fetches_of_a_certificate_share_one_request() {
+        use axum::{extract::State, routing::get, Router};
+
+        let fetches = Arc::new(AtomicU64::new(0));
+        let router = Router::new()
+            .route("/v1/samply_pki/cert/0a:1b/raw/pem", get(|State(fetches): State<Arc<AtomicU64>>| async move {
+                fetches.fetch_add(1, Ordering::Relaxed);
+                // Keep the request in flight until all callers are waiting for it
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                "pem"
+            }))
+            .with_state(fetches.clone());
+        let url = serve(router).await;
+        let getter = Arc::new(test_getter(&url, CancellationToken::new()));
+
+        let mut callers = tokio::task::JoinSet::new();
+        for _ in 0..50 {
+            let getter = getter.clone();
+            callers.spawn(async move { getter.certificate_by_serial_as_pem("0a:1b").await });
+        }
+        while let Some(pem) = callers.join_next().await {
+            assert_eq!(pem.unwrap().unwrap(), "pem");
+        }
+        assert_eq!(fetches.load(Ordering::Relaxed), 1, "Concurrent callers must share a single request");
+        assert!(getter.pending_certificates.lock().unwrap().is_empty());
+
+        // Once the fetch has completed the certificate is requested anew
+        getter.certificate_by_serial_as_pem("0a:1b").await.unwrap();
+        assert_eq!(fetches.load(Ordering::Relaxed), 2);
+    }
+
+    /// The recorded metrics by name and labels, e.g. `beam_cert_cache_hits_total{cache=list}`
+    fn recorded_metrics(snapshotter: &metrics_util::debugging::Snapshotter) -> HashMap<String, metrics_util::debugging::DebugValue> {
+        snapshotter
+            .snapshot()
+            .into_vec()
+            .into_iter()
+            .map(|(key, _, _, value)| {
+                let key = key.key();
+                let labels = key.labels().map(|l| format!("{}={}", l.key(), l.value())).collect::<Vec<_>>().join(",");
+                (format!("{}{{{labels}}}", key.name()), value)
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_vault_requests_are_measured() {
+        use axum::{extract::State, routing::get, Router};
+        use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        // The test runtime is single-threaded, so every metric is recorded on this thread
+        let _guard = metrics::set_default_local_recorder(&recorder);
+
+        let requests = Arc::new(AtomicU64::new(0));
+        let router = Router::new()
+            .route("/v1/samply_pki/ca/pem", get(|State(requests): State<Arc<AtomicU64>>| async move {
+                if requests.fetch_add(1, Ordering::Relaxed) == 0 {
+                    Err(StatusCode::TOO_MANY_REQUESTS)
+                } else {
+                    Ok("pem")
+                }
+            }))
+            .with_state(requests);
+        let url = serve(router).await;
+        let getter = test_getter(&url, CancellationToken::new());
+        getter.resilient_vault_request(&Method::GET, "samply_pki/ca/pem", VaultOperation::Ca, getter.response_limits.single).await.unwrap();
+
+        let metrics = recorded_metrics(&snapshotter);
+        assert_eq!(metrics["beam_vault_requests_total{operation=ca,outcome=client_error}"], DebugValue::Counter(1));
+        assert_eq!(metrics["beam_vault_requests_total{operation=ca,outcome=success}"], DebugValue::Counter(1));
+        assert_eq!(metrics["beam_vault_retries_total{operation=ca}"], DebugValue::Counter(1));
+        let DebugValue::Histogram(ref latencies) = metrics["beam_vault_request_duration_seconds{operation=ca}"] else {
+            panic!("Latency must be a histogram");
+        };
+        assert_eq!(latencies.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_vault_requests_are_limited() {
+        use axum::{extract::State, routing::get, Router};
+        use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+
+        #[derive(Default)]
+        struct InFlight {
+            current: AtomicUsize,
+            max: AtomicUsize,
+        }
+        let in_flight = Arc::new(InFlight::default());
+        let router = Router::new()
+            .route("/v1/samply_pki/ca/pem", get(|State(in_flight): State<Arc<InFlight>>| async move {
+                let current = in_flight.current.fetch_add(1, Ordering::SeqCst) + 1;
+                in_flight.max.fetch_max(current, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                in_flight.current.fetch_sub(1, Ordering::SeqCst);
+                "pem"
+            }))
+            .with_state(in_flight.clone());
+        let url = serve(router).await;
+        let mut getter = test_getter(&url, CancellationToken::new());
+        getter.request_limit = VaultRequestLimit::new(3);
+        let getter = Arc::new(getter);
+
+        let mut callers = tokio::task::JoinSet::new();
+        for _ in 0..10 {
+            let getter = getter.clone();
+            callers.spawn(async move { getter.resilient_vault_request(&Method::GET, "samply_pki/ca/pem", VaultOperation::Ca, getter.response_limits.single).await });
+        }
+        while let Some(resp) = callers.join_next().await {
+            assert!(resp.unwrap().is_ok(), "Requests beyond the limit must queue instead of failing");
+        }
+        assert_eq!(in_flight.max.load(Ordering::SeqCst), 3);
+        assert_eq!(recorded_metrics(&snapshotter)["beam_vault_requests_in_flight{}"], DebugValue::Gauge(0.0.into()));
+    }
+
+    #[tokio::test]
+    async fn test_oversized_vault_responses_are_rejected() {
+        use axum::{body::Body, routing::{any, get}, Router};
+
+        let router = Router::new()
+            .route("/v1/samply_pki/cert/0a:1b/raw/pem", get(|| async { "x".repeat(2048) }))
+            .route("/v1/samply_pki/ca/pem", get(|| async { "x".repeat(1024) }))
+            // Without a Content-Length and never ending
+            .route("/v1/samply_pki/certs", any(|| async {
+                Body::from_stream(async_stream::stream! {
+                    loop {
+                        yield Ok::<_, std::io::Error>(vec![b' '; 1024]);
+                    }
+                })
+            }));
+        let url = serve(router).await;
+        let mut getter = test_getter(&url, CancellationToken::new());
+        getter.response_limits = VaultResponseLimits { list: 64 * 1024, single: 1024 };
+
+        let res = getter.fetch_certificate_by_serial("0a:1b").await;
+        assert!(matches!(res, Err(SamplyBeamError::VaultOtherError(ref e)) if e.contains("limit of 1024 bytes")), "{res:?}");
+        assert_eq!(getter.im_certificate_as_pem().await.unwrap().len(), 1024, "Responses of exactly the limit are fine");
+        let res = timeout(Duration::from_secs(5), getter.refresh_certificate_list()).await.expect("Reading must stop at the limit");
+        assert!(matches!(res, Err(SamplyBeamError::VaultOtherError(ref e)) if e.contains("limit of 65536 bytes")), "{res:?}");
+    }
+
+    #[tokio::test]
+    async fn test_unknown_serials_are_cached_as_not_found() {
+        use axum::{extract::{Path, State}, routing::{any, get}, Json, Router};
+
+        let fetches = Arc::new(AtomicU64::new(0));
+        let router = Router::new()
+            .route("/v1/samply_pki/cert/:serial/raw/pem", get(|State(fetches): State<Arc<AtomicU64>>, Path(serial): Path<String>| async move {
+                fetches.fetch_add(1, Ordering::Relaxed);
+                match serial.as_str() {
+                    "0a:1b" => StatusCode::NO_CONTENT,
+                    _ => StatusCode::NOT_FOUND,
+                }
+            }))
+            .route("/v1/samply_pki/certs", any(|| async {
+                Json(json!({ "request_id": "", "lease_id": "", "renewable": false, "lease_duration": 0, "data": { "keys": ["0a:1b"] } }))
+            }))
+            .with_state(fetches.clone());
+        let url = serve(router).await;
+        let mut getter = test_getter(&url, CancellationToken::new());
+        getter.not_found_cache_ttl = Duration::from_millis(200);
+
+        for serial in ["0a:1b", "0c:2d"] {
+            for _ in 0..3 {
+                let res = getter.certificate_by_serial_as_pem(serial).await;
+                assert!(matches!(res, Err(SamplyBeamError::CertificateNotFound(ref s)) if s == serial), "{res:?}");
+            }
+        }
+        assert_eq!(fetches.load(Ordering::Relaxed), 2, "Missing serials must not be asked for again");
+
+        // Listed certificates have been issued since
+        getter.refresh_certificate_list().await.unwrap();
+        _ = getter.certificate_by_serial_as_pem("0a:1b").await;
+        _ = getter.certificate_by_serial_as_pem("0c:2d").await;
+        assert_eq!(fetches.load(Ordering::Relaxed), 3);
+
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        _ = getter.certificate_by_serial_as_pem("0c:2d").await;
+        assert_eq!(fetches.load(Ordering::Relaxed), 4, "Serials are asked for again after the TTL");
+    }
+
+    #[tokio::test]
+    async fn test_invalidated_certificates_are_fetched_again() {
+        use axum::{extract::State, routing::{any, get}, Json, Router};
+
+        let fetches = Arc::new(AtomicU64::new(0));
+        let router = Router::new()
+            .route("/v1/samply_pki/cert/:serial/raw/pem", get(|State(fetches): State<Arc<AtomicU64>>| async move {
+                format!("pem {}", fetches.fetch_add(1, Ordering::Relaxed))
+            }))
+            .route("/v1/samply_pki/certs", any(|State(fetches): State<Arc<AtomicU64>>| async move {
+                fetches.fetch_add(1, Ordering::Relaxed);
+                Json(json!({ "request_id": "", "lease_id": "", "renewable": false, "lease_duration": 3600, "data": { "keys": ["0a", "0b"] } }))
+            }))
+            .with_state(fetches.clone());
+        let url = serve(router).await;
+        let mut getter = test_getter(&url, CancellationToken::new());
+        getter.serve_stale_on_error = true;
+
+        getter.warm_cache().await;
+        assert_eq!(fetches.load(Ordering::Relaxed), 3);
+        getter.missing_certificates.lock().unwrap().insert("0c".into(), Instant::now());
+
+        getter.invalidate("0a");
+        assert!(!getter.prefetched_certificates.lock().unwrap().contains_key("0a"));
+        assert!(getter.prefetched_certificates.lock().unwrap().contains_key("0b"), "Other certificates are kept");
+        assert_eq!(getter.certificate_by_serial_as_pem("0a").await.unwrap(), "pem 3", "The certificate is fetched again");
+        getter.certificate_list_via_network().await.unwrap();
+        assert_eq!(fetches.load(Ordering::Relaxed), 4, "The list is still cached");
+
+        getter.invalidate_all();
+        assert!(getter.prefetched_certificates.lock().unwrap().is_empty());
+        assert!(getter.known_good_certificates.lock().unwrap().is_empty());
+        assert!(getter.missing_certificates.lock().unwrap().is_empty());
+        getter.certificate_list_via_network().await.unwrap();
+        assert_eq!(fetches.load(Ordering::Relaxed), 5, "The list is fetched again");
+        assert_eq!(getter.certificate_by_serial_as_pem("0b").await.unwrap(), "pem 5");
+    }
+
+    /// Issues `REVOKED_CERT` and signs `CRL_PEM` and `OUTDATED_CRL_PEM`
+    const CRL_ISSUER: &str = "-----BEGIN CERTIFICATE-----\nMIIDDzCCAfegAwIBAgIULXe1ZmX8lVVLf1N6vUpZtmKt3QowDQYJKoZIhvcNAQEL\nBQAwFjEUMBIGA1UEAwwLY3JsLXRlc3QtY2EwIBcNMjYxMDE1MjExNDUyWhgPMjEy\nNjA5MjEyMTE0NTJaMBYxFDASBgNVBAMMC2NybC10ZXN0LWNhMIIBIjANBgkqhkiG\n9w0BAQEFAAOCAQ8AMIIBCgKCAQEAz7gki862IKqSg9cNAj9s9Rjyz77rD4BylMhB\nIpyeoMmpq+TDXFEpFAOb4FdFODa2Yhf4t/4kK3j5HNGDx9KH44FeDcL0EFnHEC9G\n34gcgze7//ZrS5S429MAHu7Z1ewgnpHsUAkjt+UI9VX+E0CCBjj8ukunbyG/0kMe\nD1289DDJUokq9rJShWURZ6l5LlrllWgsAfXcDSiM+N+2CsCsDDjqVIBRxJDtkmoq\ngrjG+w1OitJ0JRrEpkFI97kqQ8uFgDdoCEAeVzHFer0J6o2MivCp0t5IeMkfqVFR\nvMI+eeIi4rA7scjY0qWcgt9wDkAnI+PDY0SBrY+0MPS9NKHpQwIDAQABo1MwUTAd\nBgNVHQ4EFgQUvLfcV9umiw3aeXcO/vUSrPfA7jkwHwYDVR0jBBgwFoAUvLfcV9um\niw3aeXcO/vUSrPfA7jkwDwYDVR0TAQH/BAUwAwEB/zANBgkqhkiG9w0BAQsFAAOC\nAQEAM+FZTBZ884oX50xHKDoWekM/dEpCyECWuDO1oNGoRha2S2db3BafK2NUhO2w\nb4FOdnEna0hfn5ZODbEZtXy1Cs3kqhJpKA8jvgi5PLpkfldACVLFWuF4w3xATKah\nx1Nxj9dUkYO80/mRObTeIJrRysbjhXST2Y1g+VZdLKC87YIO4NIefGn3qkTqzt6T\n0VMgBHcw4+Ko0KmWwYJRJC7Hm0Cm3agc/FwVllJfohJ4eTA6IUzD54FtJjRtzdWm\n7YthX0URjYfpXJPaB73cMs8bNJv/OLVWBACmskGYVAKyo8VXSWTKxJ6VoIP5VUrh\nZX8QjchLWkY27pMUnK385XuQnw==\n-----END CERTIFICATE-----";
+    const REVOKED_CERT: &str = "-----BEGIN CERTIFICATE-----\nMIIC5zCCAc+gAwIBAgIBATANBgkqhkiG9w0BAQsFADAWMRQwEgYDVQQDDAtjcmwt\ndGVzdC1jYTAgFw0yNjEwMTUyMTE0NTJaGA8yMTI2MDkyMTIxMTQ1MlowEjEQMA4G\nA1UEAwwHcmV2b2tlZDCCASIwDQYJKoZIhvcNAQEBBQADggEPADCCAQoCggEBAJyn\nz3PvQMe0getsVFGk8AfOvix+Zbfy+DvK2Vva6DtqRYrnqRcQ6D0xQi/BbAQbs7J3\n44amHXpX3nia48Zx+K2hcjZKu8KlD9b6sWdyLvgBn2iCP0p+simN0/j1IhsT4QFQ\naH+5vXFnusJFOz1yBjLs9ZqPfv0e19/4sZ9UyA+AlHm03g0ljmOpkfGd1YLsdJJA\n8MUbf09Z8yKsQiK1UIy8CR3l3dGlbrrDoFUmM8KVwqutd4GdrKe+EINlNwNdMBPx\ny64u678OiGGWrxw4Kz2G9YHX8ENBPUy4doeqcRmIrPlDT08lSIxtlYqhivFvT1xf\nB7kCx2RHQJtaD/GrW6MCAwEAAaNCMEAwHQYDVR0OBBYEFAJRQOg0zpA+VIQ73wAf\nuxwR6rdpMB8GA1UdIwQYMBaAFLy33FfbposN2nl3Dv71Eqz3wO45MA0GCSqGSIb3\nDQEBCwUAA4IBAQByyASl0P8cpD0L5zEjKWTdwTLTodWHR+dF5AvfFd/HPt/wrpm/\nBOmuP5zpYJH9u3jA4pVq2g8hQLO0CgVzaXU8u66bWh0c1HzruXFrqlZ5iFWb4I/9\nE2naevYsK/ukX2XS+EyY1+MLq67lnvbN07Lnc+12VbXjEfSDAaxeMBUDz9Lg5CJu\nhPfKOdoH7FOuJjmANVl3lnT9ZsFjQaQNAf7mnDjjKHie5FOkFkr1Pd8LNnVJ2ysI\nS2aFasK/l1o9NjcJy6ANWCZRxC8xygTsTfOVPq38F2w73OB+NGNE87DliLCIrNb1\nWEyPVI9CCfN2ax8j/+UlrYo5kJrmYZ5NbhVv\n-----END CERTIFICATE-----";
+    /// Revokes `REVOKED_CERT` and is due for an update in 2126
+    const CRL_PEM: &[u8] = b"-----BEGIN X509 CRL-----\nMIIBhzBxAgEBMA0GCSqGSIb3DQEBCwUAMBYxFDASBgNVBAMMC2NybC10ZXN0LWNh\nFw0yNjEwMTUyMTE0NTJaGA8yMTI2MDkyMTIxMTQ1MlowFDASAgEBFw0yNjEwMTUy\nMTE0NTJaoA8wDTALBgNVHRQEBAICEAAwDQYJKoZIhvcNAQELBQADggEBAE1kpN5w\n+Ubkoa2hWtMSkeEv8MAupcPRfvUiROjfKL/sKH7v1VhI7fGQxMynEBx4DCcdekOt\n6r1CKJAEMg9+0x+vlu/oZRr62APKZK/HpvSGCjxaKUvZaZ2zjANU2sodDPc0M3vZ\nkPJS6f5EW9UXeO6SZTeOYQKNn+e+y2nY5nC2T4wX9GC/6RKlc9hUJPekTpcrIdb+\njshXsg1BQ9xgcVCm6uVnt3Q/15N1LauoTk1pBCt2HuZMMcvY1GWIt3bBpahUr1TX\nZc5I1l04WLm1L0YdlLVA2EE4R6zwkYrhbl7cD6dpe7pWSVlyn1Ws1J4cgqfn986F\nUqDhxElYLZmQvWA=\n-----END X509 CRL-----";
+    /// Like `CRL_PEM` but its next update has passed
+    const OUTDATED_CRL_PEM: &[u8] = b"-----BEGIN X509 CRL-----\nMIIBhTBvAgEBMA0GCSqGSIb3DQEBCwUAMBYxFDASBgNVBAMMC2NybC10ZXN0LWNh\nFw0yNjEwMTUyMTE0NTJaFw0yNjEwMTUyMTE0NTNaMBQwEgIBARcNMjYxMDE1MjEx\nNDUyWqAPMA0wCwYDVR0UBAQCAhABMA0GCSqGSIb3DQEBCwUAA4IBAQByoYTlqqnx\n2pRNUjYA6nPczcaN5REr934u+rTOjx4Xtip2NFpakmOtTSfvm753jGgZmEajJjvW\nLLd/4zs/jE8K1gauJjy6zF9ApqLssEZvo7wS8hL6R9ZEzWF72L3o6tBsQ07gyRDF\nxYHsBCnSClp1edBmXGDeIxj12FQcYzpuDOlB1+zmo7twZCDtaPBKdorxcepNDOeH\nHfDxucrhwzjNKB3sokHyg7f2x5k/Cn8t3lYwmLEFZa6xk38sC4bYq0R4z01vDi+V\npU6SwK3Ykwzf7WzoelGJz46p9gHZfIFIxl93v6/050TsgSqFeoHZT+fTDz+xVz9U\nwz2uJkw9oTTR\n-----END X509 CRL-----";
+    /// Signed by another CA than `CRL_ISSUER`
+    const FOREIGN_CRL_PEM: &[u8] = b"-----BEGIN X509 CRL-----\nMIIB1zCBwAIBATANBgkqhkiG9w0BAQsFADBUMQswCQYDVQQGEwJERTETMBEGA1UE\nCAwKSGVpZGVsYmVyZzEhMB8GA1UECgwYSW50ZXJuZXQgV2lkZ2l0cyBQdHkgTHRk\nMQ0wCwYDVQQDDAR0ZXN0Fw0yMzA4MjQwODM3MTRaFw0yMzA5MjMwODM3MTRaMCcw\nJQIUI27ICLbN8DI5EMOLCwmd8miMGTwXDTIzMDgyNDA4MzQxNlqgDzANMAsGA1Ud\nFAQEAgIQADANBgkqhkiG9w0BAQsFAAOCAQEAJCLrxzeDdgRIqfGEPjBff21Tefir\n3mbxZtrCa232zJLmurX1zQ5S9pa/QvGQ/Fj91FUbNezomh1NTmJkscj3Mh8Ph/Mv\nIbburXhPG5ypHeOXAGQqpKADZyBPMRwIWaTqmtsMg5kdHzYScvvHFZRcy8KCKx6e\niFdqNc9qZkyvCazpzjWK+JpK6TPCpI68LO/DxhWPirclhjZLs3z6iAuxmW8TM71T\nC7YzZ0Z17xCttNW7155LpFWUo1YOQk1Cy9W2d3EIBMmZhn6yBUExusXzcj4BnXZ7\nzCqIhPnMU4nLrarkzgmy+v1ysdo1lFGQ4fC3XFY+oWxUsImFP9JKHKEbBA==\n-----END X509 CRL-----";
+
+    #[tokio::test]
+    async fn test_crl_is_cached_and_refreshed() {
+        use axum::{extract::State, routing::get, Router};
+
+        struct Crl {
+            fetches: AtomicU64,
+            available: AtomicBool,
+        }
+        let crl = Arc::new(Crl { fetches: AtomicU64::new(0), available: AtomicBool::new(true) });
+        let router = Router::new()
+            .route("/pki.crl", get(|State(crl): State<Arc<Crl>>| async move {
+                crl.fetches.fetch_add(1, Ordering::Relaxed);
+                if crl.available.load(Ordering::Relaxed) {
+                    Ok(CRL_PEM)
+                } else {
+                    Err(StatusCode::SERVICE_UNAVAILABLE)
+                }
+            }))
+            // Vault itself is only asked for the CA certificate to verify the CRL with
+            .route("/v1/samply_pki/ca/pem", get(|| async { CRL_ISSUER }))
+            .with_state(crl.clone());
+        let url = serve(router).await;
+        let mut getter = test_getter(&url, CancellationToken::new());
+        getter.crl_settings = CrlSettings {
+            url: Some(format!("{url}/pki.crl").parse().unwrap()),
+            refresh_interval: Duration::from_millis(200),
+            fail_open: false,
+        };
+        let cert = X509::from_pem(REVOKED_CERT.as_bytes()).unwrap();
+
+        assert!(getter.is_revoked(&cert).await.unwrap());
+        assert!(!getter.is_revoked(&X509::from_pem(CRL_ISSUER.as_bytes()).unwrap()).await.unwrap());
+        assert!(getter.get_crl().await.unwrap().is_some());
+        assert_eq!(crl.fetches.load(Ordering::Relaxed), 1, "The CRL is cached");
+
+        crl.available.store(false, Ordering::Relaxed);
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        assert!(getter.is_revoked(&cert).await.unwrap(), "The last CRL is used while it cannot be refreshed");
+        assert!(getter.is_revoked(&cert).await.unwrap());
+        assert_eq!(crl.fetches.load(Ordering::Relaxed), 2, "A failed refresh is not tried again before the refresh interval");
+
+        getter.invalidate_all();
+        assert!(matches!(getter.is_revoked(&cert).await, Err(SamplyBeamError::VaultOtherError(_))), "Fails closed without any CRL");
+        getter.crl_settings.fail_open = true;
+        assert!(!getter.is_revoked(&cert).await.unwrap());
+        assert!(getter.get_crl().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_crl_from_url_must_be_signed_and_current() {
+        use axum::{routing::get, Router};
+
+        let router = Router::new()
+            .route("/foreign.crl", get(|| async { FOREIGN_CRL_PEM }))
+            .route("/outdated.crl", get(|| async { OUTDATED_CRL_PEM }))
+            .route("/v1/samply_pki/ca/pem", get(|| async { CRL_ISSUER }));
+        let url = serve(router).await;
+        let mut getter = test_getter(&url, CancellationToken::new());
+        let cert = X509::from_pem(REVOKED_CERT.as_bytes()).unwrap();
+
+        for (path, reason) in [("foreign.crl", "not signed"), ("outdated.crl", "outdated")] {
+            getter.crl_settings = CrlSettings {
+                url: Some(format!("{url}/{path}").parse().unwrap()),
+                refresh_interval: Duration::from_secs(300),
+                fail_open: false,
+            };
+            getter.invalidate_all();
+            match getter.is_revoked(&cert).await {
+                Err(SamplyBeamError::VaultOtherError(e)) => assert!(e.contains(reason), "{e}"),
+                other => panic!("The CRL from {path} must be rejected: {other:?}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_vault_without_crl_revokes_nothing() {
+        use axum::{routing::get, Router};
+
+        let router = Router::new().route("/v1/samply_pki/crl", get(|| async { StatusCode::NOT_FOUND }));
+        let url = serve(router).await;
+        let getter = test_getter(&url, CancellationToken::new());
+
+        assert!(!getter.is_revoked(&X509::from_pem(REVOKED_CERT.as_bytes()).unwrap()).await.unwrap());
+        assert!(getter.get_crl().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_open_circuit_fails_fast() {
+        let mut getter = test_getter("http://127.0.0.1:1", CancellationToken::new());
+        getter.circuit_breaker = CircuitBreaker::new(CircuitBreakerSettings {
+            threshold: 2,
+            window: Duration::from_secs(60),
+            cooldown: Duration::from_secs(60),
+        });
+        let res = getter.certificate_list_via_network().await;
+        assert!(matches!(res, Err(SamplyBeamError::VaultCircuitOpen(_))), "The retries must stop once the circuit opens: {res:?}");
+
+        let res = getter.certificate_by_serial_as_pem("01").await;
+        assert!(matches!(res, Err(SamplyBeamError::VaultCircuitOpen(_))), "Must fail without asking Vault: {res:?}");
+        // The CA certificate is still requested, e.g. at startup
+        getter.retry_deadline = Duration::from_millis(100);
+        let res = getter.resilient_vault_request(&Method::GET, "samply_pki/ca/pem", VaultOperation::Ca, getter.response_limits.single).await;
+        assert!(matches!(res, Err(SamplyBeamError::VaultGaveUp { .. })), "Unexpected result: {res:?}");
+    }
+
+    #[tokio::test]
+    async fn test_stale_certificates_are_served_while_vault_is_unavailable() {
+        use axum::{extract::{Path, State}, routing::{any, get}, Json, Router};
+        use std::sync::atomic::AtomicBool;
+
+        let down = Arc::new(AtomicBool::new(false));
+        let router = Router::new()
+            .route("/v1/samply_pki/certs", any(|State(down): State<Arc<AtomicBool>>| async move {
+                if down.load(Ordering::Relaxed) {
+                    return Err(StatusCode::TOO_MANY_REQUESTS);
+                }
+                Ok(Json(json!({ "request_id": "", "lease_id": "", "renewable": false, "lease_duration": 0, "data": { "keys": ["01", "02"] } })))
+            }))
+            .route("/v1/samply_pki/cert/:serial/raw/pem", get(|State(down): State<Arc<AtomicBool>>, Path(serial): Path<String>| async move {
+                if down.load(Ordering::Relaxed) {
+                    return Err(StatusCode::TOO_MANY_REQUESTS);
+                }
+                Ok(format!("pem {serial}"))
+            }))
+            .with_state(down.clone());
+        let url = serve(router).await;
+        let mut getter = test_getter(&url, CancellationToken::new());
+        getter.serve_stale_on_error = true;
+        getter.retry_deadline = Duration::from_millis(100);
+        // Without a lease the list is fetched every time
+        getter.cache_ttl_bounds.default = Duration::ZERO;
+
+        assert_eq!(getter.certificate_list_or_stale().await.unwrap(), MaybeStale::Fresh(vec!["01".to_string(), "02".to_string()]));
+        assert_eq!(getter.certificate_by_serial_or_stale("01").await.unwrap(), MaybeStale::Fresh("pem 01".to_string()));
+
+        down.store(true, Ordering::Relaxed);
+        assert!(getter.certificate_list_or_stale().await.unwrap().is_stale());
+        assert_eq!(getter.certificate_by_serial_or_stale("01").await.unwrap(), MaybeStale::Stale("pem 01".to_string()));
+        let res = getter.certificate_by_serial_as_pem("02").await;
+        assert!(matches!(res, Err(SamplyBeamError::VaultGaveUp { .. })), "Nothing to fall back to: {res:?}");
+
+        getter.serve_stale_on_error = false;
+        assert!(getter.certificate_list_via_network().await.is_err());
+        assert!(getter.certificate_by_serial_as_pem("01").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_invalid_token_is_not_retried() {
+        let mut getter = test_getter("http://127.0.0.1:1", CancellationToken::new());
+        getter.vault = Arc::new(test_vault("http://127.0.0.1:1", VaultAuth::Token("s.token\n".into()), CancellationToken::new()));
+        let res = timeout(
+            Duration::from_millis(500),
+            getter.resilient_vault_request(&Method::GET, "samply_pki/ca/pem", VaultOperation::Ca, getter.response_limits.single),
+        )
+        .await
+        .expect("Must not be retried");
+        assert!(matches!(res, Err(SamplyBeamError::HttpRequestBuildError(_))), "Unexpected result: {res:?}");
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_retries_share_a_health_check() {
+        use axum::{extract::State, routing::get, Router};
+
+        #[derive(Default)]
+        struct Calls {
+            ca: AtomicU64,
+            health: AtomicU64,
+        }
+        let calls = Arc::new(Calls::default());
+        let router = Router::new()
+            .route("/v1/samply_pki/ca/pem", get(|State(calls): State<Arc<Calls>>| async move {
+                // Both requests fail once
+                if calls.ca.fetch_add(1, Ordering::Relaxed) < 2 {
+                    Err(StatusCode::INTERNAL_SERVER_ERROR)
+                } else {
+                    Ok("pem")
+                }
+            }))
+            .route("/v1/sys/health", get(|State(calls): State<Arc<Calls>>| async move {
+                calls.health.fetch_add(1, Ordering::Relaxed);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                StatusCode::OK
+            }))
+            .with_state(calls.clone());
+        let url = serve(router).await;
+        let getter = test_getter(&url, CancellationToken::new());
+
+        let (a, b) = tokio::join!(
+            getter.resilient_vault_request(&Method::GET, "samply_pki/ca/pem", VaultOperation::Ca, getter.response_limits.single),
+            getter.resilient_vault_request(&Method::GET, "samply_pki/ca/pem", VaultOperation::Ca, getter.response_limits.single),
+        );
+        a.unwrap();
+        b.unwrap();
+        assert_eq!(calls.health.load(Ordering::Relaxed), 1, "Both retries must share one health check");
+    }
+
+    #[tokio::test]
+    async fn test_health_path_is_configurable() {
+        use axum::{extract::RawQuery, routing::get, Router};
+
+        // A proxy in front of Vault which only exposes its own health path
+        let router = Router::new().route("/healthz", get(|RawQuery(query): RawQuery| async move {
+            if query.as_deref() == Some("standbyok=true") { StatusCode::OK } else { StatusCode::TOO_MANY_REQUESTS }
+        }));
+        let url = serve(router).await;
+
+        let mut getter = test_getter(&url, CancellationToken::new());
+        getter.health_path = "/healthz".into();
+        let res = getter.check_vault_health().await;
+        assert!(matches!(res, Err(SamplyBeamError::VaultOtherError(_))), "A standby is not healthy by default: {res:?}");
+
+        let mut getter = test_getter(&url, CancellationToken::new());
+        getter.health_path = "/healthz?standbyok=true".into();
+        getter.check_vault_health().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sealed_vault_is_checked_again_soon() {
+        use axum::{extract::State, routing::get, Router};
+
+        let health_checks = Arc::new(AtomicU64::new(0));
+        let router = Router::new()
+            .route("/v1/sys/health", get(|State(checks): State<Arc<AtomicU64>>| async move {
+                checks.fetch_add(1, Ordering::Relaxed);
+                StatusCode::SERVICE_UNAVAILABLE
+            }))
+            .with_state(health_checks.clone());
+        let url = serve(router).await;
+        let mut getter = test_getter(&url, CancellationToken::new());
+        getter.health_cache_ttl = Duration::from_secs(60);
+
+        assert!(matches!(getter.check_vault_health().await, Err(SamplyBeamError::VaultSealed)));
+        assert!(matches!(getter.check_vault_health().await, Err(SamplyBeamError::VaultSealed)));
+        assert_eq!(health_checks.load(Ordering::Relaxed), 1);
+        tokio::time::sleep(SEALED_HEALTH_CACHE_TTL).await;
+        getter.check_vault_health().await.unwrap_err();
+        assert_eq!(health_checks.load(Ordering::Relaxed), 2, "A sealed Vault must not be remembered for the whole TTL");
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_vault_to_be_unsealed() {
+        use axum::{extract::State, routing::get, Router};
+
+        let health_checks = Arc::new(AtomicU64::new(0));
+        let router = Router::new()
+            .route("/v1/sys/health", get(|State(checks): State<Arc<AtomicU64>>| async move {
+                // Sealed for the first three checks
+                if checks.fetch_add(1, Ordering::Relaxed) < 3 { StatusCode::SERVICE_UNAVAILABLE } else { StatusCode::OK }
+            }))
+            .with_state(health_checks.clone());
+        let url = serve(router).await;
+        let mut getter = test_getter(&url, CancellationToken::new());
+        getter.health_cache_ttl = Duration::ZERO;
+
+        getter.wait_for_vault(Duration::from_secs(5)).await.unwrap();
+        assert_eq!(health_checks.load(Ordering::Relaxed), 4);
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_vault_gives_up_after_timeout() {
+        use axum::{routing::get, Router};
+
+        let router = Router::new().route("/v1/sys/health", get(|| async { StatusCode::NOT_IMPLEMENTED }));
+        let url = serve(router).await;
+        let mut getter = test_getter(&url, CancellationToken::new());
+        getter.health_cache_ttl = Duration::ZERO;
+
+        let started = Instant::now();
+        let res = timeout(Duration::from_secs(2), getter.wait_for_vault(Duration::from_millis(300)))
+            .await
+            .expect("Waiting must stop at the timeout");
+        assert!(matches!(res, Err(SamplyBeamError::VaultNotInitialized)), "Unexpected result: {res:?}");
+        assert!(started.elapsed() >= Duration::from_millis(300));
+
+        // Other failures are not waited for
+        let mut getter = test_getter("http://127.0.0.1:1", CancellationToken::new());
+        getter.retry_budgets.health = 1;
+        timeout(Duration::from_secs(2), getter.wait_for_vault(Duration::from_secs(60))).await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_fail_over_prefers_healthy_addresses() {
+        let vault = test_vault("http://vault-0:8200,http://vault-1:8200,http://vault-2:8200", VaultAuth::Token("token".into()), CancellationToken::new());
+        assert_eq!(vault.pki_url("sys/health").unwrap().as_str(), "http://vault-0:8200/v1/sys/health");
+        vault.fail_over(0);
+        assert_eq!(vault.current_address(), 1);
+        vault.fail_over(0);
+        assert_eq!(vault.current_address(), 1, "Another request has already failed over");
+        vault.fail_over(1);
+        assert_eq!(vault.current_address(), 2);
+        vault.mark_healthy(1);
+        vault.fail_over(2);
+        assert_eq!(vault.current_address(), 1, "Address 0 has failed while 1 has recovered");
+        vault.fail_over(1);
+        assert_eq!(vault.current_address(), 2, "If all have failed, simply the next one is tried");
+    }
+
+    #[tokio::test]
+    async fn test_requests_fail_over_to_the_next_vault() {
+        use axum::{routing::get, Router};
+
+        let sealed = serve(Router::new()
+            .route("/v1/samply_pki/ca/pem", get(|| async { StatusCode::INTERNAL_SERVER_ERROR }))
+            .route("/v1/sys/health", get(|| async { StatusCode::SERVICE_UNAVAILABLE }))).await;
+        let active = serve(Router::new().route("/v1/samply_pki/ca/pem", get(|| async { "pem" }))).await;
+
+        // Nothing listens at the first address
+        let getter = test_getter(&format!("http://127.0.0.1:1,{sealed},{active}"), CancellationToken::new());
+        let resp = getter.resilient_vault_request(&Method::GET, "samply_pki/ca/pem", VaultOperation::Ca, getter.response_limits.single).await.unwrap();
+        assert_eq!(resp.text().await.unwrap(), "pem");
+        assert_eq!(getter.vault.current_address(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_retry_after_is_capped_by_the_backoff_maximum() {
+        use axum::{http::HeaderMap, routing::get, Router};
+
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counted = requests.clone();
+        let router = Router::new().route("/v1/samply_pki/ca/pem", get(move || async move {
+            match counted.fetch_add(1, Ordering::Relaxed) {
+                0 => Err((StatusCode::TOO_MANY_REQUESTS, HeaderMap::from_iter([(header::RETRY_AFTER, "7200".parse().unwrap())]))),
+                _ => Ok("-----BEGIN CERTIFICATE-----"),
+            }
+        }));
+        let getter = test_getter(&serve(router).await, CancellationToken::new());
+        let resp = timeout(
+            Duration::from_secs(2),
+            getter.resilient_vault_request(&Method::GET, "samply_pki/ca/pem", VaultOperation::Ca, getter.response_limits.single),
+        )
+        .await
+        .expect("Retry-After must not be waited for longer than the backoff maximum")
+        .unwrap();
+        assert_eq!(resp.text().await.unwrap(), "-----BEGIN CERTIFICATE-----");
+        assert_eq!(requests.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn test_retries_stop_at_deadline() {
+        // Nothing listens here, so every attempt fails and would be retried 100 times
+        let mut getter = test_getter("http://127.0.0.1:1", CancellationToken::new());
+        getter.retry_deadline = Duration::from_millis(300);
+        let res = timeout(
+            Duration::from_secs(2),
+            getter.resilient_vault_request(&Method::GET, "samply_pki/ca/pem", VaultOperation::Ca, getter.response_limits.single),
+        )
+        .await
+        .expect("Retries must stop at the deadline");
+        let Err(err @ SamplyBeamError::VaultGaveUp { .. }) = res else {
+            panic!("Unexpected result: {res:?}");
+        };
+        let msg = err.to_string();
+        assert!(msg.contains(" attempts in ") && msg.ends_with("s. Giving up."), "Unexpected message: {msg}");
+    }
+
+    #[tokio::test]
+    async fn test_ca_chain_includes_root() {
+        use axum::{routing::get, Router};
+
+        let router = Router::new()
+            .route("/v1/samply_pki/ca/pem", get(|| async { "intermediate" }))
+            .route("/v1/samply_pki/ca_chain", get(|| async { "intermediate\nroot" }));
+        let url = serve(router).await;
+        let getter = test_getter(&url, CancellationToken::new());
+
+        assert_eq!(getter.im_certificate_as_pem().await.unwrap(), "intermediate");
+        assert_eq!(getter.ca_chain_as_pem().await.unwrap(), "intermediate\nroot");
+    }
+
+    /// Valid from 2023-08-24 until 2023-09-23
+    const EXPIRED_CERT: &str = "-----BEGIN CERTIFICATE-----\nMIIDLjCCAhYCFCNuyAi2zfAyORDDiwsJnfJojBk8MA0GCSqGSIb3DQEBCwUAMFQx\nCzAJBgNVBAYTAkRFMRMwEQYDVQQIDApIZWlkZWxiZXJnMSEwHwYDVQQKDBhJbnRl\ncm5ldCBXaWRnaXRzIFB0eSBMdGQxDTALBgNVBAMMBHRlc3QwHhcNMjMwODI0MDc1\nMjM1WhcNMjMwOTIzMDc1MjM1WjBTMQswCQYDVQQGEwJERTETMBEGA1UECAwKU29t\nZS1TdGF0ZTEhMB8GA1UECgwYSW50ZXJuZXQgV2lkZ2l0cyBQdHkgTHRkMQwwCgYD\nVQQDDANmb28wggEiMA0GCSqGSIb3DQEBAQUAA4IBDwAwggEKAoIBAQDd2aLmn3EX\nkSMIxdMWXe8oQNyWBktyBoNK+gSyYBO3SkIcRKM41Ama4GgeIJnDRbL2XLC3Gkhv\nHyvBocVYeP/kWtw8Zvmmi/9Ztv04pVn6LzX2Yaqtm9X78Jo3n2ug2cC8IEoMaYbF\nTcUuV7IX1oSF4Fo3KRRoAUki6yok3uEFVH5cl/UPYyYRJ+CKvoras4c9arZ3Nk3G\na9ImlniBPZ3qQwnkJX5pKcKFzYka7xrNbCpInF/v68R9Hiy4YwUQbGeTfTM+W3i9\nn5ZnSWuwY5lew3WSnpcfYKJQCLhJ9iAXq13+oYbDFSA12pSBIEz0xve3/zR5Cg81\nLGKtvpllzfGVAgMBAAEwDQYJKoZIhvcNAQELBQADggEBAGk2Zii31WPqXwzAUNc0\nS6GjTkHMP5gzdTjYspdBOm8bdJROEp9O/vjAc2Oci4waI9FT6oZPhwX/a6TDtUGs\nAZeQYt9vlS4LPgs6RTF4sFXy+pl7EA/wYqb7e0LSVsx7feTpeRRCIbFXenTKa7m+\nMXsDRCR9weplJdFeyBodFBsNMpShOe3WbnQ7Gi3jLYCUb7acX4I4H4VA7HdakZJr\nEJzP0TQzt/vrSwA2GsNWgO5sOXYkYvjieqzfi89fqY6ZT2jWQ+v+wc7kDiBRbkVU\nGooK1Vo2TJYeaPPmyNomRZtlpgXBGztYyJTfPY0A0M1Fky8Y8QLObtxG0/fkWOft\nHyU=\n-----END CERTIFICATE-----";
+
+    #[tokio::test]
+    async fn test_expired_certificates_are_rejected_if_configured() {
+        use axum::{routing::get, Router};
+
+        let router = Router::new().route("/v1/samply_pki/cert/0a:1b/raw/pem", get(|| async { EXPIRED_CERT }));
+        let url = serve(router).await;
+        let mut getter = test_getter(&url, CancellationToken::new());
+
+        assert_eq!(getter.certificate_by_serial_as_pem("0a:1b").await.unwrap(), EXPIRED_CERT, "Permissive by default");
+
+        getter.reject_expired_certs = true;
+        let res = getter.certificate_by_serial_as_pem("0a:1b").await;
+        let Err(SamplyBeamError::CertificateExpired { serial, not_after }) = res else {
+            panic!("Unexpected result: {res:?}");
+        };
+        assert_eq!(serial, "0a:1b");
+        assert_eq!(not_after, "Sep 23 07:52:35 2023 GMT");
+    }
+
+    #[tokio::test]
+    async fn test_warm_cache_prefetches_certificates() {
+        use axum::{extract::{Path, State}, routing::{any, get}, Json, Router};
+
+        let fetches = Arc::new(AtomicU64::new(0));
+        let router = Router::new()
+            .route("/v1/samply_pki/certs", any(|| async { Json(json!({ "request_id": "", "lease_id": "", "renewable": false, "lease_duration": 600, "data": { "keys": ["01", "02", "03"] } })) }))
+            .route("/v1/samply_pki/cert/:serial/raw/pem", get(|State(fetches): State<Arc<AtomicU64>>, Path(serial): Path<String>| async move {
+                fetches.fetch_add(1, Ordering::Relaxed);
+                // Unknown to Vault despite being listed, which is not retried
+                if serial == "03" {
+                    return Err(StatusCode::NOT_FOUND);
+                }
+                Ok(format!("pem {serial}"))
+            }))
+            .with_state(fetches.clone());
+        let url = serve(router).await;
+        let getter = test_getter(&url, CancellationToken::new());
+
+        getter.warm_cache().await;
+        assert_eq!(fetches.load(Ordering::Relaxed), 3);
+        assert_eq!(getter.prefetched_certificates.lock().unwrap().len(), 2, "A failed certificate must not prevent the others");
+
+        assert_eq!(getter.certificate_by_serial_as_pem("01").await.unwrap(), "pem 01");
+        assert_eq!(getter.certificate_by_serial_as_pem("02").await.unwrap(), "pem 02");
+        assert_eq!(fetches.load(Ordering::Relaxed), 3, "Prefetched certificates must not be fetched again");
+        // Prefetched certificates are only handed out once so that they do not go stale
+        getter.certificate_by_serial_as_pem("01").await.unwrap();
+        assert_eq!(fetches.load(Ordering::Relaxed), 4);
+    }
+
+    #[test]
+    fn test_refresh_interval() {
+        let mut getter = test_getter("http://vault:8200", CancellationToken::new());
+        getter.cache_ttl.store(600, Ordering::Relaxed);
+        assert_eq!(getter.refresh_interval(), Duration::from_secs(600), "Follows the list's lease by default");
+        getter.refresh_interval = Some(Duration::from_secs(30));
+        assert_eq!(getter.refresh_interval(), Duration::from_secs(30));
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_awaits_background_tasks() {
+        let getter = test_getter("http://vault:8200", CancellationToken::new());
+        let stopped = Arc::new(AtomicBool::new(false));
+        getter.spawn({
+            let vault = getter.vault.clone();
+            let stopped = stopped.clone();
+            async move {
+                assert!(vault.unless_shutdown(tokio::time::sleep(Duration::from_secs(3600))).await.is_err());
+                // Cleaning up takes a moment, which shutdown must wait for
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                stopped.store(true, Ordering::Relaxed);
+            }
+        });
+        getter.spawn(async {});
+
+        tokio::time::timeout(Duration::from_secs(5), getter.shutdown()).await.expect("Background tasks did not stop");
+        assert!(stopped.load(Ordering::Relaxed));
+        assert!(getter.background_tasks.lock().unwrap().is_empty());
+        assert!(getter.vault.shutdown.is_cancelled());
+    }
+
+    #[test]
+    fn test_response_action() {
+        let default = RetryableStatusCodes::default();
+        for code in 100..=599 {
+            let code = StatusCode::from_u16(code).unwrap();
+            let expected = if code.is_success() {
+                ResponseAction::Return
+            } else if code == StatusCode::TOO_MANY_REQUESTS || (code.is_server_error() && code != StatusCode::NOT_IMPLEMENTED) {
+                ResponseAction::Retry
+            } else {
+                ResponseAction::Fail
+            };
+            assert_eq!(response_action(code, VaultOperation::List, &default), expected, "{code}");
+        }
+        assert_eq!(response_action(StatusCode::NOT_FOUND, VaultOperation::Fetch, &default), ResponseAction::Return);
+        assert_eq!(response_action(StatusCode::NOT_FOUND, VaultOperation::Ca, &default), ResponseAction::Fail);
+
+        let configured = RetryableStatusCodes(vec!["503".parse().unwrap(), " 520 - 530 ".parse().unwrap()]);
+        assert_eq!(response_action(StatusCode::SERVICE_UNAVAILABLE, VaultOperation::List, &configured), ResponseAction::Retry);
+        assert_eq!(response_action(StatusCode::from_u16(525).unwrap(), VaultOperation::List, &configured), ResponseAction::Retry);
+        assert_eq!(response_action(StatusCode::TOO_MANY_REQUESTS, VaultOperation::List, &configured), ResponseAction::Fail);
+        assert_eq!(response_action(StatusCode::BAD_GATEWAY, VaultOperation::List, &configured), ResponseAction::Fail);
+        for invalid in ["", "5xx", "99-200", "599-500", "1000"] {
+            assert!(invalid.parse::<StatusCodeRange>().is_err(), "{invalid}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unretryable_status_codes_are_given_up_right_away() {
+        use axum::{extract::State, routing::get, Router};
+
+        let requests = Arc::new(AtomicU64::new(0));
+        let router = Router::new()
+            .route("/v1/samply_pki/ca/pem", get(|State(requests): State<Arc<AtomicU64>>| async move {
+                requests.fetch_add(1, Ordering::Relaxed);
+                StatusCode::NOT_IMPLEMENTED
+            }))
+            .with_state(requests.clone());
+        let url = serve(router).await;
+        let getter = test_getter(&url, CancellationToken::new());
+
+        let res = getter.im_certificate_as_pem().await;
+        assert!(matches!(res, Err(SamplyBeamError::VaultOtherError(ref msg)) if msg.contains("code 501")), "{res:?}");
+        assert_eq!(requests.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_certificates_are_found_by_fingerprint() {
+        use axum::{extract::{Path, State}, routing::{any, get}, Json, Router};
+
+        let fetches = Arc::new(AtomicU64::new(0));
+        let router = Router::new()
+            .route("/v1/samply_pki/certs", any(|| async { Json(json!({ "request_id": "", "lease_id": "", "renewable": false, "lease_duration": 600, "data": { "keys": ["01", "02"] } })) }))
+            .route("/v1/samply_pki/cert/:serial/raw/pem", get(|State(fetches): State<Arc<AtomicU64>>, Path(serial): Path<String>| async move {
+                fetches.fetch_add(1, Ordering::Relaxed);
+                if serial == "01" { EXPIRED_CERT.to_string() } else { format!("pem {serial}") }
+            }))
+            .with_state(fetches.clone());
+        let url = serve(router).await;
+        let getter = test_getter(&url, CancellationToken::new());
+        let fingerprint = sha256_fingerprint(&parse_single_certificate(EXPIRED_CERT).unwrap()).unwrap();
+
+        getter.warm_cache().await;
+        assert_eq!(getter.fingerprints.lock().unwrap().len(), 1, "Garbled certificates are not indexed");
+        let cert = getter.certificate_by_fingerprint(&fingerprint.to_uppercase()).await.unwrap();
+        assert_eq!(cert.to_pem().unwrap(), EXPIRED_CERT.as_bytes().iter().chain(b"\n").copied().collect::<Vec<_>>());
+        assert_eq!(fetches.load(Ordering::Relaxed), 2, "The index spares fetching the other certificates");
+
+        let res = getter.certificate_by_fingerprint(&"0".repeat(64)).await;
+        assert!(matches!(res, Err(SamplyBeamError::CertificateFingerprintNotFound(_))), "{res:?}");
+        // Searched for on the whole list, of which "02" is still prefetched
+        assert_eq!(fetches.load(Ordering::Relaxed), 3);
+        let res = getter.certificate_by_fingerprint(&"0".repeat(64)).await;
+        assert!(matches!(res, Err(SamplyBeamError::CertificateFingerprintNotFound(_))), "{res:?}");
+        assert_eq!(fetches.load(Ordering::Relaxed), 3, "Unknown fingerprints are not searched for again right away");
+
+        getter.invalidate("01");
+        assert!(getter.fingerprints.lock().unwrap().is_empty());
+        getter.certificate_by_fingerprint(&fingerprint).await.unwrap();
+        assert_eq!(getter.fingerprints.lock().unwrap().get(&fingerprint).map(String::as_str), Some("01"), "Found certificates are indexed again");
+    }
+
+    #[tokio::test]
+    async fn test_cache_metrics() {
+        use axum::{extract::{Path, State}, routing::{any, get}, Json, Router};
+        use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+
+        let listed = Arc::new(Mutex::new(vec!["01", "02", "03"]));
+        let router = Router::new()
+            .route("/v1/samply_pki/certs", any(|State(listed): State<Arc<Mutex<Vec<&'static str>>>>| async move {
+                let keys = listed.lock().unwrap().clone();
+                Json(json!({ "request_id": "", "lease_id": "", "renewable": false, "lease_duration": 600, "data": { "keys": keys } }))
+            }))
+            .route("/v1/samply_pki/cert/:serial/raw/pem", get(|Path(serial): Path<String>| async move { format!("pem {serial}") }))
+            .with_state(listed.clone());
+        let url = serve(router).await;
+        let getter = test_getter(&url, CancellationToken::new());
+
+        getter.warm_cache().await;
+        getter.certificate_list_via_network().await.unwrap();
+        getter.certificate_by_serial_as_pem("01").await.unwrap();
+        // "03" is no longer listed, so its prefetched certificate is dropped
+        listed.lock().unwrap().pop();
+        getter.refresh_certificate_list().await.unwrap();
+
+        let metrics = recorded_metrics(&snapshotter);
+        assert_eq!(metrics["beam_cert_cache_misses_total{cache=list}"], DebugValue::Counter(1));
+        assert_eq!(metrics["beam_cert_cache_hits_total{cache=list}"], DebugValue::Counter(1));
+        assert_eq!(metrics["beam_cert_cache_size{cache=list}"], DebugValue::Gauge(2.0.into()));
+        assert_eq!(metrics["beam_cert_cache_misses_total{cache=prefetched}"], DebugValue::Counter(3), "Prefetching misses every certificate");
+        assert_eq!(metrics["beam_cert_cache_hits_total{cache=prefetched}"], DebugValue::Counter(1));
+        assert_eq!(metrics["beam_cert_cache_evictions_total{cache=prefetched}"], DebugValue::Counter(1));
+        assert_eq!(metrics["beam_cert_cache_size{cache=prefetched}"], DebugValue::Gauge(1.0.into()));
+
+        // Lookups in the certificate cache which messages are verified with
+        getter.on_cache_lookup(true);
+        getter.on_cache_lookup(true);
+        getter.on_cache_lookup(false);
+        let metrics = recorded_metrics(&snapshotter);
+        assert_eq!(metrics["beam_cert_cache_hits_total{cache=serial}"], DebugValue::Counter(2));
+        assert_eq!(metrics["beam_cert_cache_misses_total{cache=serial}"], DebugValue::Counter(1));
+    }
+
+    #[tokio::test]
+    async fn test_ca_certificates_are_reloaded() {
+        const WAIT: Duration = Duration::from_secs(10);
+        let dir = std::env::temp_dir().join(format!("beam-ca-reload-{}", std::process::id()));
+        let ca_dir = dir.join("cacerts");
+        std::fs::create_dir_all(&ca_dir).unwrap();
+        let bundle = dir.join("ca-bundle.pem");
+        std::fs::write(&bundle, EXPIRED_CERT).unwrap();
+        let vault = test_vault("http://vault:8200", VaultAuth::Token("token".into()), CancellationToken::new());
+        let reloads = Mutex::new(Vec::new());
+        let build = |certs: &Vec<reqwest::Certificate>| {
+            reloads.lock().unwrap().push(certs.len());
+            test_http_client(certs)
+        };
+        let (_watcher, mut changes) = watch_ca_certificates(Some(&ca_dir), Some(&bundle)).unwrap();
+        let initial = vault.hyper_client.load_full();
+
+        std::fs::write(ca_dir.join("a.pem"), EXPIRED_CERT).unwrap();
+        std::fs::write(ca_dir.join("b.pem"), EXPIRED_CERT).unwrap();
+        timeout(WAIT, next_ca_change(&mut changes)).await.expect("Changes in the directory must be noticed");
+        assert!(changes.is_empty(), "Changes in quick succession must be taken together");
+        assert_eq!(vault.reload_ca_certificates(Some(ca_dir.clone()), Some(&bundle), false, &build).unwrap(), 3, "The bundle must be included");
+        let reloaded = vault.hyper_client.load_full();
+        assert!(!Arc::ptr_eq(&initial, &reloaded));
+
+        // Replaced like cert-manager does it
+        std::fs::write(dir.join("ca-bundle.pem.tmp"), EXPIRED_CERT).unwrap();
+        std::fs::rename(dir.join("ca-bundle.pem.tmp"), &bundle).unwrap();
+        timeout(WAIT, next_ca_change(&mut changes)).await.expect("Changes of the bundle must be noticed");
+
+        std::fs::remove_file(&bundle).unwrap();
+        timeout(WAIT, next_ca_change(&mut changes)).await.expect("Removing the bundle must be noticed");
+        assert!(vault.reload_ca_certificates(Some(ca_dir.clone()), Some(&bundle), false, &build).is_err(), "Loading fails without the bundle");
+        assert!(Arc::ptr_eq(&reloaded, &vault.hyper_client.load_full()), "The previous client must be kept");
+        assert_eq!(*reloads.lock().unwrap(), [3]);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_ca_certificate_reloading_stops_on_shutdown() {
+        let shutdown = CancellationToken::new();
+        let vault = Arc::new(test_vault("http://vault:8200", VaultAuth::Token("token".into()), shutdown.clone()));
+        let watcher = tokio::spawn(vault.reload_ca_certificates_on_change(Some(std::env::temp_dir()), None, false, test_http_client));
+        shutdown.cancel();
+        timeout(Duration::from_secs(1), watcher).await.expect("Watching must stop on shutdown").unwrap();
+    }
 }
@@ -0,0 +1,120 @@
+use std::path::{Path, PathBuf};
+
+use axum::async_trait;
+use shared::{
+    crypto::GetCerts,
+    errors::{CertificateInvalidReason, SamplyBeamError},
+};
+use tracing::debug;
+
+/// Serves certificates from a local directory instead of Vault:
+/// the proxies' certificates as `certs/<serial>.pem`, the intermediate CA certificate as `ca.pem`
+/// and optionally the chain from the intermediate CA up to the root as `ca_chain.pem`
+pub struct GetCertsFromDir {
+    dir: PathBuf,
+}
+
+impl GetCertsFromDir {
+    pub(crate) fn new(dir: PathBuf) -> Result<Self, SamplyBeamError> {
+        if !dir.join("certs").is_dir() {
+            return Err(SamplyBeamError::ConfigurationFailed(format!(
+                "Certificate directory {} does not contain a certs subdirectory",
+                dir.display()
+            )));
+        }
+        Ok(Self { dir })
+    }
+
+    fn cert_path(&self, serial: &str) -> Result<PathBuf, SamplyBeamError> {
+        // Serials end up in a path, so only allow what Vault uses to format them
+        if serial.is_empty() || !serial.chars().all(|c| c.is_ascii_hexdigit() || c == ':' || c == '-') {
+            return Err(CertificateInvalidReason::WrongSerial.into());
+        }
+        Ok(self.dir.join("certs").join(format!("{serial}.pem")))
+    }
+}
+
+async fn read_pem(path: &Path) -> Result<String, SamplyBeamError> {
+    tokio::fs::read_to_string(path).await.map_err(|e| {
+        SamplyBeamError::from(CertificateInvalidReason::Other(format!("Unable to read certificate {}: {e}", path.display())))
+    })
+}
+
+async fn read_ca(path: &Path) -> Result<String, SamplyBeamError> {
+    tokio::fs::read_to_string(path).await.map_err(|e| {
+        SamplyBeamError::ConfigurationFailed(format!("Unable to read CA certificate {}: {e}", path.display()))
+    })
+}
+
+#[async_trait]
+impl GetCerts for GetCertsFromDir {
+    async fn certificate_list_via_network(&self) -> Result<Vec<String>, SamplyBeamError> {
+        let certs_dir = self.dir.join("certs");
+        let mut entries = tokio::fs::read_dir(&certs_dir).await.map_err(|e| {
+            SamplyBeamError::ConfigurationFailed(format!("Unable to read certificate directory {}: {e}", certs_dir.display()))
+        })?;
+        let mut serials = Vec::new();
+        while let Some(entry) = entries.next_entry().await.map_err(|e| {
+            SamplyBeamError::ConfigurationFailed(format!("Unable to read certificate directory {}: {e}", certs_dir.display()))
+        })? {
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == "pem") {
+                if let Some(serial) = path.file_stem().and_then(|s| s.to_str()) {
+                    serials.push(serial.to_string());
+                }
+            }
+        }
+        debug!("Found {} certificates in {}", serials.len(), certs_dir.display());
+        Ok(serials)
+    }
+
+    async fn certificate_by_serial_as_pem(&self, serial: &str) -> Result<String, SamplyBeamError> {
+        read_pem(&self.cert_path(serial)?).await
+    }
+
+    async fn im_certificate_as_pem(&self) -> Result<String, SamplyBeamError> {
+        // Without the CA no certificate can be verified, so this is not a problem of a single certificate
+        read_ca(&self.dir.join("ca.pem")).await
+    }
+
+    async fn ca_chain_as_pem(&self) -> Result<String, SamplyBeamError> {
+        let chain = self.dir.join("ca_chain.pem");
+        if tokio::fs::try_exists(&chain).await.unwrap_or(false) {
+            read_ca(&chain).await
+        } else {
+            self.im_certificate_as_pem().await
+        }
+    }
+}
+
+pub(crate) fn build_cert_getter(dir: PathBuf) -> Result<GetCertsFromDir, SamplyBeamError> {
+    GetCertsFromDir::new(dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_certs_from_dir() {
+        let dir = std::env::temp_dir().join(format!("beam-certs-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("certs")).unwrap();
+        std::fs::write(dir.join("certs").join("0a:1b.pem"), "leaf").unwrap();
+        std::fs::write(dir.join("certs").join("README"), "ignored").unwrap();
+        std::fs::write(dir.join("ca.pem"), "ca").unwrap();
+
+        let getter = GetCertsFromDir::new(dir.clone()).unwrap();
+        assert_eq!(getter.certificate_list_via_network().await.unwrap(), vec!["0a:1b".to_string()]);
+        assert_eq!(getter.certificate_by_serial_as_pem("0a:1b").await.unwrap(), "leaf");
+        assert_eq!(getter.im_certificate_as_pem().await.unwrap(), "ca");
+        assert_eq!(getter.ca_chain_as_pem().await.unwrap(), "ca", "Without a chain file the chain is the intermediate CA alone");
+        std::fs::write(dir.join("ca_chain.pem"), "ca\nroot").unwrap();
+        assert_eq!(getter.ca_chain_as_pem().await.unwrap(), "ca\nroot");
+        assert!(matches!(getter.certificate_by_serial_as_pem("ff").await, Err(SamplyBeamError::CertificateError(_))));
+        assert!(matches!(getter.certificate_by_serial_as_pem("../ca").await, Err(SamplyBeamError::CertificateError(_))));
+        std::fs::remove_file(dir.join("ca.pem")).unwrap();
+        assert!(matches!(getter.im_certificate_as_pem().await, Err(SamplyBeamError::ConfigurationFailed(_))));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -19,8 +19,20 @@ impl Default for Verdict {
     }
 }
 
+/// Whether the broker should receive traffic, as reported by `GET /v1/health/ready`
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+#[cfg_attr(not(feature = "vault"), allow(dead_code))]
+pub enum Readiness {
+    Ready,
+    /// The broker is up but Vault is sealed or not initialized, so unknown certificates cannot be fetched
+    Degraded,
+    Unhealthy,
+}
+
 #[derive(Serialize, Clone, Copy)]
 #[serde(rename_all = "lowercase")]
+#[cfg_attr(not(feature = "vault"), allow(dead_code))]
 pub enum VaultStatus {
     Ok,
     Unknown,
@@ -39,6 +51,8 @@ impl Default for VaultStatus {
 #[serde(rename_all = "lowercase")]
 pub enum InitStatus {
     Unknown,
+    /// Vault is sealed or not initialized yet, see `PKI_UNSEAL_TIMEOUT`
+    WaitingForVault,
     FetchingIntermediateCert,
     Done
 }
@@ -52,6 +66,8 @@ impl Default for InitStatus {
 pub struct Health {
     pub vault: VaultStatus,
     pub initstatus: InitStatus,
+    /// Estimated difference between the local clock and Vault's clock in seconds (positive if we are ahead)
+    pub clock_skew: Option<i64>,
     pub proxies: HashMap<ProxyId, ProxyStatus>
 }
 
@@ -86,6 +102,7 @@ impl ProxyStatus {
 pub struct Senders {
     pub vault: tokio::sync::watch::Sender<VaultStatus>,
     pub init: tokio::sync::watch::Sender<InitStatus>,
+    pub clock_skew: tokio::sync::watch::Sender<Option<i64>>,
 }
 
 impl Health {
@@ -93,13 +110,16 @@ impl Health {
         let health = Health {
             vault: VaultStatus::default(),
             initstatus: InitStatus::default(),
+            clock_skew: None,
             proxies: HashMap::default()
         };
         let (vault_tx, mut vault_rx) = tokio::sync::watch::channel(VaultStatus::default());
         let (init_tx, mut init_rx) = tokio::sync::watch::channel(InitStatus::default());
+        let (clock_skew_tx, mut clock_skew_rx) = tokio::sync::watch::channel(None);
         let health = Arc::new(RwLock::new(health));
         let health2 = health.clone();
         let health3 = health.clone();
+        let health4 = health.clone();
 
         let vault_watcher = async move {
             while vault_rx.changed().await.is_ok() {
@@ -135,8 +155,15 @@ impl Health {
             }
         };
         tokio::task::spawn(initstatus_watcher);
+        let clock_skew_watcher = async move {
+            while clock_skew_rx.changed().await.is_ok() {
+                let new_val = *clock_skew_rx.borrow();
+                health4.write().await.clock_skew = new_val;
+            }
+        };
+        tokio::task::spawn(clock_skew_watcher);
 
-        let senders = Senders { vault: vault_tx, init: init_tx };
+        let senders = Senders { vault: vault_tx, init: init_tx, clock_skew: clock_skew_tx };
         (senders, health)
     }
 }
@@ -0,0 +1,168 @@
+//! Fair sharing of long-poll connections so that a single proxy cannot starve all others
+
+use std::{collections::HashMap, fmt::Display, sync::Mutex, time::Duration};
+
+use axum::{http::StatusCode, response::{IntoResponse, Response}};
+use beam_lib::{AppOrProxyId, ProxyId};
+use once_cell::sync::Lazy;
+use shared::{config, HowLongToBlock};
+use tracing::warn;
+
+pub(crate) static LONG_POLLS: Lazy<LongPollLimits> = Lazy::new(|| {
+    LongPollLimits::new(
+        config::CONFIG_CENTRAL.long_poll_limit,
+        config::CONFIG_CENTRAL.long_poll_limit_per_proxy,
+        config::CONFIG_CENTRAL.long_poll_limit_overrides.clone(),
+    )
+});
+
+pub(crate) struct LongPollLimits {
+    global: Option<usize>,
+    per_proxy: Option<usize>,
+    overrides: HashMap<ProxyId, usize>,
+    active: Mutex<ActiveLongPolls>,
+}
+
+#[derive(Default)]
+struct ActiveLongPolls {
+    total: usize,
+    per_proxy: HashMap<ProxyId, usize>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum LongPollRejection {
+    Global,
+    Proxy,
+}
+
+impl From<LongPollRejection> for (StatusCode, &'static str) {
+    fn from(value: LongPollRejection) -> Self {
+        let msg = match value {
+            LongPollRejection::Global => "The broker is serving too many long polls, please try again later",
+            LongPollRejection::Proxy => "Your proxy has too many concurrent long polls, please try again once some of them have finished",
+        };
+        (StatusCode::TOO_MANY_REQUESTS, msg)
+    }
+}
+
+impl IntoResponse for LongPollRejection {
+    fn into_response(self) -> Response {
+        <(StatusCode, &str)>::from(self).into_response()
+    }
+}
+
+/// Counts as one of the requester's long polls until dropped
+pub(crate) struct LongPollPermit<'a> {
+    limits: &'a LongPollLimits,
+    /// Monitoring streams are not requested by a proxy
+    proxy: Option<ProxyId>,
+}
+
+impl Drop for LongPollPermit<'_> {
+    fn drop(&mut self) {
+        let mut active = self.limits.active.lock().unwrap();
+        active.total -= 1;
+        if let Some(count) = self.proxy.as_ref().and_then(|proxy| active.per_proxy.get_mut(proxy)) {
+            *count -= 1;
+            if *count == 0 {
+                active.per_proxy.remove(self.proxy.as_ref().expect("Only proxies are counted"));
+            }
+        }
+    }
+}
+
+fn is_long_poll(block: &HowLongToBlock) -> bool {
+    (block.wait_count.is_some() || block.wait_time.is_some()) && block.wait_time != Some(Duration::ZERO)
+}
+
+impl LongPollLimits {
+    pub(crate) fn new(global: Option<usize>, per_proxy: Option<usize>, overrides: HashMap<ProxyId, usize>) -> Self {
+        Self { global, per_proxy, overrides, active: Default::default() }
+    }
+
+    fn limit_for(&self, proxy: &ProxyId) -> Option<usize> {
+        self.overrides.get(proxy).copied().or(self.per_proxy)
+    }
+
+    /// Admits the request if it does not block or if neither the global nor the requesting proxy's limit is reached.
+    /// Requests which do not block need no permit.
+    pub(crate) fn acquire(&self, block: &HowLongToBlock, requester: &AppOrProxyId) -> Result<Option<LongPollPermit<'_>>, LongPollRejection> {
+        if !is_long_poll(block) {
+            return Ok(None);
+        }
+        let proxy = requester.proxy_id();
+        let mut active = self.active.lock().unwrap();
+        self.admit_globally(&mut active, requester)?;
+        let of_proxy = active.per_proxy.get(&proxy).copied().unwrap_or_default();
+        if self.limit_for(&proxy).is_some_and(|limit| of_proxy >= limit) {
+            warn!("Rejecting long poll from {requester}: {proxy} already has {of_proxy} active long polls");
+            return Err(LongPollRejection::Proxy);
+        }
+        active.total += 1;
+        *active.per_proxy.entry(proxy.clone()).or_default() += 1;
+        Ok(Some(LongPollPermit { limits: self, proxy: Some(proxy) }))
+    }
+
+    /// Admits a monitoring stream, which stays open like a long poll but only counts against the global limit
+    pub(crate) fn acquire_for_monitoring(&self) -> Result<LongPollPermit<'_>, LongPollRejection> {
+        let mut active = self.active.lock().unwrap();
+        self.admit_globally(&mut active, "monitoring")?;
+        active.total += 1;
+        Ok(LongPollPermit { limits: self, proxy: None })
+    }
+
+    fn admit_globally(&self, active: &mut ActiveLongPolls, requester: impl Display) -> Result<(), LongPollRejection> {
+        if self.global.is_some_and(|limit| active.total >= limit) {
+            warn!("Rejecting long poll from {requester}: {} long polls are already active", active.total);
+            return Err(LongPollRejection::Global);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use beam_lib::AppId;
+
+    use super::*;
+
+    #[test]
+    fn test_proxy_exceeding_its_cap_is_throttled() {
+        let greedy: AppOrProxyId = AppId::new_unchecked("app1.proxy1.broker").into();
+        let greedy_other_app: AppOrProxyId = AppId::new_unchecked("app2.proxy1.broker").into();
+        let modest: AppOrProxyId = AppId::new_unchecked("app1.proxy2.broker").into();
+        let busy: AppOrProxyId = AppId::new_unchecked("app1.proxy3.broker").into();
+        let newcomer: AppOrProxyId = AppId::new_unchecked("app1.proxy4.broker").into();
+        let limits = LongPollLimits::new(Some(7), Some(2), HashMap::from([(busy.proxy_id(), 3)]));
+        let long_poll = HowLongToBlock { wait_time: Some(Duration::from_secs(10)), wait_count: None };
+
+        let first = limits.acquire(&long_poll, &greedy).unwrap();
+        let _second = limits.acquire(&long_poll, &greedy_other_app).unwrap();
+        assert_eq!(limits.acquire(&long_poll, &greedy).err(), Some(LongPollRejection::Proxy));
+        let no_wait = HowLongToBlock { wait_time: None, wait_count: None };
+        assert!(limits.acquire(&no_wait, &greedy).unwrap().is_none(), "Requests which do not block are not limited");
+
+        let _modest = limits.acquire(&long_poll, &modest).unwrap();
+        let _busy: Vec<_> = (0..3).map(|_| limits.acquire(&long_poll, &busy).unwrap()).collect();
+        assert_eq!(limits.acquire(&long_poll, &busy).err(), Some(LongPollRejection::Proxy));
+        let _modest_again = limits.acquire(&long_poll, &modest).unwrap();
+        assert_eq!(limits.acquire(&long_poll, &newcomer).err(), Some(LongPollRejection::Global));
+
+        drop(first);
+        assert!(limits.acquire(&long_poll, &greedy).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_monitoring_streams_count_globally() {
+        let app: AppOrProxyId = AppId::new_unchecked("app1.proxy1.broker").into();
+        let limits = LongPollLimits::new(Some(2), Some(1), HashMap::new());
+        let long_poll = HowLongToBlock { wait_time: Some(Duration::from_secs(10)), wait_count: None };
+
+        let monitoring = limits.acquire_for_monitoring().unwrap();
+        let _long_poll = limits.acquire(&long_poll, &app).unwrap();
+        assert_eq!(limits.acquire_for_monitoring().err(), Some(LongPollRejection::Global));
+        drop(monitoring);
+        let _monitoring = limits.acquire_for_monitoring().unwrap();
+        assert_eq!(limits.active.lock().unwrap().per_proxy[&app.proxy_id()], 1);
+    }
+}
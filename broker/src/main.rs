@@ -1,10 +1,24 @@
 #![allow(unused_imports)]
 
 mod banner;
+mod check_config;
+#[cfg(feature = "vault")]
+mod circuit_breaker;
+mod connection;
+#[cfg(feature = "vault")]
 mod crypto;
+mod crypto_dir;
 mod health;
+mod long_poll;
+mod notifier;
+#[cfg(feature = "vault")]
+mod ocsp;
+mod proxy_protocol;
+mod quota;
 mod serve;
 mod serve_health;
+#[cfg(feature = "vault")]
+mod serve_metrics;
 mod serve_pki;
 mod serve_tasks;
 #[cfg(feature = "sockets")]
@@ -14,9 +28,10 @@ mod compare_client_server_version;
 
 use std::{collections::HashMap, sync::Arc, time::Duration};
 
-use health::{Senders, InitStatus};
-use shared::{config::CONFIG_CENTRAL, *, errors::SamplyBeamError};
+use health::{Senders, InitStatus, VaultStatus};
+use shared::{config::CONFIG_CENTRAL, config_broker::CertSource, crypto::GetCerts, *, errors::SamplyBeamError};
 use tokio::sync::{RwLock, watch};
+use tokio_util::sync::CancellationToken;
 use tracing::{error, info, warn};
 
 #[tokio::main]
@@ -24,26 +39,87 @@ pub async fn main() -> anyhow::Result<()> {
     shared::config::prepare_env();
     shared::logger::init_logger()?;
     banner::print_banner();
+    #[cfg(feature = "vault")]
+    serve_metrics::install_recorder()?;
 
-    let (Senders { init: init_status_sender, vault: vault_status_sender}, health) = health::Health::make();
-    let cert_getter = crypto::build_cert_getter(vault_status_sender)?;
+    let shutdown = CancellationToken::new();
+    tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move {
+            shared::graceful_shutdown::wait_for_signal().await;
+            shutdown.cancel();
+        }
+    });
 
+    let (Senders { init: init_status_sender, vault: vault_status_sender, clock_skew: clock_skew_sender }, health) = health::Health::make();
+    if CONFIG_CENTRAL.check_config {
+        let passed = check_config::check_config(vault_status_sender, clock_skew_sender, shutdown).await?;
+        std::process::exit(if passed { 0 } else { 1 });
+    }
+    let cert_getter = build_cert_getter(vault_status_sender, clock_skew_sender, shutdown.clone()).await?;
     shared::crypto::init_cert_getter(cert_getter);
-    tokio::task::spawn(init_broker_ca_chain(init_status_sender));
+    shared::crypto_jwt::set_accepted_signature_algorithms(CONFIG_CENTRAL.accepted_signature_algorithms.clone());
+    let init = tokio::task::spawn(init_broker_ca_chain(init_status_sender, shutdown.clone()));
     #[cfg(debug_assertions)]
     if shared::examples::print_example_objects() {
         return Ok(());
     }
 
     let _ = config::CONFIG_CENTRAL.bind_addr; // Initialize config
+    if let Some(ref quota) = *quota::TASK_QUOTA {
+        tokio::task::spawn(quota.persist_in_background(shutdown.clone()));
+    }
 
-    serve::serve(health).await?;
+    serve::serve(health, shutdown).await?;
+    shared::crypto::shutdown_cert_getter().await;
 
+    // Requests have been answered so make sure that what they changed survives the shutdown
+    if let Some(ref quota) = *quota::TASK_QUOTA {
+        if let Err(e) = quota.flush().await {
+            error!("Unable to flush task quotas: {e}");
+        }
+    }
+
+    // The broker shuts down if Vault is not unsealed in time
+    if init.is_finished() {
+        init.await??;
+    }
     Ok(())
 }
 
-async fn init_broker_ca_chain(sender: watch::Sender<InitStatus>) {
+/// Selects the source of the proxies' certificates configured in `CONFIG_CENTRAL`
+async fn build_cert_getter(
+    vault_status_sender: watch::Sender<VaultStatus>,
+    clock_skew_sender: watch::Sender<Option<i64>>,
+    shutdown: CancellationToken,
+) -> Result<Box<dyn GetCerts>, SamplyBeamError> {
+    #[cfg(not(feature = "vault"))]
+    drop((vault_status_sender, clock_skew_sender, shutdown));
+    Ok(match CONFIG_CENTRAL.cert_source.clone() {
+        #[cfg(feature = "vault")]
+        CertSource::Vault { addresses, auth } => {
+            Box::new(crypto::build_cert_getter(addresses, auth, vault_status_sender, clock_skew_sender, shutdown).await?)
+        }
+        CertSource::Dir(dir) => {
+            info!("Serving certificates from {} instead of Vault", dir.display());
+            Box::new(crypto_dir::build_cert_getter(dir)?)
+        }
+    })
+}
+
+/// Runs while the broker is already serving requests, which are reported as not ready until it is done
+async fn init_broker_ca_chain(sender: watch::Sender<InitStatus>, shutdown: CancellationToken) -> Result<(), SamplyBeamError> {
+    sender.send_replace(health::InitStatus::WaitingForVault);
+    if let Err(e) = shared::crypto::wait_for_cert_source().await {
+        if shutdown.is_cancelled() {
+            return Ok(());
+        }
+        shutdown.cancel();
+        return Err(e);
+    }
+    tokio::task::spawn(shared::crypto::prefetch_certificates());
     sender.send_replace(health::InitStatus::FetchingIntermediateCert);
     shared::crypto::init_ca_chain().await.expect("Failed to init broker ca chain");
     sender.send_replace(health::InitStatus::Done);
+    Ok(())
 }
@@ -0,0 +1,79 @@
+//! Waking up long polls when tasks and results are written, decoupled from where they are stored
+
+use axum::async_trait;
+use beam_lib::{AppOrProxyId, MsgId};
+use dashmap::DashMap;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+/// Signalled by the storage layer on every write and awaited by long polls.
+/// [`InProcessNotifier`] is enough for a single broker; to serve long polls from several
+/// broker instances an implementation has to pass notifications between them (e.g. Postgres `LISTEN/NOTIFY`).
+pub trait Notifier: Send + Sync {
+    /// Signals that a task addressed to `recipients` has been created or made available to them again
+    fn task_written(&self, task_id: &MsgId, recipients: &[AppOrProxyId]);
+    /// Signals that `from` has written a result for the given task
+    fn result_written(&self, task_id: &MsgId, from: &AppOrProxyId);
+    /// Signals that the task is gone, ending all subscriptions to its results
+    fn task_removed(&self, task_id: &MsgId);
+    /// The IDs of all tasks written from now on
+    fn subscribe_tasks(&self) -> Box<dyn Subscription<MsgId>>;
+    /// The senders of all results written for the task from now on or `None` if the task is unknown
+    fn subscribe_results(&self, task_id: &MsgId) -> Option<Box<dyn Subscription<AppOrProxyId>>>;
+}
+
+#[async_trait]
+pub trait Subscription<T>: Send {
+    /// Waits for the next notification. Fails if notifications have been missed or no more will come.
+    async fn recv(&mut self) -> Result<T, RecvError>;
+}
+
+#[async_trait]
+impl<T: Clone + Send> Subscription<T> for broadcast::Receiver<T> {
+    async fn recv(&mut self) -> Result<T, RecvError> {
+        broadcast::Receiver::recv(self).await
+    }
+}
+
+/// Notifies long polls of the same broker instance through broadcast channels
+pub struct InProcessNotifier {
+    tasks: broadcast::Sender<MsgId>,
+    results: DashMap<MsgId, broadcast::Sender<AppOrProxyId>>,
+}
+
+impl Default for InProcessNotifier {
+    fn default() -> Self {
+        let (tasks, _) = broadcast::channel(256);
+        Self { tasks, results: Default::default() }
+    }
+}
+
+impl Notifier for InProcessNotifier {
+    fn task_written(&self, task_id: &MsgId, recipients: &[AppOrProxyId]) {
+        self.results
+            .entry(*task_id)
+            .or_insert_with(|| broadcast::channel(1.max(recipients.len())).0);
+        // We dont care if noone is listening
+        _ = self.tasks.send(*task_id);
+    }
+
+    fn result_written(&self, task_id: &MsgId, from: &AppOrProxyId) {
+        // We dont care if noone is listening or if the task expired in the meantime
+        if let Some(results) = self.results.get(task_id) {
+            _ = results.send(from.clone());
+        }
+    }
+
+    fn task_removed(&self, task_id: &MsgId) {
+        self.results.remove(task_id);
+    }
+
+    fn subscribe_tasks(&self) -> Box<dyn Subscription<MsgId>> {
+        Box::new(self.tasks.subscribe())
+    }
+
+    fn subscribe_results(&self, task_id: &MsgId) -> Option<Box<dyn Subscription<AppOrProxyId>>> {
+        self.results
+            .get(task_id)
+            .map(|results| Box::new(results.subscribe()) as Box<dyn Subscription<AppOrProxyId>>)
+    }
+}
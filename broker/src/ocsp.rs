@@ -0,0 +1,333 @@
+//! Asks an OCSP responder whether the certificates of message senders have been revoked, caching the answers
+
+use std::{collections::HashMap, sync::{Arc, Mutex}, time::Duration};
+
+use axum::http::header;
+use shared::{
+    config_broker::OcspSettings,
+    crypto::CertificateStatus,
+    errors::SamplyBeamError,
+    http_client::SamplyHttpClient,
+    openssl::{
+        hash::MessageDigest,
+        ocsp::{OcspCertId, OcspCertStatus, OcspFlag, OcspRequest, OcspResponse, OcspResponseStatus},
+        stack::Stack,
+        x509::{store::X509StoreBuilder, X509},
+    },
+    reqwest::Url,
+};
+use tokio::{sync::OnceCell, time::Instant};
+use tracing::{debug, warn};
+
+use crate::crypto::{read_limited_body, shared_error};
+
+/// Responses are small as they cover a single certificate
+const MAX_RESPONSE_SIZE: usize = 64 * 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Tolerated difference between our clock and the responder's when checking a response's validity period
+const MAX_CLOCK_SKEW_SECS: u32 = 300;
+/// How long a failed lookup is remembered, so that a flaky responder is not asked again for every message
+const FAILURE_CACHE_TTL: Duration = Duration::from_secs(10);
+
+/// The status of a certificate or why it could not be looked up, shared with other callers
+type LookupResult = Result<CertificateStatus, Arc<SamplyBeamError>>;
+type PendingLookup = OnceCell<LookupResult>;
+
+pub(crate) struct OcspChecker {
+    settings: OcspSettings,
+    /// The result of the last lookup of each certificate by serial
+    cache: Mutex<HashMap<String, (LookupResult, Instant)>>,
+    /// Lookups in flight by serial, which concurrent callers wait for instead of asking the responder themselves
+    pending: Mutex<HashMap<String, Arc<PendingLookup>>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum LookupOutcome {
+    Good,
+    Revoked,
+    Unknown,
+    Error,
+}
+
+impl LookupOutcome {
+    fn record(self) {
+        let outcome = match self {
+            LookupOutcome::Good => "good",
+            LookupOutcome::Revoked => "revoked",
+            LookupOutcome::Unknown => "unknown",
+            LookupOutcome::Error => "error",
+        };
+        metrics::counter!("beam_ocsp_lookups_total", "outcome" => outcome).increment(1);
+    }
+}
+
+impl From<CertificateStatus> for LookupOutcome {
+    fn from(status: CertificateStatus) -> Self {
+        match status {
+            CertificateStatus::Good => LookupOutcome::Good,
+            CertificateStatus::Revoked => LookupOutcome::Revoked,
+            CertificateStatus::Unknown => LookupOutcome::Unknown,
+        }
+    }
+}
+
+impl OcspChecker {
+    pub(crate) fn new(settings: OcspSettings) -> Self {
+        Self { settings, cache: Default::default(), pending: Default::default() }
+    }
+
+    /// The cached status or, once that has expired, the responder's answer. If the responder cannot be asked,
+    /// returns `None` if `fail_open` is set and the error otherwise.
+    pub(crate) async fn status(&self, client: &SamplyHttpClient, cert: &X509, issuer: &X509) -> Result<Option<CertificateStatus>, SamplyBeamError> {
+        let serial = cert.serial_number().to_bn()?.to_hex_str()?.to_string();
+        let result = match self.cached(&serial) {
+            Some(cached) => cached.map_err(|e| shared_error(&e)),
+            None => self.shared_lookup(client, cert, issuer, &serial).await,
+        };
+        match result {
+            Ok(status) => Ok(Some(status)),
+            Err(e) if self.settings.fail_open => {
+                warn!("Unable to check certificate {serial} via OCSP, so it is accepted: {e}");
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Forgets all cached answers
+    pub(crate) fn clear(&self) {
+        self.cache.lock().unwrap().clear();
+    }
+
+    fn ttl(&self, result: &LookupResult) -> Duration {
+        match result {
+            Ok(_) => self.settings.cache_ttl,
+            Err(_) => FAILURE_CACHE_TTL.min(self.settings.cache_ttl),
+        }
+    }
+
+    fn cached(&self, serial: &str) -> Option<LookupResult> {
+        let cache = self.cache.lock().unwrap();
+        let (result, checked_at) = cache.get(serial)?;
+        (checked_at.elapsed() < self.ttl(result)).then(|| result.clone())
+    }
+
+    fn remember(&self, serial: String, result: LookupResult) {
+        let mut cache = self.cache.lock().unwrap();
+        // Certificates which are not checked again would otherwise pile up
+        cache.retain(|_, (result, checked_at)| checked_at.elapsed() < self.ttl(result));
+        cache.insert(serial, (result, Instant::now()));
+    }
+
+    /// Asks the responder, sharing the lookup with concurrent callers asking about the same certificate
+    async fn shared_lookup(&self, client: &SamplyHttpClient, cert: &X509, issuer: &X509, serial: &str) -> Result<CertificateStatus, SamplyBeamError> {
+        let pending = self.pending.lock().unwrap().entry(serial.to_owned()).or_default().clone();
+        // Should the caller doing the lookup be cancelled, one of the waiting callers takes over
+        let result = pending
+            .get_or_init(|| async {
+                let result = self.lookup(client, cert, issuer).await.map_err(Arc::new);
+                match result {
+                    Ok(status) => {
+                        debug!("OCSP status of certificate {serial}: {status:?}");
+                        LookupOutcome::from(status).record();
+                    }
+                    Err(_) => LookupOutcome::Error.record(),
+                }
+                self.remember(serial.to_owned(), result.clone());
+                result
+            })
+            .await
+            .clone();
+        let mut in_flight = self.pending.lock().unwrap();
+        // Later calls must not get this result but use the cache or ask anew
+        if in_flight.get(serial).is_some_and(|lookup| Arc::ptr_eq(lookup, &pending)) {
+            in_flight.remove(serial);
+        }
+        drop(in_flight);
+        drop(pending);
+        result.map_err(|e| shared_error(&e))
+    }
+
+    fn responder(&self, cert: &X509) -> Result<Url, SamplyBeamError> {
+        if let Some(ref responder) = self.settings.responder {
+            return Ok(responder.clone());
+        }
+        // Fails if the certificate lacks the authority information access extension
+        let responders = cert.ocsp_responders().ok();
+        let url = responders
+            .iter()
+            .flat_map(|responders| responders.iter())
+            .next()
+            .ok_or_else(|| SamplyBeamError::VaultOtherError("OCSP: The certificate names no responder and PKI_OCSP_RESPONDER is not set".into()))?;
+        url.parse()
+            .map_err(|e| SamplyBeamError::VaultOtherError(format!("OCSP: Invalid responder URL {url:?} in certificate: {e}")))
+    }
+
+    async fn lookup(&self, client: &SamplyHttpClient, cert: &X509, issuer: &X509) -> Result<CertificateStatus, SamplyBeamError> {
+        let url = self.responder(cert)?;
+        let mut request = OcspRequest::new()?;
+        request.add_id(OcspCertId::from_cert(MessageDigest::sha1(), cert, issuer)?)?;
+        let resp = client
+            .post(url.clone())
+            .header(header::CONTENT_TYPE, "application/ocsp-request")
+            .body(request.to_der()?)
+            .timeout(REQUEST_TIMEOUT)
+            .send()
+            .await
+            .map_err(|e| SamplyBeamError::VaultOtherError(format!("OCSP: Unable to ask the responder at {url}: {e}")))?;
+        if !resp.status().is_success() {
+            return Err(SamplyBeamError::VaultOtherError(format!("OCSP: The responder at {url} answered with code {}", resp.status())));
+        }
+        parse_response(&read_limited_body(resp, MAX_RESPONSE_SIZE).await?, cert, issuer)
+    }
+}
+
+/// Reads the certificate's status from a response which must be signed by the certificate's issuer
+fn parse_response(der: &[u8], cert: &X509, issuer: &X509) -> Result<CertificateStatus, SamplyBeamError> {
+    let invalid = |e: &dyn std::fmt::Display| SamplyBeamError::VaultOtherError(format!("OCSP: Invalid response: {e}"));
+    let response = OcspResponse::from_der(der).map_err(|e| invalid(&e))?;
+    if response.status() != OcspResponseStatus::SUCCESSFUL {
+        return Err(invalid(&format!("unsuccessful with status {}", response.status().as_raw())));
+    }
+    let basic = response.basic().map_err(|e| invalid(&e))?;
+    let mut signers = Stack::new()?;
+    signers.push(issuer.clone())?;
+    basic
+        .verify(&signers, &X509StoreBuilder::new()?.build(), OcspFlag::TRUST_OTHER)
+        .map_err(|e| invalid(&format!("not signed by the certificate's issuer: {e}")))?;
+    let id = OcspCertId::from_cert(MessageDigest::sha1(), cert, issuer)?;
+    let status = basic.find_status(&id).ok_or_else(|| invalid(&"the certificate is not covered"))?;
+    status
+        .check_validity(MAX_CLOCK_SKEW_SECS, None)
+        .map_err(|e| invalid(&format!("outdated: {e}")))?;
+    Ok(match status.status {
+        OcspCertStatus::GOOD => CertificateStatus::Good,
+        OcspCertStatus::REVOKED => CertificateStatus::Revoked,
+        _ => CertificateStatus::Unknown,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use axum::{extract::State, routing::post, Router};
+    use shared::openssl::{asn1::Asn1Time, pkey::PKey, rsa::Rsa, x509::X509NameBuilder};
+
+    use super::*;
+
+    fn self_signed() -> X509 {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "proxy1.broker").unwrap();
+        let name = name.build();
+        let mut builder = X509::builder().unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        builder.build()
+    }
+
+    /// A responder which is temporarily unable to answer
+    async fn serve_try_later() -> (String, Arc<AtomicU64>) {
+        let requests = Arc::new(AtomicU64::new(0));
+        let router = Router::new()
+            .route("/ocsp", post(|State(requests): State<Arc<AtomicU64>>| async move {
+                requests.fetch_add(1, Ordering::Relaxed);
+                OcspResponse::create(OcspResponseStatus::TRY_LATER, None).unwrap().to_der().unwrap()
+            }))
+            .with_state(requests.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/ocsp", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+        (url, requests)
+    }
+
+    fn checker(responder: Option<&str>, fail_open: bool) -> OcspChecker {
+        OcspChecker::new(OcspSettings {
+            responder: responder.map(|url| url.parse().unwrap()),
+            cache_ttl: Duration::from_secs(60),
+            fail_open,
+        })
+    }
+
+    #[test]
+    fn test_invalid_responses_are_rejected() {
+        let cert = self_signed();
+        let try_later = OcspResponse::create(OcspResponseStatus::TRY_LATER, None).unwrap().to_der().unwrap();
+        for der in [&try_later[..], b"garbage"] {
+            let res = parse_response(der, &cert, &cert);
+            assert!(matches!(res, Err(SamplyBeamError::VaultOtherError(ref msg)) if msg.starts_with("OCSP: Invalid response")), "{res:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_lookups_fail_open_or_closed() {
+        use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+        let (url, requests) = serve_try_later().await;
+        let client = SamplyHttpClient::new();
+        let cert = self_signed();
+
+        let res = checker(Some(&url), false).status(&client, &cert, &cert).await;
+        assert!(matches!(res, Err(SamplyBeamError::VaultOtherError(_))), "{res:?}");
+        assert_eq!(checker(Some(&url), true).status(&client, &cert, &cert).await.unwrap(), None);
+        assert_eq!(requests.load(Ordering::Relaxed), 2);
+        let errors = snapshotter
+            .snapshot()
+            .into_vec()
+            .into_iter()
+            .find(|(key, ..)| key.key().name() == "beam_ocsp_lookups_total" && key.key().labels().any(|l| l.value() == "error"))
+            .map(|(.., value)| value);
+        assert_eq!(errors, Some(DebugValue::Counter(2)));
+
+        // The certificate names no responder
+        let res = checker(None, false).status(&client, &cert, &cert).await;
+        assert!(matches!(res, Err(SamplyBeamError::VaultOtherError(ref msg)) if msg.contains("PKI_OCSP_RESPONDER")), "{res:?}");
+    }
+
+    #[tokio::test]
+    async fn test_status_is_cached() {
+        let (url, requests) = serve_try_later().await;
+        let client = SamplyHttpClient::new();
+        let cert = self_signed();
+        let checker = checker(Some(&url), false);
+        let serial = cert.serial_number().to_bn().unwrap().to_hex_str().unwrap().to_string();
+
+        checker.remember(serial.clone(), Ok(CertificateStatus::Revoked));
+        assert_eq!(checker.status(&client, &cert, &cert).await.unwrap(), Some(CertificateStatus::Revoked));
+        assert_eq!(requests.load(Ordering::Relaxed), 0, "The responder is not asked again");
+        checker.clear();
+        checker.status(&client, &cert, &cert).await.unwrap_err();
+        assert_eq!(requests.load(Ordering::Relaxed), 1);
+
+        // Failures are remembered for a short time
+        let res = checker.status(&client, &cert, &cert).await;
+        assert!(matches!(res, Err(SamplyBeamError::VaultOtherError(ref msg)) if msg.starts_with("OCSP: Invalid response")), "{res:?}");
+        assert_eq!(requests.load(Ordering::Relaxed), 1, "A failed lookup is not repeated right away");
+        // As if the failure had expired
+        checker.cache.lock().unwrap().get_mut(&serial).unwrap().1 -= FAILURE_CACHE_TTL;
+        checker.status(&client, &cert, &cert).await.unwrap_err();
+        assert_eq!(requests.load(Ordering::Relaxed), 2, "The responder is asked again once the failure has expired");
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_lookups_are_shared() {
+        let (url, requests) = serve_try_later().await;
+        let client = SamplyHttpClient::new();
+        let cert = self_signed();
+        let checker = checker(Some(&url), false);
+
+        let (first, second) = tokio::join!(checker.status(&client, &cert, &cert), checker.status(&client, &cert, &cert));
+        first.unwrap_err();
+        second.unwrap_err();
+        assert_eq!(requests.load(Ordering::Relaxed), 1);
+        assert!(checker.pending.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,173 @@
+//! Support for the [PROXY protocol](https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt) (v1 and v2)
+//! so that the broker sees the real client address when running behind a TCP load balancer.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+/// Maximum length of a v1 header including the trailing CRLF
+const V1_MAX_LEN: usize = 107;
+pub(crate) const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Reads a PROXY protocol header from the stream.
+/// Returns the original source address or `None` if the header does not carry one (e.g. health checks of the load balancer).
+pub(crate) async fn read_header<R: AsyncRead + Unpin>(stream: &mut R) -> io::Result<Option<SocketAddr>> {
+    let mut start = [0; 6];
+    stream.read_exact(&mut start).await?;
+    if start == V1_PREFIX {
+        read_v1(stream).await
+    } else if start == V2_SIGNATURE[..6] {
+        let mut rest = [0; 6];
+        stream.read_exact(&mut rest).await?;
+        if rest != V2_SIGNATURE[6..] {
+            return Err(invalid("Invalid PROXY protocol v2 signature"));
+        }
+        read_v2(stream).await
+    } else {
+        Err(invalid("Connection did not start with a PROXY protocol header"))
+    }
+}
+
+async fn read_v1<R: AsyncRead + Unpin>(stream: &mut R) -> io::Result<Option<SocketAddr>> {
+    // Read byte by byte so we never consume anything after the header
+    let mut line = Vec::with_capacity(V1_MAX_LEN);
+    while !line.ends_with(b"\r\n") {
+        if line.len() + V1_PREFIX.len() >= V1_MAX_LEN {
+            return Err(invalid("PROXY protocol v1 header is too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2]).map_err(|_| invalid("PROXY protocol v1 header is not ASCII"))?;
+    let mut fields = line.split(' ');
+    match fields.next() {
+        Some("TCP4" | "TCP6") => {},
+        Some("UNKNOWN") => return Ok(None),
+        _ => return Err(invalid("Unsupported protocol in PROXY protocol v1 header")),
+    }
+    let src_ip: IpAddr = fields.next().and_then(|ip| ip.parse().ok()).ok_or_else(|| invalid("Invalid source address in PROXY protocol v1 header"))?;
+    let _dst_ip = fields.next();
+    let src_port: u16 = fields.next().and_then(|port| port.parse().ok()).ok_or_else(|| invalid("Invalid source port in PROXY protocol v1 header"))?;
+    Ok(Some(SocketAddr::new(src_ip, src_port)))
+}
+
+async fn read_v2<R: AsyncRead + Unpin>(stream: &mut R) -> io::Result<Option<SocketAddr>> {
+    let version_command = stream.read_u8().await?;
+    let family = stream.read_u8().await?;
+    let len = stream.read_u16().await? as usize;
+    let mut payload = vec![0; len];
+    stream.read_exact(&mut payload).await?;
+    if version_command >> 4 != 2 {
+        return Err(invalid("Unsupported PROXY protocol version"));
+    }
+    // LOCAL connections are initiated by the load balancer itself
+    if version_command & 0x0F == 0 {
+        return Ok(None);
+    }
+    let addr = match family {
+        // TCP over IPv4
+        0x11 if len >= 12 => {
+            let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&payload[0..4]).expect("Length was checked"));
+            SocketAddr::new(ip.into(), u16::from_be_bytes([payload[8], payload[9]]))
+        },
+        // TCP over IPv6
+        0x21 if len >= 36 => {
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&payload[0..16]).expect("Length was checked"));
+            SocketAddr::new(ip.into(), u16::from_be_bytes([payload[32], payload[33]]))
+        },
+        _ => return Ok(None),
+    };
+    Ok(Some(addr))
+}
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use axum::{extract::ConnectInfo, routing::get, Router};
+    use tokio::{io::AsyncWriteExt, net::TcpListener};
+
+    use super::*;
+    use crate::connection::{self, ServeOptions};
+
+    fn v2_header(src: [u8; 4], src_port: u16) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([0x21, 0x11]);
+        header.extend(12u16.to_be_bytes());
+        header.extend(src);
+        header.extend([10, 0, 0, 1]);
+        header.extend(src_port.to_be_bytes());
+        header.extend(8080u16.to_be_bytes());
+        header
+    }
+
+    #[tokio::test]
+    async fn test_parse_headers() {
+        let mut v1 = &b"PROXY TCP4 192.0.2.7 10.0.0.1 4711 8080\r\nGET /"[..];
+        assert_eq!(read_header(&mut v1).await.unwrap(), Some("192.0.2.7:4711".parse().unwrap()));
+        assert_eq!(v1, b"GET /", "Must not consume data after the header");
+
+        let mut v1_unknown = &b"PROXY UNKNOWN\r\n"[..];
+        assert_eq!(read_header(&mut v1_unknown).await.unwrap(), None);
+
+        let header = v2_header([192, 0, 2, 7], 4711);
+        assert_eq!(read_header(&mut &header[..]).await.unwrap(), Some("192.0.2.7:4711".parse().unwrap()));
+
+        assert!(read_header(&mut &b"GET / HTTP/1.1\r\n\r\n"[..]).await.is_err());
+    }
+
+    /// Collects what is logged while it is the default subscriber
+    #[derive(Clone, Default)]
+    struct LogBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for LogBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_source_from_v2_header_is_used() {
+        let logs = LogBuffer::default();
+        let subscriber = tracing_subscriber::fmt().with_ansi(false).with_writer({
+            let logs = logs.clone();
+            move || logs.clone()
+        });
+        // The runtime of the test runs everything on this thread
+        let _guard = tracing::subscriber::set_default(subscriber.finish());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new()
+            .route("/", get(|ConnectInfo(source): ConnectInfo<SocketAddr>| async move { source.to_string() }))
+            .layer(axum::middleware::from_fn(shared::middleware::log));
+        let options = ServeOptions { proxy_protocol_from: vec![addr.ip()], idle_timeout: None };
+        tokio::spawn(connection::serve(listener, app, options, std::future::pending()));
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream.write_all(&v2_header([203, 0, 113, 9], 5555)).await.unwrap();
+        // Set by the client, so it must not end up in the log instead of the address from the header
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: broker\r\nX-Forwarded-For: 198.51.100.1\r\nConnection: close\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.ends_with("203.0.113.9:5555"), "Unexpected response: {response}");
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        assert!(logs.contains("203.0.113.9 200 OK GET /"), "Unexpected logs: {logs}");
+        assert!(!logs.contains("198.51.100.1"), "Unexpected logs: {logs}");
+    }
+}
@@ -0,0 +1,328 @@
+//! Hard quotas on the number of tasks each proxy may create per calendar window
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use beam_lib::{AppOrProxyId, ProxyId};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use shared::{config, config_broker::QuotaWindow};
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+/// How long changed counters are collected before they are written to the state file together
+const PERSIST_DELAY: Duration = Duration::from_secs(1);
+pub(crate) const QUOTA_REMAINING: HeaderName = HeaderName::from_static("x-beam-quota-remaining");
+pub(crate) const QUOTA_RESET: HeaderName = HeaderName::from_static("x-beam-quota-reset");
+
+pub(crate) static TASK_QUOTA: Lazy<Option<TaskQuota>> = Lazy::new(|| {
+    let limit = config::CONFIG_CENTRAL.task_quota?;
+    Some(TaskQuota::new(limit, config::CONFIG_CENTRAL.task_quota_window, config::CONFIG_CENTRAL.task_quota_state_file.clone()))
+});
+
+pub(crate) struct TaskQuota {
+    limit: u64,
+    window: QuotaWindow,
+    state_file: Option<PathBuf>,
+    counts: Mutex<QuotaCounts>,
+    /// Notified whenever the counters have changed since they were last persisted
+    changed: Notify,
+    /// Held while writing the state file so that the background writer and [`TaskQuota::flush`] do not interleave
+    writing: tokio::sync::Mutex<()>,
+}
+
+/// What is persisted in the state file
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+struct QuotaCounts {
+    /// Unix timestamp at which the window of these counts started
+    window_start: u64,
+    used: HashMap<ProxyId, u64>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct QuotaStatus {
+    pub remaining: u64,
+    /// Unix timestamp at which the quota is reset
+    pub resets_at: u64,
+}
+
+impl QuotaStatus {
+    pub(crate) fn headers(&self) -> HeaderMap {
+        HeaderMap::from_iter([
+            (QUOTA_REMAINING, HeaderValue::from(self.remaining)),
+            (QUOTA_RESET, HeaderValue::from(self.resets_at)),
+        ])
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct QuotaUsage {
+    window: QuotaWindow,
+    limit: u64,
+    resets_at: u64,
+    used: BTreeMap<String, u64>,
+}
+
+impl TaskQuota {
+    /// Creates a quota of `limit` tasks per window, continuing with the counts in `state_file` if they belong to the current window
+    pub(crate) fn new(limit: u64, window: QuotaWindow, state_file: Option<PathBuf>) -> Self {
+        let counts = state_file.as_ref().and_then(|path| {
+            let state = std::fs::read(path)
+                .map_err(|e| info!("Starting with fresh task quotas as {} could not be read: {e}", path.display()))
+                .ok()?;
+            serde_json::from_slice(&state)
+                .map_err(|e| warn!("Starting with fresh task quotas as {} is invalid: {e}", path.display()))
+                .ok()
+        });
+        Self {
+            limit,
+            window,
+            state_file,
+            counts: Mutex::new(counts.unwrap_or_default()),
+            changed: Notify::new(),
+            writing: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Counts a task created by `sender` if its proxy's quota is not exhausted yet
+    pub(crate) fn consume(&self, sender: &AppOrProxyId, now: SystemTime) -> Result<QuotaStatus, QuotaStatus> {
+        let (window_start, resets_at) = window_bounds(self.window, unix_secs(now));
+        let mut counts = self.counts.lock().unwrap();
+        if counts.window_start != window_start {
+            *counts = QuotaCounts { window_start, used: HashMap::new() };
+        }
+        let used = counts.used.entry(sender.proxy_id()).or_default();
+        if *used >= self.limit {
+            return Err(QuotaStatus { remaining: 0, resets_at });
+        }
+        *used += 1;
+        let remaining = self.limit - *used;
+        self.changed.notify_one();
+        Ok(QuotaStatus { remaining, resets_at })
+    }
+
+    /// Gives back a task counted by [`TaskQuota::consume`] which has not been created after all
+    pub(crate) fn refund(&self, sender: &AppOrProxyId) {
+        let mut counts = self.counts.lock().unwrap();
+        if let Some(used) = counts.used.get_mut(&sender.proxy_id()) {
+            *used = used.saturating_sub(1);
+            self.changed.notify_one();
+        }
+    }
+
+    pub(crate) fn usage(&self, now: SystemTime) -> QuotaUsage {
+        let (window_start, resets_at) = window_bounds(self.window, unix_secs(now));
+        let counts = self.counts.lock().unwrap();
+        let used = if counts.window_start == window_start {
+            counts.used.iter().map(|(proxy, used)| (proxy.to_string(), *used)).collect()
+        } else {
+            BTreeMap::new()
+        };
+        QuotaUsage { window: self.window, limit: self.limit, resets_at, used }
+    }
+
+    /// Writes changed counters to the state file in the background until `shutdown`,
+    /// so that creating a task never waits for the disk
+    pub(crate) async fn persist_in_background(&self, shutdown: CancellationToken) {
+        if self.state_file.is_none() {
+            return;
+        }
+        loop {
+            tokio::select! {
+                _ = self.changed.notified() => {},
+                _ = shutdown.cancelled() => return,
+            }
+            // Tasks created in the meantime are persisted by the same write
+            tokio::select! {
+                _ = tokio::time::sleep(PERSIST_DELAY) => {},
+                _ = shutdown.cancelled() => return,
+            }
+            if let Err(e) = self.persist().await {
+                warn!("Unable to persist task quotas: {e}");
+            }
+        }
+    }
+
+    /// Writes the current counters to the state file and completes once they survive a crash of the machine, e.g. before shutting down
+    pub(crate) async fn flush(&self) -> io::Result<()> {
+        let Some(ref path) = self.state_file else {
+            return Ok(());
+        };
+        let _writing = self.writing.lock().await;
+        self.write_state_file(path).await?;
+        tokio::fs::File::open(path).await?.sync_all().await?;
+        // The file has been replaced by renaming so the directory entry has to be synced as well
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        tokio::fs::File::open(dir).await?.sync_all().await
+    }
+
+    /// Writes the current counters to the state file. They are only durable after [`TaskQuota::flush`].
+    async fn persist(&self) -> io::Result<()> {
+        let Some(ref path) = self.state_file else {
+            return Ok(());
+        };
+        let _writing = self.writing.lock().await;
+        self.write_state_file(path).await
+    }
+
+    async fn write_state_file(&self, path: &Path) -> io::Result<()> {
+        let state = serde_json::to_vec(&*self.counts.lock().unwrap()).expect("Quota counts are always serializable");
+        // Write to a temporary file first so that a crash never leaves a truncated state file behind
+        let tmp = path.with_extension("tmp");
+        tokio::fs::write(&tmp, state).await?;
+        tokio::fs::rename(&tmp, path).await
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// Start and end (as Unix timestamps) of the window containing `now`
+fn window_bounds(window: QuotaWindow, now: u64) -> (u64, u64) {
+    let today = now / SECS_PER_DAY;
+    match window {
+        QuotaWindow::Daily => (today * SECS_PER_DAY, (today + 1) * SECS_PER_DAY),
+        QuotaWindow::Monthly => {
+            let (year, month) = year_and_month(today);
+            let (next_year, next_month) = if month == 12 { (year + 1, 1) } else { (year, month + 1) };
+            (first_of_month(year, month) * SECS_PER_DAY, first_of_month(next_year, next_month) * SECS_PER_DAY)
+        },
+    }
+}
+
+// Calendar conversions after http://howardhinnant.github.io/date_algorithms.html
+
+/// Year and month of the given day since the Unix epoch
+fn year_and_month(days: u64) -> (u64, u64) {
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    // Months counted from March
+    let month = (5 * day_of_year + 2) / 153;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    (year, month)
+}
+
+/// Days since the Unix epoch of the first day of the given month
+fn first_of_month(year: u64, month: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year % 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use beam_lib::AppId;
+
+    use super::*;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn test_window_bounds() {
+        // 2024-02-15T12:00:00Z
+        let now = 1_707_998_400;
+        assert_eq!(window_bounds(QuotaWindow::Daily, now), (1_707_955_200, 1_708_041_600));
+        // 2024-02-01 until 2024-03-01 (leap year)
+        assert_eq!(window_bounds(QuotaWindow::Monthly, now), (1_706_745_600, 1_709_251_200));
+        // 2023-12-31T23:59:59Z belongs to December which ends with the new year
+        assert_eq!(window_bounds(QuotaWindow::Monthly, 1_704_067_199), (1_701_388_800, 1_704_067_200));
+    }
+
+    #[tokio::test]
+    async fn test_quota_is_exhausted_and_reset() {
+        beam_lib::set_broker_id("broker".to_string());
+        let state_file = std::env::temp_dir().join(format!("beam-quota-{}.json", std::process::id()));
+        let app1: AppOrProxyId = AppId::new_unchecked("app1.proxy1.broker").into();
+        let app2: AppOrProxyId = AppId::new_unchecked("app2.proxy1.broker").into();
+        let other: AppOrProxyId = AppId::new_unchecked("app1.proxy2.broker").into();
+        // 2024-02-15T12:00:00Z
+        let now = 1_707_998_400;
+        let resets_at = 1_708_041_600;
+        let quota = TaskQuota::new(2, QuotaWindow::Daily, Some(state_file.clone()));
+
+        assert_eq!(quota.consume(&app1, at(now)), Ok(QuotaStatus { remaining: 1, resets_at }));
+        // Apps of the same proxy share its quota
+        assert_eq!(quota.consume(&app2, at(now)), Ok(QuotaStatus { remaining: 0, resets_at }));
+        assert_eq!(quota.consume(&app1, at(now + 60)), Err(QuotaStatus { remaining: 0, resets_at }));
+        assert!(quota.consume(&other, at(now)).is_ok(), "Other proxies are not affected");
+
+        // The counters survive a restart within the window
+        quota.flush().await.unwrap();
+        let restarted = TaskQuota::new(2, QuotaWindow::Daily, Some(state_file.clone()));
+        assert!(restarted.consume(&app1, at(now + 120)).is_err());
+        restarted.refund(&app1);
+        assert!(restarted.consume(&app1, at(now + 120)).is_ok());
+
+        assert_eq!(restarted.consume(&app1, at(resets_at)), Ok(QuotaStatus { remaining: 1, resets_at: resets_at + SECS_PER_DAY }));
+        assert_eq!(restarted.usage(at(resets_at)).used, BTreeMap::from([("proxy1.broker".to_string(), 1)]));
+        std::fs::remove_file(state_file).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_flushed_counters_survive_restart() {
+        beam_lib::set_broker_id("broker".to_string());
+        let state_file = std::env::temp_dir().join(format!("beam-quota-flush-{}.json", std::process::id()));
+        let creator: AppOrProxyId = AppId::new_unchecked("app1.proxy1.broker").into();
+        let now = SystemTime::now();
+        let quota = TaskQuota::new(10, QuotaWindow::Monthly, Some(state_file.clone()));
+        assert!(quota.consume(&creator, now).is_ok());
+        quota.flush().await.unwrap();
+        drop(quota);
+
+        let restarted = TaskQuota::new(10, QuotaWindow::Monthly, Some(state_file.clone()));
+        assert_eq!(restarted.usage(now).used, BTreeMap::from([("proxy1.broker".to_string(), 1)]));
+        std::fs::remove_file(state_file).unwrap();
+
+        let without_state = TaskQuota::new(10, QuotaWindow::Daily, None);
+        without_state.flush().await.expect("Nothing to flush");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_counters_are_persisted_in_background() {
+        beam_lib::set_broker_id("broker".to_string());
+        let state_file = std::env::temp_dir().join(format!("beam-quota-background-{}.json", std::process::id()));
+        let creator: AppOrProxyId = AppId::new_unchecked("app1.proxy1.broker").into();
+        let now = SystemTime::now();
+        let quota: &'static TaskQuota = Box::leak(Box::new(TaskQuota::new(10, QuotaWindow::Monthly, Some(state_file.clone()))));
+        let shutdown = CancellationToken::new();
+        let persister = tokio::spawn(quota.persist_in_background(shutdown.clone()));
+
+        assert!(quota.consume(&creator, now).is_ok());
+        assert!(quota.consume(&creator, now).is_ok());
+        assert!(!state_file.exists(), "Creating a task must not write the state file");
+        // Waits for the delay, with the write running on the real file system
+        tokio::time::sleep(PERSIST_DELAY).await;
+        while !state_file.exists() {
+            tokio::task::yield_now().await;
+        }
+        shutdown.cancel();
+        persister.await.unwrap();
+
+        let restarted = TaskQuota::new(10, QuotaWindow::Monthly, Some(state_file.clone()));
+        assert_eq!(restarted.usage(now).used, BTreeMap::from([("proxy1.broker".to_string(), 2)]));
+        std::fs::remove_file(state_file).unwrap();
+    }
+}
@@ -7,9 +7,10 @@ use axum::{
     routing::{get, post},
     Extension, Json, Router,
 };
+use axum_extra::headers::{authorization::Basic, Authorization};
 use serde::Deserialize;
 use shared::{
-    config, EncryptedMsgTaskRequest, EncryptedMsgTaskResult, HasWaitId, HowLongToBlock, Msg,
+    config, middleware::HostPolicy, EncryptedMsgTaskRequest, EncryptedMsgTaskResult, HasWaitId, HowLongToBlock, Msg,
     MsgEmpty, MsgId, MsgSigned, EMPTY_VEC_APPORPROXYID,
 };
 use tokio::{
@@ -18,28 +19,105 @@ use tokio::{
         RwLock,
     }, time
 };
+use tokio_util::sync::CancellationToken;
+use tower_http::{catch_panic::CatchPanicLayer, compression::CompressionLayer, decompression::RequestDecompressionLayer};
 use tracing::{debug, info, trace, warn};
 
 use crate::{banner, crypto, health::Health, serve_health, serve_pki, serve_tasks, compare_client_server_version};
 
-pub(crate) async fn serve(health: Arc<RwLock<Health>>) -> anyhow::Result<()> {
+pub(crate) async fn serve(health: Arc<RwLock<Health>>, shutdown: CancellationToken) -> anyhow::Result<()> {
     let app = serve_tasks::router()
         .merge(serve_pki::router())
         .merge(serve_health::router(health));
+    #[cfg(feature = "vault")]
+    let app = app.merge(crate::serve_metrics::router());
     #[cfg(feature = "sockets")]
     let app = app.merge(crate::serve_sockets::router());
     // Middleware needs to be set last
     let app = app
+        // Proxies with WIRE_COMPRESSION send gzip compressed requests and ask for compressed responses
+        .layer(axum::middleware::from_fn_with_state(
+            config::CONFIG_CENTRAL.max_message_size.unwrap_or(shared::middleware::MAX_DECOMPRESSED_SIZE),
+            shared::middleware::limit_decompressed,
+        ))
+        .layer(RequestDecompressionLayer::new())
+        .layer(axum::middleware::from_fn(shared::middleware::mark_compressed))
+        .layer(CompressionLayer::new())
+        .layer(axum::middleware::from_fn_with_state(
+            HostPolicy::new(config::CONFIG_CENTRAL.missing_host, config::CONFIG_CENTRAL.bind_addr),
+            shared::middleware::check_host,
+        ))
         .layer(axum::middleware::from_fn(shared::middleware::log))
         .layer(axum::middleware::map_response(banner::set_server_header))
-        .layer(DefaultBodyLimit::disable());
+        .layer(match config::CONFIG_CENTRAL.max_message_size {
+            Some(max) => DefaultBodyLimit::max(max),
+            None => DefaultBodyLimit::disable(),
+        })
+        .layer(axum::middleware::from_fn(shared::middleware::catch_panics))
+        .layer(CatchPanicLayer::custom(shared::middleware::panic_to_json));
 
     info!(
         "Startup complete. Listening for requests on {}",
         config::CONFIG_CENTRAL.bind_addr
     );
-    axum::serve(TcpListener::bind(&config::CONFIG_CENTRAL.bind_addr).await?, app.into_make_service_with_connect_info::<SocketAddr>())
-        .with_graceful_shutdown(shared::graceful_shutdown::wait_for_signal())
-        .await?;
+    let listener = TcpListener::bind(&config::CONFIG_CENTRAL.bind_addr).await?;
+    if config::CONFIG_CENTRAL.proxy_protocol_from.is_empty() && config::CONFIG_CENTRAL.connection_idle_timeout.is_none() {
+        axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+            .with_graceful_shutdown(shutdown.cancelled_owned())
+            .await?;
+    } else {
+        if !config::CONFIG_CENTRAL.proxy_protocol_from.is_empty() {
+            info!("Expecting PROXY protocol headers from {:?}", config::CONFIG_CENTRAL.proxy_protocol_from);
+        }
+        let options = crate::connection::ServeOptions {
+            proxy_protocol_from: config::CONFIG_CENTRAL.proxy_protocol_from.clone(),
+            idle_timeout: config::CONFIG_CENTRAL.connection_idle_timeout,
+        };
+        crate::connection::serve(listener, app, options, shutdown.cancelled_owned()).await;
+    }
     Ok(())
 }
+
+/// Checks that the request is authorized with the `MONITORING_API_KEY` as Basic auth password.
+/// Without a configured key the monitoring endpoints are not available.
+pub(crate) fn check_monitoring_key(auth: &Authorization<Basic>) -> Result<(), StatusCode> {
+    let Some(ref monitoring_key) = config::CONFIG_CENTRAL.monitoring_api_key else {
+        return Err(StatusCode::NOT_IMPLEMENTED);
+    };
+    if auth.password() != monitoring_key {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::{to_bytes, Body}, http::Request};
+    use serde_json::Value;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    async fn panicking_handler() -> StatusCode {
+        assert!(shared::middleware::panic_is_caught());
+        panic!("Handler exploded")
+    }
+
+    #[tokio::test]
+    async fn test_panic_becomes_json_500() {
+        let app = Router::new()
+            .route("/panic", get(panicking_handler))
+            .layer(axum::middleware::from_fn(shared::middleware::catch_panics))
+            .layer(CatchPanicLayer::custom(shared::middleware::panic_to_json));
+        let res = app
+            .oneshot(Request::get("/panic").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert!(!shared::middleware::panic_is_caught(), "Panics outside of request handlers must not be caught");
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(res.headers()[header::CONTENT_TYPE], "application/json");
+        let body: Value = serde_json::from_slice(&to_bytes(res.into_body(), usize::MAX).await.unwrap()).unwrap();
+        assert_eq!(body["error"], "Internal server error");
+        assert!(body["correlation_id"].as_str().is_some_and(|id| !id.is_empty()));
+    }
+}
@@ -1,26 +1,38 @@
-use std::{sync::Arc, time::{Duration, SystemTime}};
+use std::{collections::BTreeMap, sync::Arc, time::{Duration, SystemTime}};
 
 use axum::{extract::{State, Path}, http::StatusCode, routing::get, Json, Router, response::Response};
 use axum_extra::{headers::{authorization::Basic, Authorization}, TypedHeader};
 use beam_lib::ProxyId;
 use serde::{Serialize, Deserialize};
-use shared::{crypto_jwt::Authorized, Msg, config::CONFIG_CENTRAL};
+use shared::{crypto, crypto_jwt::{self, Authorized}, Msg, errors::SamplyBeamError};
 use tokio::sync::RwLock;
+use tracing::debug;
 
-use crate::{health::{Health, VaultStatus, Verdict, ProxyStatus, InitStatus}, compare_client_server_version::log_version_mismatch};
+use crate::{serve::check_monitoring_key, health::{Health, VaultStatus, Verdict, ProxyStatus, InitStatus, Readiness}, compare_client_server_version::log_version_mismatch, quota::{QuotaUsage, TASK_QUOTA}};
 
 #[derive(Serialize)]
 struct HealthOutput {
     summary: Verdict,
     vault: VaultStatus,
-    init_status: InitStatus
+    init_status: InitStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    clock_skew_secs: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct ReadinessOutput {
+    status: Readiness,
+    vault: VaultStatus,
+    init_status: InitStatus,
 }
 
 pub(crate) fn router(health: Arc<RwLock<Health>>) -> Router {
     Router::new()
         .route("/v1/health", get(handler))
+        .route("/v1/health/ready", get(readiness))
         .route("/v1/health/proxies/:proxy_id", get(proxy_health))
         .route("/v1/health/proxies", get(get_all_proxies))
+        .route("/v1/health/quotas", get(get_quotas))
         .route("/v1/control", get(get_control_tasks).layer(axum::middleware::from_fn(log_version_mismatch)))
         .with_state(health)
 }
@@ -30,8 +42,10 @@ async fn handler(
     State(state): State<Arc<RwLock<Health>>>,
 ) -> (StatusCode, Json<HealthOutput>) {
     let state = state.read().await;
-    let (statuscode, summary) = match (state.initstatus, state.vault) {
-        (InitStatus::Done, VaultStatus::Ok) => (StatusCode::OK, Verdict::Healthy),
+    // Without Vault support there is no Vault whose health matters
+    let vault_ok = !cfg!(feature = "vault") || matches!(state.vault, VaultStatus::Ok);
+    let (statuscode, summary) = match (state.initstatus, vault_ok) {
+        (InitStatus::Done, true) => (StatusCode::OK, Verdict::Healthy),
         _ => (
             StatusCode::SERVICE_UNAVAILABLE,
             Verdict::Unhealthy,
@@ -40,11 +54,54 @@ async fn handler(
     let health_as_json = HealthOutput {
         summary,
         vault: state.vault,
-        init_status: state.initstatus
+        init_status: state.initstatus,
+        clock_skew_secs: state.clock_skew,
     };
     (statuscode, Json(health_as_json))
 }
 
+/// GET /v1/health/ready
+/// Checks the certificate source (Vault) now, reusing a recent health check, instead of reporting the last known status
+async fn readiness(
+    State(state): State<Arc<RwLock<Health>>>,
+) -> (StatusCode, Json<ReadinessOutput>) {
+    let init_status = state.read().await.initstatus;
+    let check = if matches!(init_status, InitStatus::Done) {
+        crypto::check_cert_source_health().await
+    } else {
+        Ok(())
+    };
+    if let Err(ref e) = check {
+        debug!("Reporting the broker as not ready: {e}");
+    }
+    let status = readiness_of(init_status, &check);
+    let statuscode = match status {
+        Readiness::Ready => StatusCode::OK,
+        Readiness::Degraded | Readiness::Unhealthy => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    let output = ReadinessOutput {
+        status,
+        // Updated by the health check above
+        vault: state.read().await.vault,
+        init_status,
+    };
+    (statuscode, Json(output))
+}
+
+fn readiness_of(init_status: InitStatus, check: &Result<(), SamplyBeamError>) -> Readiness {
+    match init_status {
+        InitStatus::Done => {},
+        InitStatus::WaitingForVault => return Readiness::Degraded,
+        _ => return Readiness::Unhealthy,
+    }
+    match check {
+        Ok(()) => Readiness::Ready,
+        #[cfg(feature = "vault")]
+        Err(SamplyBeamError::VaultSealed | SamplyBeamError::VaultNotInitialized) => Readiness::Degraded,
+        Err(_) => Readiness::Unhealthy,
+    }
+}
+
 async fn get_all_proxies(State(state): State<Arc<RwLock<Health>>>) -> Json<Vec<ProxyId>> {
     Json(state.read().await.proxies.keys().cloned().collect())
 }
@@ -54,13 +111,7 @@ async fn proxy_health(
     Path(proxy): Path<ProxyId>,
     auth: TypedHeader<Authorization<Basic>>
 ) -> Result<(StatusCode, Json<ProxyStatus>), StatusCode> {
-    let Some(ref monitoring_key) = CONFIG_CENTRAL.monitoring_api_key else {
-        return Err(StatusCode::NOT_IMPLEMENTED);
-    };
-
-    if auth.password() != monitoring_key {
-        return Err(StatusCode::UNAUTHORIZED)
-    }
+    check_monitoring_key(&auth)?;
 
     if let Some(reported_back) = state.read().await.proxies.get(&proxy) {
         if reported_back.online() {
@@ -73,6 +124,19 @@ async fn proxy_health(
     }
 }
 
+/// GET /v1/health/quotas
+/// Number of tasks each proxy has created in the current quota window
+async fn get_quotas(
+    auth: TypedHeader<Authorization<Basic>>
+) -> Result<Json<QuotaUsage>, StatusCode> {
+    check_monitoring_key(&auth)?;
+
+    let Some(ref quota) = *TASK_QUOTA else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    Ok(Json(quota.usage(SystemTime::now())))
+}
+
 async fn get_control_tasks(
     State(state): State<Arc<RwLock<Health>>>,
     proxy_auth: Authorized,
@@ -121,3 +185,36 @@ impl<'a> Drop for ConnectedGuard<'a> {
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "vault")]
+    #[test]
+    fn test_readiness_of_vault_errors() {
+        let done = InitStatus::Done;
+        assert_eq!(readiness_of(done, &Ok(())), Readiness::Ready);
+        assert_eq!(readiness_of(done, &Err(SamplyBeamError::VaultSealed)), Readiness::Degraded);
+        assert_eq!(readiness_of(done, &Err(SamplyBeamError::VaultNotInitialized)), Readiness::Degraded);
+        assert_eq!(readiness_of(done, &Err(SamplyBeamError::VaultCircuitOpen(Duration::from_secs(30)))), Readiness::Unhealthy);
+        assert_eq!(readiness_of(done, &Err(SamplyBeamError::VaultGaveUp { attempts: 1, elapsed: Duration::ZERO })), Readiness::Unhealthy);
+        assert_eq!(readiness_of(done, &Err(SamplyBeamError::VaultRequestCancelled)), Readiness::Unhealthy);
+        assert_eq!(readiness_of(done, &Err(SamplyBeamError::VaultOtherError("boom".into()))), Readiness::Unhealthy);
+        assert_eq!(readiness_of(InitStatus::FetchingIntermediateCert, &Ok(())), Readiness::Unhealthy);
+        assert_eq!(readiness_of(InitStatus::WaitingForVault, &Ok(())), Readiness::Degraded);
+    }
+
+    #[test]
+    fn test_readiness_output_format() {
+        let output = ReadinessOutput {
+            status: Readiness::Degraded,
+            vault: VaultStatus::LockedOrSealed,
+            init_status: InitStatus::Done,
+        };
+        let json = serde_json::to_value(output).unwrap();
+        assert_eq!(json["status"], "degraded");
+        assert_eq!(json["init_status"], "done");
+        assert_eq!(json["vault"], "lockedorsealed");
+    }
+}
@@ -0,0 +1,66 @@
+// GET /metrics
+
+use std::time::Duration;
+
+use axum::{http::StatusCode, routing::get, Router};
+use axum_extra::{headers::{authorization::Basic, Authorization}, TypedHeader};
+use metrics_exporter_prometheus::{BuildError, PrometheusBuilder, PrometheusHandle};
+use once_cell::sync::OnceCell;
+
+use crate::serve::check_monitoring_key;
+
+/// How often histograms are condensed, which the exporter does not do by itself without its own HTTP listener
+const UPKEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+static PROMETHEUS: OnceCell<PrometheusHandle> = OnceCell::new();
+
+/// Installs the recorder for all metrics of the broker, which are then rendered at `GET /metrics`.
+/// Metrics recorded before are lost, so this is called first thing at startup.
+pub(crate) fn install_recorder() -> Result<(), BuildError> {
+    let handle = PROMETHEUS.get_or_try_init(|| PrometheusBuilder::new().install_recorder())?;
+    tokio::task::spawn(async move {
+        let mut interval = tokio::time::interval(UPKEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            handle.run_upkeep();
+        }
+    });
+    Ok(())
+}
+
+pub(crate) fn router() -> Router {
+    Router::new().route("/metrics", get(get_metrics))
+}
+
+/// GET /metrics
+/// All metrics in the Prometheus text format
+async fn get_metrics(
+    auth: TypedHeader<Authorization<Basic>>,
+) -> Result<String, StatusCode> {
+    let Some(handle) = PROMETHEUS.get() else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+
+    check_monitoring_key(&auth)?;
+
+    Ok(handle.render())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metrics_are_rendered_for_prometheus() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let handle = recorder.handle();
+        metrics::with_local_recorder(&recorder, || {
+            metrics::counter!("beam_vault_requests_total", "operation" => "list", "outcome" => "success").increment(2);
+            metrics::gauge!("beam_vault_requests_in_flight").set(1.0);
+        });
+        let rendered = handle.render();
+        assert!(rendered.contains("# TYPE beam_vault_requests_total counter"), "{rendered}");
+        assert!(rendered.contains(r#"beam_vault_requests_total{operation="list",outcome="success"} 2"#), "{rendered}");
+        assert!(rendered.contains("beam_vault_requests_in_flight 1"), "{rendered}");
+    }
+}
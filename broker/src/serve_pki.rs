@@ -6,18 +6,20 @@ use axum::{
     extract::{ConnectInfo, Path, Query},
     http::{Request, StatusCode},
     response::{IntoResponse, Response},
-    routing::{get, Route},
+    routing::{delete, get, Route},
     Extension, Json, Router,
 };
+use axum_extra::{headers::{authorization::Basic, Authorization}, TypedHeader};
 use serde::{Deserialize, Serialize};
 use shared::{
-    config::CONFIG_CENTRAL,
     crypto_jwt::Authorized,
     errors::{CertificateInvalidReason, SamplyBeamError},
 };
 use thiserror::Error;
 use tracing::{debug, error, info, log::warn};
 
+use crate::serve::check_monitoring_key;
+
 #[derive(Error, Debug)]
 enum PkiError {
     #[error("Broker has trouble communicating with PKI. {0}")]
@@ -46,10 +48,41 @@ pub(crate) fn router() -> Router {
     Router::new()
         .route("/v1/pki/certs", get(get_certificate_list))
         .route("/v1/pki/certs/im-ca", get(get_im_cert))
+        .route("/v1/pki/certs/ca-chain", get(get_ca_chain))
         .route(
             "/v1/pki/certs/by_serial/:serial",
             get(get_certificate_by_serial),
         )
+        .route("/v1/pki/cache", delete(invalidate_all_certificates))
+        .route("/v1/pki/cache/:serial", delete(invalidate_certificate))
+}
+
+/// DELETE /v1/pki/cache/:serial
+/// Makes the broker fetch a certificate anew, e.g. after it was replaced out-of-band
+async fn invalidate_certificate(
+    Path(serial): Path<String>,
+    auth: TypedHeader<Authorization<Basic>>,
+) -> StatusCode {
+    if let Err(code) = check_monitoring_key(&auth) {
+        return code;
+    }
+    info!("Invalidating the cached certificate {serial} as requested");
+    if shared::crypto::invalidate_certificate(&serial).await {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+/// DELETE /v1/pki/cache
+/// Makes the broker fetch the certificate list and all certificates anew
+async fn invalidate_all_certificates(auth: TypedHeader<Authorization<Basic>>) -> StatusCode {
+    if let Err(code) = check_monitoring_key(&auth) {
+        return code;
+    }
+    info!("Invalidating all cached certificates as requested");
+    shared::crypto::invalidate_all_certificates().await;
+    StatusCode::NO_CONTENT
 }
 
 #[tracing::instrument(name = "/v1/pki/certs/by_serial/:serial")]
@@ -97,6 +130,18 @@ async fn get_im_cert(
     Ok(cert)
 }
 
+#[tracing::instrument(name = "/v1/pki/certs/ca-chain")]
+async fn get_ca_chain(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    _: Authorized,
+) -> Result<String, PkiError> {
+    debug!("=> Asked for CA chain by {addr}");
+    let chain = shared::crypto::get_ca_chain()
+        .await
+        .or(Err(PkiError::CommunicationWithVault(String::new())))?;
+    Ok(chain)
+}
+
 #[tracing::instrument(name = "/v1/pki/certs")]
 async fn get_certificate_list(
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
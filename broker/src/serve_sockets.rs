@@ -8,7 +8,7 @@ use shared::{config::{CONFIG_CENTRAL, CONFIG_SHARED}, crypto_jwt::Authorized, ex
 use tokio::sync::{RwLock, broadcast::{Sender, self}, oneshot};
 use tracing::{debug, log::error, warn};
 
-use crate::task_manager::{TaskManager, Task};
+use crate::{long_poll::LONG_POLLS, task_manager::{TaskManager, Task}};
 
 
 #[derive(Clone)]
@@ -33,7 +33,7 @@ impl Default for SocketState {
             }
         });
         Self {
-            task_manager: TaskManager::new(),
+            task_manager: TaskManager::new(None, None),
             waiting_connections
         }
     }
@@ -51,17 +51,18 @@ async fn get_socket_requests(
     mut block: HowLongToBlock,
     state: State<SocketState>,
     msg: MsgSigned<MsgEmpty>,
-) -> Result<DerefSerializer, StatusCode> {
+) -> Result<DerefSerializer, Response> {
     if block.wait_count.is_none() && block.wait_time.is_none() {
         block.wait_count = Some(1);
     }
     let requester = msg.get_from();
+    let _permit = LONG_POLLS.acquire(&block, requester).map_err(IntoResponse::into_response)?;
     let filter = |req: &MsgSocketRequest<Encrypted>| req.to.contains(requester);
 
-    let socket_reqs = state.task_manager.wait_for_tasks(&block, filter).await?;
+    let socket_reqs = state.task_manager.wait_for_tasks(&block, filter).await.map_err(|e| StatusCode::from(e).into_response())?;
     DerefSerializer::new(socket_reqs, block.wait_count).map_err(|e| {
         warn!("Failed to serialize socket tasks: {e}");
-        StatusCode::INTERNAL_SERVER_ERROR
+        StatusCode::INTERNAL_SERVER_ERROR.into_response()
     })
 }
 
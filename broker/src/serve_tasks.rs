@@ -1,35 +1,45 @@
 use std::{
     collections::HashMap, convert::Infallible, fmt::Debug, mem::Discriminant, net::SocketAddr,
-    sync::Arc,
+    sync::Arc, time::{Duration, SystemTime},
 };
 
 use axum::{
     extract::ConnectInfo,
     extract::{Path, Query, State},
     http::{header, HeaderValue, StatusCode, HeaderMap},
-    response::{sse::Event, IntoResponse, Response, Sse},
+    response::{sse::{Event, KeepAlive}, IntoResponse, Response, Sse},
     routing::{get, post, put},
     Json, Router,
 };
+use axum_extra::{headers::{authorization::Basic, Authorization}, TypedHeader};
 use beam_lib::AppOrProxyId;
 use futures_core::{stream, Stream};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use beam_lib::WorkStatus;
 use shared::{
-    config, errors::SamplyBeamError, sse_event::SseEventType,
+    config, ct_codecs::{Base64UrlSafeNoPadding, Decoder}, sse_event::SseEventType,
     EncryptedMsgTaskRequest, EncryptedMsgTaskResult, HasWaitId, HowLongToBlock, Msg, MsgEmpty,
     MsgId, MsgSigned, MsgTaskRequest, MsgTaskResult, EMPTY_VEC_APPORPROXYID, serde_helpers::DerefSerializer,
 };
 use tokio::{
     sync::{
-        broadcast::{Receiver, Sender},
+        broadcast::{error::RecvError, Receiver, Sender},
         RwLock,
     },
     time,
 };
 use tracing::{debug, error, info, trace, warn};
 
-use crate::task_manager::TaskManager;
+use crate::long_poll::{LongPollPermit, LONG_POLLS};
+use crate::quota::TASK_QUOTA;
+use crate::serve::check_monitoring_key;
+use crate::task_manager::{DeadLetter, TaskLifecycleEvent, TaskManager, TaskManagerError, TaskStatus, TaskSummary};
+
+/// Probes are meant for a single synchronous round trip so they do not need to be kept for long
+const MAX_PROBE_TTL: Duration = Duration::from_secs(60);
+/// How long a requeued task that had already expired is kept unless the request says otherwise
+const DEFAULT_REQUEUE_TTL: Duration = Duration::from_secs(60 * 60);
 
 #[derive(Clone)]
 struct TasksState {
@@ -40,6 +50,14 @@ pub(crate) fn router() -> Router {
     let state = TasksState::default();
     Router::new()
         .route("/v1/tasks", get(get_tasks).post(post_task))
+        .route("/v1/tasks/summary", get(get_task_summary))
+        .route("/v1/tasks/events", get(get_lifecycle_events))
+        .route("/v1/tasks/deadletter", get(get_dead_letters))
+        .route("/v1/tasks/deadletter/:task_id", get(get_dead_letter))
+        .route("/v1/tasks/deadletter/:task_id/requeue", post(requeue_dead_letter))
+        .route("/v1/tasks/:task_id", get(get_task))
+        .route("/v1/tasks/:task_id/status", get(get_task_status))
+        .route("/v1/tasks/:task_id/recipients", post(post_recipients))
         .route("/v1/tasks/:task_id/results", get(get_results_for_task))
         .route("/v1/tasks/:task_id/results/:app_id", put(put_result))
         .with_state(state)
@@ -48,16 +66,56 @@ pub(crate) fn router() -> Router {
 impl Default for TasksState {
     fn default() -> Self {
         TasksState {
-            task_manager: TaskManager::new()
+            task_manager: TaskManager::new(config::CONFIG_CENTRAL.storage_cap, config::CONFIG_CENTRAL.poison_threshold)
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ResultsQuery {
+    /// Only return the [`ResultMetadata`] of each result
+    #[serde(default)]
+    metadata_only: bool,
+}
+
+/// What can be told about a result without its body, e.g. to check whether a task is done
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+struct ResultMetadata {
+    from: AppOrProxyId,
+    status: WorkStatus,
+    /// Size of the encrypted body in bytes
+    size: usize,
+    /// Unix timestamp at which the sender signed the result
+    #[serde(skip_serializing_if = "Option::is_none")]
+    signed_at: Option<u64>,
+    metadata: Value,
+}
+
+impl ResultMetadata {
+    fn of(result: &MsgSigned<EncryptedMsgTaskResult>) -> Self {
+        Self {
+            from: result.msg.from.clone(),
+            status: result.msg.status,
+            size: result.msg.body.encrypted.len(),
+            signed_at: signed_at(&result.jwt),
+            metadata: result.msg.metadata.clone(),
         }
     }
 }
 
+/// The `iat` claim of a JWT whose signature has already been checked
+fn signed_at(jwt: &str) -> Option<u64> {
+    let claims = jwt.split('.').nth(1)?;
+    let claims = Base64UrlSafeNoPadding::decode_to_vec(claims, None).ok()?;
+    serde_json::from_slice::<Value>(&claims).ok()?.get("iat")?.as_u64()
+}
+
 async fn get_results_for_task(
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
     State(state): State<TasksState>,
     block: HowLongToBlock,
     Path(task_id): Path<MsgId>,
+    Query(query): Query<ResultsQuery>,
     headers: HeaderMap,
     msg: MsgSigned<MsgEmpty>,
 ) -> Response {
@@ -71,12 +129,19 @@ async fn get_results_for_task(
         .find(|part| *part == "text/event-stream")
         .is_some();
 
+    if *found && query.metadata_only {
+        return (StatusCode::BAD_REQUEST, "metadata_only is not supported for server-sent events").into_response();
+    }
+    let permit = match LONG_POLLS.acquire(&block, msg.get_from()) {
+        Ok(permit) => permit,
+        Err(rejection) => return rejection.into_response(),
+    };
     if *found {
-        get_results_for_task_stream(addr, state, block, task_id, msg)
+        get_results_for_task_stream(addr, state, block, task_id, msg, permit)
             .await
             .into_response()
     } else {
-        get_results_for_task_nostream(addr, state, block, task_id, msg)
+        get_results_for_task_nostream(addr, state, block, task_id, query.metadata_only, msg)
             .await
             .into_response()
     }
@@ -88,6 +153,7 @@ async fn get_results_for_task_nostream(
     state: TasksState,
     block: HowLongToBlock,
     task_id: MsgId,
+    metadata_only: bool,
     msg: MsgSigned<MsgEmpty>,
 ) -> Result<DerefSerializer, StatusCode> {
     debug!(
@@ -99,17 +165,36 @@ async fn get_results_for_task_nostream(
     if msg.get_from() != state.task_manager.get(&task_id)?.get_from() {
         return Err(StatusCode::UNAUTHORIZED);
     }
+    wait_for_results_for(&state, &block, &task_id, msg.get_from(), metadata_only).await
+}
+
+async fn wait_for_results_for(
+    state: &TasksState,
+    block: &HowLongToBlock,
+    task_id: &MsgId,
+    requester: &AppOrProxyId,
+    metadata_only: bool,
+) -> Result<DerefSerializer, StatusCode> {
     let filter_for_me = MsgFilterNoTask {
         from: None,
-        to: Some(msg.get_from().clone()),
+        to: Some(requester.clone()),
         mode: MsgFilterMode::Or,
     };
-    let task_with_results = state.task_manager.wait_for_results(&task_id, &block, |m| filter_for_me.matches(&m.msg)).await?;
-    
-    DerefSerializer::new(task_with_results.msg.results.values().filter(|m| filter_for_me.matches(&m.msg)), block.wait_count).map_err(|e| {
+    let task_with_results = state.task_manager.wait_for_results(task_id, block, |m| filter_for_me.matches(&m.msg)).await?;
+
+    let results = task_with_results.msg.results.values().filter(|m| filter_for_me.matches(&m.msg));
+    let results = if metadata_only {
+        DerefSerializer::new(results.map(|m| Box::new(ResultMetadata::of(m))), block.wait_count)
+    } else {
+        DerefSerializer::new(results, block.wait_count)
+    };
+    let results = results.map_err(|e| {
         warn!("Failed to serialize task results: {e}");
         StatusCode::INTERNAL_SERVER_ERROR
-    })
+    })?;
+    drop(task_with_results);
+    state.task_manager.finish_probe(task_id);
+    Ok(results)
 }
 
 // GET /v1/tasks/:task_id/results/stream
@@ -119,6 +204,7 @@ async fn get_results_for_task_stream(
     block: HowLongToBlock,
     task_id: MsgId,
     msg: MsgSigned<MsgEmpty>,
+    permit: Option<LongPollPermit<'static>>,
 ) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, StatusCode> {
     debug!(
         "get_results_for_task_stream(task={}) called by {} with IP {addr}, wait={:?}",
@@ -132,11 +218,18 @@ async fn get_results_for_task_stream(
     }
 
     let filter = MsgFilterNoTask { from: None, to: Some(from), mode: MsgFilterMode::Or };
-    let stream = state.task_manager.stream_results(
+    let results = state.task_manager.stream_results(
         task_id,
         block,
         move |m| filter.matches(&m.msg)
     );
+    // Counts as a long poll for as long as the stream is open
+    let stream = async_stream::stream! {
+        let _permit = permit;
+        for await event in results {
+            yield event;
+        }
+    };
 
     Ok(Sse::new(stream))
 }
@@ -147,6 +240,7 @@ struct TaskFilter {
     from: Option<AppOrProxyId>,
     to: Option<AppOrProxyId>,
     filter: Option<FilterParam>,
+    role: Option<RoleParam>,
 }
 
 #[derive(Deserialize)]
@@ -155,6 +249,13 @@ enum FilterParam {
     Todo,
 }
 
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum RoleParam {
+    /// List the requester's own tasks together with their status
+    Creator,
+}
+
 /// GET /v1/tasks
 /// Will retrieve tasks that are at least FROM or TO the supplied parameters.
 async fn get_tasks(
@@ -163,6 +264,19 @@ async fn get_tasks(
     State(state): State<TasksState>,
     msg: MsgSigned<MsgEmpty>,
 ) -> Result<DerefSerializer, (StatusCode, impl IntoResponse)> {
+    if let Some(RoleParam::Creator) = taskfilter.role {
+        if taskfilter.from.is_some() || taskfilter.to.is_some() || taskfilter.filter.is_some() {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "The \"role\" query parameter cannot be combined with other filters.",
+            ));
+        }
+        let created = state.task_manager.created_by(msg.get_from());
+        return DerefSerializer::new(created.into_iter().map(Box::new), None).map_err(|e| {
+            warn!("Failed to serialize created tasks: {e}");
+            (StatusCode::INTERNAL_SERVER_ERROR, "Failed to serialize tasks.")
+        });
+    }
     let from = taskfilter.from;
     let mut to = taskfilter.to;
     let unanswered_by = match taskfilter.filter {
@@ -180,15 +294,15 @@ async fn get_tasks(
             "Please supply either \"from\" or \"to\" query parameter.",
         ));
     }
-    if (from.is_some() && *from.as_ref().unwrap() != msg.msg.from)
-        || (to.is_some() && *to.as_ref().unwrap() != msg.msg.from)
+    if from.as_ref().is_some_and(|from| *from != msg.msg.from)
+        || to.as_ref().is_some_and(|to| *to != msg.msg.from)
     {
-        // Rewrite in Rust 1.64: https://github.com/rust-lang/rust/pull/94927
         return Err((
             StatusCode::UNAUTHORIZED,
             "You can only list messages created by you (from) or directed to you (to).",
         ));
     }
+    let _permit = LONG_POLLS.acquire(&block, msg.get_from()).map_err(<(StatusCode, &str)>::from)?;
     // Step 1: Get initial vector fill from HashMap + receiver for new elements
     let filter = MsgFilterNoTask {
         from,
@@ -203,13 +317,216 @@ async fn get_tasks(
             .map(std::mem::discriminant)
             .collect(),
     };
-    let tasks = state.task_manager
-        .wait_for_tasks(&block, move |m| filter.matches(m))
+    let requester = msg.get_from();
+    let task_manager = &state.task_manager;
+    let tasks = task_manager
+        .wait_for_tasks(&block, move |m| {
+            filter.matches(m) && !(m.get_to().contains(requester) && (task_manager.is_held_back(m, requester) || task_manager.is_poisoned(m, requester)))
+        })
         .await?;
-    DerefSerializer::new(tasks, block.wait_count).map_err(|e| {
+    // Tasks which opted into ordered delivery are returned by ascending sequence number
+    let mut tasks: Vec<_> = tasks.collect();
+    tasks.sort_by_key(|task| task.msg.sequence);
+    let mut delivered = Vec::new();
+    let tasks = tasks.into_iter().inspect(|task| if task.get_to().contains(requester) {
+        delivered.push(task.wait_id());
+    });
+    let response = DerefSerializer::new(tasks, block.wait_count).map_err(|e| {
         warn!("Failed to serialize tasks: {e}");
         (StatusCode::INTERNAL_SERVER_ERROR, "Failed to serialize tasks")
-    })
+    })?;
+    for task_id in &delivered {
+        state.task_manager.mark_delivered(task_id, requester);
+    }
+    Ok(response)
+}
+
+/// GET /v1/tasks/:task_id/status
+/// Reports whether all recipients have fetched the task and whether all of them have answered it.
+async fn get_task_status(
+    State(state): State<TasksState>,
+    Path(task_id): Path<MsgId>,
+    msg: MsgSigned<MsgEmpty>,
+) -> Result<Json<TaskStatus>, StatusCode> {
+    if msg.get_from() != state.task_manager.get(&task_id)?.get_from() {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    Ok(Json(state.task_manager.status(&task_id)?))
+}
+
+/// GET /v1/tasks/:task_id
+/// Returns a single task to its creator, e.g. to add recipients to it.
+async fn get_task(
+    State(state): State<TasksState>,
+    Path(task_id): Path<MsgId>,
+    msg: MsgSigned<MsgEmpty>,
+) -> Result<Json<MsgSigned<EncryptedMsgTaskRequest>>, StatusCode> {
+    let task = state.task_manager.get(&task_id)?;
+    if msg.get_from() != task.get_from() {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    Ok(Json(MsgSigned { msg: task.msg.clone(), jwt: task.jwt.clone() }))
+}
+
+/// POST /v1/tasks/:task_id/recipients
+/// Replaces a task with a version re-signed by its creator which additionally carries the
+/// wrapped keys for new recipients. Payload and existing recipients must remain unchanged.
+async fn post_recipients(
+    State(state): State<TasksState>,
+    Path(task_id): Path<MsgId>,
+    msg: MsgSigned<EncryptedMsgTaskRequest>,
+) -> Result<StatusCode, Response> {
+    if task_id != msg.msg.id {
+        return Err((StatusCode::BAD_REQUEST, "Task IDs supplied in path and payload do not match.").into_response());
+    }
+    let known_recipients = state.task_manager.get(&task_id).map_err(|e| <(StatusCode, &str)>::from(e).into_response())?.get_to().len();
+    let new_recipients = msg.get_to().get(known_recipients..).unwrap_or_default();
+    // Unknown proxies are reported with 424 Failed Dependency
+    shared::crypto::get_proxy_public_keys(new_recipients).await.map_err(IntoResponse::into_response)?;
+    state.task_manager.add_recipients(msg, |stored, new| {
+        let keys = &new.body.encryption_keys;
+        let unchanged = new.body.encrypted == stored.body.encrypted
+            && keys.len() == new.to.len()
+            && keys.starts_with(&stored.body.encryption_keys)
+            && new.failure_strategy == stored.failure_strategy
+            && new.metadata == stored.metadata
+            && new.sequence == stored.sequence;
+        // The TTL is relative to the time of signing so we keep the original expiry
+        new.expire = stored.expire;
+        if unchanged { Ok(()) } else { Err(TaskManagerError::InvalidUpdate) }
+    }).map_err(|e| <(StatusCode, &str)>::from(e).into_response())?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// GET /v1/tasks/summary
+async fn get_task_summary(
+    State(state): State<TasksState>,
+    TypedHeader(auth): TypedHeader<Authorization<Basic>>,
+) -> Result<Json<TaskSummary>, StatusCode> {
+    check_monitoring_key(&auth)?;
+    Ok(Json(state.task_manager.summary()))
+}
+
+#[derive(Debug, Deserialize)]
+struct LifecycleFilter {
+    /// Only events of tasks created by, addressed to or answered by this app or proxy
+    beam_id: Option<AppOrProxyId>,
+    task_id: Option<MsgId>,
+}
+
+impl LifecycleFilter {
+    fn matches(&self, event: &TaskLifecycleEvent) -> bool {
+        self.beam_id.as_ref().is_none_or(|beam_id| event.concerns(beam_id))
+            && self.task_id.is_none_or(|task_id| event.task_id == task_id)
+    }
+}
+
+/// Number of lifecycle events a subscriber has missed
+#[derive(Debug, PartialEq, Eq)]
+struct MissedEvents(u64);
+
+/// The lifecycle events matching `filter`, starting after the event with the ID `last_id` as far as it is still known.
+/// The stream ends after it fell behind so that the subscriber resumes from the last event it has received.
+fn lifecycle_events(
+    task_manager: &TaskManager<EncryptedMsgTaskRequest>,
+    filter: LifecycleFilter,
+    last_id: Option<u64>,
+) -> impl Stream<Item = Result<Arc<TaskLifecycleEvent>, MissedEvents>> {
+    let (missed, mut events) = task_manager.subscribe_lifecycle(last_id);
+    async_stream::stream! {
+        if let (Some(last_id), Some(first)) = (last_id, missed.first()) {
+            if first.id > last_id + 1 {
+                yield Err(MissedEvents(first.id - last_id - 1));
+            }
+        }
+        for event in missed.into_iter().filter(|event| filter.matches(event)) {
+            yield Ok(event);
+        }
+        loop {
+            match events.recv().await {
+                Ok(event) if filter.matches(&event) => yield Ok(event),
+                Ok(_) => {},
+                Err(RecvError::Lagged(n)) => {
+                    warn!("Lifecycle event subscriber missed {n} events");
+                    yield Err(MissedEvents(n));
+                    break;
+                },
+                Err(RecvError::Closed) => break,
+            }
+        }
+    }
+}
+
+/// GET /v1/tasks/events
+/// Streams lifecycle events of all tasks (without any message bodies) for monitoring and integration
+async fn get_lifecycle_events(
+    State(state): State<TasksState>,
+    TypedHeader(auth): TypedHeader<Authorization<Basic>>,
+    Query(filter): Query<LifecycleFilter>,
+    headers: HeaderMap,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, StatusCode> {
+    check_monitoring_key(&auth)?;
+    let permit = LONG_POLLS.acquire_for_monitoring().map_err(|_| StatusCode::TOO_MANY_REQUESTS)?;
+    let last_id = headers
+        .get("last-event-id")
+        .map(|id| id.to_str().ok().and_then(|id| id.parse().ok()).ok_or(StatusCode::BAD_REQUEST))
+        .transpose()?;
+    let events = lifecycle_events(&state.task_manager, filter, last_id);
+    let stream = async_stream::stream! {
+        let _permit = permit;
+        for await event in events {
+            yield Ok(match event {
+                Ok(event) => Event::default()
+                    .id(event.id.to_string())
+                    .event(event.state.as_str())
+                    .json_data(&*event)
+                    .expect("Lifecycle events are always serializable"),
+                Err(MissedEvents(n)) => Event::default()
+                    .event(SseEventType::Error)
+                    .data(format!("Missed {n} events")),
+            });
+        }
+    };
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+/// GET /v1/tasks/deadletter
+async fn get_dead_letters(
+    State(state): State<TasksState>,
+    TypedHeader(auth): TypedHeader<Authorization<Basic>>,
+) -> Result<Json<Vec<DeadLetter>>, StatusCode> {
+    check_monitoring_key(&auth)?;
+    Ok(Json(state.task_manager.dead_letters()))
+}
+
+/// GET /v1/tasks/deadletter/:task_id
+async fn get_dead_letter(
+    State(state): State<TasksState>,
+    Path(task_id): Path<MsgId>,
+    TypedHeader(auth): TypedHeader<Authorization<Basic>>,
+) -> Result<Json<DeadLetter>, StatusCode> {
+    check_monitoring_key(&auth)?;
+    state.task_manager.dead_letter(&task_id).map(Json).map_err(StatusCode::from)
+}
+
+#[derive(Deserialize)]
+struct RequeueParams {
+    /// Seconds to keep a task that had already expired
+    ttl: Option<u64>,
+}
+
+/// POST /v1/tasks/deadletter/:task_id/requeue
+async fn requeue_dead_letter(
+    State(state): State<TasksState>,
+    Path(task_id): Path<MsgId>,
+    Query(params): Query<RequeueParams>,
+    TypedHeader(auth): TypedHeader<Authorization<Basic>>,
+) -> Result<StatusCode, StatusCode> {
+    check_monitoring_key(&auth)?;
+    let ttl = params.ttl.map_or(DEFAULT_REQUEUE_TTL, Duration::from_secs);
+    state.task_manager.requeue(&task_id, ttl).map_err(StatusCode::from)?;
+    info!("Requeued dead-lettered task {task_id}");
+    Ok(StatusCode::NO_CONTENT)
 }
 
 trait MsgFilterTrait<M: Msg> {
@@ -333,12 +650,31 @@ impl<'a, M: Msg> MsgFilterTrait<M> for MsgFilterNoTask {
     }
 }
 
+/// Returns true if the client asked to not wait for the task's results via `Prefer: respond-async` (RFC 7240)
+fn prefers_respond_async(headers: &HeaderMap) -> bool {
+    headers
+        .get_all("prefer")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|preference| preference.split([';', '=']).next().unwrap_or_default().trim())
+        .any(|preference| preference.eq_ignore_ascii_case("respond-async"))
+}
+
 // POST /v1/tasks
+/// Without any blocking parameters the task is created and `201 Created` is returned immediately.
+/// If `wait_count` or `wait_time` is set, the results are awaited and returned like on `GET /v1/tasks/:task_id/results`,
+/// unless the client sent `Prefer: respond-async` in which case `202 Accepted` is returned right away.
 async fn post_task(
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
     State(state): State<TasksState>,
-    msg: MsgSigned<EncryptedMsgTaskRequest>,
-) -> Result<(StatusCode, impl IntoResponse), StatusCode> {
+    block: HowLongToBlock,
+    headers: HeaderMap,
+    mut msg: MsgSigned<EncryptedMsgTaskRequest>,
+) -> Result<Response, StatusCode> {
+    if msg.msg.probe {
+        msg.msg.expire = msg.msg.expire.min(SystemTime::now() + MAX_PROBE_TTL);
+    }
         // let id = MsgId::new();
     // msg.id = id;
     // TODO: Check if ID is taken
@@ -347,11 +683,37 @@ async fn post_task(
         msg.msg.from, msg
     );
     let id = msg.msg.id;
-    state.task_manager.post_task(msg)?;
-    Ok((
-        StatusCode::CREATED,
-        [(header::LOCATION, format!("/v1/tasks/{}", id))],
-    ))
+    let from = msg.get_from().clone();
+    // Probes are short-lived and do not count towards the quota
+    let quota = TASK_QUOTA.as_ref().filter(|_| !msg.msg.probe);
+    let quota_headers = match quota.map(|quota| quota.consume(&from, SystemTime::now())) {
+        Some(Ok(status)) => status.headers(),
+        Some(Err(status)) => {
+            warn!("Rejecting task {id} from {from}: Task quota exhausted until {}", status.resets_at);
+            return Ok((StatusCode::TOO_MANY_REQUESTS, status.headers(), "Task quota exhausted").into_response());
+        },
+        None => HeaderMap::new(),
+    };
+    if let Err(e) = state.task_manager.post_task(msg) {
+        if let Some(quota) = quota {
+            quota.refund(&from);
+        }
+        return Err(e.into());
+    }
+    let location = [(header::LOCATION, format!("/v1/tasks/{}", id))];
+    if prefers_respond_async(&headers) {
+        return Ok((
+            StatusCode::ACCEPTED,
+            location,
+            quota_headers,
+            [(header::CONTENT_LOCATION, format!("/v1/tasks/{}/status", id))],
+        ).into_response());
+    }
+    if block.wait_count.is_none() && block.wait_time.is_none() {
+        return Ok((StatusCode::CREATED, location, quota_headers).into_response());
+    }
+    let results = wait_for_results_for(&state, &block, &id, &from, false).await?;
+    Ok((location, quota_headers, results).into_response())
 }
 
 // PUT /v1/tasks/:task_id/results/:app_id
@@ -385,18 +747,24 @@ async fn put_result(
     Ok(status)
 }
 
-#[cfg(all(test, never))] // Removed until the errors down below are fixed
+#[cfg(test)]
 mod test {
-    use serde_json::Value;
-    use shared::{
-        beam_id::{AppId, AppOrProxyId, BeamId, BrokerId, ProxyId},
-        EncryptedMsgTaskRequest, Msg, MsgSigned, MsgTaskRequest, MsgTaskResult, WorkStatus,
-    };
+    use beam_lib::{AppId, FailureStrategy};
+    use http_body_util::BodyExt;
+    use shared::{ct_codecs::Encoder, Encrypted};
 
-    use super::{MsgFilterForTask, MsgFilterMode, MsgFilterNoTask, MsgFilterTrait};
+    use super::*;
 
+    #[cfg(never)] // Removed until the errors down below are fixed
     #[test]
     fn filter_task() {
+        use shared::{
+            beam_id::{AppId, AppOrProxyId, BeamId, BrokerId, ProxyId},
+            EncryptedMsgTaskRequest, Msg, MsgSigned, MsgTaskRequest, MsgTaskResult, WorkStatus,
+        };
+
+        use super::{MsgFilterForTask, MsgFilterMode, MsgFilterNoTask, MsgFilterTrait};
+
         const BROKER_ID: &str = "broker";
         BrokerId::set_broker_id(BROKER_ID.into());
         let broker = BrokerId::new(BROKER_ID).unwrap();
@@ -467,4 +835,144 @@ mod test {
             "It's done, so I shouldn't get it"
         );
     }
+
+    const SIGNED_AT: u64 = 1_700_000_000;
+
+    /// Like the JWTs of the proxies, just without a valid signature
+    fn sign(result: EncryptedMsgTaskResult) -> MsgSigned<EncryptedMsgTaskResult> {
+        let mut claims = serde_json::to_value(&result).unwrap();
+        claims["iat"] = SIGNED_AT.into();
+        let claims = Base64UrlSafeNoPadding::encode_to_string(claims.to_string()).unwrap();
+        MsgSigned { msg: result, jwt: format!("header.{claims}.signature") }
+    }
+
+    fn claims(jwt: &str) -> EncryptedMsgTaskResult {
+        let claims = Base64UrlSafeNoPadding::decode_to_vec(jwt.split('.').nth(1).unwrap(), None).unwrap();
+        serde_json::from_slice(&claims).unwrap()
+    }
+
+    async fn results_json(state: &TasksState, task_id: &MsgId, requester: &AppOrProxyId, metadata_only: bool) -> (StatusCode, Value) {
+        let block = HowLongToBlock { wait_time: None, wait_count: Some(2) };
+        let resp = wait_for_results_for(state, &block, task_id, requester, metadata_only).await.unwrap().into_response();
+        let status = resp.status();
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_metadata_only_results_match_full_results() {
+        beam_lib::set_broker_id("broker".to_string());
+        let creator: AppOrProxyId = AppId::new_unchecked("app0.proxy0.broker").into();
+        let app1: AppOrProxyId = AppId::new_unchecked("app1.proxy1.broker").into();
+        let app2: AppOrProxyId = AppId::new_unchecked("app2.proxy2.broker").into();
+        let state = TasksState { task_manager: TaskManager::new(None, None) };
+        let task = EncryptedMsgTaskRequest {
+            id: MsgId::new(),
+            from: creator.clone(),
+            to: vec![app1.clone(), app2.clone()],
+            body: Encrypted::default(),
+            expire: SystemTime::now() + Duration::from_secs(60),
+            failure_strategy: FailureStrategy::Discard,
+            results: HashMap::new(),
+            metadata: Value::Null,
+            sequence: None,
+            probe: false,
+        };
+        let id = task.id;
+        state.task_manager.post_task(MsgSigned { msg: task, jwt: String::new() }).unwrap();
+        for (from, status, size) in [(&app1, WorkStatus::Succeeded, 1000), (&app2, WorkStatus::PermFailed, 10)] {
+            let result = EncryptedMsgTaskResult {
+                from: from.clone(),
+                to: vec![creator.clone()],
+                task: id,
+                status,
+                body: Encrypted { encrypted: vec![0; size], encryption_keys: vec![] },
+                metadata: serde_json::json!({ "site": from.to_string() }),
+            };
+            state.task_manager.put_result(&id, sign(result)).unwrap();
+        }
+
+        let (full_status, full) = results_json(&state, &id, &creator, false).await;
+        let (status, metadata_only) = results_json(&state, &id, &creator, true).await;
+        assert_eq!(status, full_status);
+        let mut expected: Vec<_> = full.as_array().unwrap().iter().map(|signed| {
+            let result = claims(signed["jwt"].as_str().unwrap());
+            ResultMetadata {
+                from: result.from,
+                status: result.status,
+                size: result.body.encrypted.len(),
+                signed_at: Some(SIGNED_AT),
+                metadata: result.metadata,
+            }
+        }).collect();
+        let mut metadata_only: Vec<ResultMetadata> = serde_json::from_value(metadata_only).unwrap();
+        let by_sender = |m: &ResultMetadata| m.from.to_string();
+        expected.sort_by_key(by_sender);
+        metadata_only.sort_by_key(by_sender);
+        assert_eq!(metadata_only, expected);
+        assert_eq!(metadata_only.iter().map(|m| m.size).sum::<usize>(), 1010);
+    }
+
+    async fn next_event<S: Stream + Unpin>(events: &mut S) -> S::Item {
+        let next = std::future::poll_fn(|cx| std::pin::Pin::new(&mut *events).poll_next(cx));
+        time::timeout(Duration::from_secs(1), next).await.expect("Event must be emitted").unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_lifecycle_feed_of_completed_task() {
+        beam_lib::set_broker_id("broker".to_string());
+        let creator: AppOrProxyId = AppId::new_unchecked("app0.proxy0.broker").into();
+        let app1: AppOrProxyId = AppId::new_unchecked("app1.proxy1.broker").into();
+        let task_manager = TaskManager::<EncryptedMsgTaskRequest>::new(None, None);
+        let task = |to: &AppOrProxyId| EncryptedMsgTaskRequest {
+            id: MsgId::new(),
+            from: creator.clone(),
+            to: vec![to.clone()],
+            body: Encrypted::default(),
+            expire: SystemTime::now() + Duration::from_secs(60),
+            failure_strategy: FailureStrategy::Discard,
+            results: HashMap::new(),
+            metadata: Value::Null,
+            sequence: None,
+            probe: false,
+        };
+        let filter = LifecycleFilter { beam_id: Some(app1.clone()), task_id: None };
+        let mut events = Box::pin(lifecycle_events(&task_manager, filter, None));
+
+        // Not addressed to app1 so it must not show up on the feed
+        let unrelated = task(&AppId::new_unchecked("app2.proxy2.broker").into());
+        task_manager.post_task(MsgSigned { msg: unrelated, jwt: String::new() }).unwrap();
+        let task = task(&app1);
+        let id = task.id;
+        task_manager.post_task(MsgSigned { msg: task, jwt: String::new() }).unwrap();
+        task_manager.mark_delivered(&id, &app1);
+        let result = EncryptedMsgTaskResult {
+            from: app1.clone(),
+            to: vec![creator.clone()],
+            task: id,
+            status: WorkStatus::Succeeded,
+            body: Encrypted::default(),
+            metadata: Value::Null,
+        };
+        task_manager.put_result(&id, sign(result)).unwrap();
+
+        let mut received = Vec::new();
+        for _ in 0..4 {
+            let event = next_event(&mut events).await.unwrap();
+            assert_eq!((event.task_id, &event.from, &event.to), (id, &creator, &vec![app1.clone()]));
+            received.push(event);
+        }
+        let states: Vec<_> = received.iter().map(|event| event.state.as_str()).collect();
+        assert_eq!(states, ["created", "delivered", "result_received", "completed"]);
+        assert_eq!(received[2].result_from, Some(app1.clone()));
+        assert!(received.windows(2).all(|pair| pair[0].id < pair[1].id));
+        let json = serde_json::to_value(&*received[3]).unwrap();
+        assert!(json.get("body").is_none(), "Events carry no message bodies");
+
+        // Resuming after the delivery replays the rest
+        let filter = LifecycleFilter { beam_id: None, task_id: Some(id) };
+        let mut resumed = Box::pin(lifecycle_events(&task_manager, filter, Some(received[1].id)));
+        assert_eq!(next_event(&mut resumed).await.unwrap().id, received[2].id);
+        assert_eq!(next_event(&mut resumed).await.unwrap().id, received[3].id);
+    }
 }
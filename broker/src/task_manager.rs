@@ -1,7 +1,7 @@
 use std::{
     borrow::Cow,
     ops::Deref,
-    time::{Duration, SystemTime}, collections::HashMap, sync::Arc, convert::Infallible,
+    time::{Duration, SystemTime, UNIX_EPOCH}, collections::{BTreeSet, HashMap, HashSet, VecDeque}, sync::{Arc, Mutex, atomic::{AtomicUsize, Ordering}}, convert::Infallible,
 };
 
 use axum::{response::{IntoResponse, sse::Event, Sse}, Json, http::StatusCode};
@@ -9,28 +9,62 @@ use dashmap::DashMap;
 use futures_core::Stream;
 use once_cell::sync::Lazy;
 use serde::Serialize;
-use serde_json::json;
+use serde_json::{json, Value};
 use beam_lib::{AppOrProxyId, MsgEmpty, MsgId, WorkStatus};
 use shared::{
     HasWaitId, HowLongToBlock, Msg, MsgSigned,
     MsgState, MsgTaskRequest, MsgTaskResult, sse_event::SseEventType,
 };
 use tokio::{sync::broadcast, time::Instant};
-use tracing::{warn, error};
+use tracing::{debug, warn, error};
+
+use crate::notifier::{InProcessNotifier, Notifier};
 
 pub trait Task {
-    type Result;
+    type Result: StoredSize;
 
     fn get_results(&self) -> &HashMap<AppOrProxyId, Self::Result>;
     /// Returns true if the value as been updated and false if it was a result from a new app
     fn insert_result(&mut self, result: Self::Result) -> bool;
-    fn is_expired(&self) -> bool;
+    fn take_results(&mut self) -> HashMap<AppOrProxyId, Self::Result>;
+    fn expire(&self) -> SystemTime;
+    fn set_expire(&mut self, expire: SystemTime);
+    fn metadata(&self) -> &Value;
+    fn is_expired(&self) -> bool {
+        self.expire() < SystemTime::now()
+    }
+    /// Position of this task among the tasks of its sender if it opted into ordered delivery
+    fn sequence(&self) -> Option<u64> {
+        None
+    }
+    /// Probes are kept out of the storage accounting and the summary and are dropped once answered
+    fn is_probe(&self) -> bool {
+        false
+    }
 }
 
 pub trait HasStatus {
     fn get_status(&self) -> WorkStatus;
 }
 
+/// Approximate number of bytes a message occupies in the broker's memory
+pub trait StoredSize {
+    fn stored_size(&self) -> usize;
+}
+
+impl<M: Msg> StoredSize for MsgSigned<M> {
+    fn stored_size(&self) -> usize {
+        // The jwt contains the whole message which is also kept deserialized next to it
+        2 * self.jwt.len()
+    }
+}
+
+impl StoredSize for () {
+    fn stored_size(&self) -> usize {
+        0
+    }
+}
+
 impl<State: MsgState> Task for MsgTaskRequest<State> {
     type Result = MsgSigned<MsgTaskResult<State>>;
 
@@ -42,8 +76,28 @@ impl<State: MsgState> Task for MsgTaskRequest<State> {
         &self.results
     }
 
-    fn is_expired(&self) -> bool {
-        self.expire < SystemTime::now()
+    fn take_results(&mut self) -> HashMap<AppOrProxyId, Self::Result> {
+        std::mem::take(&mut self.results)
+    }
+
+    fn expire(&self) -> SystemTime {
+        self.expire
+    }
+
+    fn set_expire(&mut self, expire: SystemTime) {
+        self.expire = expire;
+    }
+
+    fn metadata(&self) -> &Value {
+        &self.metadata
+    }
+
+    fn sequence(&self) -> Option<u64> {
+        self.sequence
+    }
+
+    fn is_probe(&self) -> bool {
+        self.probe
     }
 }
 
@@ -61,8 +115,20 @@ impl<State: MsgState> Task for shared::MsgSocketRequest<State> {
 
     fn insert_result(&mut self, _result: Self::Result) -> bool { false }
 
-    fn is_expired(&self) -> bool {
-        self.expire < SystemTime::now()
+    fn take_results(&mut self) -> HashMap<AppOrProxyId, Self::Result> {
+        HashMap::new()
+    }
+
+    fn expire(&self) -> SystemTime {
+        self.expire
+    }
+
+    fn set_expire(&mut self, expire: SystemTime) {
+        self.expire = expire;
+    }
+
+    fn metadata(&self) -> &Value {
+        &self.metadata
     }
 }
 
@@ -78,33 +144,199 @@ impl<T: HasStatus + Msg> HasStatus for MsgSigned<T> {
     }
 }
 
+/// Lifecycle milestones of a task
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskLifecycle {
+    Created,
+    /// Every recipient has fetched the task at least once
+    Delivered,
+    /// A recipient has submitted or updated its result
+    ResultReceived,
+    /// Every recipient has submitted a result which is not [`WorkStatus::Claimed`]
+    Completed,
+    /// The task has been removed from the broker as it expired
+    Expired,
+}
+
+impl TaskLifecycle {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TaskLifecycle::Created => "created",
+            TaskLifecycle::Delivered => "delivered",
+            TaskLifecycle::ResultReceived => "result_received",
+            TaskLifecycle::Completed => "completed",
+            TaskLifecycle::Expired => "expired",
+        }
+    }
+}
+
+/// What happened to which task, without any message bodies
+#[derive(Debug, Clone, Serialize)]
+pub struct TaskLifecycleEvent {
+    /// Counts the events since the broker started, starting at 1
+    pub id: u64,
+    pub task_id: MsgId,
+    pub state: TaskLifecycle,
+    /// Creator of the task
+    pub from: AppOrProxyId,
+    pub to: Vec<AppOrProxyId>,
+    /// Sender of the result if the state is [`TaskLifecycle::ResultReceived`]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result_from: Option<AppOrProxyId>,
+    /// Unix timestamp in milliseconds
+    pub timestamp: u64,
+}
+
+impl TaskLifecycleEvent {
+    /// Returns true if `beam_id` has created the task, is one of its recipients or has sent the result
+    pub fn concerns(&self, beam_id: &AppOrProxyId) -> bool {
+        &self.from == beam_id || self.to.contains(beam_id) || self.result_from.as_ref() == Some(beam_id)
+    }
+}
+
+/// The most recent lifecycle events so that subscribers can catch up after reconnecting
+#[derive(Default)]
+struct LifecycleLog {
+    last_id: u64,
+    recent: VecDeque<Arc<TaskLifecycleEvent>>,
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct TaskStatus {
+    pub recipients: usize,
+    pub delivered: usize,
+    pub completed: usize,
+    pub fully_delivered: bool,
+    pub fully_completed: bool,
+    /// Recipients to which the task is no longer delivered as they kept fetching it without ever answering
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub poisoned: Vec<AppOrProxyId>,
+}
+
+/// A task as listed for its creator so that it can find out which of its tasks are still outstanding
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct CreatedTask {
+    pub task_id: MsgId,
+    pub to: Vec<AppOrProxyId>,
+    /// Unix timestamp of when the task expires
+    pub expire: u64,
+    /// Number of results including those which only claim the task
+    pub results: usize,
+    #[serde(flatten)]
+    pub status: TaskStatus,
+}
+
+/// Why a task has been moved to the dead-letter queue
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeadLetterReason {
+    /// The task expired before all of its recipients fetched it
+    ExpiredUndelivered,
+    /// Some recipients kept fetching the task without answering it, see [`TaskManager::is_poisoned`]
+    Poisoned,
+}
+
+/// What the monitoring API shows of a dead-lettered task. The task's body is never included.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct DeadLetter {
+    pub task_id: MsgId,
+    pub from: AppOrProxyId,
+    pub reason: DeadLetterReason,
+    /// The recipients which did not fetch the task in time or for which it is poison
+    pub recipients: Vec<AppOrProxyId>,
+    /// Unix timestamp of when the task was dead-lettered
+    pub dead_lettered_at: u64,
+    /// Unix timestamp of when the task expires or expired
+    pub expire: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Value>,
+}
+
+struct DeadLetterEntry<T: Msg> {
+    reason: DeadLetterReason,
+    from: AppOrProxyId,
+    recipients: Vec<AppOrProxyId>,
+    dead_lettered_at: SystemTime,
+    expire: SystemTime,
+    /// The task once it has been reaped from the regular task store
+    task: Option<MsgSigned<T>>,
+}
+
+impl<T: Msg> DeadLetterEntry<T> {
+    fn describe(&self, task_id: MsgId) -> DeadLetter {
+        DeadLetter {
+            task_id,
+            from: self.from.clone(),
+            reason: self.reason,
+            recipients: self.recipients.clone(),
+            dead_lettered_at: unix_secs(self.dead_lettered_at),
+            expire: unix_secs(self.expire),
+            metadata: None,
+        }
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+#[derive(Debug, Default, Serialize, PartialEq, Eq)]
+pub struct TaskSummary {
+    pub tasks: usize,
+    pub fully_delivered: usize,
+    pub fully_completed: usize,
+    pub stored_bytes: usize,
+    pub storage_cap: Option<usize>,
+}
+
+type SenderAndRecipient = (AppOrProxyId, AppOrProxyId);
+
 pub struct TaskManager<T: HasWaitId<MsgId> + Task + Msg> {
     tasks: DashMap<MsgId, MsgSigned<T>>,
-    new_tasks: broadcast::Sender<MsgId>,
-    /// Send the index at which the new result for the given Task was inserted
-    new_results: DashMap<MsgId, broadcast::Sender<AppOrProxyId>>,
+    notifier: Box<dyn Notifier>,
+    /// Recipients which have fetched the given task
+    deliveries: DashMap<MsgId, HashSet<AppOrProxyId>>,
+    lifecycle: broadcast::Sender<Arc<TaskLifecycleEvent>>,
+    lifecycle_log: Mutex<LifecycleLog>,
+    /// Approximate number of bytes occupied by all stored tasks and their results
+    stored_bytes: AtomicUsize,
+    storage_cap: Option<usize>,
+    /// Sequence numbers of ordered tasks per sender and recipient which the recipient has not yet fetched
+    pending_in_order: Mutex<HashMap<SenderAndRecipient, BTreeSet<(u64, MsgId)>>>,
+    /// How often each recipient has fetched the given task without submitting a result
+    unanswered_fetches: DashMap<MsgId, HashMap<AppOrProxyId, u32>>,
+    poison_threshold: Option<u32>,
+    /// Tasks which expired undelivered or are poison for some recipients.
+    /// Reaped tasks kept here still count towards the stored bytes until they are evicted.
+    dead_letters: DashMap<MsgId, DeadLetterEntry<T>>,
 }
 
 impl<T: HasWaitId<MsgId> + Task + Msg + Send + Sync + 'static> TaskManager<T> {
     const EXPIRE_CHECK_INTERVAL: Duration = Duration::from_secs(5 * 60);
 
-    pub fn new() -> Arc<Self> {
-        let (new_tasks, _) = broadcast::channel(256);
+    /// Creates a task manager which rejects new tasks and results once `storage_cap` bytes are stored
+    /// and stops delivering a task to a recipient once it has fetched it `poison_threshold` times without answering it
+    pub fn new(storage_cap: Option<usize>, poison_threshold: Option<u32>) -> Arc<Self> {
+        let (lifecycle, _) = broadcast::channel(256);
         let task_manager = Arc::new(Self {
             tasks: Default::default(),
-            new_tasks,
-            new_results: Default::default(),
+            notifier: Box::new(InProcessNotifier::default()),
+            deliveries: Default::default(),
+            lifecycle,
+            lifecycle_log: Default::default(),
+            stored_bytes: AtomicUsize::new(0),
+            storage_cap,
+            pending_in_order: Default::default(),
+            unanswered_fetches: Default::default(),
+            poison_threshold,
+            dead_letters: Default::default(),
         });
         let tm = Arc::clone(&task_manager);
         std::thread::spawn(move || {
             loop {
                 std::thread::sleep(Self::EXPIRE_CHECK_INTERVAL);
-                tm.tasks.retain(|_, task| if task.msg.is_expired() {
-                    tm.new_results.remove(&task.msg.wait_id());
-                    false
-                } else {
-                    true
-                });
+                tm.reap_expired();
                 // If the memory footprint of the Dashmap will get too large we might need to consider calling DashMap::shrink_to_fit or find a better solution as
                 // this would need to lock the whole map making it inaccessible until everything is reallocated
             }
@@ -115,13 +347,342 @@ impl<T: HasWaitId<MsgId> + Task + Msg + Send + Sync + 'static> TaskManager<T> {
 }
 
 impl<T: HasWaitId<MsgId> + Task + Msg> TaskManager<T> {
+    /// Dead-lettered tasks beyond this many are evicted, oldest first
+    const MAX_DEAD_LETTERS: usize = 1000;
+    /// Number of lifecycle events which can be replayed to resuming subscribers
+    const LIFECYCLE_REPLAY: usize = 1024;
 
     pub fn get(&self, task_id: &MsgId) -> Result<impl Deref<Target = MsgSigned<T>> + '_, TaskManagerError> {
         self.tasks.get(task_id).ok_or(TaskManagerError::NotFound)
     }
 
+    /// Removes a task including its dead letter, if any, which also holds the task if it has already been reaped
     pub fn remove(&self, task_id: &MsgId) -> Result<MsgSigned<T>, TaskManagerError> {
-        self.tasks.remove(task_id).ok_or(TaskManagerError::NotFound).map(|v| v.1)
+        self.deliveries.remove(task_id);
+        self.unanswered_fetches.remove(task_id);
+        if let Some((_, DeadLetterEntry { task: Some(reaped), .. })) = self.dead_letters.remove(task_id) {
+            self.release_storage(Self::stored_size_of(&reaped));
+        }
+        self.notifier.task_removed(task_id);
+        let (_, task) = self.tasks.remove(task_id).ok_or(TaskManagerError::NotFound)?;
+        self.release_storage(Self::stored_size_of(&task));
+        self.forget_ordering(&task.msg);
+        Ok(task)
+    }
+
+    fn register_ordering(&self, task: &T) {
+        self.register_ordering_for(task, task.get_to());
+    }
+
+    fn register_ordering_for(&self, task: &T, recipients: &[AppOrProxyId]) {
+        let Some(sequence) = task.sequence() else {
+            return;
+        };
+        let mut pending = self.pending_in_order.lock().unwrap();
+        for recipient in recipients {
+            pending
+                .entry((task.get_from().clone(), recipient.clone()))
+                .or_default()
+                .insert((sequence, task.wait_id()));
+        }
+    }
+
+    fn forget_ordering_for(&self, task: &T, recipients: &[AppOrProxyId]) {
+        let Some(sequence) = task.sequence() else {
+            return;
+        };
+        let mut pending = self.pending_in_order.lock().unwrap();
+        for recipient in recipients {
+            let key = (task.get_from().clone(), recipient.clone());
+            if let Some(sequences) = pending.get_mut(&key) {
+                sequences.remove(&(sequence, task.wait_id()));
+                if sequences.is_empty() {
+                    pending.remove(&key);
+                }
+            }
+        }
+    }
+
+    fn forget_ordering(&self, task: &T) {
+        self.forget_ordering_for(task, task.get_to());
+    }
+
+    /// Returns true if the task opted into ordered delivery and `recipient` has not yet fetched
+    /// a task from the same sender with a lower sequence number.
+    pub fn is_held_back(&self, task: &T, recipient: &AppOrProxyId) -> bool {
+        let Some(sequence) = task.sequence() else {
+            return false;
+        };
+        self.pending_in_order
+            .lock()
+            .unwrap()
+            .get(&(task.get_from().clone(), recipient.clone()))
+            .and_then(BTreeSet::first)
+            .is_some_and(|(first, id)| (*first, *id) < (sequence, task.wait_id()))
+    }
+
+    fn stored_size_of(task: &MsgSigned<T>) -> usize {
+        task.stored_size() + task.msg.get_results().values().map(StoredSize::stored_size).sum::<usize>()
+    }
+
+    /// Removes all expired tasks, moving those which did not reach all recipients to the dead-letter queue.
+    /// This must not be called while holding a reference into `self.tasks`.
+    fn reap_expired(&self) {
+        let expired: Vec<MsgId> = self.tasks.iter().filter(|task| task.msg.is_expired()).map(|task| *task.key()).collect();
+        for task_id in expired {
+            let Some((_, task)) = self.tasks.remove_if(&task_id, |_, task| task.msg.is_expired()) else {
+                continue;
+            };
+            self.notifier.task_removed(&task_id);
+            self.emit_lifecycle(Self::lifecycle_event(&task.msg, TaskLifecycle::Expired, None));
+            self.unanswered_fetches.remove(&task_id);
+            self.forget_ordering(&task.msg);
+            let delivered = self.deliveries.remove(&task_id).map(|(_, delivered)| delivered).unwrap_or_default();
+            self.dead_letter_expired(task, delivered);
+        }
+    }
+
+    fn dead_letter_expired(&self, task: MsgSigned<T>, delivered: HashSet<AppOrProxyId>) {
+        let task_id = task.wait_id();
+        if let Some(mut poisoned) = self.dead_letters.get_mut(&task_id) {
+            // Keep the task so that it can still be requeued
+            poisoned.task = Some(task);
+            return;
+        }
+        let undelivered: Vec<_> = task.get_to().iter().filter(|recipient| !delivered.contains(*recipient)).cloned().collect();
+        if undelivered.is_empty() || task.msg.is_probe() {
+            self.release_storage(Self::stored_size_of(&task));
+            return;
+        }
+        warn!("Task {task_id} expired before it was fetched by {undelivered:?}; moving it to the dead-letter queue");
+        self.dead_letters.insert(task_id, DeadLetterEntry {
+            reason: DeadLetterReason::ExpiredUndelivered,
+            from: task.get_from().clone(),
+            recipients: undelivered,
+            dead_lettered_at: SystemTime::now(),
+            expire: task.msg.expire(),
+            task: Some(task),
+        });
+        self.cap_dead_letters();
+    }
+
+    fn cap_dead_letters(&self) {
+        while self.dead_letters.len() > Self::MAX_DEAD_LETTERS && self.evict_oldest_dead_letter(false) {}
+    }
+
+    /// Drops the oldest dead-lettered task or, if `reaped_only` is set, the oldest one which has already been reaped
+    /// from the task store, as only these free storage. Returns false if there is none.
+    fn evict_oldest_dead_letter(&self, reaped_only: bool) -> bool {
+        let oldest = self.dead_letters
+            .iter()
+            .filter(|entry| !reaped_only || entry.task.is_some())
+            .min_by_key(|entry| entry.dead_lettered_at)
+            .map(|entry| *entry.key());
+        let Some((task_id, evicted)) = oldest.and_then(|task_id| self.dead_letters.remove(&task_id)) else {
+            return false;
+        };
+        debug!("Evicting dead-lettered task {task_id}");
+        if let Some(task) = evicted.task {
+            self.release_storage(Self::stored_size_of(&task));
+        }
+        true
+    }
+
+    /// Accounts for `bytes` more of stored data if this does not exceed the storage cap.
+    /// Expired tasks are reaped before the new data is rejected.
+    /// This must not be called while holding a reference into `self.tasks`.
+    fn reserve_storage(&self, bytes: usize) -> Result<(), TaskManagerError> {
+        let Some(cap) = self.storage_cap else {
+            self.stored_bytes.fetch_add(bytes, Ordering::Relaxed);
+            return Ok(());
+        };
+        if self.try_reserve_storage(bytes, cap) {
+            return Ok(());
+        }
+        self.reap_expired();
+        // Dead letters give way to new data
+        while !self.try_reserve_storage(bytes, cap) {
+            if !self.evict_oldest_dead_letter(true) {
+                let stored = self.stored_bytes.load(Ordering::Relaxed);
+                warn!("Rejecting {bytes} bytes as {stored} of {cap} bytes are already in use");
+                return Err(TaskManagerError::InsufficientStorage);
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks and accounts for the bytes in one step, so that concurrent reservations cannot exceed the cap together
+    fn try_reserve_storage(&self, bytes: usize, cap: usize) -> bool {
+        self.stored_bytes
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |stored| {
+                stored.checked_add(bytes).filter(|&total| total <= cap)
+            })
+            .is_ok()
+    }
+
+    fn release_storage(&self, bytes: usize) {
+        self.stored_bytes.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    pub fn stored_bytes(&self) -> usize {
+        self.stored_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Subscribe to the lifecycle milestones of all tasks.
+    /// If `last_id` is given the events after it which are still known are returned as well so that nothing is missed in between.
+    pub fn subscribe_lifecycle(&self, last_id: Option<u64>) -> (Vec<Arc<TaskLifecycleEvent>>, broadcast::Receiver<Arc<TaskLifecycleEvent>>) {
+        let log = self.lifecycle_log.lock().unwrap();
+        let missed = last_id
+            .map(|last_id| log.recent.iter().filter(|event| event.id > last_id).cloned().collect())
+            .unwrap_or_default();
+        (missed, self.lifecycle.subscribe())
+    }
+
+    /// The event for `task` reaching `state`, which is taken while the task is borrowed and emitted with
+    /// [`Self::emit_lifecycle`] once it is released again
+    fn lifecycle_event(task: &T, state: TaskLifecycle, result_from: Option<&AppOrProxyId>) -> TaskLifecycleEvent {
+        TaskLifecycleEvent {
+            // Assigned when emitting so that ids are in the order of the feed
+            id: 0,
+            task_id: task.wait_id(),
+            state,
+            from: task.get_from().clone(),
+            to: task.get_to().clone(),
+            result_from: result_from.cloned(),
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64,
+        }
+    }
+
+    /// Must not be called while holding a reference into `self.tasks`
+    fn emit_lifecycle(&self, mut event: TaskLifecycleEvent) {
+        debug!("Task {} is now {:?}", event.task_id, event.state);
+        let mut log = self.lifecycle_log.lock().unwrap();
+        log.last_id += 1;
+        event.id = log.last_id;
+        let event = Arc::new(event);
+        if log.recent.len() == Self::LIFECYCLE_REPLAY {
+            log.recent.pop_front();
+        }
+        log.recent.push_back(event.clone());
+        // Sent while holding the log so that subscribers neither miss nor duplicate events when catching up
+        // We dont care if noone is listening
+        _ = self.lifecycle.send(event);
+    }
+
+    /// Records that `recipient` has fetched the given task.
+    /// Fetches by anyone who is not a recipient of the task are ignored.
+    pub fn mark_delivered(&self, task_id: &MsgId, recipient: &AppOrProxyId) {
+        let Ok(task) = self.get(task_id) else {
+            return;
+        };
+        let recipients = task.get_to();
+        if !recipients.contains(recipient) {
+            return;
+        }
+        if !task.msg.get_results().contains_key(recipient) {
+            self.count_unanswered_fetch(&task.msg, recipient);
+        }
+        let mut delivered = self.deliveries.entry(*task_id).or_default();
+        let newly_delivered = delivered.insert(recipient.clone());
+        let fully_delivered = recipients.iter().all(|r| delivered.contains(r));
+        drop(delivered);
+        if newly_delivered {
+            self.forget_ordering_for(&task.msg, std::slice::from_ref(recipient));
+        }
+        if newly_delivered && fully_delivered {
+            let event = Self::lifecycle_event(&task.msg, TaskLifecycle::Delivered, None);
+            drop(task);
+            self.emit_lifecycle(event);
+        }
+    }
+
+    fn count_unanswered_fetch(&self, task: &T, recipient: &AppOrProxyId) {
+        let Some(threshold) = self.poison_threshold else {
+            return;
+        };
+        let task_id = task.wait_id();
+        let mut fetches = self.unanswered_fetches.entry(task_id).or_default();
+        let count = fetches.entry(recipient.clone()).or_default();
+        *count += 1;
+        if *count != threshold {
+            return;
+        }
+        warn!("{recipient} has fetched task {task_id} {count} times without answering it; no longer delivering it to {recipient}");
+        drop(fetches);
+        self.dead_letters
+            .entry(task_id)
+            .or_insert_with(|| DeadLetterEntry {
+                reason: DeadLetterReason::Poisoned,
+                from: task.get_from().clone(),
+                recipients: Vec::new(),
+                dead_lettered_at: SystemTime::now(),
+                expire: task.expire(),
+                task: None,
+            })
+            .recipients
+            .push(recipient.clone());
+        self.cap_dead_letters();
+    }
+
+    /// Whether `recipient` has fetched the task so often without answering it that the task is
+    /// considered poison for it (e.g. because it cannot be decrypted or processed) and is no longer delivered to it
+    pub fn is_poisoned(&self, task: &T, recipient: &AppOrProxyId) -> bool {
+        let Some(threshold) = self.poison_threshold else {
+            return false;
+        };
+        self.unanswered_fetches
+            .get(&task.wait_id())
+            .and_then(|fetches| fetches.get(recipient).copied())
+            .is_some_and(|count| count >= threshold)
+    }
+
+    fn poisoned_recipients(&self, task_id: &MsgId) -> Vec<AppOrProxyId> {
+        let (Some(threshold), Some(fetches)) = (self.poison_threshold, self.unanswered_fetches.get(task_id)) else {
+            return Vec::new();
+        };
+        fetches.iter().filter(|(_, count)| **count >= threshold).map(|(recipient, _)| recipient.clone()).collect()
+    }
+
+    /// All dead-lettered tasks, oldest first
+    pub fn dead_letters(&self) -> Vec<DeadLetter> {
+        let mut dead_letters: Vec<_> = self.dead_letters.iter().map(|entry| entry.describe(*entry.key())).collect();
+        dead_letters.sort_by_key(|dead_letter| dead_letter.dead_lettered_at);
+        dead_letters
+    }
+
+    /// A dead-lettered task including its metadata
+    pub fn dead_letter(&self, task_id: &MsgId) -> Result<DeadLetter, TaskManagerError> {
+        let entry = self.dead_letters.get(task_id).ok_or(TaskManagerError::NotFound)?;
+        let mut dead_letter = entry.describe(*task_id);
+        dead_letter.metadata = entry.task.as_ref().map(|task| task.msg.metadata().clone());
+        drop(entry);
+        if dead_letter.metadata.is_none() {
+            dead_letter.metadata = Some(self.get(task_id)?.msg.metadata().clone());
+        }
+        Ok(dead_letter)
+    }
+
+    /// Takes a task out of the dead-letter queue and delivers it again:
+    /// Poisoned recipients get another chance and reaped tasks are stored again for `ttl`.
+    /// This must not be called while holding a reference into `self.tasks`.
+    pub fn requeue(&self, task_id: &MsgId, ttl: Duration) -> Result<(), TaskManagerError> {
+        let (_, mut entry) = self.dead_letters.remove(task_id).ok_or(TaskManagerError::NotFound)?;
+        self.unanswered_fetches.remove(task_id);
+        let Some(mut task) = entry.task.take() else {
+            debug!("Requeued task {task_id} for {:?}", entry.recipients);
+            self.notifier.task_written(task_id, &entry.recipients);
+            return Ok(());
+        };
+        if self.tasks.get(task_id).is_some_and(|stored| !stored.msg.is_expired()) {
+            entry.task = Some(task);
+            self.dead_letters.insert(*task_id, entry);
+            return Err(TaskManagerError::Conflict);
+        }
+        debug!("Requeued task {task_id} for {ttl:?}");
+        task.msg.set_expire(SystemTime::now() + ttl);
+        // Dead letters still count towards the stored bytes
+        self.insert_task(task);
+        Ok(())
     }
 
     pub fn get_tasks_by(&self, filter: impl Fn(&T) -> bool) -> impl Iterator<Item = impl Deref<Target = MsgSigned<T>> + '_> {
@@ -141,7 +702,7 @@ impl<T: HasWaitId<MsgId> + Task + Msg> TaskManager<T> {
     ) -> Result<impl Iterator<Item = impl Deref<Target = MsgSigned<T>> + '_>, TaskManagerError>
     {
         let (max_elements, wait_until) = decide_blocking_conditions(block);
-        let mut new_tasks = self.new_tasks.subscribe();
+        let mut new_tasks = self.notifier.subscribe_tasks();
 
         let mut num_of_tasks = self.get_tasks_by(&filter).count();
         while num_of_tasks < max_elements && Instant::now() < wait_until {
@@ -159,7 +720,7 @@ impl<T: HasWaitId<MsgId> + Task + Msg> TaskManager<T> {
                             }
                         },
                         Err(e) => {
-                            warn!("new_tasks channel lagged: {e}");
+                            warn!("Missed notifications about new tasks: {e}");
                             return Err(TaskManagerError::BroadcastBufferOverflow);
                         }
                     }
@@ -177,12 +738,81 @@ impl<T: HasWaitId<MsgId> + Task + Msg> TaskManager<T> {
                 return Err(TaskManagerError::Conflict);
             }
         }
-        let max_receivers = task.get_to().len();
-        self.tasks.insert(id.clone(), task);
-        let (results_sender, _) = broadcast::channel(1.max(max_receivers));
-        self.new_results.insert(id.clone(), results_sender);
-        // We dont care if noone is listening
-        _ = self.new_tasks.send(id);
+        self.reserve_storage(Self::stored_size_of(&task))?;
+        self.insert_task(task);
+        Ok(())
+    }
+
+    /// Stores a task for which storage has already been reserved and notifies everyone waiting for new tasks
+    fn insert_task(&self, task: MsgSigned<T>) {
+        let id = task.wait_id();
+        let recipients = task.get_to().clone();
+        if let Some(expired) = self.tasks.get(&id) {
+            self.forget_ordering(&expired.msg);
+        }
+        self.register_ordering(&task.msg);
+        if let Some(expired) = self.tasks.insert(id.clone(), task) {
+            self.release_storage(Self::stored_size_of(&expired));
+            // Nobody may receive the results of the new task while waiting for the expired one
+            self.notifier.task_removed(&id);
+        }
+        self.deliveries.remove(&id);
+        let created = self.tasks.get(&id).map(|task| Self::lifecycle_event(&task.msg, TaskLifecycle::Created, None));
+        if let Some(created) = created {
+            self.emit_lifecycle(created);
+        }
+        self.notifier.task_written(&id, &recipients);
+    }
+
+    /// Replaces a task with a version of it that is addressed to additional recipients, keeping its results.
+    /// The recipients of the stored task have to be a prefix of the recipients of the new one and
+    /// `validate` is called with the stored and the new task to check (and possibly adjust) everything else.
+    pub fn add_recipients(
+        &self,
+        mut task: MsgSigned<T>,
+        validate: impl FnOnce(&T, &mut T) -> Result<(), TaskManagerError>,
+    ) -> Result<(), TaskManagerError> {
+        let id = task.wait_id();
+        let old_size = self.get(&id)?.stored_size();
+        let added_size = task.stored_size().saturating_sub(old_size);
+        // Reserve before locking the task as reserving may need to reap expired tasks
+        self.reserve_storage(added_size)?;
+        let mut stored = match self.tasks.get_mut(&id) {
+            Some(stored) if !stored.msg.is_expired() => stored,
+            _ => {
+                self.release_storage(added_size);
+                return Err(TaskManagerError::NotFound);
+            }
+        };
+        let old_recipients = stored.get_to();
+        let is_extension = task.get_from() == stored.get_from()
+            && task.get_to().len() > old_recipients.len()
+            && task.get_to().starts_with(old_recipients);
+        let validation = if is_extension {
+            validate(&stored.msg, &mut task.msg)
+        } else {
+            Err(TaskManagerError::InvalidUpdate)
+        };
+        if let Err(e) = validation {
+            drop(stored);
+            self.release_storage(added_size);
+            return Err(e);
+        }
+        let new_recipients = task.get_to()[old_recipients.len()..].to_vec();
+        for result in stored.msg.take_results().into_values() {
+            task.msg.insert_result(result);
+        }
+        let replaced_size = stored.stored_size();
+        let new_size = task.stored_size();
+        self.register_ordering_for(&task.msg, &new_recipients);
+        *stored = task;
+        drop(stored);
+        // Settle the reservation with the actual size of the replaced task
+        self.stored_bytes.fetch_add(new_size, Ordering::Relaxed);
+        self.release_storage(replaced_size + added_size);
+        debug!("Task {id} has been extended to {new_recipients:?}");
+        // Wake up the new recipients if they are waiting for tasks
+        self.notifier.task_written(&id, &new_recipients);
         Ok(())
     }
 }
@@ -200,6 +830,75 @@ fn decide_blocking_conditions(block: &HowLongToBlock) -> (usize, Instant) {
     }
 }
 
+impl<T: HasWaitId<MsgId> + Task + Msg> TaskManager<T>
+where
+    T::Result: HasStatus,
+{
+    fn completed_by(task: &T) -> usize {
+        task.get_results()
+            .iter()
+            .filter(|(from, result)| task.get_to().contains(from) && result.get_status() != WorkStatus::Claimed)
+            .count()
+    }
+
+    fn status_of(&self, task: &MsgSigned<T>) -> TaskStatus {
+        let recipients = task.get_to();
+        let delivered = self.deliveries
+            .get(&task.wait_id())
+            .map(|d| recipients.iter().filter(|r| d.contains(r)).count())
+            .unwrap_or(0);
+        let completed = Self::completed_by(&task.msg);
+        TaskStatus {
+            recipients: recipients.len(),
+            delivered,
+            completed,
+            fully_delivered: delivered == recipients.len(),
+            fully_completed: completed == recipients.len(),
+            poisoned: self.poisoned_recipients(&task.wait_id()),
+        }
+    }
+
+    pub fn status(&self, task_id: &MsgId) -> Result<TaskStatus, TaskManagerError> {
+        let task = self.get(task_id)?;
+        Ok(self.status_of(&task))
+    }
+
+    /// All tasks created by `creator` which have not yet expired, those expiring first listed first
+    pub fn created_by(&self, creator: &AppOrProxyId) -> Vec<CreatedTask> {
+        let mut created: Vec<_> = self
+            .get_tasks_by(|task| task.get_from() == creator && !task.is_probe())
+            .map(|task| CreatedTask {
+                task_id: task.wait_id(),
+                to: task.get_to().clone(),
+                expire: unix_secs(task.msg.expire()),
+                results: task.msg.get_results().len(),
+                status: self.status_of(&task),
+            })
+            .collect();
+        created.sort_by_key(|task| task.expire);
+        created
+    }
+
+    /// Counts the lifecycle milestones reached by all tasks which have not yet expired
+    pub fn summary(&self) -> TaskSummary {
+        let summary = self.tasks
+            .iter()
+            .filter(|entry| !entry.msg.is_expired() && !entry.msg.is_probe())
+            .fold(TaskSummary::default(), |mut summary, task| {
+                let status = self.status_of(&task);
+                summary.tasks += 1;
+                summary.fully_delivered += status.fully_delivered as usize;
+                summary.fully_completed += status.fully_completed as usize;
+                summary
+            });
+        TaskSummary {
+            stored_bytes: self.stored_bytes(),
+            storage_cap: self.storage_cap,
+            ..summary
+        }
+    }
+}
+
 impl<T: HasWaitId<MsgId> + Task + Msg> TaskManager<T>
 where
     T::Result: Msg + HasStatus,
@@ -219,11 +918,11 @@ where
             .values()
             .filter(|result| filter(result) && result.get_status() != WorkStatus::Claimed)
             .count();
+        // The task might have expired since we looked it up
         let mut new_results = self
-            .new_results
-            .get(task_id)
-            .expect("Found task but no corresponding results channel")
-            .subscribe();
+            .notifier
+            .subscribe_results(task_id)
+            .ok_or(TaskManagerError::Gone)?;
         while num_of_results < max_elements && Instant::now() < wait_until {
             tokio::select! {
                 _ = tokio::time::sleep_until(wait_until) => {
@@ -233,8 +932,7 @@ where
                     match result {
                         Ok(key) => {
                             if let Ok(task) = self.get(task_id) {
-                                let result = &task.msg.get_results()[&key];
-                                if filter(result) && result.get_status() != WorkStatus::Claimed {
+                                if task.msg.get_results().get(&key).is_some_and(|result| filter(result) && result.get_status() != WorkStatus::Claimed) {
                                     num_of_results += 1;
                                 }
                             } else {
@@ -242,7 +940,7 @@ where
                             }
                         },
                         Err(e) => {
-                            warn!("new_results channel lagged: {e}");
+                            warn!("Missed notifications about new results: {e}");
                             return Err(TaskManagerError::BroadcastBufferOverflow);
                         }
                     }
@@ -293,11 +991,10 @@ where
             for event in events {
                 yield Ok(event);
             }
-            let mut new_results = self
-                .new_results
-                .get(&task_id)
-                .expect("Found task but no corresponding results channel")
-                .subscribe();
+            let Some(mut new_results) = self.notifier.subscribe_results(&task_id) else {
+                yield Ok(to_event(json!({"task_id": task_id}), SseEventType::DeletedTask));
+                return;
+            };
             while num_of_results < max_elements && Instant::now() < wait_until {
                 tokio::select! {
                     _ = tokio::time::sleep_until(wait_until) => {
@@ -308,7 +1005,9 @@ where
                         match result {
                             Ok(key) => {
                                 if let Ok(task) = self.get(&task_id) {
-                                    let new_result = &task.msg.get_results()[&key];
+                                    let Some(new_result) = task.msg.get_results().get(&key) else {
+                                        continue;
+                                    };
                                     if filter(new_result) {
                                         if new_result.get_status() != WorkStatus::Claimed {
                                             num_of_results += 1;
@@ -322,7 +1021,7 @@ where
                                 }
                             },
                             Err(broadcast::error::RecvError::Lagged(n)) => {
-                                warn!("new_results channel lagged by: {n} results.");
+                                warn!("Missed notifications about {n} new results.");
                                 yield Ok(to_event("Internal server error", SseEventType::Error));
                             },
                             Err(broadcast::error::RecvError::Closed) => {
@@ -333,30 +1032,62 @@ where
                     },
                 }
             }
+            self.finish_probe(&task_id);
         }
     }
 
     /// This will push the result to the given task by its id.
     /// Returns true if the given result was an update to an existing result
     pub fn put_result(&self, task_id: &MsgId, result: T::Result) -> Result<bool, TaskManagerError> {
+        let size = result.stored_size();
+        let previous_size = |task: &MsgSigned<T>| task.msg.get_results().get(result.get_from()).map_or(0, StoredSize::stored_size);
+        // Only what an updated result adds has to fit. Reserve before locking the task as reserving may need to reap expired tasks.
+        let added_size = size.saturating_sub(previous_size(&*self.get(task_id)?));
+        self.reserve_storage(added_size)?;
         let Some(mut task) = self.tasks.get_mut(task_id) else {
+            self.release_storage(added_size);
             return Err(TaskManagerError::NotFound);
         };
         if !task.get_to().contains(result.get_from()) {
+            self.release_storage(added_size);
             return Err(TaskManagerError::Unauthorized);
         }
         let sender = result.get_from().clone();
+        // Settle the reservation with the size of the result replaced by now
+        self.stored_bytes.fetch_add(size, Ordering::Relaxed);
+        self.release_storage(previous_size(&task) + added_size);
+        let was_completed = Self::completed_by(&task.msg) == task.get_to().len();
         let is_updated = task.msg.insert_result(result);
-        // We dont care if noone is listening
-        _ = self
-            .new_results
-            .get(task_id)
-            .expect(
-                "This task id must be present because it is present at the start of the function",
-            )
-            .send(sender);
+        let is_completed = Self::completed_by(&task.msg) == task.get_to().len();
+        let received = Self::lifecycle_event(&task.msg, TaskLifecycle::ResultReceived, Some(&sender));
+        let completed = (is_completed && !was_completed).then(|| Self::lifecycle_event(&task.msg, TaskLifecycle::Completed, None));
+        drop(task);
+        self.emit_lifecycle(received);
+        if let Some(completed) = completed {
+            self.emit_lifecycle(completed);
+        }
+        // Answering shows that the recipient is able to process the task after all
+        if let Some(mut fetches) = self.unanswered_fetches.get_mut(task_id) {
+            fetches.remove(&sender);
+        }
+        if let Some(mut dead_letter) = self.dead_letters.get_mut(task_id) {
+            dead_letter.recipients.retain(|recipient| recipient != &sender);
+        }
+        self.dead_letters.remove_if(task_id, |_, dead_letter| dead_letter.recipients.is_empty());
+        self.notifier.result_written(task_id, &sender);
         Ok(is_updated)
     }
+
+    /// Removes the given task if it is a probe which all recipients have answered
+    pub fn finish_probe(&self, task_id: &MsgId) {
+        let answered = self.get(task_id).is_ok_and(|task| {
+            task.msg.is_probe() && Self::completed_by(&task.msg) == task.get_to().len()
+        });
+        if answered {
+            debug!("Probe {task_id} has been answered; removing it");
+            _ = self.remove(task_id);
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -366,6 +1097,8 @@ pub enum TaskManagerError {
     Unauthorized,
     Gone,
     BroadcastBufferOverflow,
+    InsufficientStorage,
+    InvalidUpdate,
 }
 
 impl TaskManagerError {
@@ -376,6 +1109,8 @@ impl TaskManagerError {
             TaskManagerError::Unauthorized => "Unauthorized to access this task",
             TaskManagerError::Gone => "Task expired while waiting on it",
             TaskManagerError::BroadcastBufferOverflow => "Internal server error",
+            TaskManagerError::InsufficientStorage => "Broker storage is exhausted, try again later",
+            TaskManagerError::InvalidUpdate => "A task may only be updated by its creator to add recipients",
         }
     }
 }
@@ -395,6 +1130,8 @@ impl From<TaskManagerError> for StatusCode {
             TaskManagerError::BroadcastBufferOverflow => StatusCode::INTERNAL_SERVER_ERROR,
             TaskManagerError::Unauthorized => StatusCode::UNAUTHORIZED,
             TaskManagerError::Gone => StatusCode::GONE,
+            TaskManagerError::InsufficientStorage => StatusCode::INSUFFICIENT_STORAGE,
+            TaskManagerError::InvalidUpdate => StatusCode::BAD_REQUEST,
         }
     }
 }
@@ -407,3 +1144,443 @@ fn to_event(json: impl Serialize, event_type: impl AsRef<str>) -> Event {
             .data("Internal error: Unable to serialize message.")
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use beam_lib::{AppId, FailureStrategy};
+    use serde_json::Value;
+    use shared::{Encrypted, EncryptedMsgTaskRequest, EncryptedMsgTaskResult};
+
+    use super::*;
+
+    fn signed<M: Msg>(msg: M) -> MsgSigned<M> {
+        MsgSigned { msg, jwt: String::new() }
+    }
+
+    fn result(task: &MsgId, from: &AppOrProxyId, status: WorkStatus) -> MsgSigned<EncryptedMsgTaskResult> {
+        signed(EncryptedMsgTaskResult {
+            from: from.clone(),
+            to: vec![],
+            task: *task,
+            status,
+            body: Encrypted::default(),
+            metadata: Value::Null,
+        })
+    }
+
+    fn task(from: &AppOrProxyId, to: Vec<AppOrProxyId>, ttl: Duration) -> MsgSigned<EncryptedMsgTaskRequest> {
+        MsgSigned {
+            msg: EncryptedMsgTaskRequest {
+                id: MsgId::new(),
+                from: from.clone(),
+                to,
+                body: Encrypted::default(),
+                expire: SystemTime::now() + ttl,
+                failure_strategy: FailureStrategy::Discard,
+                results: HashMap::new(),
+                metadata: Value::Null,
+                sequence: None,
+                probe: false,
+            },
+            jwt: "x".repeat(50),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_storage_cap() {
+        let creator: AppOrProxyId = AppId::new_unchecked("app0.proxy0.broker").into();
+        let app1: AppOrProxyId = AppId::new_unchecked("app1.proxy1.broker").into();
+        // Every task and result in this test occupies 100 bytes
+        let task_manager = TaskManager::<EncryptedMsgTaskRequest>::new(Some(250), None);
+        let long_lived = task(&creator, vec![app1.clone()], Duration::from_secs(60));
+        let long_lived_id = long_lived.msg.id;
+        task_manager.post_task(long_lived).unwrap();
+        task_manager.post_task(task(&creator, vec![app1.clone()], Duration::from_millis(50))).unwrap();
+        assert_eq!(task_manager.stored_bytes(), 200);
+
+        assert!(matches!(
+            task_manager.post_task(task(&creator, vec![app1.clone()], Duration::from_secs(60))),
+            Err(TaskManagerError::InsufficientStorage)
+        ));
+        let mut res = result(&long_lived_id, &app1, WorkStatus::Succeeded);
+        res.jwt = "x".repeat(50);
+        assert!(matches!(
+            task_manager.put_result(&long_lived_id, res),
+            Err(TaskManagerError::InsufficientStorage)
+        ));
+        assert_eq!(task_manager.stored_bytes(), 200);
+
+        // Stored tasks can still be retrieved while the broker is full
+        let block = HowLongToBlock { wait_time: None, wait_count: None };
+        assert_eq!(task_manager.wait_for_tasks(&block, |t| t.to.contains(&app1)).await.unwrap().count(), 2);
+
+        // Once a task has expired it is reaped to make room for the new one
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        task_manager.post_task(task(&creator, vec![app1.clone()], Duration::from_secs(60))).unwrap();
+        assert_eq!(task_manager.stored_bytes(), 200);
+        assert_eq!(task_manager.summary().stored_bytes, 200);
+    }
+
+    #[tokio::test]
+    async fn test_storage_cap_with_updated_results_and_concurrent_tasks() {
+        let creator: AppOrProxyId = AppId::new_unchecked("app0.proxy0.broker").into();
+        let app1: AppOrProxyId = AppId::new_unchecked("app1.proxy1.broker").into();
+        let task_manager = TaskManager::<EncryptedMsgTaskRequest>::new(Some(200), None);
+        let claimed = task(&creator, vec![app1.clone()], Duration::from_secs(60));
+        let task_id = claimed.msg.id;
+        task_manager.post_task(claimed).unwrap();
+        let mut res = result(&task_id, &app1, WorkStatus::Claimed);
+        res.jwt = "x".repeat(50);
+        task_manager.put_result(&task_id, res.clone()).unwrap();
+        assert_eq!(task_manager.stored_bytes(), 200);
+
+        // Replacing a result only needs room for what it adds
+        res.msg.status = WorkStatus::Succeeded;
+        task_manager.put_result(&task_id, res).unwrap();
+        assert_eq!(task_manager.stored_bytes(), 200);
+
+        // Concurrent tasks never exceed the cap together
+        let task_manager = TaskManager::<EncryptedMsgTaskRequest>::new(Some(1000), None);
+        std::thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    for _ in 0..10 {
+                        let _ = task_manager.post_task(task(&creator, vec![app1.clone()], Duration::from_secs(60)));
+                    }
+                });
+            }
+        });
+        assert_eq!(task_manager.stored_bytes(), 1000);
+        assert_eq!(task_manager.summary().tasks, 10);
+    }
+
+    #[tokio::test]
+    async fn test_ordered_delivery() {
+        let sender: AppOrProxyId = AppId::new_unchecked("app0.proxy0.broker").into();
+        let other_sender: AppOrProxyId = AppId::new_unchecked("app3.proxy3.broker").into();
+        let app1: AppOrProxyId = AppId::new_unchecked("app1.proxy1.broker").into();
+        let app2: AppOrProxyId = AppId::new_unchecked("app2.proxy2.broker").into();
+        let task_manager = TaskManager::<EncryptedMsgTaskRequest>::new(None, None);
+        let ordered = |from: &AppOrProxyId, sequence| {
+            let mut task = task(from, vec![app1.clone(), app2.clone()], Duration::from_secs(60));
+            task.msg.sequence = Some(sequence);
+            task
+        };
+        // Tasks arrive out of order
+        let (second, first) = (ordered(&sender, 2), ordered(&sender, 1));
+        let (second_id, first_id) = (second.msg.id, first.msg.id);
+        task_manager.post_task(second).unwrap();
+        task_manager.post_task(ordered(&other_sender, 5)).unwrap();
+        task_manager.post_task(task(&sender, vec![app1.clone()], Duration::from_secs(60))).unwrap();
+        task_manager.post_task(first).unwrap();
+
+        let deliverable = |recipient: &AppOrProxyId| {
+            let mut ids: Vec<_> = task_manager
+                .get_tasks_by(|t| t.from == sender && t.to.contains(recipient) && t.sequence.is_some() && !task_manager.is_held_back(t, recipient))
+                .map(|t| t.msg.id)
+                .collect();
+            ids.sort();
+            ids
+        };
+        assert_eq!(deliverable(&app1), [first_id]);
+        // Tasks without a sequence number and tasks of other senders are not held back
+        assert_eq!(task_manager.get_tasks_by(|t| !task_manager.is_held_back(t, &app1)).count(), 3);
+
+        task_manager.mark_delivered(&first_id, &app1);
+        let mut both = vec![first_id, second_id];
+        both.sort();
+        assert_eq!(deliverable(&app1), both);
+        // Ordering is tracked per recipient
+        assert_eq!(deliverable(&app2), [first_id]);
+    }
+
+    #[tokio::test]
+    async fn test_task_lifecycle() {
+        let creator: AppOrProxyId = AppId::new_unchecked("app0.proxy0.broker").into();
+        let app1: AppOrProxyId = AppId::new_unchecked("app1.proxy1.broker").into();
+        let app2: AppOrProxyId = AppId::new_unchecked("app2.proxy2.broker").into();
+        let task_manager = TaskManager::<EncryptedMsgTaskRequest>::new(None, None);
+        let (_, mut events) = task_manager.subscribe_lifecycle(None);
+        let new_task = task(&creator, vec![app1.clone(), app2.clone()], Duration::from_secs(60));
+        let id = new_task.msg.id;
+        task_manager.post_task(new_task).unwrap();
+        let status = task_manager.status(&id).unwrap();
+        assert!(!status.fully_delivered && !status.fully_completed);
+
+        task_manager.mark_delivered(&id, &app1);
+        // Fetches by non recipients and repeated fetches don't count
+        task_manager.mark_delivered(&id, &creator);
+        task_manager.mark_delivered(&id, &app1);
+        assert_eq!(task_manager.status(&id).unwrap().delivered, 1);
+        task_manager.mark_delivered(&id, &app2);
+        let status = task_manager.status(&id).unwrap();
+        assert!(status.fully_delivered && !status.fully_completed);
+
+        task_manager.put_result(&id, result(&id, &app1, WorkStatus::Succeeded)).unwrap();
+        task_manager.put_result(&id, result(&id, &app2, WorkStatus::Claimed)).unwrap();
+        assert!(!task_manager.status(&id).unwrap().fully_completed);
+        task_manager.put_result(&id, result(&id, &app2, WorkStatus::PermFailed)).unwrap();
+        // Updating a result of a completed task must not complete it again
+        task_manager.put_result(&id, result(&id, &app1, WorkStatus::Succeeded)).unwrap();
+        assert_eq!(task_manager.status(&id).unwrap(), TaskStatus {
+            recipients: 2,
+            delivered: 2,
+            completed: 2,
+            fully_delivered: true,
+            fully_completed: true,
+            poisoned: Vec::new(),
+        });
+        let summary = task_manager.summary();
+        assert_eq!((summary.tasks, summary.fully_delivered, summary.fully_completed), (1, 1, 1));
+
+        let mut states = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            assert_eq!(event.task_id, id);
+            states.push(event.state);
+        }
+        assert_eq!(states, [
+            TaskLifecycle::Created,
+            TaskLifecycle::Delivered,
+            TaskLifecycle::ResultReceived,
+            TaskLifecycle::ResultReceived,
+            TaskLifecycle::ResultReceived,
+            TaskLifecycle::Completed,
+            TaskLifecycle::ResultReceived,
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_add_recipients() {
+        let creator: AppOrProxyId = AppId::new_unchecked("app0.proxy0.broker").into();
+        let app1: AppOrProxyId = AppId::new_unchecked("app1.proxy1.broker").into();
+        let app2: AppOrProxyId = AppId::new_unchecked("app2.proxy2.broker").into();
+        let task_manager = TaskManager::new(None, None);
+
+        let original = task(&creator, vec![app1.clone()], Duration::from_secs(60));
+        let id = original.msg.id;
+        let mut extended = MsgSigned { msg: original.msg.clone(), jwt: "y".repeat(60) };
+        extended.msg.to.push(app2.clone());
+        task_manager.post_task(original).unwrap();
+        task_manager.put_result(&id, result(&id, &app1, WorkStatus::Succeeded)).unwrap();
+
+        // Only extensions of the recipient list are accepted
+        let mut reordered = MsgSigned { msg: extended.msg.clone(), jwt: String::new() };
+        reordered.msg.to.reverse();
+        assert!(matches!(task_manager.add_recipients(reordered, |_, _| Ok(())), Err(TaskManagerError::InvalidUpdate)));
+        assert!(matches!(
+            task_manager.add_recipients(MsgSigned { msg: extended.msg.clone(), jwt: String::new() }, |_, _| Err(TaskManagerError::InvalidUpdate)),
+            Err(TaskManagerError::InvalidUpdate)
+        ));
+
+        task_manager.add_recipients(extended, |_, _| Ok(())).unwrap();
+        let stored = task_manager.get(&id).unwrap();
+        assert_eq!(stored.get_to(), &vec![app1.clone(), app2.clone()]);
+        assert!(stored.msg.get_results().contains_key(&app1), "Results must be kept");
+        drop(stored);
+        assert_eq!(task_manager.stored_bytes(), 120);
+
+        let for_app2 = task_manager.get_tasks_by(|t| t.get_to().contains(&app2)).count();
+        assert_eq!(for_app2, 1);
+    }
+
+    #[tokio::test]
+    async fn test_probe_round_trip() {
+        let creator: AppOrProxyId = AppId::new_unchecked("app0.proxy0.broker").into();
+        let app1: AppOrProxyId = AppId::new_unchecked("app1.proxy1.broker").into();
+        let mut probe = task(&creator, vec![app1.clone()], Duration::from_secs(10));
+        probe.msg.probe = true;
+        let id = probe.msg.id;
+        // Probes take up storage like any other task while they are kept
+        assert!(matches!(
+            TaskManager::new(Some(10), None).post_task(probe.clone()),
+            Err(TaskManagerError::InsufficientStorage)
+        ));
+        let task_manager = TaskManager::new(None, None);
+        task_manager.post_task(probe).unwrap();
+        assert_eq!(task_manager.summary().tasks, 0, "Probes must not count as stored tasks");
+        assert!(task_manager.stored_bytes() > 0);
+
+        let block = HowLongToBlock { wait_time: Some(Duration::from_secs(5)), wait_count: Some(1) };
+        let waiting_for_result = task_manager.wait_for_results(&id, &block, |_| true);
+        let answering = async {
+            let fetched = task_manager.wait_for_tasks(&block, |t| t.get_to().contains(&app1)).await.unwrap().count();
+            assert_eq!(fetched, 1);
+            task_manager.mark_delivered(&id, &app1);
+            task_manager.put_result(&id, result(&id, &app1, WorkStatus::Succeeded)).unwrap();
+        };
+        let (task_with_result, ()) = tokio::join!(waiting_for_result, answering);
+        assert_eq!(task_with_result.unwrap().msg.get_results().len(), 1);
+
+        task_manager.finish_probe(&id);
+        assert!(matches!(task_manager.get(&id), Err(TaskManagerError::NotFound)), "Answered probes must be removed");
+        assert_eq!(task_manager.stored_bytes(), 0);
+
+        // Streaming the results removes an answered probe as well
+        let mut probe = task(&creator, vec![app1.clone()], Duration::from_secs(10));
+        probe.msg.probe = true;
+        let id = probe.msg.id;
+        task_manager.post_task(probe).unwrap();
+        task_manager.put_result(&id, result(&id, &app1, WorkStatus::Succeeded)).unwrap();
+        let mut stream = std::pin::pin!(task_manager.clone().stream_results(id, block, |_| true));
+        let mut events = 0;
+        while std::future::poll_fn(|cx| futures_core::Stream::poll_next(stream.as_mut(), cx)).await.is_some() {
+            events += 1;
+        }
+        assert_eq!(events, 1);
+        assert!(matches!(task_manager.get(&id), Err(TaskManagerError::NotFound)), "Streamed probes must be removed");
+        assert_eq!(task_manager.stored_bytes(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_poison_task_stops_being_delivered() {
+        let creator: AppOrProxyId = AppId::new_unchecked("app0.proxy0.broker").into();
+        let app1: AppOrProxyId = AppId::new_unchecked("app1.proxy1.broker").into();
+        let app2: AppOrProxyId = AppId::new_unchecked("app2.proxy2.broker").into();
+        let task_manager = TaskManager::new(None, Some(3));
+        let poison = task(&creator, vec![app1.clone(), app2.clone()], Duration::from_secs(60));
+        let id = poison.msg.id;
+        task_manager.post_task(poison).unwrap();
+
+        let fetch = |recipient: &AppOrProxyId| {
+            let fetched: Vec<_> = task_manager
+                .get_tasks_by(|t| t.to.contains(recipient) && !task_manager.is_poisoned(t, recipient))
+                .map(|t| t.msg.id)
+                .collect();
+            for task_id in &fetched {
+                task_manager.mark_delivered(task_id, recipient);
+            }
+            fetched.len()
+        };
+        // app2 makes progress by claiming the task, app1 never answers
+        for _ in 0..3 {
+            assert_eq!(fetch(&app1), 1);
+            assert_eq!(fetch(&app2), 1);
+            task_manager.put_result(&id, result(&id, &app2, WorkStatus::Claimed)).unwrap();
+        }
+        assert_eq!(fetch(&app1), 0, "Poison task must not be delivered again");
+        assert_eq!(fetch(&app2), 1);
+        assert_eq!(task_manager.status(&id).unwrap().poisoned, vec![app1.clone()]);
+        let dead_letters = task_manager.dead_letters();
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!((dead_letters[0].reason, &dead_letters[0].recipients), (DeadLetterReason::Poisoned, &vec![app1.clone()]));
+
+        // An answer after all lifts the poison mark
+        task_manager.put_result(&id, result(&id, &app1, WorkStatus::TempFailed)).unwrap();
+        assert!(task_manager.dead_letters().is_empty());
+        assert_eq!(fetch(&app1), 1);
+    }
+
+    #[tokio::test]
+    async fn test_expired_task_is_dead_lettered_and_requeued() {
+        let creator: AppOrProxyId = AppId::new_unchecked("app0.proxy0.broker").into();
+        let app1: AppOrProxyId = AppId::new_unchecked("app1.proxy1.broker").into();
+        let app2: AppOrProxyId = AppId::new_unchecked("app2.proxy2.broker").into();
+        let task_manager = TaskManager::new(None, None);
+        let mut undelivered = task(&creator, vec![app1.clone(), app2.clone()], Duration::from_millis(50));
+        undelivered.msg.metadata = json!({"project": "dlq"});
+        let id = undelivered.msg.id;
+        task_manager.post_task(undelivered).unwrap();
+        let delivered = task(&creator, vec![app1.clone()], Duration::from_millis(50));
+        let delivered_id = delivered.msg.id;
+        task_manager.post_task(delivered).unwrap();
+        task_manager.mark_delivered(&id, &app1);
+        task_manager.mark_delivered(&delivered_id, &app1);
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        task_manager.reap_expired();
+        assert!(task_manager.get(&id).is_err());
+        let dead_letter = task_manager.dead_letter(&id).unwrap();
+        assert_eq!(dead_letter.reason, DeadLetterReason::ExpiredUndelivered);
+        assert_eq!(dead_letter.recipients, vec![app2.clone()]);
+        assert_eq!(dead_letter.metadata, Some(json!({"project": "dlq"})));
+        assert_eq!(task_manager.dead_letters().len(), 1, "Tasks which reached all recipients are not dead-lettered");
+        assert_eq!(task_manager.stored_bytes(), 100);
+
+        task_manager.requeue(&id, Duration::from_secs(60)).unwrap();
+        assert!(task_manager.dead_letters().is_empty());
+        assert_eq!(task_manager.get_tasks_by(|t| t.to.contains(&app2)).count(), 1);
+        assert_eq!(task_manager.stored_bytes(), 100);
+        assert!(matches!(task_manager.requeue(&id, Duration::from_secs(60)), Err(TaskManagerError::NotFound)));
+    }
+
+    #[tokio::test]
+    async fn test_removing_dead_letters_releases_storage() {
+        let creator: AppOrProxyId = AppId::new_unchecked("app0.proxy0.broker").into();
+        let app1: AppOrProxyId = AppId::new_unchecked("app1.proxy1.broker").into();
+        let task_manager = TaskManager::new(None, Some(1));
+        let undelivered = task(&creator, vec![app1.clone()], Duration::from_millis(50));
+        let id = undelivered.msg.id;
+        task_manager.post_task(undelivered).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        task_manager.reap_expired();
+        assert_eq!(task_manager.stored_bytes(), 100, "Dead letters count towards the stored bytes");
+        assert!(matches!(task_manager.remove(&id), Err(TaskManagerError::NotFound)), "The task has already been reaped");
+        assert!(task_manager.dead_letters().is_empty());
+        assert_eq!(task_manager.stored_bytes(), 0);
+
+        // Poisoned tasks are dead-lettered while they are still stored, but are capped all the same
+        for _ in 0..=TaskManager::<EncryptedMsgTaskRequest>::MAX_DEAD_LETTERS {
+            let poison = task(&creator, vec![app1.clone()], Duration::from_secs(60));
+            let id = poison.msg.id;
+            task_manager.post_task(poison).unwrap();
+            task_manager.mark_delivered(&id, &app1);
+        }
+        assert_eq!(task_manager.dead_letters().len(), TaskManager::<EncryptedMsgTaskRequest>::MAX_DEAD_LETTERS);
+    }
+
+    #[test]
+    fn test_creator_lists_only_its_own_tasks() {
+        let creator: AppOrProxyId = AppId::new_unchecked("app0.proxy0.broker").into();
+        let other_creator: AppOrProxyId = AppId::new_unchecked("app1.proxy0.broker").into();
+        let app1: AppOrProxyId = AppId::new_unchecked("app1.proxy1.broker").into();
+        let app2: AppOrProxyId = AppId::new_unchecked("app2.proxy2.broker").into();
+        let task_manager = TaskManager::new(None, None);
+        let long_lived = task(&creator, vec![app1.clone(), app2.clone()], Duration::from_secs(120));
+        let short_lived = task(&creator, vec![app1.clone()], Duration::from_secs(60));
+        let (long_lived_id, short_lived_id) = (long_lived.msg.id, short_lived.msg.id);
+        task_manager.post_task(long_lived).unwrap();
+        task_manager.post_task(short_lived).unwrap();
+        task_manager.post_task(task(&other_creator, vec![app1.clone()], Duration::from_secs(60))).unwrap();
+        // Tasks addressed to the creator are not created by it
+        task_manager.post_task(task(&app1, vec![creator.clone()], Duration::from_secs(60))).unwrap();
+        task_manager.put_result(&long_lived_id, result(&long_lived_id, &app1, WorkStatus::Claimed)).unwrap();
+        task_manager.put_result(&long_lived_id, result(&long_lived_id, &app2, WorkStatus::Succeeded)).unwrap();
+
+        let created = task_manager.created_by(&creator);
+        assert_eq!(created.iter().map(|t| t.task_id).collect::<Vec<_>>(), vec![short_lived_id, long_lived_id]);
+        assert_eq!(created[1].to, vec![app1.clone(), app2]);
+        assert_eq!((created[1].results, created[1].status.completed), (2, 1));
+        assert_eq!(created[0].results, 0);
+        assert_eq!(task_manager.created_by(&other_creator).len(), 1);
+        assert!(task_manager.created_by(&AppId::new_unchecked("app3.proxy0.broker").into()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_write_wakes_waiting_long_poll() {
+        let creator: AppOrProxyId = AppId::new_unchecked("app0.proxy0.broker").into();
+        let app1: AppOrProxyId = AppId::new_unchecked("app1.proxy1.broker").into();
+        let task_manager = TaskManager::<EncryptedMsgTaskRequest>::new(None, None);
+        let long_poll = HowLongToBlock { wait_time: Some(Duration::from_secs(30)), wait_count: Some(1) };
+
+        let waiting = tokio::spawn({
+            let task_manager = task_manager.clone();
+            let app1 = app1.clone();
+            async move { task_manager.wait_for_tasks(&long_poll, |t| t.to.contains(&app1)).await.unwrap().count() }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let new_task = task(&creator, vec![app1.clone()], Duration::from_secs(60));
+        let id = new_task.msg.id;
+        task_manager.post_task(new_task).unwrap();
+        let woken = tokio::time::timeout(Duration::from_secs(5), waiting).await.expect("Long poll must be woken by the new task");
+        assert_eq!(woken.unwrap(), 1);
+
+        let waiting = tokio::spawn({
+            let task_manager = task_manager.clone();
+            async move { task_manager.wait_for_results(&id, &long_poll, |_| true).await.is_ok() }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        task_manager.put_result(&id, result(&id, &app1, WorkStatus::Succeeded)).unwrap();
+        let woken = tokio::time::timeout(Duration::from_secs(5), waiting).await.expect("Long poll must be woken by the new result");
+        assert!(woken.unwrap());
+    }
+}
@@ -2,51 +2,95 @@ use std::collections::HashMap;
 
 use axum::{
     async_trait,
-    extract::{FromRequest, FromRequestParts},
-    http::{header::{self, HeaderName}, request::Parts, Request, StatusCode},
+    extract::FromRequestParts,
+    http::{header::{self, HeaderName}, request::Parts, HeaderMap, StatusCode},
 };
 use beam_lib::{AppId, AppOrProxyId};
 use shared::{
-    config, config_proxy, middleware::ProxyLogger,
+    config, config_proxy::ApiKey, middleware::ProxyLogger,
 };
+use subtle::ConstantTimeEq;
 
-use tracing::debug;
+use tracing::{debug, warn};
+
+const SCHEME: &str = "ApiKey";
+type AuthRejection = (StatusCode, [(HeaderName, &'static str); 1]);
+
+const UNAUTH_ERR: AuthRejection = (
+    StatusCode::UNAUTHORIZED,
+    [(header::WWW_AUTHENTICATE, SCHEME)],
+);
 
 pub(crate) struct AuthenticatedApp(pub(crate) AppId);
 
 #[async_trait]
 impl<S: Send + Sync> FromRequestParts<S> for AuthenticatedApp {
-    type Rejection = (StatusCode, [(HeaderName, &'static str); 1]);
+    type Rejection = AuthRejection;
 
     async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
-        const SCHEME: &str = "ApiKey";
-        const UNAUTH_ERR: (StatusCode, [(HeaderName, &str); 1]) = (
-            StatusCode::UNAUTHORIZED,
-            [(header::WWW_AUTHENTICATE, SCHEME)],
-        );
-        if let Some(auth) = parts.headers.get(header::AUTHORIZATION) {
-            let auth = auth.to_str().map_err(|_| UNAUTH_ERR)?;
-            let mut auth = auth.split(' ');
-            if auth.next().unwrap_or("") != SCHEME {
-                return Err(UNAUTH_ERR);
-            }
-            let client_id = auth.next().unwrap_or("");
-            let client_id = AppId::new(client_id).map_err(|_| UNAUTH_ERR)?;
-            let api_key_actual = config::CONFIG_PROXY
-                .api_keys
-                .get(&client_id)
-                .ok_or(UNAUTH_ERR)?;
-            let api_key_claimed = auth.next().ok_or(UNAUTH_ERR)?;
-            if api_key_claimed != api_key_actual {
-                return Err(UNAUTH_ERR);
-            }
-            debug!("Request authenticated (ClientID {})", client_id);
-            _ = parts.extensions.remove::<ProxyLogger>()
-                .expect("Added by middleware")
-                .send(AppOrProxyId::App(client_id.clone()));
-            Ok(Self(client_id))
-        } else {
-            Err(UNAUTH_ERR)
-        }
+        let client_id = authenticate(&parts.headers, &config::CONFIG_PROXY.api_keys)?;
+        debug!("Request authenticated (ClientID {})", client_id);
+        _ = parts.extensions.remove::<ProxyLogger>()
+            .expect("Added by middleware")
+            .send(AppOrProxyId::App(client_id.clone()));
+        Ok(Self(client_id))
+    }
+}
+
+/// Returns the app identity claimed in the `Authorization: ApiKey <app id> <key>` header
+/// if it is hosted by this proxy and the key is the one configured for it.
+fn authenticate(headers: &HeaderMap, api_keys: &HashMap<AppId, ApiKey>) -> Result<AppId, AuthRejection> {
+    let auth = headers.get(header::AUTHORIZATION).ok_or(UNAUTH_ERR)?;
+    let auth = auth.to_str().map_err(|_| UNAUTH_ERR)?;
+    let mut auth = auth.split(' ');
+    if auth.next().unwrap_or("") != SCHEME {
+        return Err(UNAUTH_ERR);
+    }
+    let client_id = auth.next().unwrap_or("");
+    let client_id = AppId::new(client_id).map_err(|_| UNAUTH_ERR)?;
+    let Some(api_key_actual) = api_keys.get(&client_id) else {
+        warn!("Rejecting request claiming to be {client_id} which is not hosted by this proxy");
+        return Err(UNAUTH_ERR);
+    };
+    let api_key_claimed = auth.next().ok_or(UNAUTH_ERR)?;
+    // Compare in constant time so that other local processes cannot guess the key byte by byte
+    if !bool::from(api_key_claimed.as_bytes().ct_eq(api_key_actual.as_bytes())) {
+        warn!("Rejecting request claiming to be {client_id} with a wrong API key");
+        return Err(UNAUTH_ERR);
+    }
+    Ok(client_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    fn authorization(value: &str) -> HeaderMap {
+        HeaderMap::from_iter([(header::AUTHORIZATION, HeaderValue::from_str(value).unwrap())])
+    }
+
+    #[test]
+    fn test_api_keys_authenticate_hosted_apps() {
+        beam_lib::set_broker_id("broker".to_string());
+        let app1 = AppId::new_unchecked("app1.proxy1.broker");
+        let app2 = AppId::new_unchecked("app2.proxy1.broker");
+        let api_keys = HashMap::from([(app1.clone(), "App1Secret".to_string()), (app2.clone(), "App2Secret".to_string())]);
+
+        let valid = authorization("ApiKey app1.proxy1.broker App1Secret");
+        assert_eq!(authenticate(&valid, &api_keys).unwrap(), app1);
+
+        // A process knowing app2's key must not act as app1
+        let wrong_key = authorization("ApiKey app1.proxy1.broker App2Secret");
+        assert_eq!(authenticate(&wrong_key, &api_keys).unwrap_err().0, StatusCode::UNAUTHORIZED);
+        let missing_key = authorization("ApiKey app1.proxy1.broker");
+        assert_eq!(authenticate(&missing_key, &api_keys).unwrap_err().0, StatusCode::UNAUTHORIZED);
+
+        let unknown = authorization("ApiKey app3.proxy1.broker App1Secret");
+        assert_eq!(authenticate(&unknown, &api_keys).unwrap_err().0, StatusCode::UNAUTHORIZED);
+        let other_scheme = authorization("Bearer App1Secret");
+        assert_eq!(authenticate(&other_scheme, &api_keys).unwrap_err().0, StatusCode::UNAUTHORIZED);
+        assert!(authenticate(&HeaderMap::new(), &api_keys).is_err());
     }
 }
@@ -0,0 +1,79 @@
+//! Compression between proxy and broker (`WIRE_COMPRESSION`) is independent of what the local apps support:
+//! Requests to the broker are compressed by [`crate::serve_tasks::sign_request`] and responses are decompressed by the HTTP client.
+//! Towards the apps, bodies are only compressed if they ask for it.
+
+use std::io::Write;
+
+use axum::Router;
+use flate2::{write::GzEncoder, Compression};
+use tower_http::{compression::CompressionLayer, decompression::RequestDecompressionLayer};
+
+pub(crate) fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data).expect("Writing to a Vec never fails");
+    encoder.finish().expect("Writing to a Vec never fails")
+}
+
+/// Accepts compressed request bodies from apps, up to `max_decompressed_size` bytes once decompressed, and compresses responses according to their `Accept-Encoding`
+pub(crate) fn negotiate_with_apps(router: Router, max_decompressed_size: usize) -> Router {
+    router
+        .layer(axum::middleware::from_fn_with_state(max_decompressed_size, shared::middleware::limit_decompressed))
+        .layer(RequestDecompressionLayer::new())
+        .layer(axum::middleware::from_fn(shared::middleware::mark_compressed))
+        .layer(CompressionLayer::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use axum::{http::{header, StatusCode}, routing::post};
+    use flate2::read::GzDecoder;
+    use shared::http_client::{self, ConnectionSettings};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// Serves an app facing echo route which accepts 1000 decompressed bytes
+    async fn serve_echo() -> String {
+        let router = Router::new().route("/echo", post(|body: String| async move { body }));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/echo", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, negotiate_with_apps(router, 1000)).await.unwrap() });
+        url
+    }
+
+    #[tokio::test]
+    async fn test_compression_is_negotiated_with_apps() {
+        let url = serve_echo().await;
+        let app = http_client::build(&vec![], None, &ConnectionSettings::default(), None, &[]).unwrap();
+        let message = "A task body that compresses well. ".repeat(20);
+
+        // Apps which know nothing about compression
+        let res = app.post(&url).body(message.clone()).send().await.unwrap();
+        assert_eq!(res.headers().get(header::CONTENT_ENCODING), None);
+        assert_eq!(res.text().await.unwrap(), message);
+
+        // Apps which can handle compressed bodies get them
+        let res = app
+            .post(&url)
+            .header(header::CONTENT_ENCODING, "gzip")
+            .header(header::ACCEPT_ENCODING, "gzip")
+            .body(gzip(message.as_bytes()))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.headers()[header::CONTENT_ENCODING], "gzip");
+        let mut body = String::new();
+        GzDecoder::new(&res.bytes().await.unwrap()[..]).read_to_string(&mut body).unwrap();
+        assert_eq!(body, message);
+
+        // Compressed bodies may not expand beyond the limit, unlike uncompressed ones which are not limited here
+        let bomb = gzip(&[b'A'; 1001]);
+        assert!(bomb.len() < 100);
+        let res = app.post(&url).header(header::CONTENT_ENCODING, "gzip").body(bomb).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let res = app.post(&url).body("A".repeat(1001)).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+}
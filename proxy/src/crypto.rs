@@ -28,7 +28,6 @@ impl GetCertsFromBroker {
         let (parts, body) = Request::builder()
             .method(Method::GET)
             .uri(&uri)
-            .header(header::USER_AGENT, env!("SAMPLY_USER_AGENT"))
             .body(body)
             .expect("To build request successfully")
             .into_parts();
@@ -8,7 +8,7 @@ use beam_lib::AppOrProxyId;
 use shared::{reqwest, EncryptedMessage, MsgEmpty, PlainMessage};
 use shared::crypto::CryptoPublicPortion;
 use shared::errors::SamplyBeamError;
-use shared::http_client::{self, SamplyHttpClient};
+use shared::http_client::{self, ConnectionSettings, Http2, SamplyHttpClient};
 use shared::{config, config_proxy::Config};
 use tracing::{debug, error, info, warn};
 
@@ -16,6 +16,7 @@ use crate::serve_tasks::sign_request;
 
 mod auth;
 mod banner;
+mod compression;
 mod crypto;
 mod serve;
 mod serve_health;
@@ -34,8 +35,20 @@ pub async fn main() -> anyhow::Result<()> {
     let config = config::CONFIG_PROXY.clone();
     let client = http_client::build(
         &config::CONFIG_SHARED.tls_ca_certificates,
-        Some(Duration::from_secs(PROXY_TIMEOUT)),
+        config::CONFIG_SHARED.tls_client_identity.as_ref(),
+        &ConnectionSettings {
+            gzip: config.wire_compression,
+            tls_session_resumption: config.tls_session_resumption,
+            // Sockets are upgraded HTTP/1.1 connections
+            http2: Http2::Off,
+            ..config::CONFIG_SHARED
+                .http_connection
+                .clone()
+                .with_default_connect_timeout(Duration::from_secs(PROXY_TIMEOUT))
+                .with_user_agent(env!("SAMPLY_USER_AGENT"))
+        },
         Some(Duration::from_secs(20)),
+        &config.tls_name_overrides,
     )?;
 
     if let Err(err) = retry_notify(
@@ -83,11 +96,11 @@ pub async fn main() -> anyhow::Result<()> {
 
 async fn init_crypto(config: Config, client: SamplyHttpClient) -> Result<(), SamplyBeamError> {
     let private_crypto_proxy = shared::config_shared::load_private_crypto_for_proxy()?;
-    shared::crypto::init_cert_getter(crypto::build_cert_getter(
+    shared::crypto::init_cert_getter(Box::new(crypto::build_cert_getter(
         config.clone(),
         client.clone(),
         private_crypto_proxy.clone(),
-    )?);
+    )?));
     shared::crypto::init_ca_chain().await?;
 
     let _public_info: Vec<_> =
@@ -124,11 +137,7 @@ async fn get_broker_health(
             .with_max_elapsed_time(Some(Duration::from_secs(30)))
             .build(),
         || async {
-            Ok(client
-                .get(uri.clone())
-                .header(header::USER_AGENT, HeaderValue::from_static(env!("SAMPLY_USER_AGENT")))
-                .send()
-                .await?)
+            Ok(client.get(uri.clone()).send().await?)
         },
         |err, b: Duration| {
             warn!(
@@ -157,7 +166,6 @@ fn spawn_controller_polling(client: SamplyHttpClient, config: Config) {
                 from: AppOrProxyId::Proxy(config.proxy_id.clone()),
             });
             let (parts, body) = axum::http::Request::get(format!("{}v1/control", config.broker_uri))
-                .header(header::USER_AGENT, env!("SAMPLY_USER_AGENT"))
                 .body(body)
                 .expect("To build request successfully")
                 .into_parts();
@@ -3,11 +3,12 @@ use std::{fmt::Write, net::SocketAddr};
 use axum::extract::DefaultBodyLimit;
 use shared::{
     config, config_proxy, config_shared, errors::SamplyBeamError, http_client::SamplyHttpClient,
+    middleware::HostPolicy,
 };
 use tokio::net::TcpListener;
 use tracing::{debug, error, info, warn};
 
-use crate::{banner, serve_health, serve_tasks};
+use crate::{banner, compression, serve_health, serve_tasks};
 
 pub(crate) async fn serve(
     config: config_proxy::Config,
@@ -22,7 +23,11 @@ pub(crate) async fn serve(
     #[cfg(feature = "sockets")]
     let app = app.merge(crate::serve_sockets::router(client));
     // Middleware needs to be set last
-    let app = app
+    let app = compression::negotiate_with_apps(app, shared::middleware::MAX_DECOMPRESSED_SIZE)
+        .layer(axum::middleware::from_fn_with_state(
+            HostPolicy::new(config.missing_host, config.bind_addr),
+            shared::middleware::check_host,
+        ))
         .layer(axum::middleware::from_fn(shared::middleware::log))
         .layer(axum::middleware::map_response(banner::set_server_header))
         .layer(DefaultBodyLimit::disable());
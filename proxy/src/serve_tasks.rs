@@ -1,11 +1,13 @@
 use std::{
+    collections::BTreeMap,
     convert::Infallible,
     str::FromStr,
+    sync::Mutex,
     time::{Duration, SystemTime},
 };
 
 use axum::{
-    body::Bytes, extract::{FromRef, Request, State}, http::{header, request::Parts, HeaderMap, HeaderValue, StatusCode, Uri}, response::{sse::Event, IntoResponse, Response, Sse}, routing::{any, get, put}, Json, RequestExt, Router
+    body::Bytes, extract::{FromRef, Path, Query, Request, State}, http::{header, request::Parts, HeaderMap, HeaderValue, Method, StatusCode, Uri, Version}, response::{sse::Event, IntoResponse, Response, Sse}, routing::{any, get, post, put}, Json, RequestExt, Router
 };
 use futures::{
     stream::{StreamExt, TryStreamExt},
@@ -15,14 +17,15 @@ use httpdate::fmt_http_date;
 use rsa::{pkcs8::DecodePublicKey, RsaPublicKey};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::Value;
-use beam_lib::{AppId, AppOrProxyId, ProxyId};
+use beam_lib::{AppId, AppOrProxyId, FailureStrategy, ProxyId, WorkStatus};
 use shared::{
-    config::{self, CONFIG_PROXY}, config_proxy, config_shared::ConfigCrypto, crypto::{self, CryptoPublicPortion}, crypto_jwt, errors::SamplyBeamError, http_client::SamplyHttpClient, reqwest, sse_event::SseEventType, DecryptableMsg, EncryptableMsg, EncryptedMessage, EncryptedMsgTaskRequest, EncryptedMsgTaskResult, MessageType, Msg, MsgEmpty, MsgId, MsgSigned, MsgTaskRequest, MsgTaskResult, PlainMessage
+    config::{self, CONFIG_PROXY}, config_proxy, config_shared::ConfigCrypto, crypto::{self, CryptoPublicPortion}, crypto_jwt, errors::SamplyBeamError, http_client::SamplyHttpClient, reqwest, sse_event::SseEventType, DecryptableMsg, EncryptableMsg, EncryptedMessage, EncryptedMsgTaskRequest, EncryptedMsgTaskResult, HowLongToBlock, MessageType, Msg, MsgEmpty, MsgId, MsgSigned, MsgTaskRequest, MsgTaskResult, PlainMessage, SymmetricKey
 };
 use tokio::io::BufReader;
 use tracing::{debug, error, info, trace, warn};
+use zeroize::{Zeroize, Zeroizing};
 
-use crate::{auth::AuthenticatedApp, PROXY_TIMEOUT};
+use crate::{auth::AuthenticatedApp, compression, PROXY_TIMEOUT};
 
 #[derive(Clone, FromRef)]
 pub(crate) struct TasksState {
@@ -41,6 +44,9 @@ pub(crate) fn router(client: &SamplyHttpClient) -> Router {
         .route("/v1/tasks", get(handler_task).post(handler_task))
         .route("/v1/tasks/:task_id/results", get(handler_task))
         .route("/v1/tasks/:task_id/results/:app_id", put(handler_task))
+        .route("/v1/tasks/:task_id/status", get(handler_task_status))
+        .route("/v1/tasks/:task_id/recipients", post(handler_add_recipients))
+        .route("/v1/probe/:app_id", post(handler_probe))
         .with_state(state)
 }
 
@@ -77,6 +83,11 @@ pub(crate) async fn forward_request(
         Uri::try_from(config.broker_uri.to_string() + path_query.trim_start_matches('/'))
             .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid path queried.").into_response())?;
     *req.uri_mut() = target_uri;
+    // Legacy apps may speak HTTP/1.0 to us but the request to the broker is made like those of all other apps
+    if req.version() < Version::HTTP_11 {
+        debug!("Forwarding {:?} request {} as HTTP/1.1", req.version(), req.uri());
+        *req.version_mut() = Version::HTTP_11;
+    }
 
     req.headers_mut().append(
         header::VIA,
@@ -125,12 +136,238 @@ pub(crate) async fn handler_task(
     }
 }
 
+/// The task status is not a signed message so it is passed through as is.
+/// Once the task is completed, its key is no longer kept for adding recipients.
+async fn handler_task_status(
+    State(client): State<SamplyHttpClient>,
+    State(config): State<config_proxy::Config>,
+    AuthenticatedApp(sender): AuthenticatedApp,
+    Path(task_id): Path<MsgId>,
+    req: Request,
+) -> Result<Response, Response> {
+    #[derive(Deserialize)]
+    struct Completion {
+        fully_completed: bool,
+    }
+    let resp = forward_request(req, &config, &sender, &client).await?;
+    let (parts, body) = axum::http::Response::from(resp).into_parts();
+    let body = axum::body::to_bytes(axum::body::Body::new(body), usize::MAX).await.map_err(|e| {
+        warn!("Unable to read task status from broker: {e}");
+        ERR_UPSTREAM.into_response()
+    })?;
+    if parts.status.is_success() && serde_json::from_slice::<Completion>(&body).is_ok_and(|status| status.fully_completed) {
+        CREATED_TASKS.lock().unwrap().remove(&task_id);
+    }
+    Ok(Response::from_parts(parts, body.into()))
+}
+
+/// A task created via this proxy whose key we keep so that recipients can be added later on
+struct CreatedTask {
+    creator: AppId,
+    key: SymmetricKey,
+    expire: SystemTime,
+}
+
+impl Drop for CreatedTask {
+    fn drop(&mut self) {
+        self.key.zeroize();
+    }
+}
+
+/// Number of tasks whose keys are kept at most. Beyond that, the keys of the tasks expiring first are dropped.
+const MAX_CREATED_TASKS: usize = 10_000;
+
+/// The keys of the tasks created via this proxy until they expire, are completed or make room for newer ones
+struct CreatedTasks(BTreeMap<MsgId, CreatedTask>);
+
+impl CreatedTasks {
+    fn insert(&mut self, id: MsgId, task: CreatedTask, now: SystemTime) {
+        self.0.retain(|_, task| task.expire > now);
+        while self.0.len() >= MAX_CREATED_TASKS {
+            let Some(first_to_expire) = self.0.iter().min_by_key(|(_, task)| task.expire).map(|(id, _)| *id) else {
+                break;
+            };
+            debug!("Dropping the key of task {first_to_expire} to make room for new tasks");
+            self.0.remove(&first_to_expire);
+        }
+        self.0.insert(id, task);
+    }
+
+    /// A copy of the task's key if it is known, has not expired and was created by `creator`
+    fn key(&self, id: &MsgId, creator: &AppId, now: SystemTime) -> Option<SymmetricKey> {
+        self.0.get(id).filter(|task| task.creator == *creator && task.expire > now).map(|task| task.key)
+    }
+
+    fn remove(&mut self, id: &MsgId) {
+        self.0.remove(id);
+    }
+}
+
+static CREATED_TASKS: Mutex<CreatedTasks> = Mutex::new(CreatedTasks(BTreeMap::new()));
+
+fn remember_created_task(task: &EncryptedMsgTaskRequest, creator: &AppId, key: SymmetricKey) {
+    let created = CreatedTask { creator: creator.clone(), key, expire: task.expire };
+    CREATED_TASKS.lock().unwrap().insert(task.id, created, SystemTime::now());
+}
+
+/// POST /v1/tasks/:task_id/recipients
+/// Adds recipients to a task created via this proxy by wrapping the task's key for them,
+/// so that they can decrypt it without the payload being sent again.
+async fn handler_add_recipients(
+    State(client): State<SamplyHttpClient>,
+    State(config): State<config_proxy::Config>,
+    AuthenticatedApp(sender): AuthenticatedApp,
+    Path(task_id): Path<MsgId>,
+    Json(new_recipients): Json<Vec<AppOrProxyId>>,
+) -> Result<Response, Response> {
+    let Some(key) = CREATED_TASKS.lock().unwrap().key(&task_id, &sender, SystemTime::now()).map(Zeroizing::new) else {
+        return Err((StatusCode::CONFLICT, "The key of this task is not available; recipients can only be added by the task's creator via the proxy it was created with.").into_response());
+    };
+    let broker_request = |method: Method, path: String, body: EncryptedMessage| {
+        let (parts, body) = Request::builder()
+            .method(method)
+            .uri(format!("{}{}", config.broker_uri, path.trim_start_matches('/')))
+            .body(body)
+            .expect("To build request successfully")
+            .into_parts();
+        sign_request(body, parts, &config, None)
+    };
+    let send = |req: reqwest::Request| client.execute(req).map_err(|e| {
+        warn!("Request to broker failed: {e}");
+        (StatusCode::BAD_GATEWAY, "Upstream error; see server logs.").into_response()
+    });
+
+    // Fetch the task as it is stored at the broker
+    let req = broker_request(Method::GET, format!("/v1/tasks/{task_id}"), EncryptedMessage::MsgEmpty(MsgEmpty { from: sender.clone().into() }))
+        .await
+        .map_err(IntoResponse::into_response)?;
+    let resp = send(req).await?;
+    if !resp.status().is_success() {
+        return Ok(axum::http::Response::from(resp).map(axum::body::Body::new));
+    }
+    #[derive(Deserialize)]
+    struct MsgSignedHelper {
+        jwt: String,
+    }
+    let signed: MsgSignedHelper = resp.bytes().await.map_err(|e| e.to_string()).and_then(|body| serde_json::from_slice(&body).map_err(|e| e.to_string())).map_err(|e| {
+        warn!("Unable to parse task from broker: {e}");
+        ERR_UPSTREAM.into_response()
+    })?;
+    let mut task = to_server_error(MsgSigned::<EncryptedMsgTaskRequest>::verify(&signed.jwt).await)?.msg;
+    if task.from != sender {
+        return Err(ERR_VALIDATION.into_response());
+    }
+
+    let new_recipients: Vec<_> = new_recipients.into_iter().filter(|r| !task.to.contains(r)).collect();
+    if new_recipients.is_empty() {
+        return Ok(StatusCode::NO_CONTENT.into_response());
+    }
+    let keys = crypto::get_proxy_public_keys(&new_recipients).await.map_err(|e| match e {
+        SamplyBeamError::InvalidReceivers(proxies) => (StatusCode::FAILED_DEPENDENCY, Json(proxies)).into_response(),
+        e => {
+            warn!("Unable to get keys of new recipients: {e}");
+            ERR_INTERNALCRYPTO.into_response()
+        }
+    })?;
+    let wrapped_keys = shared::wrap_symmetric_key(&key, &keys).map_err(|e| {
+        warn!("Unable to wrap key for new recipients: {e}");
+        ERR_INTERNALCRYPTO.into_response()
+    })?;
+    task.to.extend(new_recipients);
+    task.body.encryption_keys.extend(wrapped_keys);
+
+    let req = broker_request(Method::POST, format!("/v1/tasks/{task_id}/recipients"), EncryptedMessage::MsgTaskRequest(task))
+        .await
+        .map_err(IntoResponse::into_response)?;
+    let resp = send(req).await?;
+    Ok(axum::http::Response::from(resp).map(axum::body::Body::new))
+}
+
+/// How long to wait for the answer to a probe unless the app asks for a different `wait_time`
+const DEFAULT_PROBE_WAIT: Duration = Duration::from_secs(30);
+
+#[derive(Serialize)]
+struct ProbeReport {
+    to: AppOrProxyId,
+    task_id: MsgId,
+    /// Milliseconds from sending the probe until the decrypted answer was available
+    #[serde(skip_serializing_if = "Option::is_none")]
+    round_trip_ms: Option<u128>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<WorkStatus>,
+}
+
+/// POST /v1/probe/:app_id
+/// Sends a probe task to the given app and waits for its answer, which exercises the whole path
+/// including encryption, routing via the broker and decryption on both ends.
+async fn handler_probe(
+    State(client): State<SamplyHttpClient>,
+    State(config): State<config_proxy::Config>,
+    AuthenticatedApp(sender): AuthenticatedApp,
+    Path(to): Path<AppOrProxyId>,
+    block: HowLongToBlock,
+) -> Result<Response, Response> {
+    let wait_time = block.wait_time.unwrap_or(DEFAULT_PROBE_WAIT);
+    let mut probe = MsgTaskRequest::new(sender.clone().into(), vec![to.clone()], "probe".into(), FailureStrategy::Discard, Value::Null);
+    probe.probe = true;
+    probe.expire = SystemTime::now() + wait_time;
+    let task_id = probe.id;
+    let req = Request::builder()
+        .method(Method::POST)
+        .uri(format!("/v1/tasks?wait_count=1&wait_time={}", wait_time.as_millis()))
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(axum::body::Body::from(serde_json::to_vec(&probe).expect("Probe should serialize")))
+        .expect("To build request successfully");
+
+    let started = std::time::Instant::now();
+    let (parts, body) = handler_tasks_nostream(client, config, sender, req).await?.into_parts();
+    let body = axum::body::to_bytes(body, usize::MAX).await.map_err(|e| {
+        warn!("Unable to read probe results: {e}");
+        ERR_UPSTREAM.into_response()
+    })?;
+    if !parts.status.is_success() {
+        return Ok(Response::from_parts(parts, body.into()));
+    }
+    let results: Vec<MsgTaskResult> = serde_json::from_slice(&body).map_err(|e| {
+        warn!("Unable to parse probe results: {e}");
+        ERR_UPSTREAM.into_response()
+    })?;
+    let answer = results.into_iter().find(|result| result.from == to);
+    let report = ProbeReport {
+        round_trip_ms: answer.is_some().then(|| started.elapsed().as_millis()),
+        status: answer.map(|result| result.status),
+        to,
+        task_id,
+    };
+    let code = if report.status.is_some() { StatusCode::OK } else { StatusCode::GATEWAY_TIMEOUT };
+    Ok((code, Json(report)).into_response())
+}
+
+/// Query parameters asking the broker for something it compiles itself instead of messages signed by their senders
+#[derive(Deserialize)]
+struct BrokerCompiledQuery {
+    #[serde(default)]
+    metadata_only: bool,
+    role: Option<String>,
+}
+
+impl BrokerCompiledQuery {
+    fn is_compiled_by_broker(&self) -> bool {
+        self.metadata_only || self.role.as_deref() == Some("creator")
+    }
+}
+
 async fn handler_tasks_nostream(
     client: SamplyHttpClient,
     config: config_proxy::Config,
     sender: AppId,
     req: Request,
 ) -> Result<Response, Response> {
+    // Answers compiled by the broker are not signed by any sender so they are passed through as is
+    if Query::<BrokerCompiledQuery>::try_from_uri(req.uri()).is_ok_and(|Query(query)| query.is_compiled_by_broker()) {
+        let resp = forward_request(req, &config, &sender, &client).await?;
+        return Ok(axum::http::Response::from(resp).map(axum::body::Body::new));
+    }
     // Validate Query, forward to server, get response.
 
     let resp = forward_request(req, &config, &sender, &client).await?;
@@ -353,7 +590,14 @@ pub async fn sign_request(
             error!("Crypto failed: {}", e);
             ERR_INTERNALCRYPTO
         })?;
-    let body: reqwest::Body = token_without_extended_signature.into();
+    // Whatever the app asked for, the encoding between proxy and broker is negotiated by the client
+    headers_mut.remove(header::ACCEPT_ENCODING);
+    let body: reqwest::Body = if config.wire_compression {
+        headers_mut.insert(header::CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+        compression::gzip(token_without_extended_signature.as_bytes()).into()
+    } else {
+        token_without_extended_signature.into()
+    };
     let mut auth_header = String::from("SamplyJWT ");
     auth_header.push_str(&token_with_extended_signature);
     headers_mut.insert(header::HOST, config.broker_host_header.clone());
@@ -446,7 +690,7 @@ async fn encrypt_request(
     if msg.get_from() != sender {
         return Err(ERR_FAKED_FROM.into_response());
     }
-    let body = encrypt_msg(msg).await.map_err(|e| {
+    let (body, key) = encrypt_msg(msg).await.map_err(|e| {
         match e {
             SamplyBeamError::InvalidReceivers(proxies) => {
                 (StatusCode::FAILED_DEPENDENCY, Json(proxies)).into_response()
@@ -457,10 +701,47 @@ async fn encrypt_request(
             }
         }
     })?;
+    if let EncryptedMessage::MsgTaskRequest(task) = &body {
+        remember_created_task(task, sender, key);
+    }
     Ok((body, parts))
 }
 
-async fn encrypt_msg<M: EncryptableMsg>(msg: M) -> Result<M::Output, SamplyBeamError> {
+async fn encrypt_msg<M: EncryptableMsg>(msg: M) -> Result<(M::Output, SymmetricKey), SamplyBeamError> {
     let receivers_keys = crypto::get_proxy_public_keys(msg.get_to()).await?;
-    msg.encrypt(&receivers_keys)
+    msg.encrypt_keeping_key(&receivers_keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_created_tasks_are_evicted() {
+        beam_lib::set_broker_id("broker".to_string());
+        let creator = AppId::new_unchecked("app1.proxy1.broker");
+        let other = AppId::new_unchecked("app2.proxy1.broker");
+        let now = SystemTime::now();
+        let task = |expire| CreatedTask { creator: creator.clone(), key: SymmetricKey::default(), expire };
+        let mut created = CreatedTasks(BTreeMap::new());
+
+        let (expired, live) = (MsgId::new(), MsgId::new());
+        created.insert(expired, task(now + Duration::from_secs(1)), now);
+        created.insert(live, task(now + Duration::from_secs(60)), now);
+        assert!(created.key(&live, &creator, now).is_some());
+        assert!(created.key(&live, &other, now).is_none(), "Only the creator may use the key");
+        assert!(created.key(&expired, &creator, now + Duration::from_secs(2)).is_none());
+
+        let later = now + Duration::from_secs(2);
+        for i in 0..MAX_CREATED_TASKS as u64 {
+            created.insert(MsgId::new(), task(later + Duration::from_secs(120 + i)), later);
+        }
+        assert_eq!(created.0.len(), MAX_CREATED_TASKS);
+        assert!(!created.0.contains_key(&expired), "Expired tasks are dropped first");
+        assert!(!created.0.contains_key(&live), "Then those expiring first");
+
+        let id = *created.0.keys().next().unwrap();
+        created.remove(&id);
+        assert!(created.key(&id, &creator, later).is_none());
+    }
 }
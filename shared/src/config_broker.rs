@@ -1,10 +1,14 @@
-use std::{fs::read_to_string, net::SocketAddr, path::PathBuf};
+use std::{collections::HashMap, fs::read_to_string, net::{IpAddr, SocketAddr}, path::PathBuf, time::Duration};
 
 use crate::{
-    errors::SamplyBeamError,
+    errors::SamplyBeamError, middleware::MissingHost,
 };
-use axum::http::Uri;
+#[cfg(feature = "vault")]
+use crate::http_client::Http2;
+use axum::http::{HeaderValue, Uri};
+use beam_lib::ProxyId;
 use clap::Parser;
+use serde::Serialize;
 use reqwest::Url;
 use std::str::FromStr;
 use tracing::info;
@@ -25,22 +29,103 @@ struct CliArgs {
     #[clap(long, env, value_parser)]
     pub tls_ca_certificates_dir: Option<PathBuf>,
 
+    /// Outgoing HTTP proxy: PEM file with CA certificates to trust for TLS connections in addition to TLS_CA_CERTIFICATES_DIR (e.g. /etc/ssl/certs/ca-bundle.pem)
+    #[clap(long, env, value_parser)]
+    tls_ca_certificates_file: Option<PathBuf>,
+
+    /// Outgoing HTTP proxy: Refuse to start if a CA certificate from TLS_CA_CERTIFICATES_DIR or TLS_CA_CERTIFICATES_FILE is expired or not yet valid instead of warning about it
+    #[clap(long, env)]
+    tls_ca_certificates_strict: bool,
+
+    /// Outgoing HTTP: PEM file with the client certificate (optionally followed by its chain) presented to servers requiring mutual TLS, e.g. the broker, Vault or a proxy's upstreams. Requires TLS_CLIENT_KEY_FILE
+    #[clap(long, env, value_parser)]
+    tls_client_cert_file: Option<PathBuf>,
+
+    /// Outgoing HTTP: PEM file with the private key of TLS_CLIENT_CERT_FILE
+    #[clap(long, env, value_parser)]
+    tls_client_key_file: Option<PathBuf>,
+
+    /// Outgoing HTTP: PKCS#12 file with the client certificate, its chain and private key, as an alternative to TLS_CLIENT_CERT_FILE and TLS_CLIENT_KEY_FILE
+    #[clap(long, env, value_parser)]
+    tls_client_pkcs12_file: Option<PathBuf>,
+
+    /// Outgoing HTTP: File containing the password of TLS_CLIENT_PKCS12_FILE (default: no password)
+    #[clap(long, env, value_parser)]
+    tls_client_pkcs12_password_file: Option<PathBuf>,
+
+    /// Outgoing HTTP: Seconds to wait for a connection to be established, including the TLS handshake (default: 120 for the proxy's connection to the broker, 30 for the broker's connection to Vault)
+    #[clap(long, env, value_parser = clap::value_parser!(u64).range(1..))]
+    http_connect_timeout: Option<u64>,
+
+    /// Outgoing HTTP: Seconds after which idle connections are closed
+    #[clap(long, env, value_parser, default_value_t = 90)]
+    http_pool_idle_timeout: u64,
+
+    /// Outgoing HTTP: Maximum number of idle connections kept per host (default: unlimited)
+    #[clap(long, env, value_parser)]
+    http_pool_max_idle_per_host: Option<usize>,
+
+    /// Outgoing HTTP: Oldest TLS version accepted from servers. TLS 1.3 makes use of rustls instead of OpenSSL
+    #[clap(long, env, value_enum, default_value = "1.2")]
+    tls_min_version: crate::http_client::TlsVersion,
+
+    /// Outgoing HTTP: The only TLS cipher suites offered to servers in rustls' naming, e.g. TLS13_AES_256_GCM_SHA384,TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384 (comma-separated, default: all supported by rustls). Makes use of rustls instead of OpenSSL
+    #[clap(long, env, value_parser, value_delimiter = ',')]
+    tls_cipher_suites: Vec<String>,
+
+    /// Outgoing HTTP: Set to "true" to accept any TLS certificate, e.g. of a self-signed Vault in a test setup. NEVER use this in production! Only "true" or "false" are accepted
+    // Unlike other flags, a value which is not exactly "true" does not enable this
+    #[clap(long, env, action = clap::ArgAction::Set, value_parser = clap::builder::BoolValueParser::new(), default_value_t = false)]
+    danger_accept_invalid_certs: bool,
+
+    /// Outgoing HTTP: Appended to the User-Agent of all outgoing requests, e.g. a site identifier for a web application firewall or Vault's audit log (default: none)
+    #[clap(long, env, value_parser)]
+    http_user_agent_suffix: Option<HeaderValue>,
+
     /// The broker's base URL, e.g. https://beam.samply.de
     #[clap(long, env, value_parser)]
     broker_url: Uri,
 
-    /// samply.pki: URL to HTTPS endpoint
-    #[clap(long, env, value_parser)]
-    pki_address: Url,
+    /// samply.pki: URL to HTTPS endpoint (required unless PKI_CERT_DIR is set). With Vault in HA mode, the addresses of all nodes, which are failed over to in turn (comma-separated)
+    #[cfg(feature = "vault")]
+    #[clap(long, env, value_parser, value_delimiter = ',')]
+    pki_address: Vec<Url>,
 
     /// samply.pki: Authentication realm
+    #[cfg(feature = "vault")]
     #[clap(long, env, value_parser, default_value = "samply_pki")]
     pki_realm: String,
 
-    /// samply.pki: File containing the authentication token
+    /// samply.pki: How to authenticate to Vault
+    #[cfg(feature = "vault")]
+    #[clap(long, env, value_enum, default_value_t = VaultAuthMethod::Token)]
+    pki_auth_method: VaultAuthMethod,
+
+    /// samply.pki: File containing the authentication token (only used with PKI_AUTH_METHOD=token)
+    #[cfg(feature = "vault")]
     #[clap(long, env, value_parser, default_value = "/run/secrets/pki.secret")]
     pki_apikey_file: PathBuf,
 
+    /// samply.pki: Role ID to log in with (required for PKI_AUTH_METHOD=approle)
+    #[cfg(feature = "vault")]
+    #[clap(long, env, value_parser)]
+    pki_approle_role_id: Option<String>,
+
+    /// samply.pki: File containing the AppRole secret ID (only used with PKI_AUTH_METHOD=approle)
+    #[cfg(feature = "vault")]
+    #[clap(long, env, value_parser, default_value = "/run/secrets/pki-approle.secret")]
+    pki_approle_secret_id_file: PathBuf,
+
+    /// samply.pki: Vault role to log in with (required for PKI_AUTH_METHOD=kubernetes)
+    #[cfg(feature = "vault")]
+    #[clap(long, env, value_parser)]
+    pki_kubernetes_role: Option<String>,
+
+    /// samply.pki: The service account token presented to Vault (only used with PKI_AUTH_METHOD=kubernetes)
+    #[cfg(feature = "vault")]
+    #[clap(long, env, value_parser, default_value = "/var/run/secrets/kubernetes.io/serviceaccount/token")]
+    pki_kubernetes_token_file: PathBuf,
+
     /// samply.pki: Path to own secret key
     #[clap(long, env, value_parser, default_value = "/run/secrets/privkey.pem")]
     privkey_file: PathBuf,
@@ -53,6 +138,272 @@ struct CliArgs {
     #[clap(long, env, value_parser)]
     monitoring_api_key: Option<String>,
 
+    /// samply.pki: Maximum number of attempts when listing certificates
+    #[cfg(feature = "vault")]
+    #[clap(long, env, value_parser = clap::value_parser!(u32).range(1..), default_value_t = 10)]
+    pki_max_tries_list: u32,
+
+    /// samply.pki: Maximum number of attempts when fetching a single certificate or the CRL
+    #[cfg(feature = "vault")]
+    #[clap(long, env, value_parser = clap::value_parser!(u32).range(1..), default_value_t = 10)]
+    pki_max_tries_fetch: u32,
+
+    /// samply.pki: Maximum number of attempts when checking Vault's health
+    #[cfg(feature = "vault")]
+    #[clap(long, env, value_parser = clap::value_parser!(u32).range(1..), default_value_t = 1)]
+    pki_max_tries_health: u32,
+
+    /// samply.pki: Maximum number of attempts when fetching the intermediate CA certificate (needed at startup)
+    #[cfg(feature = "vault")]
+    #[clap(long, env, value_parser = clap::value_parser!(u32).range(1..), default_value_t = 100)]
+    pki_max_tries_ca: u32,
+
+    /// samply.pki: Maximum number of retries of any Vault request, which caps the number of attempts of each type above
+    #[cfg(feature = "vault")]
+    #[clap(long, env, value_parser, default_value_t = 60)]
+    pki_max_retries: u32,
+
+    /// samply.pki: Number of certificates fetched from Vault concurrently, e.g. when filling the certificate cache at startup
+    #[cfg(feature = "vault")]
+    #[clap(long, env, value_parser = clap::value_parser!(u32).range(1..), default_value_t = 8)]
+    pki_fetch_concurrency: u32,
+
+    /// samply.pki: Maximum number of requests to Vault in flight at the same time. Further requests wait for one of them to finish
+    #[cfg(feature = "vault")]
+    #[clap(long, env, value_parser = clap::value_parser!(u32).range(1..), default_value_t = 16)]
+    pki_max_concurrent_requests: u32,
+
+    /// samply.pki: Maximum size in bytes of Vault's response with the certificate list or the CRL. Larger responses are rejected
+    #[cfg(feature = "vault")]
+    #[clap(long, env, value_parser = clap::value_parser!(u64).range(1..), default_value_t = 16 * 1024 * 1024)]
+    pki_max_list_response_size: u64,
+
+    /// samply.pki: Maximum size in bytes of any other response from Vault, e.g. a single certificate. Larger responses are rejected
+    #[cfg(feature = "vault")]
+    #[clap(long, env, value_parser = clap::value_parser!(u64).range(1..), default_value_t = 256 * 1024)]
+    pki_max_response_size: u64,
+
+    /// samply.pki: Seconds for which a certificate serial unknown to Vault is answered as not found without asking Vault again (0 to always ask)
+    #[cfg(feature = "vault")]
+    #[clap(long, env, value_parser, default_value_t = 10)]
+    pki_not_found_cache_ttl: u64,
+
+    /// samply.pki: Seconds to wait at startup for a sealed or uninitialized Vault to be unsealed before giving up (0 to fail right away)
+    #[cfg(feature = "vault")]
+    #[clap(long, env, value_parser, default_value_t = 300)]
+    pki_unseal_timeout: u64,
+
+    /// samply.pki: URL of the CRL distribution point to check proxy certificates against (default: Vault's CRL of PKI_REALM)
+    #[cfg(feature = "vault")]
+    #[clap(long, env, value_parser)]
+    pki_crl_url: Option<Url>,
+
+    /// samply.pki: Seconds after which the CRL is fetched again
+    #[cfg(feature = "vault")]
+    #[clap(long, env, value_parser = clap::value_parser!(u64).range(1..), default_value_t = 300)]
+    pki_crl_refresh_interval: u64,
+
+    /// samply.pki: Accept messages if the CRL has never been fetched successfully instead of rejecting them
+    #[cfg(feature = "vault")]
+    #[clap(long, env, value_parser, default_value_t = false)]
+    pki_crl_fail_open: bool,
+
+    /// samply.pki: Ask an OCSP responder whether the certificates of message senders have been revoked
+    #[cfg(feature = "vault")]
+    #[clap(long, env, value_parser, default_value_t = false)]
+    pki_ocsp: bool,
+
+    /// samply.pki: URL of the OCSP responder (default: the one named in each certificate)
+    #[cfg(feature = "vault")]
+    #[clap(long, env, value_parser)]
+    pki_ocsp_responder: Option<Url>,
+
+    /// samply.pki: Seconds for which the OCSP status of a certificate is cached
+    #[cfg(feature = "vault")]
+    #[clap(long, env, value_parser, default_value_t = 300)]
+    pki_ocsp_cache_ttl: u64,
+
+    /// samply.pki: Accept messages if the OCSP responder cannot be asked instead of rejecting them
+    #[cfg(feature = "vault")]
+    #[clap(long, env, value_parser, default_value_t = false)]
+    pki_ocsp_fail_open: bool,
+
+    /// samply.pki: Reject certificates from Vault which are expired or not yet valid instead of passing them on
+    #[cfg(feature = "vault")]
+    #[clap(long, env, value_parser, default_value_t = false)]
+    pki_reject_expired_certs: bool,
+
+    /// samply.pki: If Vault is unavailable, serve the certificate list and certificates it returned last instead of failing
+    #[cfg(feature = "vault")]
+    #[clap(long, env, value_parser, default_value_t = false)]
+    pki_serve_stale_on_error: bool,
+
+    /// samply.pki: Seconds after which a failing Vault request is given up regardless of the remaining attempts
+    #[cfg(feature = "vault")]
+    #[clap(long, env, value_parser = clap::value_parser!(u64).range(1..), default_value_t = 600)]
+    pki_retry_deadline: u64,
+
+    /// samply.pki: Seconds after which a single attempt of a Vault request, including reading the response, is given up and retried
+    #[cfg(feature = "vault")]
+    #[clap(long, env, value_parser = clap::value_parser!(u64).range(1..), default_value_t = 60)]
+    pki_attempt_timeout: u64,
+
+    /// samply.pki: Status codes of Vault's responses after which a request is retried, as single codes or ranges like 502-599 (comma-separated). Requests failing with other codes are given up right away
+    #[cfg(feature = "vault")]
+    #[clap(long, env, value_parser, value_delimiter = ',', default_value = DEFAULT_RETRY_STATUS_CODES)]
+    pki_retry_status_codes: Vec<StatusCodeRange>,
+
+    /// samply.pki: Milliseconds to wait before the first retry of a failed Vault request
+    #[cfg(feature = "vault")]
+    #[clap(long, env, value_parser, default_value_t = 200)]
+    pki_retry_backoff_base_ms: u64,
+
+    /// samply.pki: Maximum number of milliseconds to wait between retries of a failed Vault request
+    #[cfg(feature = "vault")]
+    #[clap(long, env, value_parser, default_value_t = 30_000)]
+    pki_retry_backoff_max_ms: u64,
+
+    /// samply.pki: Factor by which the wait between retries of a failed Vault request grows
+    #[cfg(feature = "vault")]
+    #[clap(long, env, value_parser, default_value_t = 2.0)]
+    pki_retry_backoff_multiplier: f64,
+
+    /// samply.pki: Number of failed Vault requests within PKI_CIRCUIT_BREAKER_WINDOW after which no more requests are sent for PKI_CIRCUIT_BREAKER_COOLDOWN
+    #[cfg(feature = "vault")]
+    #[clap(long, env, value_parser = clap::value_parser!(u32).range(1..), default_value_t = 5)]
+    pki_circuit_breaker_threshold: u32,
+
+    /// samply.pki: Seconds within which PKI_CIRCUIT_BREAKER_THRESHOLD consecutive failures open the circuit breaker
+    #[cfg(feature = "vault")]
+    #[clap(long, env, value_parser, default_value_t = 60)]
+    pki_circuit_breaker_window: u64,
+
+    /// samply.pki: Seconds to fail requests to Vault immediately once the circuit breaker is open before a single request may probe Vault again
+    #[cfg(feature = "vault")]
+    #[clap(long, env, value_parser, default_value_t = 30)]
+    pki_circuit_breaker_cooldown: u64,
+
+    /// samply.pki: Path and query of Vault's health check relative to `<PKI_ADDRESS>/v1/` or, if starting with `/`, to PKI_ADDRESS's host, e.g. `sys/health?standbyok=true&perfstandbyok=true`
+    #[cfg(feature = "vault")]
+    #[clap(long, env, value_parser, default_value = "sys/health")]
+    pki_health_path: String,
+
+    /// samply.pki: Milliseconds for which the result of a health check of Vault is shared by all failing requests (results saying that Vault is sealed for at most 500 ms)
+    #[cfg(feature = "vault")]
+    #[clap(long, env, value_parser, default_value_t = 2000)]
+    pki_health_cache_ttl_ms: u64,
+
+    /// samply.pki: Seconds to cache the certificate list if Vault does not report a lease duration
+    #[cfg(feature = "vault")]
+    #[clap(long, env, value_parser, default_value_t = 60)]
+    pki_cache_ttl_default: u64,
+
+    /// samply.pki: Minimum number of seconds to cache the certificate list regardless of Vault's lease duration
+    #[cfg(feature = "vault")]
+    #[clap(long, env, value_parser, default_value_t = 10)]
+    pki_cache_ttl_min: u64,
+
+    /// samply.pki: Maximum number of seconds to cache the certificate list regardless of Vault's lease duration
+    #[cfg(feature = "vault")]
+    #[clap(long, env, value_parser, default_value_t = 3600)]
+    pki_cache_ttl_max: u64,
+
+    /// samply.pki: Seconds between fetches of the certificate list in the background, which also fetch newly listed certificates and drop unlisted ones (default: whenever the cached list expires)
+    #[cfg(feature = "vault")]
+    #[clap(long, env, value_parser = clap::value_parser!(u64).range(1..))]
+    pki_refresh_interval: Option<u64>,
+
+    /// samply.pki: Warn if the local clock deviates from the time reported by Vault by more than this many seconds
+    #[cfg(feature = "vault")]
+    #[clap(long, env, value_parser, default_value_t = 30)]
+    pki_max_clock_skew: u64,
+
+    /// samply.pki: User-Agent sent with requests to Vault, e.g. to tell them apart in Vault's audit log (default: the broker's User-Agent with a `+pki` suffix)
+    #[cfg(feature = "vault")]
+    #[clap(long, env, value_parser)]
+    pki_user_agent: Option<HeaderValue>,
+
+    /// samply.pki: Vault Enterprise namespace containing the PKI mount and the auth method, sent as `X-Vault-Namespace` (default: none)
+    #[cfg(feature = "vault")]
+    #[clap(long, env, value_parser)]
+    pki_namespace: Option<HeaderValue>,
+
+    /// samply.pki: Seconds after which idle connections to Vault are closed (default: as for other outgoing HTTP)
+    #[cfg(feature = "vault")]
+    #[clap(long, env, value_parser)]
+    pki_pool_idle_timeout: Option<u64>,
+
+    /// samply.pki: Maximum number of idle connections kept to Vault (default: as for other outgoing HTTP)
+    #[cfg(feature = "vault")]
+    #[clap(long, env, value_parser)]
+    pki_pool_max_idle_per_host: Option<usize>,
+
+    /// samply.pki: Whether to talk to Vault via HTTP/2: `negotiate` it via TLS, use it with `prior-knowledge` (also for plain HTTP), or stay at HTTP/1.1 (`off`)
+    #[cfg(feature = "vault")]
+    #[clap(long, env, value_enum, default_value_t = Http2::Negotiate)]
+    pki_http2: Http2,
+
+    /// Directory containing the proxies' certificates as `certs/<serial>.pem` and the intermediate CA certificate as `ca.pem`.
+    /// If set, certificates are served from there instead of Vault (required if the broker has been built without Vault support).
+    #[clap(long, env, value_parser)]
+    pki_cert_dir: Option<PathBuf>,
+
+    /// Check the configured source of certificates, e.g. that Vault is reachable, accepts the credentials and knows PKI_REALM, print the results and exit with a non-zero code if any check fails
+    #[clap(long, env, value_parser, default_value_t = false)]
+    check_config: bool,
+
+    /// Maximum number of bytes of tasks and results to keep in memory. New tasks and results are rejected once it is reached (default: unlimited)
+    #[clap(long, env, value_parser)]
+    storage_cap: Option<usize>,
+
+    /// Stop delivering a task to a recipient once it has fetched the task this many times without answering it, e.g. because it cannot decrypt or process it (default: never)
+    #[clap(long, env, value_parser = clap::value_parser!(u32).range(1..))]
+    poison_threshold: Option<u32>,
+
+    /// Maximum size of a single message (e.g. a task or a result) in bytes. Larger messages are rejected with 413 while they are being received (default: unlimited)
+    #[clap(long, env, value_parser)]
+    max_message_size: Option<usize>,
+
+    /// Addresses of load balancers that prefix connections with a PROXY protocol (v1 or v2) header carrying the original client address. Connections from these addresses must start with such a header (comma-separated)
+    #[clap(long, env, value_parser, value_delimiter = ',')]
+    proxy_protocol_from: Vec<IpAddr>,
+
+    /// Close client connections that have transferred no data for this many seconds while no request is pending (default: never)
+    #[clap(long, env, value_parser = clap::value_parser!(u64).range(1..))]
+    connection_idle_timeout: Option<u64>,
+
+    /// How to handle requests without a Host header, e.g. from HTTP/1.0 clients: reject them or assume the broker's own address
+    #[clap(long, env, value_enum, default_value_t = MissingHost::Reject)]
+    missing_host: MissingHost,
+
+    /// Maximum number of concurrent long polls (requests waiting for tasks or results) across all proxies. Excess long polls are rejected with 429 (default: unlimited)
+    #[clap(long, env, value_parser)]
+    long_poll_limit: Option<usize>,
+
+    /// Maximum number of concurrent long polls per proxy, counting all of its apps. Excess long polls are rejected with 429 (default: unlimited)
+    #[clap(long, env, value_parser)]
+    long_poll_limit_per_proxy: Option<usize>,
+
+    /// Per-proxy exceptions from LONG_POLL_LIMIT_PER_PROXY, e.g. for proxies serving many apps (comma-separated, e.g. proxy1.broker.example=500)
+    #[clap(long, env, value_parser, value_delimiter = ',')]
+    long_poll_limit_overrides: Vec<String>,
+
+    /// Maximum number of tasks each proxy (all of its apps together) may create per TASK_QUOTA_WINDOW. Further tasks are rejected with 429 until the window ends (default: unlimited)
+    #[clap(long, env, value_parser)]
+    task_quota: Option<u64>,
+
+    /// Calendar window (in UTC) after which the task quotas are reset
+    #[clap(long, env, value_enum, default_value_t = QuotaWindow::Daily)]
+    task_quota_window: QuotaWindow,
+
+    /// File in which the task quota counters are kept so that they survive restarts (default: counters are reset on restart)
+    #[clap(long, env, value_parser)]
+    task_quota_state_file: Option<PathBuf>,
+
+    /// JWT signature algorithms accepted for messages; messages signed with other algorithms are rejected even if their signature is valid (comma-separated)
+    #[clap(long, env, value_parser, value_delimiter = ',', default_value = "RS256,PS256,PS384,PS512")]
+    accepted_signature_algorithms: Vec<String>,
+
     /// (included for technical reasons)
     #[clap(long, hide(true))]
     test_threads: Option<String>,
@@ -60,37 +411,448 @@ struct CliArgs {
 
 pub struct Config {
     pub bind_addr: SocketAddr,
-    pub pki_address: Url,
+    pub cert_source: CertSource,
+    pub check_config: bool,
+    #[cfg(feature = "vault")]
     pub pki_realm: String,
-    pub pki_token: String,
     pub tls_ca_certificates_dir: Option<PathBuf>,
+    pub tls_ca_certificates_file: Option<PathBuf>,
+    pub tls_ca_certificates_strict: bool,
     pub monitoring_api_key: Option<String>,
+    #[cfg(feature = "vault")]
+    pub pki_retry_budgets: VaultRetryBudgets,
+    #[cfg(feature = "vault")]
+    pub pki_retry_backoff: RetryBackoff,
+    #[cfg(feature = "vault")]
+    pub pki_retry_deadline: Duration,
+    #[cfg(feature = "vault")]
+    pub pki_attempt_timeout: Duration,
+    #[cfg(feature = "vault")]
+    pub pki_retry_status_codes: RetryableStatusCodes,
+    #[cfg(feature = "vault")]
+    pub pki_circuit_breaker: CircuitBreakerSettings,
+    #[cfg(feature = "vault")]
+    pub pki_health_path: String,
+    #[cfg(feature = "vault")]
+    pub pki_health_cache_ttl: Duration,
+    #[cfg(feature = "vault")]
+    pub pki_reject_expired_certs: bool,
+    #[cfg(feature = "vault")]
+    pub pki_serve_stale_on_error: bool,
+    #[cfg(feature = "vault")]
+    pub pki_fetch_concurrency: usize,
+    #[cfg(feature = "vault")]
+    pub pki_max_concurrent_requests: usize,
+    #[cfg(feature = "vault")]
+    pub pki_response_limits: VaultResponseLimits,
+    #[cfg(feature = "vault")]
+    pub pki_not_found_cache_ttl: Duration,
+    #[cfg(feature = "vault")]
+    pub pki_unseal_timeout: Duration,
+    #[cfg(feature = "vault")]
+    pub pki_crl: CrlSettings,
+    /// `None` unless OCSP is enabled
+    #[cfg(feature = "vault")]
+    pub pki_ocsp: Option<OcspSettings>,
+    pub storage_cap: Option<usize>,
+    pub poison_threshold: Option<u32>,
+    pub max_message_size: Option<usize>,
+    pub proxy_protocol_from: Vec<IpAddr>,
+    pub connection_idle_timeout: Option<Duration>,
+    pub missing_host: MissingHost,
+    pub accepted_signature_algorithms: Vec<String>,
+    pub long_poll_limit: Option<usize>,
+    pub long_poll_limit_per_proxy: Option<usize>,
+    pub long_poll_limit_overrides: HashMap<ProxyId, usize>,
+    pub task_quota: Option<u64>,
+    pub task_quota_window: QuotaWindow,
+    pub task_quota_state_file: Option<PathBuf>,
+    #[cfg(feature = "vault")]
+    pub pki_cache_ttl: CacheTtlBounds,
+    #[cfg(feature = "vault")]
+    pub pki_refresh_interval: Option<Duration>,
+    #[cfg(feature = "vault")]
+    pub pki_max_clock_skew: Duration,
+    #[cfg(feature = "vault")]
+    pub pki_user_agent: Option<HeaderValue>,
+    #[cfg(feature = "vault")]
+    pub pki_namespace: Option<HeaderValue>,
+    #[cfg(feature = "vault")]
+    pub pki_pool_idle_timeout: Option<Duration>,
+    #[cfg(feature = "vault")]
+    pub pki_pool_max_idle_per_host: Option<usize>,
+    #[cfg(feature = "vault")]
+    pub pki_http2: Http2,
+}
+
+/// Calendar window after which task quotas are reset
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QuotaWindow {
+    Daily,
+    Monthly,
+}
+
+#[cfg(feature = "vault")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum VaultAuthMethod {
+    Token,
+    #[value(name = "approle")]
+    AppRole,
+    Kubernetes,
+}
+
+/// How the broker authenticates to Vault
+#[cfg(feature = "vault")]
+#[derive(Clone)]
+pub enum VaultAuth {
+    /// A static token read from PKI_APIKEY_FILE
+    Token(String),
+    /// Logging in via `auth/approle/login` to obtain short-lived tokens
+    AppRole { role_id: String, secret_id: String },
+    /// Logging in via `auth/kubernetes/login` with the service account token, which is read anew for each login as Kubernetes rotates it
+    Kubernetes { role: String, token_file: PathBuf },
+}
+
+/// Where the broker gets the proxies' certificates from
+#[derive(Clone)]
+pub enum CertSource {
+    #[cfg(feature = "vault")]
+    /// `addresses` is never empty
+    Vault { addresses: Vec<Url>, auth: VaultAuth },
+    /// A directory with the certificates as PEM files, e.g. for tests and air-gapped deployments
+    Dir(PathBuf),
+}
+
+/// Maximum number of attempts per kind of Vault operation
+#[cfg(feature = "vault")]
+#[derive(Debug, Clone, Copy)]
+pub struct VaultRetryBudgets {
+    pub list: u32,
+    pub fetch: u32,
+    pub health: u32,
+    pub ca: u32,
+    /// Retries on top of the first attempt, whatever the budget of the request's type
+    pub max_retries: u32,
+}
+
+/// Maximum sizes in bytes of response bodies from Vault, which are otherwise read into memory whatever their size
+#[cfg(feature = "vault")]
+#[derive(Debug, Clone, Copy)]
+pub struct VaultResponseLimits {
+    /// For the certificate list and the CRL, which grow with the number of certificates
+    pub list: usize,
+    /// For everything else, e.g. a single certificate
+    pub single: usize,
+}
+
+/// Where the CRL comes from and how failures to fetch it are handled
+#[cfg(feature = "vault")]
+#[derive(Debug, Clone)]
+pub struct CrlSettings {
+    /// A CRL distribution point to use instead of Vault
+    pub url: Option<Url>,
+    pub refresh_interval: Duration,
+    /// Whether certificates are considered not revoked as long as no CRL could be fetched
+    pub fail_open: bool,
+}
+
+/// How certificates are checked via OCSP
+#[cfg(feature = "vault")]
+#[derive(Debug, Clone)]
+pub struct OcspSettings {
+    /// A responder to use instead of the one in each certificate's Authority Information Access extension
+    pub responder: Option<Url>,
+    pub cache_ttl: Duration,
+    /// Whether certificates are considered not revoked if the responder cannot be asked
+    pub fail_open: bool,
+}
+
+/// Exponentially growing waits between retries of failed Vault requests
+#[cfg(feature = "vault")]
+#[derive(Debug, Clone, Copy)]
+pub struct RetryBackoff {
+    pub base: Duration,
+    pub max: Duration,
+    pub multiplier: f64,
+}
+
+#[cfg(feature = "vault")]
+impl RetryBackoff {
+    /// How long to wait before the given retry (starting at 1).
+    /// A random part of up to half the wait is left out so that broker replicas do not retry in lockstep.
+    pub fn delay_before_retry(&self, retry: u32) -> Duration {
+        let exponent = retry.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let delay = self.base.as_secs_f64() * self.multiplier.powi(exponent);
+        let delay = Duration::try_from_secs_f64(delay).unwrap_or(self.max).min(self.max);
+        delay.mul_f64(1.0 - rand::random::<f64>() / 2.0)
+    }
+}
+
+/// Everything Vault might answer temporarily, but not `501 Not Implemented`, which will not change by retrying
+#[cfg(feature = "vault")]
+const DEFAULT_RETRY_STATUS_CODES: &str = "429,500,502-599";
+
+/// A single status code or an inclusive range of them, parsed from e.g. `503` or `502-599`
+#[cfg(feature = "vault")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StatusCodeRange {
+    pub first: u16,
+    pub last: u16,
+}
+
+#[cfg(feature = "vault")]
+impl FromStr for StatusCodeRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |code: &str| {
+            code.trim()
+                .parse::<reqwest::StatusCode>()
+                .map(|code| code.as_u16())
+                .map_err(|e| format!("Invalid status code {code:?}: {e}"))
+        };
+        let (first, last) = match s.split_once('-') {
+            Some((first, last)) => (parse(first)?, parse(last)?),
+            None => (parse(s)?, parse(s)?),
+        };
+        if first > last {
+            return Err(format!("Empty range of status codes {s:?}"));
+        }
+        Ok(Self { first, last })
+    }
+}
+
+/// Status codes of Vault's responses after which a request is retried
+#[cfg(feature = "vault")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryableStatusCodes(pub Vec<StatusCodeRange>);
+
+#[cfg(feature = "vault")]
+impl RetryableStatusCodes {
+    pub fn contains(&self, code: reqwest::StatusCode) -> bool {
+        self.0.iter().any(|range| (range.first..=range.last).contains(&code.as_u16()))
+    }
+}
+
+#[cfg(feature = "vault")]
+impl Default for RetryableStatusCodes {
+    fn default() -> Self {
+        Self(DEFAULT_RETRY_STATUS_CODES.split(',').map(|range| range.parse().unwrap()).collect())
+    }
+}
+
+/// When to stop sending requests to a Vault which keeps failing
+#[cfg(feature = "vault")]
+#[derive(Debug, Clone, Copy)]
+pub struct CircuitBreakerSettings {
+    /// Number of consecutive failures which open the circuit
+    pub threshold: u32,
+    /// Failures further apart than this are not consecutive
+    pub window: Duration,
+    /// How long the circuit stays open before a probe request is let through
+    pub cooldown: Duration,
+}
+
+/// Bounds for how long data fetched from Vault is cached
+#[cfg(feature = "vault")]
+#[derive(Debug, Clone, Copy)]
+pub struct CacheTtlBounds {
+    /// Used if Vault does not report a lease duration
+    pub default: Duration,
+    pub min: Duration,
+    pub max: Duration,
+}
+
+#[cfg(feature = "vault")]
+impl CacheTtlBounds {
+    /// Derives the cache TTL from the `lease_duration` (in seconds) reported by Vault
+    pub fn ttl_for_lease(&self, lease_duration: u64) -> Duration {
+        if lease_duration == 0 {
+            return self.default;
+        }
+        Duration::from_secs(lease_duration).clamp(self.min, self.max)
+    }
 }
 
 impl crate::config::Config for Config {
     fn load() -> Result<Self, SamplyBeamError> {
         let cli_args = CliArgs::parse();
         beam_lib::set_broker_id(cli_args.broker_url.host().unwrap().to_string());
-        let pki_token = read_to_string(&cli_args.pki_apikey_file)
-            .map_err(|e| {
-                SamplyBeamError::ConfigurationFailed(format!(
-                    "Unable to read PKI API key at {}: {}",
-                    &cli_args.pki_apikey_file.to_string_lossy(),
-                    e
+        let cert_source = match cli_args.pki_cert_dir {
+            Some(dir) => CertSource::Dir(dir),
+            #[cfg(feature = "vault")]
+            None if cli_args.pki_address.is_empty() => {
+                return Err(SamplyBeamError::ConfigurationFailed("PKI_ADDRESS is required unless PKI_CERT_DIR is set".into()))
+            }
+            #[cfg(feature = "vault")]
+            None => CertSource::Vault {
+                addresses: cli_args.pki_address,
+                auth: match cli_args.pki_auth_method {
+                    VaultAuthMethod::Token => VaultAuth::Token(read_secret(&cli_args.pki_apikey_file, "PKI API key")?),
+                    VaultAuthMethod::AppRole => VaultAuth::AppRole {
+                        role_id: cli_args.pki_approle_role_id.ok_or_else(|| {
+                            SamplyBeamError::ConfigurationFailed("PKI_AUTH_METHOD=approle requires PKI_APPROLE_ROLE_ID".into())
+                        })?,
+                        secret_id: read_secret(&cli_args.pki_approle_secret_id_file, "PKI AppRole secret ID")?,
+                    },
+                    VaultAuthMethod::Kubernetes => VaultAuth::Kubernetes {
+                        role: cli_args.pki_kubernetes_role.ok_or_else(|| {
+                            SamplyBeamError::ConfigurationFailed("PKI_AUTH_METHOD=kubernetes requires PKI_KUBERNETES_ROLE".into())
+                        })?,
+                        token_file: cli_args.pki_kubernetes_token_file,
+                    },
+                },
+            },
+            #[cfg(not(feature = "vault"))]
+            None => {
+                return Err(SamplyBeamError::ConfigurationFailed(
+                    "PKI_CERT_DIR is required as the broker has been built without Vault support".into(),
                 ))
-            })?
-            .trim()
-            .to_string();
+            }
+        };
+
+        #[cfg(feature = "vault")]
+        if cli_args.pki_retry_backoff_multiplier.is_nan() || cli_args.pki_retry_backoff_multiplier < 1.0 {
+            return Err(SamplyBeamError::ConfigurationFailed(format!(
+                "PKI_RETRY_BACKOFF_MULTIPLIER ({}) must be at least 1",
+                cli_args.pki_retry_backoff_multiplier
+            )));
+        }
+
+        #[cfg(feature = "vault")]
+        if cli_args.pki_cache_ttl_min > cli_args.pki_cache_ttl_max {
+            return Err(SamplyBeamError::ConfigurationFailed(format!(
+                "PKI_CACHE_TTL_MIN ({}) must not be greater than PKI_CACHE_TTL_MAX ({})",
+                cli_args.pki_cache_ttl_min, cli_args.pki_cache_ttl_max
+            )));
+        }
+
+        let long_poll_limit_overrides = cli_args
+            .long_poll_limit_overrides
+            .iter()
+            .map(|entry| parse_long_poll_limit_override(entry))
+            .collect::<Result<_, _>>()?;
 
         info!("Successfully read config and API keys from CLI and secrets files.");
         let config = Config {
             bind_addr: cli_args.bind_addr,
-            pki_address: cli_args.pki_address,
+            cert_source,
+            check_config: cli_args.check_config,
+            #[cfg(feature = "vault")]
             pki_realm: cli_args.pki_realm,
-            pki_token,
             tls_ca_certificates_dir: cli_args.tls_ca_certificates_dir,
+            tls_ca_certificates_file: cli_args.tls_ca_certificates_file,
+            tls_ca_certificates_strict: cli_args.tls_ca_certificates_strict,
             monitoring_api_key: cli_args.monitoring_api_key,
+            #[cfg(feature = "vault")]
+            pki_retry_budgets: VaultRetryBudgets {
+                list: cli_args.pki_max_tries_list,
+                fetch: cli_args.pki_max_tries_fetch,
+                health: cli_args.pki_max_tries_health,
+                ca: cli_args.pki_max_tries_ca,
+                max_retries: cli_args.pki_max_retries,
+            },
+            #[cfg(feature = "vault")]
+            pki_retry_backoff: RetryBackoff {
+                base: Duration::from_millis(cli_args.pki_retry_backoff_base_ms),
+                max: Duration::from_millis(cli_args.pki_retry_backoff_max_ms),
+                multiplier: cli_args.pki_retry_backoff_multiplier,
+            },
+            #[cfg(feature = "vault")]
+            pki_retry_deadline: Duration::from_secs(cli_args.pki_retry_deadline),
+            #[cfg(feature = "vault")]
+            pki_attempt_timeout: Duration::from_secs(cli_args.pki_attempt_timeout),
+            #[cfg(feature = "vault")]
+            pki_retry_status_codes: RetryableStatusCodes(cli_args.pki_retry_status_codes),
+            #[cfg(feature = "vault")]
+            pki_circuit_breaker: CircuitBreakerSettings {
+                threshold: cli_args.pki_circuit_breaker_threshold,
+                window: Duration::from_secs(cli_args.pki_circuit_breaker_window),
+                cooldown: Duration::from_secs(cli_args.pki_circuit_breaker_cooldown),
+            },
+            #[cfg(feature = "vault")]
+            pki_health_path: cli_args.pki_health_path,
+            #[cfg(feature = "vault")]
+            pki_health_cache_ttl: Duration::from_millis(cli_args.pki_health_cache_ttl_ms),
+            #[cfg(feature = "vault")]
+            pki_reject_expired_certs: cli_args.pki_reject_expired_certs,
+            #[cfg(feature = "vault")]
+            pki_serve_stale_on_error: cli_args.pki_serve_stale_on_error,
+            #[cfg(feature = "vault")]
+            pki_fetch_concurrency: cli_args.pki_fetch_concurrency as usize,
+            #[cfg(feature = "vault")]
+            pki_max_concurrent_requests: cli_args.pki_max_concurrent_requests as usize,
+            #[cfg(feature = "vault")]
+            pki_response_limits: VaultResponseLimits {
+                list: cli_args.pki_max_list_response_size as usize,
+                single: cli_args.pki_max_response_size as usize,
+            },
+            #[cfg(feature = "vault")]
+            pki_not_found_cache_ttl: Duration::from_secs(cli_args.pki_not_found_cache_ttl),
+            #[cfg(feature = "vault")]
+            pki_unseal_timeout: Duration::from_secs(cli_args.pki_unseal_timeout),
+            #[cfg(feature = "vault")]
+            pki_crl: CrlSettings {
+                url: cli_args.pki_crl_url,
+                refresh_interval: Duration::from_secs(cli_args.pki_crl_refresh_interval),
+                fail_open: cli_args.pki_crl_fail_open,
+            },
+            #[cfg(feature = "vault")]
+            pki_ocsp: cli_args.pki_ocsp.then(|| OcspSettings {
+                responder: cli_args.pki_ocsp_responder,
+                cache_ttl: Duration::from_secs(cli_args.pki_ocsp_cache_ttl),
+                fail_open: cli_args.pki_ocsp_fail_open,
+            }),
+            storage_cap: cli_args.storage_cap,
+            poison_threshold: cli_args.poison_threshold,
+            max_message_size: cli_args.max_message_size,
+            proxy_protocol_from: cli_args.proxy_protocol_from,
+            connection_idle_timeout: cli_args.connection_idle_timeout.map(Duration::from_secs),
+            missing_host: cli_args.missing_host,
+            accepted_signature_algorithms: cli_args.accepted_signature_algorithms,
+            long_poll_limit: cli_args.long_poll_limit,
+            long_poll_limit_per_proxy: cli_args.long_poll_limit_per_proxy,
+            long_poll_limit_overrides,
+            task_quota: cli_args.task_quota,
+            task_quota_window: cli_args.task_quota_window,
+            task_quota_state_file: cli_args.task_quota_state_file,
+            #[cfg(feature = "vault")]
+            pki_cache_ttl: CacheTtlBounds {
+                default: Duration::from_secs(cli_args.pki_cache_ttl_default),
+                min: Duration::from_secs(cli_args.pki_cache_ttl_min),
+                max: Duration::from_secs(cli_args.pki_cache_ttl_max),
+            },
+            #[cfg(feature = "vault")]
+            pki_refresh_interval: cli_args.pki_refresh_interval.map(Duration::from_secs),
+            #[cfg(feature = "vault")]
+            pki_max_clock_skew: Duration::from_secs(cli_args.pki_max_clock_skew),
+            #[cfg(feature = "vault")]
+            pki_user_agent: cli_args.pki_user_agent,
+            #[cfg(feature = "vault")]
+            pki_namespace: cli_args.pki_namespace,
+            #[cfg(feature = "vault")]
+            pki_pool_idle_timeout: cli_args.pki_pool_idle_timeout.map(Duration::from_secs),
+            #[cfg(feature = "vault")]
+            pki_pool_max_idle_per_host: cli_args.pki_pool_max_idle_per_host,
+            #[cfg(feature = "vault")]
+            pki_http2: cli_args.pki_http2,
         };
         Ok(config)
     }
 }
+
+fn parse_long_poll_limit_override(entry: &str) -> Result<(ProxyId, usize), SamplyBeamError> {
+    let invalid = |reason: String| SamplyBeamError::ConfigurationFailed(format!("Invalid entry {entry:?} in LONG_POLL_LIMIT_OVERRIDES: {reason}"));
+    let (proxy, limit) = entry.split_once('=').ok_or_else(|| invalid("expected <proxy id>=<limit>".into()))?;
+    let proxy = ProxyId::new(proxy.trim()).map_err(|e| invalid(e.to_string()))?;
+    let limit = limit.trim().parse().map_err(|e: std::num::ParseIntError| invalid(e.to_string()))?;
+    Ok((proxy, limit))
+}
+
+#[cfg(feature = "vault")]
+fn read_secret(file: &std::path::Path, what: &str) -> Result<String, SamplyBeamError> {
+    read_to_string(file)
+        .map(|secret| secret.trim().to_string())
+        .map_err(|e| SamplyBeamError::ConfigurationFailed(format!("Unable to read {what} at {}: {e}", file.to_string_lossy())))
+}
@@ -17,7 +17,7 @@ use serde::Deserialize;
 use tracing::{debug, info, warn};
 
 use beam_lib::{AppId, ProxyId};
-use crate::errors::SamplyBeamError;
+use crate::{errors::SamplyBeamError, http_client::{self, TlsNameOverride}, middleware::MissingHost};
 
 #[derive(Clone, Debug)]
 pub struct Config {
@@ -27,6 +27,10 @@ pub struct Config {
     pub proxy_id: ProxyId,
     pub api_keys: HashMap<AppId, ApiKey>,
     pub tls_ca_certificates: Vec<reqwest::Certificate>,
+    pub tls_name_overrides: Vec<TlsNameOverride>,
+    pub wire_compression: bool,
+    pub tls_session_resumption: bool,
+    pub missing_host: MissingHost,
 }
 
 pub type ApiKey = String;
@@ -47,6 +51,59 @@ pub struct CliArgs {
     #[clap(long, env, value_parser)]
     pub tls_ca_certificates_dir: Option<PathBuf>,
 
+    /// Outgoing HTTP proxy: PEM file with CA certificates to trust for TLS connections in addition to TLS_CA_CERTIFICATES_DIR (e.g. /etc/ssl/certs/ca-bundle.pem)
+    #[clap(long, env, value_parser)]
+    pub tls_ca_certificates_file: Option<PathBuf>,
+
+    /// Outgoing HTTP proxy: Refuse to start if a CA certificate from TLS_CA_CERTIFICATES_DIR or TLS_CA_CERTIFICATES_FILE is expired or not yet valid instead of warning about it
+    #[clap(long, env)]
+    pub tls_ca_certificates_strict: bool,
+
+    /// Outgoing HTTP: PEM file with the client certificate (optionally followed by its chain) presented to servers requiring mutual TLS, e.g. the broker, Vault or a proxy's upstreams. Requires TLS_CLIENT_KEY_FILE
+    #[clap(long, env, value_parser)]
+    pub tls_client_cert_file: Option<PathBuf>,
+
+    /// Outgoing HTTP: PEM file with the private key of TLS_CLIENT_CERT_FILE
+    #[clap(long, env, value_parser)]
+    pub tls_client_key_file: Option<PathBuf>,
+
+    /// Outgoing HTTP: PKCS#12 file with the client certificate, its chain and private key, as an alternative to TLS_CLIENT_CERT_FILE and TLS_CLIENT_KEY_FILE
+    #[clap(long, env, value_parser)]
+    pub tls_client_pkcs12_file: Option<PathBuf>,
+
+    /// Outgoing HTTP: File containing the password of TLS_CLIENT_PKCS12_FILE (default: no password)
+    #[clap(long, env, value_parser)]
+    pub tls_client_pkcs12_password_file: Option<PathBuf>,
+
+    /// Outgoing HTTP: Seconds to wait for a connection to be established, including the TLS handshake (default: 120 for the proxy's connection to the broker, 30 for the broker's connection to Vault)
+    #[clap(long, env, value_parser = clap::value_parser!(u64).range(1..))]
+    pub http_connect_timeout: Option<u64>,
+
+    /// Outgoing HTTP: Seconds after which idle connections are closed
+    #[clap(long, env, value_parser, default_value_t = 90)]
+    pub http_pool_idle_timeout: u64,
+
+    /// Outgoing HTTP: Maximum number of idle connections kept per host (default: unlimited)
+    #[clap(long, env, value_parser)]
+    pub http_pool_max_idle_per_host: Option<usize>,
+
+    /// Outgoing HTTP: Oldest TLS version accepted from servers. TLS 1.3 makes use of rustls instead of OpenSSL
+    #[clap(long, env, value_enum, default_value = "1.2")]
+    pub tls_min_version: crate::http_client::TlsVersion,
+
+    /// Outgoing HTTP: The only TLS cipher suites offered to servers in rustls' naming, e.g. TLS13_AES_256_GCM_SHA384,TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384 (comma-separated, default: all supported by rustls). Makes use of rustls instead of OpenSSL
+    #[clap(long, env, value_parser, value_delimiter = ',')]
+    pub tls_cipher_suites: Vec<String>,
+
+    /// Outgoing HTTP: Set to "true" to accept any TLS certificate, e.g. of a self-signed Vault in a test setup. NEVER use this in production! Only "true" or "false" are accepted
+    // Unlike other flags, a value which is not exactly "true" does not enable this
+    #[clap(long, env, action = clap::ArgAction::Set, value_parser = clap::builder::BoolValueParser::new(), default_value_t = false)]
+    pub danger_accept_invalid_certs: bool,
+
+    /// Outgoing HTTP: Appended to the User-Agent of all outgoing requests, e.g. a site identifier for a web application firewall or Vault's audit log (default: none)
+    #[clap(long, env, value_parser)]
+    pub http_user_agent_suffix: Option<HeaderValue>,
+
     /// The broker's base URL, e.g. https://broker23.beam.samply.de
     #[clap(long, env, value_parser)]
     pub broker_url: Url,
@@ -63,6 +120,22 @@ pub struct CliArgs {
     #[clap(long, env, value_parser, default_value = "/run/secrets/root.crt.pem")]
     rootcert_file: PathBuf,
 
+    /// Outgoing HTTP proxy: Comma separated list of <ip>=<hostname> pairs. Connections to the IP verify the server's TLS certificate against the hostname, e.g. 10.0.0.5=broker.beam.example.org
+    #[clap(long, env, value_parser, value_delimiter = ',')]
+    pub tls_name_overrides: Vec<TlsNameOverride>,
+
+    /// Compress the traffic between proxy and broker with gzip (requires a broker of at least this version). Local apps are unaffected.
+    #[clap(long, env)]
+    pub wire_compression: bool,
+
+    /// Resume TLS sessions (via session IDs or tickets) when reconnecting to the broker instead of doing a full handshake. Uses rustls instead of OpenSSL for connections to the broker.
+    #[clap(long, env)]
+    pub tls_session_resumption: bool,
+
+    /// How to handle requests from local apps without a Host header, e.g. from HTTP/1.0 clients: reject them or assume the proxy's own address
+    #[clap(long, env, value_enum, default_value_t = MissingHost::Synthesize)]
+    pub missing_host: MissingHost,
+
     /// (included for technical reasons)
     #[clap(long, hide(true))]
     test_threads: Option<String>,
@@ -109,22 +182,24 @@ impl crate::config::Config for Config {
         if api_keys.is_empty() {
             return Err(SamplyBeamError::ConfigurationFailed(format!("No API keys have been defined. Please set environment vars à la {0}_<clientname>_KEY=<key>", APP_PREFIX)));
         }
-        let tls_ca_certificates = crate::crypto::load_certificates_from_dir(
+        let tls_ca_certificates = crate::crypto::load_tls_ca_certificates(
             cli_args.tls_ca_certificates_dir,
-        )
-        .map_err(|e| {
-            SamplyBeamError::ConfigurationFailed(format!(
-                "Unable to read from TLS CA directory: {}",
-                e
-            ))
-        })?;
+            cli_args.tls_ca_certificates_file.as_deref(),
+            cli_args.tls_ca_certificates_strict,
+        )?;
+        let mut broker_uri = cli_args.broker_url;
+        http_client::apply_tls_name_override(&mut broker_uri, &cli_args.tls_name_overrides);
         let config = Config {
-            broker_host_header: uri_to_host_header(&cli_args.broker_url)?,
-            broker_uri: cli_args.broker_url,
+            broker_host_header: uri_to_host_header(&broker_uri)?,
+            broker_uri,
             bind_addr: cli_args.bind_addr,
             proxy_id,
             api_keys,
             tls_ca_certificates,
+            tls_name_overrides: cli_args.tls_name_overrides,
+            wire_compression: cli_args.wire_compression,
+            tls_session_resumption: cli_args.tls_session_resumption,
+            missing_host: cli_args.missing_host,
         };
         info!("Successfully read config and API keys from CLI and secrets file.");
         Ok(config)
@@ -6,9 +6,10 @@ use crate::{
         self, get_all_certs_and_clients_by_cname_as_pemstr, load_certificates_from_dir,
         CryptoPublicPortion, GetCerts,
     },
+    http_client::{self, ClientIdentity, ConnectionSettings},
     SamplyBeamError,
 };
-use axum::async_trait;
+use axum::{async_trait, http::HeaderValue};
 use clap::Parser;
 use jwt_simple::prelude::RS256KeyPair;
 use openssl::{
@@ -16,8 +17,8 @@ use openssl::{
     x509::{self, X509},
 };
 use rsa::{pkcs1::DecodeRsaPrivateKey, pkcs8::DecodePrivateKey, RsaPrivateKey};
-use std::{fs::read_to_string, path::PathBuf, rc::Rc, sync::Arc};
-use tracing::{debug, info};
+use std::{fs::read_to_string, path::PathBuf, rc::Rc, sync::Arc, time::Duration};
+use tracing::{debug, info, warn};
 
 pub(crate) const CLAP_FOOTER: &str = "For proxy support, environment variables HTTP_PROXY, HTTPS_PROXY, ALL_PROXY and NO_PROXY (and their lower-case variants) are supported. Usually, you want to set HTTP_PROXY *and* HTTPS_PROXY or set ALL_PROXY if both values are the same.\n\nFor updates and detailed usage instructions, visit https://github.com/samply/beam";
 
@@ -33,6 +34,59 @@ struct CliArgs {
     #[clap(long, env, value_parser)]
     tls_ca_certificates_dir: Option<PathBuf>,
 
+    /// Outgoing HTTP proxy: PEM file with CA certificates to trust for TLS connections in addition to TLS_CA_CERTIFICATES_DIR (e.g. /etc/ssl/certs/ca-bundle.pem)
+    #[clap(long, env, value_parser)]
+    tls_ca_certificates_file: Option<PathBuf>,
+
+    /// Outgoing HTTP proxy: Refuse to start if a CA certificate from TLS_CA_CERTIFICATES_DIR or TLS_CA_CERTIFICATES_FILE is expired or not yet valid instead of warning about it
+    #[clap(long, env)]
+    tls_ca_certificates_strict: bool,
+
+    /// Outgoing HTTP: PEM file with the client certificate (optionally followed by its chain) presented to servers requiring mutual TLS, e.g. the broker, Vault or a proxy's upstreams. Requires TLS_CLIENT_KEY_FILE
+    #[clap(long, env, value_parser)]
+    tls_client_cert_file: Option<PathBuf>,
+
+    /// Outgoing HTTP: PEM file with the private key of TLS_CLIENT_CERT_FILE
+    #[clap(long, env, value_parser)]
+    tls_client_key_file: Option<PathBuf>,
+
+    /// Outgoing HTTP: PKCS#12 file with the client certificate, its chain and private key, as an alternative to TLS_CLIENT_CERT_FILE and TLS_CLIENT_KEY_FILE
+    #[clap(long, env, value_parser)]
+    tls_client_pkcs12_file: Option<PathBuf>,
+
+    /// Outgoing HTTP: File containing the password of TLS_CLIENT_PKCS12_FILE (default: no password)
+    #[clap(long, env, value_parser)]
+    tls_client_pkcs12_password_file: Option<PathBuf>,
+
+    /// Outgoing HTTP: Seconds to wait for a connection to be established, including the TLS handshake (default: 120 for the proxy's connection to the broker, 30 for the broker's connection to Vault)
+    #[clap(long, env, value_parser = clap::value_parser!(u64).range(1..))]
+    http_connect_timeout: Option<u64>,
+
+    /// Outgoing HTTP: Seconds after which idle connections are closed
+    #[clap(long, env, value_parser, default_value_t = 90)]
+    http_pool_idle_timeout: u64,
+
+    /// Outgoing HTTP: Maximum number of idle connections kept per host (default: unlimited)
+    #[clap(long, env, value_parser)]
+    http_pool_max_idle_per_host: Option<usize>,
+
+    /// Outgoing HTTP: Oldest TLS version accepted from servers. TLS 1.3 makes use of rustls instead of OpenSSL
+    #[clap(long, env, value_enum, default_value = "1.2")]
+    tls_min_version: crate::http_client::TlsVersion,
+
+    /// Outgoing HTTP: The only TLS cipher suites offered to servers in rustls' naming, e.g. TLS13_AES_256_GCM_SHA384,TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384 (comma-separated, default: all supported by rustls). Makes use of rustls instead of OpenSSL
+    #[clap(long, env, value_parser, value_delimiter = ',')]
+    tls_cipher_suites: Vec<String>,
+
+    /// Outgoing HTTP: Set to "true" to accept any TLS certificate, e.g. of a self-signed Vault in a test setup. NEVER use this in production! Only "true" or "false" are accepted
+    // Unlike other flags, a value which is not exactly "true" does not enable this
+    #[clap(long, env, action = clap::ArgAction::Set, value_parser = clap::builder::BoolValueParser::new(), default_value_t = false)]
+    danger_accept_invalid_certs: bool,
+
+    /// Outgoing HTTP: Appended to the User-Agent of all outgoing requests, e.g. a site identifier for a web application firewall or Vault's audit log (default: none)
+    #[clap(long, env, value_parser)]
+    http_user_agent_suffix: Option<HeaderValue>,
+
     /// samply.pki: Path to own secret key
     #[clap(long, env, value_parser, default_value = "/run/secrets/privkey.pem")]
     privkey_file: PathBuf,
@@ -65,6 +119,8 @@ pub struct Config {
     pub broker_domain: String,
     pub root_cert: X509,
     pub tls_ca_certificates: Vec<Certificate>,
+    pub http_connection: ConnectionSettings,
+    pub tls_client_identity: Option<ClientIdentity>,
 }
 
 #[derive(Debug, Clone)]
@@ -78,6 +134,7 @@ impl crate::config::Config for Config {
     fn load() -> Result<Self, SamplyBeamError> {
         let cli_args = CliArgs::parse();
         beam_lib::set_broker_id(cli_args.broker_url.host().unwrap().to_string());
+        let tls_client_identity = load_client_identity(&cli_args)?;
 
         let root_cert = crypto::load_certificates_from_file(cli_args.rootcert_file)?;
         let broker_domain = cli_args.broker_url.host();
@@ -86,24 +143,62 @@ impl crate::config::Config for Config {
         }
         let broker_domain = broker_domain.unwrap().to_string();
         let tls_ca_certificates_dir = cli_args.tls_ca_certificates_dir;
-        let tls_ca_certificates = crate::crypto::load_certificates_from_dir(
+        let tls_ca_certificates = crate::crypto::load_tls_ca_certificates(
             tls_ca_certificates_dir.clone(),
-        )
-        .map_err(|e| {
-            SamplyBeamError::ConfigurationFailed(format!(
-                "Unable to read from TLS CA directory: {}",
-                e
-            ))
-        })?;
+            cli_args.tls_ca_certificates_file.as_deref(),
+            cli_args.tls_ca_certificates_strict,
+        )?;
+        let ca_sources = [("TLS_CA_CERTIFICATES_DIR", &tls_ca_certificates_dir), ("TLS_CA_CERTIFICATES_FILE", &cli_args.tls_ca_certificates_file)]
+            .into_iter()
+            .filter_map(|(k, v)| v.as_deref().map(|v| (k, v)))
+            .collect::<Vec<_>>();
+        if let Some(warning) = http_client::ca_certificates_without_proxy_warning(&tls_ca_certificates, &ca_sources) {
+            warn!("{warning}");
+        }
+        let http_connection = ConnectionSettings {
+            connect_timeout: cli_args.http_connect_timeout.map(Duration::from_secs),
+            pool_idle_timeout: Some(Duration::from_secs(cli_args.http_pool_idle_timeout)),
+            pool_max_idle_per_host: cli_args.http_pool_max_idle_per_host.unwrap_or(usize::MAX),
+            min_tls_version: cli_args.tls_min_version,
+            tls_cipher_suites: cli_args.tls_cipher_suites.clone(),
+            danger_accept_invalid_certs: cli_args.danger_accept_invalid_certs,
+            user_agent: None,
+            user_agent_suffix: cli_args.http_user_agent_suffix.clone(),
+            ..Default::default()
+        };
         Ok(Config {
             broker_domain,
             tls_ca_certificates_dir,
             root_cert,
             tls_ca_certificates,
+            http_connection,
+            tls_client_identity,
         })
     }
 }
 
+fn load_client_identity(cli_args: &CliArgs) -> Result<Option<ClientIdentity>, SamplyBeamError> {
+    match (&cli_args.tls_client_cert_file, &cli_args.tls_client_key_file, &cli_args.tls_client_pkcs12_file) {
+        (None, None, None) => Ok(None),
+        (Some(cert), Some(key), None) => ClientIdentity::from_pem_files(cert, key).map(Some),
+        (None, None, Some(pkcs12)) => {
+            let password = match &cli_args.tls_client_pkcs12_password_file {
+                Some(file) => read_to_string(file).map_err(|e| {
+                    SamplyBeamError::ConfigurationFailed(format!(
+                        "Unable to read the password of the client certificate from {}: {e}",
+                        file.to_string_lossy()
+                    ))
+                })?,
+                None => String::new(),
+            };
+            ClientIdentity::from_pkcs12_file(pkcs12, password.trim_end_matches(['\r', '\n'])).map(Some)
+        }
+        _ => Err(SamplyBeamError::ConfigurationFailed(
+            "Set either both TLS_CLIENT_CERT_FILE and TLS_CLIENT_KEY_FILE or TLS_CLIENT_PKCS12_FILE for a client certificate".into(),
+        )),
+    }
+}
+
 fn get_enrollment_msg(proxy_id: &Option<String>) -> String {
     let divider = "***************************************************************************\n
                    ***              Beam Certificate Enrollment Warning                    ***\n
@@ -1,5 +1,6 @@
 use axum::{async_trait, body::Body, http::Request, Json};
 
+use futures_util::{stream, Stream, StreamExt};
 use itertools::Itertools;
 use once_cell::sync::{Lazy, OnceCell};
 use openssl::{
@@ -7,7 +8,8 @@ use openssl::{
     error::ErrorStack,
     rand::rand_bytes,
     string::OpensslString,
-    x509::{X509, X509Crl, CrlStatus},
+    stack::Stack,
+    x509::{X509, X509Crl, X509CrlRef, X509Ref, X509StoreContext, CrlStatus, store::{X509Store, X509StoreBuilder, X509StoreRef}},
 };
 use rsa::{
     pkcs1::DecodeRsaPublicKey, pkcs8::DecodePublicKey, RsaPrivateKey, RsaPublicKey, traits::PublicKeyParts,
@@ -15,14 +17,16 @@ use rsa::{
 use sha2::{Digest, Sha256};
 use std::{
     borrow::BorrowMut,
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     error::Error,
     fs::read_to_string,
+    future::Future,
     path::{Path, PathBuf},
     sync::Arc,
     time::{Duration, SystemTime},
 };
 use tokio::{sync::{mpsc, oneshot, RwLock}, time::Instant};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
 use tracing::{debug, error, info, warn};
 
 use beam_lib::{AppOrProxyId, ProxyId};
@@ -82,6 +86,50 @@ impl TryFrom<&X509> for ProxyCertInfo {
     }
 }
 
+/// A certificate from the PKI as listed by [`GetCerts::certificate_list_with_metadata`]
+#[derive(Debug)]
+pub struct CertificateListEntry {
+    pub serial: Serial,
+    /// Fetching or parsing a certificate only fails its own entry
+    pub metadata: Result<CertificateMetadata, SamplyBeamError>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CertificateMetadata {
+    pub common_name: String,
+    pub not_before: SystemTime,
+    pub not_after: SystemTime,
+    /// Whether the certificate is valid now, judging only by its validity period
+    pub valid: bool,
+}
+
+impl TryFrom<&X509> for CertificateMetadata {
+    type Error = SamplyBeamError;
+
+    fn try_from(cert: &X509) -> Result<Self, Self::Error> {
+        let common_name = cert
+            .subject_name()
+            .entries_by_nid(openssl::nid::Nid::COMMONNAME)
+            .next()
+            .ok_or(CertificateInvalidReason::NoCommonName)?
+            .data()
+            .to_string()?;
+        let not_before = asn1_time_to_system_time(cert.not_before())?;
+        let not_after = asn1_time_to_system_time(cert.not_after())?;
+        let now = SystemTime::now();
+        Ok(Self { common_name, not_before, not_after, valid: not_before < now && now < not_after })
+    }
+}
+
+/// The status of a certificate as reported by an OCSP responder
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CertificateStatus {
+    Good,
+    Revoked,
+    /// The responder does not know the certificate
+    Unknown,
+}
+
 #[derive(Clone, Debug)]
 pub enum CertificateCacheEntry {
     Valid(X509),
@@ -109,17 +157,153 @@ pub struct CertificateCache {
     update_trigger: mpsc::UnboundedSender<oneshot::Sender<Result<CertificateCacheUpdate, SamplyBeamError>>>,
     root_cert: Option<X509>, // Might not be available at initialization time
     im_cert: Option<X509>,   // Might not be available at initialization time
+    trust_store: Option<Arc<X509Store>>, // Built on first use from the root certificate and the CA chain
+}
+
+/// Data fetched from the PKI or, if it is unavailable, the last data it returned
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MaybeStale<T> {
+    Fresh(T),
+    Stale(T),
+}
+
+impl<T> MaybeStale<T> {
+    pub fn into_inner(self) -> T {
+        match self {
+            MaybeStale::Fresh(data) | MaybeStale::Stale(data) => data,
+        }
+    }
+
+    pub fn is_stale(&self) -> bool {
+        matches!(self, MaybeStale::Stale(_))
+    }
+}
+
+impl<T> AsRef<T> for MaybeStale<T> {
+    fn as_ref(&self) -> &T {
+        match self {
+            MaybeStale::Fresh(data) | MaybeStale::Stale(data) => data,
+        }
+    }
 }
 
 #[async_trait]
 pub trait GetCerts: Sync + Send {
     async fn certificate_list_via_network(&self) -> Result<Vec<String>, SamplyBeamError>;
     async fn certificate_by_serial_as_pem(&self, serial: &str) -> Result<String, SamplyBeamError>;
+    /// Like [`GetCerts::certificate_list_via_network`] but tells whether the list is stale.
+    /// Only implementations which fall back to old data if the PKI is unavailable override this.
+    async fn certificate_list_or_stale(&self) -> Result<MaybeStale<Vec<String>>, SamplyBeamError> {
+        self.certificate_list_via_network().await.map(MaybeStale::Fresh)
+    }
+    /// Like [`GetCerts::certificate_by_serial_as_pem`] but tells whether the certificate is stale
+    async fn certificate_by_serial_or_stale(&self, serial: &str) -> Result<MaybeStale<String>, SamplyBeamError> {
+        self.certificate_by_serial_as_pem(serial).await.map(MaybeStale::Fresh)
+    }
+    /// Like [`GetCerts::certificate_by_serial_as_pem`] but parsed. Anything but exactly one certificate is a `CertificateError`.
+    async fn certificate_by_serial(&self, serial: &str) -> Result<X509, SamplyBeamError> {
+        parse_single_certificate(&self.certificate_by_serial_as_pem(serial).await?)
+    }
+    /// Fetches up to [`GetCerts::fetch_concurrency`] certificates at a time, in no particular order.
+    /// A failure only affects the certificate it occurred for.
+    async fn certificates_by_serials(&self, serials: &[String]) -> Vec<(String, Result<String, SamplyBeamError>)> {
+        stream::iter(serials.to_vec())
+            .map(|serial| async move {
+                let pem = self.certificate_by_serial_as_pem(&serial).await;
+                (serial, pem)
+            })
+            .buffer_unordered(self.fetch_concurrency().max(1))
+            .collect()
+            .await
+    }
+    /// All certificates on the list with their common name and validity period, e.g. to see which are about to expire.
+    /// The certificates are fetched concurrently like in [`GetCerts::certificates_by_serials`].
+    async fn certificate_list_with_metadata(&self) -> Result<Vec<CertificateListEntry>, SamplyBeamError> {
+        let serials = self.certificate_list_via_network().await?;
+        let mut entries: Vec<_> = self
+            .certificates_by_serials(&serials)
+            .await
+            .into_iter()
+            .map(|(serial, pem)| {
+                let metadata = pem
+                    .and_then(|pem| parse_single_certificate(&pem))
+                    .and_then(|cert| CertificateMetadata::try_from(&cert));
+                CertificateListEntry { serial, metadata }
+            })
+            .collect();
+        entries.sort_by(|a, b| a.serial.cmp(&b.serial));
+        Ok(entries)
+    }
+    /// The certificate whose SHA-256 fingerprint is given in hex, with or without colons. Fails with
+    /// [`SamplyBeamError::CertificateFingerprintNotFound`] if no listed certificate matches. This fetches all certificates on the list,
+    /// so implementations which can look fingerprints up directly should override this.
+    async fn certificate_by_fingerprint(&self, sha256_hex: &str) -> Result<X509, SamplyBeamError> {
+        let fingerprint = normalize_fingerprint(sha256_hex);
+        let serials = self.certificate_list_via_network().await?;
+        for (_, pem) in self.certificates_by_serials(&serials).await {
+            let Ok(cert) = pem.and_then(|pem| parse_single_certificate(&pem)) else {
+                continue;
+            };
+            if sha256_fingerprint(&cert)? == fingerprint {
+                return Ok(cert);
+            }
+        }
+        Err(SamplyBeamError::CertificateFingerprintNotFound(sha256_hex.to_owned()))
+    }
+    /// How many certificates [`GetCerts::certificates_by_serials`] fetches concurrently
+    fn fetch_concurrency(&self) -> usize { 8 }
+    /// Only the intermediate CA certificate which issues the proxies' certificates
     async fn im_certificate_as_pem(&self) -> Result<String, SamplyBeamError>;
+    /// The concatenated PEM certificates of the chain from the intermediate CA up to the root,
+    /// needed to build a complete trust path. Defaults to the intermediate CA certificate alone.
+    async fn ca_chain_as_pem(&self) -> Result<String, SamplyBeamError> { self.im_certificate_as_pem().await }
     /// A callback that runs on a timer and returns if the cache changed
     async fn on_timer(&self, _cache: &mut CertificateCache) -> CertificateCacheUpdate { CertificateCacheUpdate::UnChanged }
     async fn on_cert_expired(&self, _expired_cert: X509) {}
-    async fn get_crl(&self) -> Result<Option<X509Crl>, SamplyBeamError> { Ok(None) }
+    /// The PKI's CRL, or `None` if there is none or the implementation does not check CRLs. It is asked for whenever a message
+    /// is checked and whenever the cache is updated, so implementations which fetch it should cache it.
+    async fn get_crl(&self) -> Result<Option<Arc<X509Crl>>, SamplyBeamError> { Ok(None) }
+    /// The certificate's status according to an OCSP responder, or `None` if the implementation does not check OCSP
+    async fn ocsp_status(&self, _cert: &X509, _issuer: &X509) -> Result<Option<CertificateStatus>, SamplyBeamError> { Ok(None) }
+    /// Whether the certificate is on the CRL from [`GetCerts::get_crl`]
+    async fn is_revoked(&self, cert: &X509) -> Result<bool, SamplyBeamError> {
+        Ok(self.get_crl().await?.is_some_and(|crl| crl_revokes(&crl, cert)))
+    }
+    /// How long to wait before the next timed refresh of the cache
+    fn refresh_interval(&self) -> Duration { Duration::from_secs(60) }
+    /// Checks whether the source of the certificates can currently serve them.
+    /// Sources without a backing service, e.g. a directory, are always healthy.
+    async fn check_health(&self) -> Result<(), SamplyBeamError> { Ok(()) }
+    /// Waits until the source is able to serve certificates at all, e.g. until Vault has been unsealed.
+    /// Called in the background at startup, so requests are answered in the meantime.
+    async fn wait_until_ready(&self) -> Result<(), SamplyBeamError> { Ok(()) }
+    /// Fetches certificates ahead of time so that the first messages do not wait for the source.
+    /// Failures are only logged, as the certificates are fetched again when needed.
+    async fn prefetch(&self) {}
+    /// Called on every lookup in the [`CertificateCache`] with whether a valid certificate was cached, e.g. to measure its hit rate
+    fn on_cache_lookup(&self, _hit: bool) {}
+    /// Forgets what is cached about the certificate with this serial, so that it is fetched anew when needed
+    fn invalidate(&self, _serial: &str) {}
+    /// Forgets the cached certificate list and all cached certificates
+    fn invalidate_all(&self) {}
+    /// Stops the implementation's background tasks and waits for them to finish
+    async fn shutdown(&self) {}
+}
+
+/// Yields the certificates on the list as `(serial, pem)` while they are fetched, up to [`GetCerts::fetch_concurrency`]
+/// at a time and in no particular order, so that callers can process them one by one instead of holding all of them.
+/// Certificates are only fetched as the stream is polled. A failure only affects the certificate it occurred for,
+/// while a failure to fetch the list is the only item.
+pub fn certificates_stream<G: GetCerts + ?Sized>(getter: &G) -> impl Stream<Item = Result<(String, String), SamplyBeamError>> + Send + '_ {
+    stream::once(getter.certificate_list_via_network())
+        .map(move |serials| match serials {
+            Ok(serials) => stream::iter(serials)
+                .map(move |serial| async move { getter.certificate_by_serial_as_pem(&serial).await.map(|pem| (serial, pem)) })
+                .buffer_unordered(getter.fetch_concurrency().max(1))
+                .left_stream(),
+            Err(e) => stream::iter([Err(e)]).right_stream(),
+        })
+        .flatten()
 }
 
 impl CertificateCache {
@@ -132,6 +316,7 @@ impl CertificateCache {
             update_trigger,
             root_cert: None,
             im_cert: None,
+            trust_store: None,
         }
     }
 
@@ -203,7 +388,9 @@ impl CertificateCache {
     pub async fn get_all_certs_by_cname(cname: &ProxyId) -> Vec<CertificateCacheEntry> {
         // TODO: What if multiple certs are found?
         let mut result = get_all_certs_from_cache_by_cname(cname).await; // Drop Read Locks
-        if result.iter().filter(|cert| matches!(cert, CertificateCacheEntry::Valid(_))).count() == 0 {
+        let hit = result.iter().any(|cert| matches!(cert, CertificateCacheEntry::Valid(_)));
+        record_cache_lookup(hit);
+        if !hit {
             
             // requires write lock.
             Self::update_certificates().await.unwrap_or_else(|e| {
@@ -227,9 +414,11 @@ impl CertificateCache {
             // TODO: Do smart caching: Return reference to existing certificate that exists only once in memory.
             let cache = CERT_CACHE.read().await;
             if let Some(CertificateCacheEntry::Valid(cert)) = cache.serial_to_x509.get(serial) {
+                record_cache_lookup(true);
                 return Some(cert.clone());
             }
         }
+        record_cache_lookup(false);
         Self::update_certificates().await.unwrap_or_else(|e| {
             // requires write lock.
             warn!("Updating certificates failed: {}", e);
@@ -272,11 +461,29 @@ impl CertificateCache {
         }
     }
 
+    fn remove_certificate(&mut self, serial: &str) -> bool {
+        self.cn_to_serial.retain(|_, serials| {
+            serials.retain(|other| other != serial);
+            !serials.is_empty()
+        });
+        self.serial_to_x509.remove(serial).is_some()
+    }
+
+    /// Drops the certificates whose serials are not in `serials`, e.g. as they are no longer on the PKI's list,
+    /// and returns how many were dropped
+    pub fn retain_listed(&mut self, serials: &HashSet<&str>) -> usize {
+        let unlisted: Vec<_> = self.serial_to_x509.keys().filter(|serial| !serials.contains(serial.as_str())).cloned().collect();
+        for serial in &unlisted {
+            self.remove_certificate(serial);
+        }
+        unlisted.len()
+    }
+
     fn invalidate_revoked_certs(&mut self, crl: &X509Crl) -> usize {
         let mut revoked_certs = 0;
         self.serial_to_x509.values_mut().for_each(|cert_entry| {
             if let CertificateCacheEntry::Valid(ref cert) = cert_entry {
-                if is_revoked(crl.get_by_cert(cert)) {
+                if crl_revokes(crl, cert) {
                     *cert_entry = CertificateCacheEntry::Invalid(CertificateInvalidReason::Revoked);
                     revoked_certs += 1;
                 }
@@ -295,9 +502,10 @@ impl CertificateCache {
             .map(|crl| self.invalidate_revoked_certs(crl))
             .unwrap_or_default();
         debug!("Revoked {revoked_certs} certificates from cache.");
-        let new_certificate_serials: Vec<&String> = certificate_list
+        let new_certificate_serials: Vec<String> = certificate_list
             .iter()
             .filter(|serial| !self.serial_to_x509.contains_key(*serial))
+            .cloned()
             .collect();
         debug!(
             "Received {} certificates ({} of which were new).",
@@ -306,41 +514,42 @@ impl CertificateCache {
         );
 
         let mut new_count = 0;
+        let new_certificates = CERT_GETTER
+            .get()
+            .unwrap()
+            .certificates_by_serials(&new_certificate_serials)
+            .await;
         //TODO Check for validity
-        for serial in new_certificate_serials {
+        for (ref serial, certificate) in new_certificates {
             debug!("Checking certificate with serial {serial}");
 
-            let certificate = CERT_GETTER
-                .get()
-                .unwrap()
-                .certificate_by_serial_as_pem(serial)
-                .await;
-            if let Err(e) = certificate {
-                match e {
-                    SamplyBeamError::CertificateError(err) => {
-                        debug!("Will skip invalid certificate {serial} from now on.");
-                        self.serial_to_x509
-                            .insert(serial.clone(), CertificateCacheEntry::Invalid(err));
-                    }
-                    other_error => {
-                        warn!(
-                            "Could not retrieve certificate for serial {serial}: {}",
-                            other_error
-                        );
-                    }
-                };
-                continue;
-            }
-            let certificate = certificate.unwrap();
-            let opensslcert = match X509::from_pem(certificate.as_bytes()) {
-                Ok(x) => x,
-                Err(err) => {
-                    error!("Skipping unparsable certificate {serial}: {err}");
+            let opensslcert = match certificate.and_then(|pem| parse_single_certificate(&pem)) {
+                Ok(cert) => cert,
+                Err(e) => {
+                    match e {
+                        // Certificates which could not be read or parsed may just have been truncated on the way, so they are fetched again with the next update
+                        SamplyBeamError::CertificateError(err) if is_definitive(&err) => {
+                            debug!("Will skip invalid certificate {serial} from now on: {err}");
+                            self.serial_to_x509
+                                .insert(serial.clone(), CertificateCacheEntry::Invalid(err));
+                        }
+                        SamplyBeamError::CertificateExpired { not_after, .. } => {
+                            debug!("Will skip certificate {serial} from now on as it is not valid now (valid until {not_after}).");
+                            self.serial_to_x509
+                                .insert(serial.clone(), CertificateCacheEntry::Invalid(CertificateInvalidReason::InvalidDate));
+                        }
+                        other_error => {
+                            warn!(
+                                "Could not retrieve certificate for serial {serial}: {}",
+                                other_error
+                            );
+                        }
+                    };
                     continue;
                 }
             };
             // Check if the new cert is already revoked
-            if certificate_revocation_list.as_ref().is_some_and(|list| is_revoked(list.get_by_cert(&opensslcert))) {
+            if certificate_revocation_list.as_ref().is_some_and(|crl| crl_revokes(crl, &opensslcert)) {
                 self.serial_to_x509.insert(serial.clone(), CertificateCacheEntry::Invalid(CertificateInvalidReason::Revoked));
                 revoked_certs += 1;
                 continue;
@@ -416,13 +625,23 @@ impl CertificateCache {
         result
     }*/
 
+    /// Forgets all certificates and the trust store built from the CA chain
+    fn clear(&mut self) {
+        self.serial_to_x509.clear();
+        self.cn_to_serial.clear();
+        self.trust_store = None;
+    }
+
     /// Sets the root certificate, which is usually not available at static time. Must be called before certificate validation
     pub fn set_root_cert(&mut self, root_certificate: &X509) {
         self.root_cert = Some(root_certificate.clone());
+        self.trust_store = None;
     }
 
     pub async fn set_im_cert(&mut self) -> Result<(), SamplyBeamError> {
         self.im_cert = Some(X509::from_pem(&get_im_cert().await.unwrap().as_bytes())?);
+        // The CA chain may have been rotated along with the intermediate certificate
+        self.trust_store = None;
         let _ = verify_cert(&self.im_cert.as_ref().expect("No IM certificate provided"), &self.root_cert.as_ref().expect("No root certificate set!"))
             .expect(&format!("The intermediate certificate is invalid. Please send this info to the central beam admin for debugging:\n---BEGIN DEBUG---\n{}\nroot\n{}\n---END DEBUG---", 
                              String::from_utf8(self.im_cert.as_ref().unwrap().to_text().unwrap_or("Cannot convert IM certificate to text".into())).unwrap_or("Invalid characters in IM certificate".to_string()),
@@ -431,6 +650,12 @@ impl CertificateCache {
     }
 }
 
+fn record_cache_lookup(hit: bool) {
+    if let Some(getter) = CERT_GETTER.get() {
+        getter.on_cache_lookup(hit);
+    }
+}
+
 async fn get_all_certs_from_cache_by_cname(cname: &ProxyId) -> Vec<CertificateCacheEntry> {
     let mut result = Vec::new();
         
@@ -493,13 +718,24 @@ pub async fn init_ca_chain() -> Result<(), SamplyBeamError> {
 
 static CERT_GETTER: OnceCell<Box<dyn GetCerts>> = OnceCell::new();
 
-pub fn init_cert_getter<G: GetCerts + 'static>(getter: G) {
-    let res = CERT_GETTER.set(Box::new(getter));
+pub fn init_cert_getter(getter: Box<dyn GetCerts>) {
+    let res = CERT_GETTER.set(getter);
     if res.is_err() {
         panic!("Internal error: Tried to initialize cert_getter twice");
     }
 }
 
+/// Stops the background tasks of the certificate cache and of the certificate source, if it has been initialized,
+/// and waits for them to finish
+pub async fn shutdown_cert_getter() {
+    CACHE_SHUTDOWN.cancel();
+    CACHE_TASKS.close();
+    CACHE_TASKS.wait().await;
+    if let Some(getter) = CERT_GETTER.get() {
+        getter.shutdown().await;
+    }
+}
+
 pub async fn get_serial_list() -> Vec<String> {
     let cache = CERT_CACHE.read().await;
     cache.serial_to_x509.iter()
@@ -509,25 +745,159 @@ pub async fn get_serial_list() -> Vec<String> {
         .collect()
 }
 
+/// Whether a certificate is invalid for good rather than just failed to be read or parsed
+fn is_definitive(reason: &CertificateInvalidReason) -> bool {
+    !matches!(reason, CertificateInvalidReason::Other(_) | CertificateInvalidReason::InternalError(_))
+}
+
+/// Parses a PEM string which must contain exactly one certificate
+pub fn parse_single_certificate(pem: &str) -> Result<X509, SamplyBeamError> {
+    let mut certs = X509::stack_from_pem(pem.as_bytes())
+        .map_err(|e| CertificateInvalidReason::Other(format!("Unable to parse certificate: {e}")))?;
+    match certs.len() {
+        1 => Ok(certs.remove(0)),
+        n => Err(CertificateInvalidReason::Other(format!("Expected a single certificate but got {n}")).into()),
+    }
+}
+
+/// The certificate's SHA-256 fingerprint as lowercase hex without colons
+pub fn sha256_fingerprint(cert: &X509) -> Result<String, SamplyBeamError> {
+    Ok(cert
+        .digest(openssl::hash::MessageDigest::sha256())?
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect())
+}
+
+/// Brings a hex fingerprint into the form of [`sha256_fingerprint`], e.g. from `AB:CD:…` as printed by `openssl x509 -fingerprint`
+pub fn normalize_fingerprint(fingerprint: &str) -> String {
+    fingerprint.trim().replace(':', "").to_ascii_lowercase()
+}
+
+/// [`certificates_stream`] of the configured source of certificates
+pub fn stream_certificates() -> impl Stream<Item = Result<(String, String), SamplyBeamError>> + Send {
+    certificates_stream(CERT_GETTER.get().unwrap().as_ref())
+}
+
 pub async fn get_im_cert() -> Result<String, SamplyBeamError> {
     CERT_GETTER.get().unwrap().im_certificate_as_pem().await
 }
 
+pub async fn get_ca_chain() -> Result<String, SamplyBeamError> {
+    CERT_GETTER.get().unwrap().ca_chain_as_pem().await
+}
+
+pub async fn check_cert_source_health() -> Result<(), SamplyBeamError> {
+    CERT_GETTER.get().unwrap().check_health().await
+}
+
+pub async fn wait_for_cert_source() -> Result<(), SamplyBeamError> {
+    CERT_GETTER.get().unwrap().wait_until_ready().await
+}
+
+pub async fn prefetch_certificates() {
+    CERT_GETTER.get().unwrap().prefetch().await
+}
+
+/// Drops a certificate from all caches, e.g. after it was replaced out-of-band, so that it is fetched again
+/// with the next update instead of being used until the cache expires. Returns whether it was cached.
+pub async fn invalidate_certificate(serial: &str) -> bool {
+    CERT_GETTER.get().unwrap().invalidate(serial);
+    CERT_CACHE.write().await.remove_certificate(serial)
+}
+
+/// Drops all certificates from the caches so that they are fetched again as needed
+pub async fn invalidate_all_certificates() {
+    CERT_GETTER.get().unwrap().invalidate_all();
+    CERT_CACHE.write().await.clear();
+}
+
+/// A store with the root certificate and the CA chain from the PKI to verify certificates against, e.g. with
+/// [`openssl::x509::X509StoreContext`]. It is built on first use and rebuilt with the CA chain fetched anew
+/// after every refresh of the certificate cache, once the intermediate certificate has been set anew and after all certificates have been invalidated.
+pub async fn trust_store() -> Result<Arc<X509Store>, SamplyBeamError> {
+    if let Some(store) = CERT_CACHE.read().await.trust_store.clone() {
+        return Ok(store);
+    }
+    let chain = get_ca_chain().await?;
+    let mut cache = CERT_CACHE.write().await;
+    if let Some(store) = cache.trust_store.clone() {
+        return Ok(store);
+    }
+    let store = Arc::new(build_trust_store(&chain, cache.root_cert.as_ref())?);
+    cache.trust_store = Some(store.clone());
+    Ok(store)
+}
+
+/// Fails with a [`SamplyBeamError::CertificateError`] naming OpenSSL's reason unless the certificate
+/// chains up to the PKI's intermediate CA, checked against the [`trust_store`]
+pub async fn verify_issued_by_ca(cert: &X509) -> Result<(), SamplyBeamError> {
+    let store = trust_store().await?;
+    let im_cert = CERT_CACHE
+        .read()
+        .await
+        .im_cert
+        .clone()
+        .ok_or_else(|| CertificateInvalidReason::InternalError("The intermediate CA certificate has not been fetched".into()))?;
+    verify_chain(&store, &im_cert, cert)
+}
+
+/// Verifies the certificate against the store and makes sure that the intermediate CA is part of the chain,
+/// so that certificates issued by another CA in the store, e.g. the root, are rejected
+pub fn verify_chain(store: &X509StoreRef, im_cert: &X509Ref, cert: &X509) -> Result<(), SamplyBeamError> {
+    let mut ctx = X509StoreContext::new()?;
+    let untrusted: Stack<X509> = Stack::new()?;
+    let result = ctx.init(store, cert, &untrusted, |ctx| {
+        if !ctx.verify_cert()? {
+            return Ok(Err(CertificateInvalidReason::Other(format!("Unable to verify the certificate chain: {}", ctx.error()))));
+        }
+        let via_im_cert = ctx.chain().is_some_and(|chain| chain.iter().skip(1).any(|ca| ca == im_cert));
+        Ok(if via_im_cert {
+            Ok(())
+        } else {
+            Err(CertificateInvalidReason::Other("The certificate was not issued by the intermediate CA".into()))
+        })
+    })?;
+    Ok(result?)
+}
+
+/// Builds a store from the concatenated PEM certificates of a CA chain and, if given, the root certificate.
+/// Certificates which occur more than once, e.g. a root that is also part of the chain, are only added once.
+pub fn build_trust_store(chain_pem: &str, root_cert: Option<&X509>) -> Result<X509Store, SamplyBeamError> {
+    let chain = X509::stack_from_pem(chain_pem.as_bytes())
+        .map_err(|e| CertificateInvalidReason::Other(format!("Unable to parse the CA chain: {e}")))?;
+    if chain.is_empty() {
+        return Err(CertificateInvalidReason::Other("The CA chain contains no certificates".into()).into());
+    }
+    let mut builder = X509StoreBuilder::new()?;
+    let mut added = Vec::new();
+    for cert in root_cert.into_iter().chain(&chain) {
+        let digest = cert.digest(openssl::hash::MessageDigest::sha256())?.to_vec();
+        if added.contains(&digest) {
+            continue;
+        }
+        builder.add_cert(cert.clone())?;
+        added.push(digest);
+    }
+    Ok(builder.build())
+}
+
 pub(crate) static CERT_CACHE: Lazy<Arc<RwLock<CertificateCache>>> = Lazy::new(|| {
     let (tx_refresh, mut rx_refresh) = mpsc::unbounded_channel::<oneshot::Sender<Result<CertificateCacheUpdate, SamplyBeamError>>>();
     let (tx_newcerts, mut rx_newcerts) = mpsc::channel::<()>(1);
     let cc = Arc::new(RwLock::new(CertificateCache::new(tx_refresh)));
     let cc2 = cc.clone();
     let cc3: Arc<RwLock<CertificateCache>> = cc.clone();
-    tokio::task::spawn(async move {
+    spawn_cache_task(async move {
         loop {
+            let refresh_interval = CERT_GETTER.get().unwrap().refresh_interval();
             let sender = tokio::select! {
                 Some(sender) = rx_refresh.recv() => {
                     debug!("Certificate cache refresh triggered by another component.");
                     Some(sender)
                 },
-                _ = tokio::time::sleep(Duration::from_secs(60)) => {
-                    debug!("Certificate cache refresh after 60 seconds ...");
+                _ = tokio::time::sleep(refresh_interval) => {
+                    debug!("Certificate cache refresh after {} seconds ...", refresh_interval.as_secs());
                     None
                 }
             };
@@ -546,6 +916,8 @@ pub(crate) static CERT_CACHE: Lazy<Arc<RwLock<CertificateCache>>> = Lazy::new(||
                 // Note: This currently only updates the Cache on the broker as the default implementation of `GetCerts` does no update the cache 
                 update = CERT_GETTER.get().unwrap().on_timer(&mut locked_cache).await;
             }
+            // The CA chain may have been rotated in the meantime
+            locked_cache.trust_store = None;
             if let CertificateCacheUpdate::Updated(count) = update {
                 info!("Added {count} new certificates.");
                 if let Err(e) = tx_newcerts.send(()).await {
@@ -561,7 +933,7 @@ pub(crate) static CERT_CACHE: Lazy<Arc<RwLock<CertificateCache>>> = Lazy::new(||
             }
         }
     });
-    tokio::spawn(async move {
+    spawn_cache_task(async move {
         loop {
             CertificateCache::wait_and_remove_oldest_cert(cc3.clone(), &mut rx_newcerts).await;
         }
@@ -569,6 +941,19 @@ pub(crate) static CERT_CACHE: Lazy<Arc<RwLock<CertificateCache>>> = Lazy::new(||
     cc
 });
 
+/// Stops the loops keeping the [`CERT_CACHE`] up to date, see [`shutdown_cert_getter`]
+static CACHE_SHUTDOWN: Lazy<CancellationToken> = Lazy::new(CancellationToken::new);
+static CACHE_TASKS: Lazy<TaskTracker> = Lazy::new(TaskTracker::new);
+
+/// Runs a loop of the certificate cache until [`shutdown_cert_getter`], which waits for it.
+/// The loop is dropped at its next `.await`, e.g. while waiting for the certificate source.
+fn spawn_cache_task(task: impl Future<Output = ()> + Send + 'static) {
+    let shutdown = CACHE_SHUTDOWN.clone();
+    CACHE_TASKS.spawn(async move {
+        shutdown.run_until_cancelled(task).await;
+    });
+}
+
 async fn get_cert_by_serial(serial: &str) -> Option<X509> {
     CertificateCache::get_by_serial(serial).await
 }
@@ -766,13 +1151,37 @@ pub fn load_certificates_from_file(ca_file: PathBuf) -> Result<X509, SamplyBeamE
     cert
 }
 
-pub fn load_certificates_from_dir(ca_dir: Option<PathBuf>) -> Result<Vec<reqwest::Certificate>, std::io::Error> {
+/// Warns about CA certificates in `pem` which are expired or not yet valid, as they break TLS connections in confusing ways.
+/// With `strict`, such a certificate is an error instead.
+fn check_ca_validity(pem: &[u8], source: &Path, strict: bool) -> Result<(), SamplyBeamError> {
+    // Certificates which OpenSSL cannot parse are reported when they are loaded
+    let (Ok(certs), Ok(now)) = (X509::stack_from_pem(pem), Asn1Time::days_from_now(0)) else {
+        return Ok(());
+    };
+    for cert in certs {
+        let problem = if cert.not_after() < now {
+            format!("expired on {}", cert.not_after())
+        } else if cert.not_before() > now {
+            format!("is not valid before {}", cert.not_before())
+        } else {
+            continue;
+        };
+        let msg = format!("CA certificate {:?} from {} {problem}", cert.subject_name(), source.to_string_lossy());
+        if strict {
+            return Err(CertificateInvalidReason::Other(msg).into());
+        }
+        warn!("{msg}; TLS connections relying on it will fail");
+    }
+    Ok(())
+}
+
+pub fn load_certificates_from_dir(ca_dir: Option<PathBuf>, strict: bool) -> Result<Vec<reqwest::Certificate>, SamplyBeamError> {
+    let io_error = |e: std::io::Error| SamplyBeamError::ConfigurationFailed(format!("Unable to read from TLS CA directory: {}", e));
     let mut result = Vec::new();
     if let Some(ca_dir) = ca_dir {
-        for file in ca_dir.read_dir()? {
-            //.map_err(|e| SamplyBeamError::ConfigurationFailed(format!("Unable to read from TLS CA directory {}: {}", ca_dir.to_string_lossy(), e)))
-            let path = file?.path();
-            let content = std::fs::read(&path)?;
+        for file in ca_dir.read_dir().map_err(io_error)? {
+            let path = file.map_err(io_error)?.path();
+            let content = std::fs::read(&path).map_err(io_error)?;
             let cert = reqwest::Certificate::from_pem(&content);
             if let Err(e) = cert {
                 warn!(
@@ -782,12 +1191,42 @@ pub fn load_certificates_from_dir(ca_dir: Option<PathBuf>) -> Result<Vec<reqwest
                 );
                 continue;
             }
+            check_ca_validity(&content, &path, strict)?;
             result.push(cert.unwrap());
         }
     }
     Ok(result)
 }
 
+/// Loads all certificates from a single PEM file, e.g. a distribution's `/etc/ssl/certs/ca-bundle.pem`
+pub fn load_certificates_from_bundle(bundle: &Path, strict: bool) -> Result<Vec<reqwest::Certificate>, SamplyBeamError> {
+    let content = std::fs::read(bundle).map_err(|e| {
+        SamplyBeamError::ConfigurationFailed(format!("Unable to read CA certificate bundle {}: {e}", bundle.to_string_lossy()))
+    })?;
+    let certs = reqwest::Certificate::from_pem_bundle(&content).map_err(|e| {
+        SamplyBeamError::ConfigurationFailed(format!("Unable to read certificates from bundle {}: {e}", bundle.to_string_lossy()))
+    })?;
+    if certs.is_empty() {
+        return Err(SamplyBeamError::ConfigurationFailed(format!(
+            "CA certificate bundle {} contains no certificates",
+            bundle.to_string_lossy()
+        )));
+    }
+    check_ca_validity(&content, bundle, strict)?;
+    debug!("Loaded {} certificates from bundle {}", certs.len(), bundle.to_string_lossy());
+    Ok(certs)
+}
+
+/// Loads the CA certificates to trust for TLS connections from both the directory and the bundle, if given.
+/// With `strict`, expired or not yet valid certificates are an error instead of a warning.
+pub fn load_tls_ca_certificates(ca_dir: Option<PathBuf>, ca_bundle: Option<&Path>, strict: bool) -> Result<Vec<reqwest::Certificate>, SamplyBeamError> {
+    let mut certs = load_certificates_from_dir(ca_dir, strict)?;
+    if let Some(bundle) = ca_bundle {
+        certs.extend(load_certificates_from_bundle(bundle, strict)?);
+    }
+    Ok(certs)
+}
+
 /// Checks whether or not a x509 certificate matches a private key by comparing the (public) modulus
 pub fn is_cert_from_privkey(cert: &X509, key: &RsaPrivateKey) -> Result<bool, ErrorStack> {
     let cert_rsa = cert.public_key()?.rsa()?;
@@ -811,8 +1250,34 @@ pub fn is_cert_from_privkey(cert: &X509, key: &RsaPrivateKey) -> Result<bool, Er
     return Ok(is_equal);
 }
 
-pub fn parse_crl(der: &[u8]) -> Result<openssl::x509::X509Crl, SamplyBeamError> {
-    Ok(openssl::x509::X509Crl::from_der(der)?)
+/// Parses a CRL in DER or, as some distribution points serve it, PEM format
+pub fn parse_crl(data: &[u8]) -> Result<openssl::x509::X509Crl, SamplyBeamError> {
+    if data.trim_ascii_start().starts_with(b"-----BEGIN") {
+        Ok(openssl::x509::X509Crl::from_pem(data)?)
+    } else {
+        Ok(openssl::x509::X509Crl::from_der(data)?)
+    }
+}
+
+pub fn crl_revokes(crl: &X509CrlRef, cert: &X509) -> bool {
+    is_revoked(crl.get_by_cert(cert))
+}
+
+/// Fails with [`SamplyBeamError::CertificateRevoked`] if the certificate is on the PKI's CRL or its OCSP responder says it has been revoked
+pub async fn check_not_revoked(cert: &X509) -> Result<(), SamplyBeamError> {
+    let getter = CERT_GETTER.get().unwrap();
+    let mut revoked = getter.is_revoked(cert).await?;
+    if !revoked {
+        let issuer = CERT_CACHE.read().await.im_cert.clone();
+        if let Some(issuer) = issuer {
+            revoked = getter.ocsp_status(cert, &issuer).await? == Some(CertificateStatus::Revoked);
+        }
+    }
+    if revoked {
+        let serial = cert.serial_number().to_bn()?.to_hex_str()?.to_string();
+        return Err(SamplyBeamError::CertificateRevoked(serial));
+    }
+    Ok(())
 }
 
 fn is_revoked(status: CrlStatus<'_>) -> bool {
@@ -903,6 +1368,63 @@ mod tests {
     use openssl::{x509::{X509NameBuilder, extension::{BasicConstraints, KeyUsage, SubjectKeyIdentifier}}, bn::{BigNum, MsbOption}, pkey::PKey, rsa::Rsa, hash::MessageDigest};
 
     use super::*;
+    use crate::crypto_mock::MockGetCerts;
+
+    #[test]
+    fn test_load_certificates_from_bundle() {
+        let dir = std::env::temp_dir().join(format!("beam-ca-bundle-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("certs")).unwrap();
+        let cert = std::str::from_utf8(CERT_TO_REVOKE).unwrap();
+        std::fs::write(dir.join("certs/single.pem"), cert).unwrap();
+        let bundle = dir.join("ca-bundle.pem");
+        std::fs::write(&bundle, format!("# Comments and other text between certificates are skipped\n{cert}\n{cert}\n")).unwrap();
+        assert_eq!(load_certificates_from_bundle(&bundle, false).unwrap().len(), 2);
+        assert_eq!(load_tls_ca_certificates(Some(dir.join("certs")), Some(&bundle), false).unwrap().len(), 3, "Both sources are merged");
+        assert_eq!(load_tls_ca_certificates(None, Some(&bundle), false).unwrap().len(), 2);
+        assert!(matches!(
+            load_tls_ca_certificates(Some(dir.join("certs")), None, true),
+            Err(SamplyBeamError::CertificateError(_))
+        ), "The certificate has expired");
+
+        let empty = dir.join("empty.pem");
+        std::fs::write(&empty, "no certificates here").unwrap();
+        let broken = dir.join("broken.pem");
+        std::fs::write(&broken, "-----BEGIN CERTIFICATE-----\nnot base64!\n-----END CERTIFICATE-----\n").unwrap();
+        for file in [empty, broken, dir.join("missing.pem")] {
+            match load_tls_ca_certificates(None, Some(&file), false) {
+                Err(SamplyBeamError::ConfigurationFailed(msg)) => assert!(msg.contains(&*file.to_string_lossy()), "{msg}"),
+                other => panic!("Expected a configuration error for {file:?}, got {other:?}"),
+            }
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_check_ca_validity() {
+        let signed_pem = |not_before: u32, not_after: u32| {
+            let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+            let mut builder = X509::builder().unwrap();
+            builder.set_pubkey(&key).unwrap();
+            builder.set_not_before(&Asn1Time::days_from_now(not_before).unwrap()).unwrap();
+            builder.set_not_after(&Asn1Time::days_from_now(not_after).unwrap()).unwrap();
+            builder.sign(&key, MessageDigest::sha256()).unwrap();
+            builder.build().to_pem().unwrap()
+        };
+        let source = Path::new("/etc/samply/cacerts/ca.pem");
+        let strict_error = |pem: &[u8]| match check_ca_validity(pem, source, true) {
+            Err(SamplyBeamError::CertificateError(CertificateInvalidReason::Other(msg))) => msg,
+            other => panic!("Expected a certificate error, got {other:?}"),
+        };
+
+        assert!(check_ca_validity(&signed_pem(0, 30), source, true).is_ok());
+        let not_yet_valid = signed_pem(30, 60);
+        assert!(check_ca_validity(&not_yet_valid, source, false).is_ok(), "Only a warning by default");
+        let msg = strict_error(&not_yet_valid);
+        assert!(msg.contains("is not valid before") && msg.contains("cacerts/ca.pem"), "{msg}");
+        assert!(check_ca_validity(CERT_TO_REVOKE, source, false).is_ok());
+        let msg = strict_error(CERT_TO_REVOKE);
+        assert!(msg.contains("expired on Sep 23") && msg.contains("commonName"), "{msg}");
+    }
 
     fn build_x509(ttl: Duration) -> X509 {
         let mut builder = X509::builder().unwrap();
@@ -915,20 +1437,7 @@ mod tests {
     #[tokio::test]
     async fn test_invalidation() {
         // Setup fake CertGetter that does nothing
-        struct DummyCertGetter;
-        #[async_trait]
-        impl GetCerts for DummyCertGetter {
-            async fn certificate_list_via_network(&self) ->  Result<Vec<String>, SamplyBeamError> {
-                todo!()
-            }
-            async fn certificate_by_serial_as_pem(&self, _serial: &str) ->  Result<String, SamplyBeamError> {
-                todo!()
-            }
-            async fn im_certificate_as_pem(&self) ->  Result<String,SamplyBeamError> {
-                todo!()
-            }
-        }
-        CERT_GETTER.set(Box::new(DummyCertGetter)).unwrap_or_else(|_| panic!("Could not set cert"));
+        CERT_GETTER.set(Box::new(MockGetCerts::new())).unwrap_or_else(|_| panic!("Could not set cert"));
         let certs: HashMap<Serial, CertificateCacheEntry> = [1, 5, 10].into_iter()
             .map(Duration::from_secs)
             .map(build_x509)
@@ -945,6 +1454,7 @@ mod tests {
             cn_to_serial: Default::default(),
             im_cert: None,
             root_cert: None,
+            trust_store: None,
         };
         let cache = Arc::new(RwLock::new(cert_cache));
         let (_tx, mut rx) = mpsc::channel(1);
@@ -958,6 +1468,165 @@ mod tests {
     const CERT_TO_REVOKE: &[u8] = b"-----BEGIN CERTIFICATE-----\nMIIDLjCCAhYCFCNuyAi2zfAyORDDiwsJnfJojBk8MA0GCSqGSIb3DQEBCwUAMFQx\nCzAJBgNVBAYTAkRFMRMwEQYDVQQIDApIZWlkZWxiZXJnMSEwHwYDVQQKDBhJbnRl\ncm5ldCBXaWRnaXRzIFB0eSBMdGQxDTALBgNVBAMMBHRlc3QwHhcNMjMwODI0MDc1\nMjM1WhcNMjMwOTIzMDc1MjM1WjBTMQswCQYDVQQGEwJERTETMBEGA1UECAwKU29t\nZS1TdGF0ZTEhMB8GA1UECgwYSW50ZXJuZXQgV2lkZ2l0cyBQdHkgTHRkMQwwCgYD\nVQQDDANmb28wggEiMA0GCSqGSIb3DQEBAQUAA4IBDwAwggEKAoIBAQDd2aLmn3EX\nkSMIxdMWXe8oQNyWBktyBoNK+gSyYBO3SkIcRKM41Ama4GgeIJnDRbL2XLC3Gkhv\nHyvBocVYeP/kWtw8Zvmmi/9Ztv04pVn6LzX2Yaqtm9X78Jo3n2ug2cC8IEoMaYbF\nTcUuV7IX1oSF4Fo3KRRoAUki6yok3uEFVH5cl/UPYyYRJ+CKvoras4c9arZ3Nk3G\na9ImlniBPZ3qQwnkJX5pKcKFzYka7xrNbCpInF/v68R9Hiy4YwUQbGeTfTM+W3i9\nn5ZnSWuwY5lew3WSnpcfYKJQCLhJ9iAXq13+oYbDFSA12pSBIEz0xve3/zR5Cg81\nLGKtvpllzfGVAgMBAAEwDQYJKoZIhvcNAQELBQADggEBAGk2Zii31WPqXwzAUNc0\nS6GjTkHMP5gzdTjYspdBOm8bdJROEp9O/vjAc2Oci4waI9FT6oZPhwX/a6TDtUGs\nAZeQYt9vlS4LPgs6RTF4sFXy+pl7EA/wYqb7e0LSVsx7feTpeRRCIbFXenTKa7m+\nMXsDRCR9weplJdFeyBodFBsNMpShOe3WbnQ7Gi3jLYCUb7acX4I4H4VA7HdakZJr\nEJzP0TQzt/vrSwA2GsNWgO5sOXYkYvjieqzfi89fqY6ZT2jWQ+v+wc7kDiBRbkVU\nGooK1Vo2TJYeaPPmyNomRZtlpgXBGztYyJTfPY0A0M1Fky8Y8QLObtxG0/fkWOft\nHyU=\n-----END CERTIFICATE-----";
     const CRL: &[u8] = b"-----BEGIN X509 CRL-----\nMIIB1zCBwAIBATANBgkqhkiG9w0BAQsFADBUMQswCQYDVQQGEwJERTETMBEGA1UE\nCAwKSGVpZGVsYmVyZzEhMB8GA1UECgwYSW50ZXJuZXQgV2lkZ2l0cyBQdHkgTHRk\nMQ0wCwYDVQQDDAR0ZXN0Fw0yMzA4MjQwODM3MTRaFw0yMzA5MjMwODM3MTRaMCcw\nJQIUI27ICLbN8DI5EMOLCwmd8miMGTwXDTIzMDgyNDA4MzQxNlqgDzANMAsGA1Ud\nFAQEAgIQADANBgkqhkiG9w0BAQsFAAOCAQEAJCLrxzeDdgRIqfGEPjBff21Tefir\n3mbxZtrCa232zJLmurX1zQ5S9pa/QvGQ/Fj91FUbNezomh1NTmJkscj3Mh8Ph/Mv\nIbburXhPG5ypHeOXAGQqpKADZyBPMRwIWaTqmtsMg5kdHzYScvvHFZRcy8KCKx6e\niFdqNc9qZkyvCazpzjWK+JpK6TPCpI68LO/DxhWPirclhjZLs3z6iAuxmW8TM71T\nC7YzZ0Z17xCttNW7155LpFWUo1YOQk1Cy9W2d3EIBMmZhn6yBUExusXzcj4BnXZ7\nzCqIhPnMU4nLrarkzgmy+v1ysdo1lFGQ4fC3XFY+oWxUsImFP9JKHKEbBA==\n-----END X509 CRL-----";
 
+    /// A certificate for `cn` signed by `issuer`, or self-signed if there is none
+    fn signed_cert(cn: &str, is_ca: bool, issuer: Option<(&X509, &PKey<openssl::pkey::Private>)>) -> (X509, PKey<openssl::pkey::Private>) {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_nid(openssl::nid::Nid::COMMONNAME, cn).unwrap();
+        let name = name.build();
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        let mut serial = BigNum::new().unwrap();
+        serial.rand(64, MsbOption::MAYBE_ZERO, false).unwrap();
+        builder.set_serial_number(&serial.to_asn1_integer().unwrap()).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(issuer.map_or(&name, |(cert, _)| cert.subject_name())).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        builder.set_not_after(&Asn1Time::days_from_now(30).unwrap()).unwrap();
+        if is_ca {
+            builder.append_extension(BasicConstraints::new().critical().ca().build().unwrap()).unwrap();
+            builder.append_extension(KeyUsage::new().critical().key_cert_sign().crl_sign().build().unwrap()).unwrap();
+        }
+        builder.sign(issuer.map_or(&key, |(_, key)| key), MessageDigest::sha256()).unwrap();
+        (builder.build(), key)
+    }
+
+    #[test]
+    fn test_build_trust_store() {
+        use openssl::{stack::Stack, x509::X509StoreContext};
+
+        let (root, root_key) = signed_cert("root", true, None);
+        let (im, im_key) = signed_cert("im", true, Some((&root, &root_key)));
+        let (leaf, _) = signed_cert("proxy1.broker", false, Some((&im, &im_key)));
+        let (stranger, _) = signed_cert("proxy2.broker", false, None);
+        let verifies = |store: &X509Store, cert: &X509| {
+            let mut ctx = X509StoreContext::new().unwrap();
+            ctx.init(store, cert, &Stack::new().unwrap(), |ctx| ctx.verify_cert()).unwrap()
+        };
+
+        let im_pem = String::from_utf8(im.to_pem().unwrap()).unwrap();
+        let store = build_trust_store(&im_pem, Some(&root)).unwrap();
+        assert!(verifies(&store, &leaf));
+        assert!(!verifies(&store, &stranger));
+        assert!(!verifies(&build_trust_store(&im_pem, None).unwrap(), &leaf), "The chain is incomplete without the root");
+
+        // Vault's CA chain may contain the root certificate as well
+        let root_pem = String::from_utf8(root.to_pem().unwrap()).unwrap();
+        let store = build_trust_store(&format!("{im_pem}{root_pem}"), Some(&root)).unwrap();
+        assert!(verifies(&store, &leaf));
+
+        assert!(matches!(build_trust_store("", Some(&root)), Err(SamplyBeamError::CertificateError(_))));
+    }
+
+    #[tokio::test]
+    async fn test_certificate_list_with_metadata() {
+        let (cert, _) = signed_cert("proxy1.broker", false, None);
+        let pem = String::from_utf8(cert.to_pem().unwrap()).unwrap();
+        let getter = MockGetCerts::new()
+            .with_cert("1a", pem)
+            .with_cert("2b", "not a certificate")
+            .with_cert("3c", std::str::from_utf8(CERT_TO_REVOKE).unwrap());
+
+        let entries = getter.certificate_list_with_metadata().await.unwrap();
+        assert_eq!(entries.iter().map(|e| e.serial.as_str()).collect::<Vec<_>>(), ["1a", "2b", "3c"]);
+        let valid = entries[0].metadata.as_ref().unwrap();
+        assert_eq!(valid.common_name, "proxy1.broker");
+        assert!(valid.valid);
+        assert!(valid.not_after > SystemTime::now() + Duration::from_secs(29 * 24 * 3600));
+        assert!(matches!(entries[1].metadata, Err(SamplyBeamError::CertificateError(_))), "Only this entry fails");
+        let expired = entries[2].metadata.as_ref().unwrap();
+        assert_eq!(expired.common_name, "foo");
+        assert!(!expired.valid);
+
+        let failing = MockGetCerts::new().fail_next();
+        assert!(failing.certificate_list_with_metadata().await.is_err(), "Without the list there is nothing to report");
+    }
+
+    #[tokio::test]
+    async fn test_certificate_by_fingerprint() {
+        let (cert, _) = signed_cert("proxy1.broker", false, None);
+        let pem = String::from_utf8(cert.to_pem().unwrap()).unwrap();
+        let getter = MockGetCerts::new()
+            .with_cert("1a", "not a certificate")
+            .with_cert("2b", pem);
+        let fingerprint = sha256_fingerprint(&cert).unwrap();
+        assert_eq!(fingerprint.len(), 64);
+
+        // As printed by `openssl x509 -fingerprint -sha256`
+        let printed = fingerprint.as_bytes().chunks(2).map(|c| std::str::from_utf8(c).unwrap().to_uppercase()).join(":");
+        for fingerprint in [&fingerprint, &printed] {
+            let found = getter.certificate_by_fingerprint(fingerprint).await.unwrap();
+            assert_eq!(found.to_der().unwrap(), cert.to_der().unwrap());
+        }
+        let res = getter.certificate_by_fingerprint(&"0".repeat(64)).await;
+        assert!(matches!(res, Err(SamplyBeamError::CertificateFingerprintNotFound(_))), "{res:?}");
+    }
+
+    #[test]
+    fn test_verify_chain() {
+        let (root, root_key) = signed_cert("root", true, None);
+        let (im, im_key) = signed_cert("im", true, Some((&root, &root_key)));
+        let (leaf, _) = signed_cert("proxy1.broker", false, Some((&im, &im_key)));
+        let (by_root, _) = signed_cert("proxy2.broker", false, Some((&root, &root_key)));
+        let (stranger, _) = signed_cert("proxy3.broker", false, None);
+        let store = build_trust_store(&String::from_utf8(im.to_pem().unwrap()).unwrap(), Some(&root)).unwrap();
+
+        verify_chain(&store, &im, &leaf).unwrap();
+        for (cert, reason) in [(&by_root, "not issued by the intermediate CA"), (&stranger, "Unable to verify the certificate chain: ")] {
+            let res = verify_chain(&store, &im, cert);
+            assert!(matches!(res, Err(SamplyBeamError::CertificateError(CertificateInvalidReason::Other(ref msg))) if msg.contains(reason)), "{res:?}");
+        }
+    }
+
+    #[test]
+    fn test_trust_store_is_rebuilt_with_new_certificates() {
+        let mut cache = CertificateCache::new(mpsc::unbounded_channel().0);
+        let (root, _) = signed_cert("root", true, None);
+        cache.trust_store = Some(Arc::new(X509StoreBuilder::new().unwrap().build()));
+        cache.set_root_cert(&root);
+        assert!(cache.trust_store.is_none());
+
+        cache.trust_store = Some(Arc::new(X509StoreBuilder::new().unwrap().build()));
+        cache.serial_to_x509.insert("1".into(), CertificateCacheEntry::Valid(root));
+        cache.clear();
+        assert!(cache.trust_store.is_none(), "Invalidating all certificates rebuilds the trust store");
+        assert!(cache.serial_to_x509.is_empty());
+    }
+
+    #[test]
+    fn test_remove_certificate() {
+        let mut cache = CertificateCache::new(mpsc::unbounded_channel().0);
+        beam_lib::set_broker_id("broker.samply.de".to_string());
+        let proxy = ProxyId::new("proxy1.broker.samply.de").unwrap();
+        for serial in ["1", "2"] {
+            cache.serial_to_x509.insert(serial.into(), CertificateCacheEntry::Valid(build_x509(Duration::from_secs(60))));
+        }
+        cache.cn_to_serial.insert(proxy.clone(), vec!["1".into(), "2".into()]);
+
+        assert!(cache.remove_certificate("1"));
+        assert_eq!(cache.cn_to_serial[&proxy], ["2"]);
+        assert!(!cache.remove_certificate("1"));
+        assert!(cache.remove_certificate("2"));
+        assert!(cache.cn_to_serial.is_empty() && cache.serial_to_x509.is_empty());
+    }
+
+    #[test]
+    fn test_retain_listed() {
+        let mut cache = CertificateCache::new(mpsc::unbounded_channel().0);
+        beam_lib::set_broker_id("broker.samply.de".to_string());
+        let proxy = ProxyId::new("proxy1.broker.samply.de").unwrap();
+        for serial in ["1", "2", "3"] {
+            cache.serial_to_x509.insert(serial.into(), CertificateCacheEntry::Valid(build_x509(Duration::from_secs(60))));
+        }
+        cache.cn_to_serial.insert(proxy.clone(), vec!["1".into(), "2".into()]);
+
+        assert_eq!(cache.retain_listed(&HashSet::from(["2", "4"])), 2);
+        assert_eq!(cache.serial_to_x509.keys().collect::<Vec<_>>(), ["2"]);
+        assert_eq!(cache.cn_to_serial[&proxy], ["2"]);
+        assert_eq!(cache.retain_listed(&HashSet::from(["2"])), 0);
+    }
+
     #[test]
     fn test_revokation() {
         let mut cache = CertificateCache::new(mpsc::unbounded_channel().0);
@@ -973,4 +1642,76 @@ mod tests {
         assert!(matches!(cache.serial_to_x509.get("3"), Some(&CertificateCacheEntry::Invalid(CertificateInvalidReason::Revoked))), "Certificate was not revoked");
         assert_eq!(cache.serial_to_x509.values().filter(|cert| matches!(cert, CertificateCacheEntry::Valid(..))).count(), 3, "No other certs have been invalidated");
     }
+
+    #[test]
+    fn test_parse_crl_in_der_and_pem() {
+        let der = X509Crl::from_pem(CRL).unwrap().to_der().unwrap();
+        let revoked = X509::from_pem(CERT_TO_REVOKE).unwrap();
+        for data in [&der[..], CRL] {
+            let crl = parse_crl(data).unwrap();
+            assert!(crl_revokes(&crl, &revoked));
+            assert!(!crl_revokes(&crl, &build_x509(Duration::from_secs(60))));
+        }
+        assert!(parse_crl(b"not a CRL").is_err());
+    }
+
+    #[test]
+    fn test_parse_single_certificate() {
+        let pem = std::str::from_utf8(CERT_TO_REVOKE).unwrap();
+        let cert = parse_single_certificate(pem).unwrap();
+        assert_eq!(cert.to_pem().unwrap(), X509::from_pem(CERT_TO_REVOKE).unwrap().to_pem().unwrap());
+
+        let two = format!("{pem}\n{pem}");
+        assert!(matches!(parse_single_certificate(&two), Err(SamplyBeamError::CertificateError(CertificateInvalidReason::Other(_)))));
+        assert!(matches!(parse_single_certificate(""), Err(SamplyBeamError::CertificateError(_))));
+        assert!(matches!(parse_single_certificate("not a certificate"), Err(SamplyBeamError::CertificateError(_))));
+
+        // A truncated certificate must be fetched again instead of being cached as invalid
+        let Err(SamplyBeamError::CertificateError(reason)) = parse_single_certificate(&pem[..pem.len() / 2]) else {
+            panic!("Parsed a truncated certificate");
+        };
+        assert!(!is_definitive(&reason));
+        assert!(is_definitive(&CertificateInvalidReason::InvalidDate));
+        assert!(is_definitive(&CertificateInvalidReason::Revoked));
+    }
+
+    #[tokio::test]
+    async fn test_certificates_are_fetched_concurrently() {
+        let getter = (0..20)
+            .fold(MockGetCerts::new(), |getter, i| getter.with_cert(i.to_string(), format!("pem {i}")))
+            .with_delay(Duration::from_millis(50))
+            .with_fetch_concurrency(4);
+        let mut serials: Vec<_> = (0..20).map(|i| i.to_string()).collect();
+        serials.push("bad".to_string());
+        let started = Instant::now();
+        let mut fetched = getter.certificates_by_serials(&serials).await;
+        assert!(started.elapsed() < Duration::from_millis(20 * 50), "Fetches must overlap");
+        assert_eq!(getter.max_concurrent_requests(), 4);
+
+        fetched.sort_by(|(a, _), (b, _)| a.cmp(b));
+        assert_eq!(fetched.len(), 21);
+        let failed: Vec<_> = fetched.iter().filter(|(_, pem)| pem.is_err()).map(|(serial, _)| serial.as_str()).collect();
+        assert_eq!(failed, ["bad"], "A bad serial must not fail the others");
+        assert!(fetched.iter().all(|(serial, pem)| serial == "bad" || *pem.as_ref().unwrap() == format!("pem {serial}")));
+    }
+
+    #[tokio::test]
+    async fn test_certificates_are_streamed_lazily() {
+        let getter = (0..100)
+            .fold(MockGetCerts::new(), |getter, i| getter.with_cert(i.to_string(), format!("pem {i}")))
+            .with_unavailable_cert("bad")
+            .with_fetch_concurrency(4);
+        let first: Vec<_> = certificates_stream(&getter).take(2).collect().await;
+        assert_eq!(first.len(), 2);
+        assert!(getter.requests() <= 1 + 2 + 4, "Only the list and as many certificates as needed must be fetched");
+
+        let all: Vec<_> = certificates_stream(&getter).collect().await;
+        assert_eq!(all.len(), 101);
+        assert_eq!(all.iter().filter(|cert| cert.is_err()).count(), 1, "A bad serial must not fail the others");
+        assert!(all.iter().flatten().all(|(serial, pem)| *pem == format!("pem {serial}")));
+
+        let unlisted = MockGetCerts::new().with_cert("1", "pem 1").fail_next();
+        let failed: Vec<_> = certificates_stream(&unlisted).collect().await;
+        assert!(matches!(failed[..], [Err(SamplyBeamError::InternalSynchronizationError(_))]));
+    }
 }
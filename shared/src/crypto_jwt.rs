@@ -11,12 +11,12 @@ use axum::{async_trait, body::HttpBody, extract::{FromRequest, Request}, http::{
 use jwt_simple::{
     claims::JWTClaims,
     prelude::{
-        Base64, Base64UrlSafeNoPadding, Claims, Duration, KeyMetadata, RS256KeyPair,
-        RS256PublicKey, RSAKeyPairLike, RSAPublicKeyLike, Token, VerificationOptions,
+        Base64, Base64UrlSafeNoPadding, Claims, Duration, KeyMetadata, PS256PublicKey, PS384PublicKey,
+        PS512PublicKey, RS256PublicKey, RSAKeyPairLike, RSAPublicKeyLike, Token, VerificationOptions,
     },
     reexports::ct_codecs::Decoder,
 };
-use once_cell::unsync::Lazy;
+use once_cell::{sync::OnceCell, unsync::Lazy};
 use openssl::base64;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::Value;
@@ -41,58 +41,225 @@ where
 
     async fn from_request(mut req: Request, _state: &S) -> Result<Self, Self::Rejection> {
         let mut parts = req.extract_parts().await.expect("Infallible");
-        let token_without_extended_signature: String = req.extract().await.map_err(|e| {
-            warn!(
-                "Unable to parse token_without_extended_signature as UTF-8: {}",
-                e
-            );
-            ERR_SIG
-        })?;
+        let token_without_extended_signature = read_body_to_string(req.with_limited_body().into_body()).await?;
         verify_with_extended_header(&mut parts, &token_without_extended_signature).await
     }
 }
 
+/// Upper bound for preallocating the body buffer based on the announced body size, which is not to be trusted before any of it has arrived
+const MAX_BODY_PREALLOCATION: u64 = 64 * 1024;
+
+/// Reads a request body chunk by chunk into a single buffer.
+/// Unlike axum's `String` extractor, this does not keep a second copy of the body around and
+/// aborts as soon as the body limit (see [`axum::extract::DefaultBodyLimit`]) is exceeded.
+/// The body itself is still buffered as a whole: its signature has to be verified before any of it may be stored,
+/// and stored messages are kept in memory anyway.
+pub(crate) async fn read_body_to_string(mut body: axum::body::Body) -> Result<String, (StatusCode, &'static str)> {
+    let hint = body.size_hint();
+    let mut buf = Vec::with_capacity(hint.exact().unwrap_or(hint.lower()).min(MAX_BODY_PREALLOCATION) as usize);
+    while let Some(frame) = std::future::poll_fn(|cx| std::pin::Pin::new(&mut body).poll_frame(cx)).await {
+        let frame = frame.map_err(|e| {
+            let e = e.into_inner();
+            if e.is::<http_body_util::LengthLimitError>() {
+                warn!("Rejecting message exceeding the maximum message size");
+                (StatusCode::PAYLOAD_TOO_LARGE, "Message exceeds the maximum message size")
+            } else {
+                warn!("Unable to read message body: {e}");
+                ERR_SIG
+            }
+        })?;
+        if let Ok(data) = frame.into_data() {
+            buf.extend_from_slice(&data);
+        }
+    }
+    String::from_utf8(buf).map_err(|e| {
+        warn!(
+            "Unable to parse token_without_extended_signature as UTF-8: {}",
+            e
+        );
+        ERR_SIG
+    })
+}
+
 pub type Authorized = MsgSigned<MsgEmpty>;
 
+/// Signature algorithms the broker accepts unless `ACCEPTED_SIGNATURE_ALGORITHMS` is configured
+pub const DEFAULT_SIGNATURE_ALGORITHMS: [&str; 4] = ["RS256", "PS256", "PS384", "PS512"];
+
+static ACCEPTED_SIGNATURE_ALGORITHMS: OnceCell<Vec<String>> = OnceCell::new();
+
+/// Restricts the JWT signature algorithms accepted when verifying messages.
+/// Without this, e.g. in the proxy, any algorithm with a matching public key type is accepted.
+pub fn set_accepted_signature_algorithms(algorithms: Vec<String>) {
+    if ACCEPTED_SIGNATURE_ALGORITHMS.set(algorithms).is_err() {
+        panic!("Internal error: Tried to set accepted signature algorithms twice");
+    }
+}
+
+/// Rejects tokens signed with an algorithm that is not accepted, regardless of whether their signature is valid
+fn check_signature_algorithm<A: AsRef<str>>(token: &str, accepted: &[A]) -> Result<(), SamplyBeamError> {
+    let metadata = Token::decode_metadata(token).map_err(|e| {
+        SamplyBeamError::RequestValidationFailed(format!("Unable to decode JWT metadata: {}", e))
+    })?;
+    let algorithm = metadata.algorithm();
+    if accepted.iter().any(|a| a.as_ref() == algorithm) {
+        Ok(())
+    } else {
+        Err(SamplyBeamError::RequestValidationFailed(format!(
+            "Signature algorithm {algorithm} is not accepted"
+        )))
+    }
+}
+
+/// A sender's public key for the RSA signature algorithm of the token it is used to verify
+pub enum JwtPublicKey {
+    Rs256(RS256PublicKey),
+    Ps256(PS256PublicKey),
+    Ps384(PS384PublicKey),
+    Ps512(PS512PublicKey),
+}
+
+impl JwtPublicKey {
+    fn for_token(token: &str, pem: &str) -> Result<Self, SamplyBeamError> {
+        let metadata = Token::decode_metadata(token).map_err(|e| {
+            SamplyBeamError::RequestValidationFailed(format!("Unable to decode JWT metadata: {}", e))
+        })?;
+        let key_error = |e: jwt_simple::Error| SamplyBeamError::SignEncryptError(format!("Unable to initialize public key: {}", e));
+        Ok(match metadata.algorithm() {
+            "RS256" => Self::Rs256(RS256PublicKey::from_pem(pem).map_err(key_error)?),
+            "PS256" => Self::Ps256(PS256PublicKey::from_pem(pem).map_err(key_error)?),
+            "PS384" => Self::Ps384(PS384PublicKey::from_pem(pem).map_err(key_error)?),
+            "PS512" => Self::Ps512(PS512PublicKey::from_pem(pem).map_err(key_error)?),
+            other => return Err(SamplyBeamError::RequestValidationFailed(format!("Signature algorithm {other} is not supported"))),
+        })
+    }
+
+    pub fn verify_token<T: DeserializeOwned + Serialize>(
+        &self,
+        token: &str,
+        options: Option<VerificationOptions>,
+    ) -> Result<JWTClaims<T>, jwt_simple::Error> {
+        match self {
+            Self::Rs256(key) => key.verify_token(token, options),
+            Self::Ps256(key) => key.verify_token(token, options),
+            Self::Ps384(key) => key.verify_token(token, options),
+            Self::Ps512(key) => key.verify_token(token, options),
+        }
+    }
+}
+
+/// Why a message was rejected while validating its signature and the sender's certificate.
+/// Used as the (deliberately coarse) `reason` label of `beam_message_rejections_total`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectionReason {
+    Malformed,
+    DisallowedAlgorithm,
+    UnknownCertificate,
+    ExpiredCertificate,
+    UntrustedChain,
+    Revoked,
+    CommonNameMismatch,
+    WeakKey,
+    InvalidSignature,
+}
+
+impl RejectionReason {
+    fn label(self) -> &'static str {
+        match self {
+            RejectionReason::Malformed => "malformed",
+            RejectionReason::DisallowedAlgorithm => "disallowed_algorithm",
+            RejectionReason::UnknownCertificate => "unknown_certificate",
+            RejectionReason::ExpiredCertificate => "expired_certificate",
+            RejectionReason::UntrustedChain => "untrusted_chain",
+            RejectionReason::Revoked => "revoked",
+            RejectionReason::CommonNameMismatch => "common_name_mismatch",
+            RejectionReason::WeakKey => "weak_key",
+            RejectionReason::InvalidSignature => "invalid_signature",
+        }
+    }
+}
+
+impl From<&CertificateInvalidReason> for RejectionReason {
+    fn from(reason: &CertificateInvalidReason) -> Self {
+        match reason {
+            CertificateInvalidReason::InvalidDate => RejectionReason::ExpiredCertificate,
+            CertificateInvalidReason::Revoked => RejectionReason::Revoked,
+            CertificateInvalidReason::NoCommonName | CertificateInvalidReason::InvalidCommonName => RejectionReason::CommonNameMismatch,
+            CertificateInvalidReason::InvalidPublicKey => RejectionReason::WeakKey,
+            CertificateInvalidReason::WrongSerial => RejectionReason::UnknownCertificate,
+            CertificateInvalidReason::NotDisclosedByBroker
+            | CertificateInvalidReason::InternalError(_)
+            | CertificateInvalidReason::Other(_) => RejectionReason::UntrustedChain,
+        }
+    }
+}
+
+/// Counts a rejected message. The sender only goes to the log to keep the number of counters bounded.
+fn record_rejection(reason: RejectionReason, sender: impl std::fmt::Display) {
+    metrics::counter!("beam_message_rejections_total", "reason" => reason.label()).increment(1);
+    warn!("Rejected message from {sender}: {reason:?}");
+}
+
+fn check_accepted_signature_algorithm(token: &str) -> Result<(), SamplyBeamError> {
+    match ACCEPTED_SIGNATURE_ALGORITHMS.get() {
+        Some(accepted) => check_signature_algorithm(token, accepted),
+        None => Ok(()),
+    }
+}
+
 #[tracing::instrument]
 pub async fn extract_jwt<T: DeserializeOwned + Serialize>(
     token: &str,
 ) -> Result<
     (
         crypto::CryptoPublicPortion,
-        RS256PublicKey,
+        JwtPublicKey,
         jwt_simple::prelude::JWTClaims<T>,
     ),
     SamplyBeamError,
 > {
     let metadata = Token::decode_metadata(token).map_err(|e| {
+        record_rejection(RejectionReason::Malformed, "unknown sender");
         SamplyBeamError::RequestValidationFailed(format!("Unable to decode JWT metadata: {}", e))
     })?;
+    let sender_hint = match metadata.key_id() {
+        Some(serial) => format!("certificate {serial}"),
+        None => "unknown sender".to_string(),
+    };
+    check_accepted_signature_algorithm(token)
+        .inspect_err(|_| record_rejection(RejectionReason::DisallowedAlgorithm, &sender_hint))?;
     let public = if let Some(serial) = metadata.key_id() {
         crypto::get_cert_and_client_by_serial_as_pemstr(serial)
             .await
             .ok_or_else(|| {
+                record_rejection(RejectionReason::UnknownCertificate, &sender_hint);
                 SamplyBeamError::VaultOtherError(format!(
                     "Unable to retrieve matching certificate for serial \"{}\"",
                     serial
                 ))
             })?
-            .map_err(|e| SamplyBeamError::CertificateError(e))?
+            .map_err(|e| {
+                record_rejection(RejectionReason::from(&e), &sender_hint);
+                SamplyBeamError::CertificateError(e)
+            })?
     } else {
         // if it does not have a serial in the metadata try to get it by reading the from field in the body
         // this happens, e.g. during proxy initialization before a certificate (serial) is received
         let data = token
             .splitn(3, ".")
             .nth(1)
-            .ok_or(SamplyBeamError::RequestValidationFailed(
-                "Invalid JWT in header".to_string(),
-            ))?;
+            .ok_or_else(|| {
+                record_rejection(RejectionReason::Malformed, &sender_hint);
+                SamplyBeamError::RequestValidationFailed("Invalid JWT in header".to_string())
+            })?;
         let data = Base64UrlSafeNoPadding::decode_to_vec(data, None).map_err(|e| {
             warn!("Failed to b64decode {data:?}. Err: {e}");
+            record_rejection(RejectionReason::Malformed, &sender_hint);
             SamplyBeamError::RequestValidationFailed("Invalid JWT in header".to_string())
         })?;
         let json = serde_json::from_slice::<JWTClaims<HeaderClaim>>(&data).map_err(|e| {
             warn!("Failed to decode {data:?} to JwtClaims<HeaderClaims>. Err: {e}");
+            record_rejection(RejectionReason::Malformed, &sender_hint);
             SamplyBeamError::RequestValidationFailed("Invalid JWT body in header".to_string())
         })?;
         let proxy_id: ProxyId = json.custom.from.proxy_id();
@@ -102,20 +269,34 @@ pub async fn extract_jwt<T: DeserializeOwned + Serialize>(
             .flatten()
             .collect::<Vec<_>>();
         // Get newest Certificate
-        crypto::get_newest_cert(&mut certs).ok_or(SamplyBeamError::CertificateError(
-            CertificateInvalidReason::NoCommonName,
-        ))?
+        crypto::get_newest_cert(&mut certs).ok_or_else(|| {
+            record_rejection(RejectionReason::UnknownCertificate, &proxy_id);
+            SamplyBeamError::CertificateError(CertificateInvalidReason::NoCommonName).with_context(&proxy_id)
+        })?
     };
-    let pubkey = RS256PublicKey::from_pem(&public.pubkey).map_err(|e| {
-        SamplyBeamError::SignEncryptError(format!("Unable to initialize public key: {}", e))
+    crypto::verify_issued_by_ca(&public.cert).await.inspect_err(|e| {
+        if let SamplyBeamError::CertificateError(reason) = e {
+            record_rejection(RejectionReason::from(reason), &public.beam_id);
+        }
+    }).map_err(|e| e.with_context(&public.beam_id))?;
+    crypto::check_not_revoked(&public.cert).await.inspect_err(|e| {
+        if matches!(e, SamplyBeamError::CertificateRevoked(_)) {
+            record_rejection(RejectionReason::Revoked, &public.beam_id);
+        }
+    }).map_err(|e| e.with_context(&public.beam_id))?;
+    let pubkey = JwtPublicKey::for_token(token, &public.pubkey).map_err(|e| {
+        record_rejection(RejectionReason::WeakKey, &public.beam_id);
+        e.with_context(&public.beam_id)
     })?;
     let content = pubkey
         .verify_token::<T>(token, Some(JWT_VERIFICATION_OPTIONS.clone()))
         .map_err(|e| {
+            record_rejection(RejectionReason::InvalidSignature, &public.beam_id);
             SamplyBeamError::RequestValidationFailed(format!(
                 "Unable to verify token and extract claims from JWT: {}",
                 e
             ))
+            .with_context(&public.beam_id)
         })?;
     Ok((public, pubkey, content))
 }
@@ -165,12 +346,10 @@ pub async fn verify_with_extended_header<M: Msg + DeserializeOwned>(
                 ERR_SIG
             })?;
 
-    req.extensions
-        .remove::<ProxyLogger>()
-        .expect("Should be set by middleware")
-        .send(header_claims.custom.from.clone())
-        .await
-        .expect("Receiver still lives in middleware");
+    // Only present if the logging middleware is installed
+    if let Some(logger) = req.extensions.remove::<ProxyLogger>() {
+        _ = logger.send(header_claims.custom.from.clone()).await;
+    }
 
     // Check extra digest
 
@@ -178,6 +357,13 @@ pub async fn verify_with_extended_header<M: Msg + DeserializeOwned>(
     let digest_claimed = custom.sig;
     let sender_claimed = custom.from;
 
+    let sender = &proxy_public_info.beam_id;
+    check_accepted_signature_algorithm(token_without_extended_signature).map_err(|e| {
+        warn!("Rejecting short token: {e}");
+        record_rejection(RejectionReason::DisallowedAlgorithm, sender);
+        ERR_SIG
+    })?;
+
     // Check if short token matches the long token
     let msg = pubkey
         .verify_token::<M>(
@@ -189,6 +375,7 @@ pub async fn verify_with_extended_header<M: Msg + DeserializeOwned>(
                 "Unable to verify short token {}: {}",
                 token_without_extended_signature, e
             );
+            record_rejection(RejectionReason::InvalidSignature, sender);
             ERR_SIG
         })?
         .custom;
@@ -213,6 +400,7 @@ pub async fn verify_with_extended_header<M: Msg + DeserializeOwned>(
             "Digests did not match: expected {}, received {}",
             digest_claimed, digest_actual
         );
+        record_rejection(RejectionReason::InvalidSignature, sender);
         return Err(ERR_SIG);
     }
 
@@ -221,6 +409,7 @@ pub async fn verify_with_extended_header<M: Msg + DeserializeOwned>(
             "Sender did not match: expected {}, received {}",
             sender_claimed, sender_actual
         );
+        record_rejection(RejectionReason::CommonNameMismatch, sender);
         return Err(ERR_SIG);
     }
 
@@ -229,6 +418,7 @@ pub async fn verify_with_extended_header<M: Msg + DeserializeOwned>(
         warn!(
             "Received messages' \"from\" attribute which should not have been signed by the proxy."
         );
+        record_rejection(RejectionReason::CommonNameMismatch, sender);
         return Err(ERR_FROM);
     }
     // TODO: Check if Date header makes sense (replay attacks)
@@ -313,3 +503,136 @@ pub fn make_extra_fields_digest(
         from: from.to_owned(),
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::{Body, Bytes};
+    use jwt_simple::prelude::{NoCustomClaims, PS256KeyPair, RS256KeyPair, RS384KeyPair};
+
+    #[test]
+    fn test_signature_algorithm_policy() {
+        let claims = Claims::create(Duration::from_mins(1));
+        let ps256 = PS256KeyPair::generate(2048).unwrap().sign(claims.clone()).unwrap();
+        let rs256 = RS256KeyPair::generate(2048).unwrap().sign(claims.clone()).unwrap();
+        let rs384 = RS384KeyPair::generate(2048).unwrap().sign(claims).unwrap();
+
+        assert!(check_signature_algorithm(&ps256, &DEFAULT_SIGNATURE_ALGORITHMS).is_ok());
+        assert!(check_signature_algorithm(&rs256, &DEFAULT_SIGNATURE_ALGORITHMS).is_ok(), "Proxies sign with RS256");
+        assert!(matches!(
+            check_signature_algorithm(&rs384, &DEFAULT_SIGNATURE_ALGORITHMS),
+            Err(SamplyBeamError::RequestValidationFailed(_))
+        ));
+        assert!(check_signature_algorithm(&ps256, &["PS256"]).is_ok());
+        assert!(check_signature_algorithm(&rs384, &["RS384"]).is_ok());
+        assert!(matches!(
+            check_signature_algorithm(&rs256, &["PS256"]),
+            Err(SamplyBeamError::RequestValidationFailed(_))
+        ));
+    }
+
+    #[test]
+    fn test_tokens_are_verified_with_their_algorithm() {
+        let key = PS256KeyPair::generate(2048).unwrap();
+        let pem = key.public_key().to_pem().unwrap();
+        let ps256 = key.sign(Claims::create(Duration::from_mins(1))).unwrap();
+        let pubkey = JwtPublicKey::for_token(&ps256, &pem).unwrap();
+        assert!(matches!(pubkey, JwtPublicKey::Ps256(_)));
+        assert!(pubkey.verify_token::<NoCustomClaims>(&ps256, None).is_ok());
+
+        let rs256 = RS256KeyPair::from_pem(&key.to_pem().unwrap()).unwrap().sign(Claims::create(Duration::from_mins(1))).unwrap();
+        assert!(pubkey.verify_token::<NoCustomClaims>(&rs256, None).is_err(), "The algorithm must match the key's");
+        assert!(JwtPublicKey::for_token(&rs256, &pem).unwrap().verify_token::<NoCustomClaims>(&rs256, None).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_rejections_are_counted_by_reason() {
+        use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+        let rejections = |reason: &str| {
+            snapshotter
+                .snapshot()
+                .into_vec()
+                .into_iter()
+                .find(|(key, ..)| key.key().name() == "beam_message_rejections_total" && key.key().labels().any(|l| l.value() == reason))
+                .map(|(.., value)| value)
+        };
+
+        assert!(extract_jwt::<HeaderClaim>("not a jwt").await.is_err());
+        assert_eq!(rejections("malformed"), Some(DebugValue::Counter(1)));
+
+        // As configured in the broker
+        ACCEPTED_SIGNATURE_ALGORITHMS.get_or_init(|| DEFAULT_SIGNATURE_ALGORITHMS.map(String::from).to_vec());
+        let rs384 = RS384KeyPair::generate(2048).unwrap().sign(Claims::create(Duration::from_mins(1))).unwrap();
+        assert!(extract_jwt::<HeaderClaim>(&rs384).await.is_err());
+        assert_eq!(rejections("disallowed_algorithm"), Some(DebugValue::Counter(1)));
+
+        for (cert_reason, reason) in [
+            (CertificateInvalidReason::InvalidDate, "expired_certificate"),
+            (CertificateInvalidReason::Revoked, "revoked"),
+            (CertificateInvalidReason::InvalidCommonName, "common_name_mismatch"),
+            (CertificateInvalidReason::InvalidPublicKey, "weak_key"),
+            (CertificateInvalidReason::Other("unable to get issuer certificate".into()), "untrusted_chain"),
+        ] {
+            assert_eq!(rejections(reason), None);
+            record_rejection(RejectionReason::from(&cert_reason), "proxy1.broker");
+            assert_eq!(rejections(reason), Some(DebugValue::Counter(1)));
+        }
+    }
+
+    fn chunks(chunk_size: usize, count: Option<usize>) -> Body {
+        let chunk = Bytes::from(vec![b'a'; chunk_size]);
+        let stream = futures_util::stream::repeat_with(move || Ok::<_, std::io::Error>(chunk.clone()));
+        match count {
+            Some(count) => Body::from_stream(futures_util::StreamExt::take(stream, count)),
+            None => Body::from_stream(stream),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_large_body() {
+        let body = read_body_to_string(chunks(64 * 1024, Some(256))).await.unwrap();
+        assert_eq!(body.len(), 16 * 1024 * 1024);
+        assert!(body.bytes().all(|b| b == b'a'));
+    }
+
+    /// Announces a body of the given size but only sends a few bytes
+    struct Announcing(u64, Option<Bytes>);
+
+    impl HttpBody for Announcing {
+        type Data = Bytes;
+        type Error = axum::Error;
+
+        fn poll_frame(
+            mut self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Option<Result<hyper::body::Frame<Bytes>, axum::Error>>> {
+            std::task::Poll::Ready(self.1.take().map(|data| Ok(hyper::body::Frame::data(data))))
+        }
+
+        fn size_hint(&self) -> hyper::body::SizeHint {
+            hyper::body::SizeHint::with_exact(self.0)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_announced_body_size_is_not_preallocated() {
+        let body = read_body_to_string(Body::new(Announcing(64 * 1024 * 1024, Some(Bytes::from_static(b"token"))))).await.unwrap();
+        assert_eq!(body, "token");
+        assert!(body.capacity() as u64 <= MAX_BODY_PREALLOCATION, "Preallocated {} bytes", body.capacity());
+    }
+
+    #[tokio::test]
+    async fn test_body_limit_is_enforced_while_streaming() {
+        // An endless body would never fit into memory, so this only returns if reading stops at the limit
+        let limited = Body::new(http_body_util::Limited::new(chunks(64 * 1024, None), 1024 * 1024));
+        let err = tokio::time::timeout(std::time::Duration::from_secs(10), read_body_to_string(limited))
+            .await
+            .expect("Reading must stop once the limit is exceeded")
+            .unwrap_err();
+        assert_eq!(err.0, StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
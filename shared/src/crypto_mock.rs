@@ -0,0 +1,156 @@
+use std::{collections::HashMap, sync::atomic::{AtomicUsize, Ordering}, time::Duration};
+
+use axum::async_trait;
+
+use crate::{crypto::GetCerts, errors::{CertificateInvalidReason, SamplyBeamError}};
+
+/// An in-memory [`GetCerts`] for tests that must not depend on a running Vault.
+///
+/// ```ignore
+/// let getter = MockGetCerts::new()
+///     .with_im_cert(CA_PEM)
+///     .with_cert("1a", PROXY_PEM)
+///     .with_unavailable_cert("2b")
+///     .fail_next();
+/// assert!(getter.certificate_by_serial_as_pem("1a").await.is_err());
+/// assert_eq!(getter.certificate_by_serial_as_pem("1a").await.unwrap(), PROXY_PEM);
+/// ```
+#[derive(Debug, Default)]
+pub struct MockGetCerts {
+    certs: HashMap<String, String>,
+    /// Listed, but fetching them fails
+    unavailable: Vec<String>,
+    im_cert: Option<String>,
+    delay: Duration,
+    fetch_concurrency: Option<usize>,
+    pending_failures: AtomicUsize,
+    requests: AtomicUsize,
+    active_requests: AtomicUsize,
+    max_active_requests: AtomicUsize,
+}
+
+impl MockGetCerts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_cert(mut self, serial: impl Into<String>, pem: impl Into<String>) -> Self {
+        self.certs.insert(serial.into(), pem.into());
+        self
+    }
+
+    /// Lists a serial whose certificate cannot be fetched
+    pub fn with_unavailable_cert(mut self, serial: impl Into<String>) -> Self {
+        self.unavailable.push(serial.into());
+        self
+    }
+
+    pub fn with_im_cert(mut self, pem: impl Into<String>) -> Self {
+        self.im_cert = Some(pem.into());
+        self
+    }
+
+    /// Makes every request take this long, e.g. to see how many are made concurrently
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// Overrides [`GetCerts::fetch_concurrency`]
+    pub fn with_fetch_concurrency(mut self, fetch_concurrency: usize) -> Self {
+        self.fetch_concurrency = Some(fetch_concurrency);
+        self
+    }
+
+    /// Makes the next request fail as if the PKI was unreachable. Can be chained to fail several requests.
+    pub fn fail_next(self) -> Self {
+        self.pending_failures.fetch_add(1, Ordering::SeqCst);
+        self
+    }
+
+    /// How many requests were made, including failed ones
+    pub fn requests(&self) -> usize {
+        self.requests.load(Ordering::SeqCst)
+    }
+
+    /// The most requests that were in progress at the same time
+    pub fn max_concurrent_requests(&self) -> usize {
+        self.max_active_requests.load(Ordering::SeqCst)
+    }
+
+    async fn request(&self) -> Result<(), SamplyBeamError> {
+        self.requests.fetch_add(1, Ordering::SeqCst);
+        let active = self.active_requests.fetch_add(1, Ordering::SeqCst) + 1;
+        self.max_active_requests.fetch_max(active, Ordering::SeqCst);
+        if !self.delay.is_zero() {
+            tokio::time::sleep(self.delay).await;
+        }
+        self.active_requests.fetch_sub(1, Ordering::SeqCst);
+        let failing = self.pending_failures
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            .is_ok();
+        if failing {
+            Err(SamplyBeamError::InternalSynchronizationError("Injected failure of MockGetCerts".into()))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[async_trait]
+impl GetCerts for MockGetCerts {
+    async fn certificate_list_via_network(&self) -> Result<Vec<String>, SamplyBeamError> {
+        self.request().await?;
+        Ok(self.certs.keys().chain(&self.unavailable).cloned().collect())
+    }
+
+    async fn certificate_by_serial_as_pem(&self, serial: &str) -> Result<String, SamplyBeamError> {
+        self.request().await?;
+        self.certs
+            .get(serial)
+            .cloned()
+            .ok_or(CertificateInvalidReason::WrongSerial.into())
+    }
+
+    async fn im_certificate_as_pem(&self) -> Result<String, SamplyBeamError> {
+        self.request().await?;
+        self.im_cert
+            .clone()
+            .ok_or_else(|| SamplyBeamError::ConfigurationFailed("MockGetCerts has no intermediate CA certificate".into()))
+    }
+
+    fn fetch_concurrency(&self) -> usize {
+        // Like the trait's default
+        self.fetch_concurrency.unwrap_or(8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_mock_get_certs() {
+        let getter = MockGetCerts::new()
+            .with_cert("1a", "pem 1a")
+            .with_cert("2b", "pem 2b")
+            .with_unavailable_cert("4d")
+            .fail_next()
+            .fail_next();
+        assert!(getter.certificate_by_serial_as_pem("1a").await.is_err());
+        assert!(getter.certificate_list_via_network().await.is_err());
+        assert_eq!(getter.certificate_by_serial_as_pem("1a").await.unwrap(), "pem 1a");
+        let mut serials = getter.certificate_list_via_network().await.unwrap();
+        serials.sort();
+        assert_eq!(serials, ["1a", "2b", "4d"]);
+        for missing in ["3c", "4d"] {
+            assert!(matches!(
+                getter.certificate_by_serial_as_pem(missing).await,
+                Err(SamplyBeamError::CertificateError(CertificateInvalidReason::WrongSerial))
+            ));
+        }
+        assert!(matches!(getter.im_certificate_as_pem().await, Err(SamplyBeamError::ConfigurationFailed(_))));
+        assert_eq!(getter.requests(), 7);
+        assert_eq!(getter.max_concurrent_requests(), 1);
+    }
+}
@@ -1,10 +1,15 @@
 use std::{net::AddrParseError, str::Utf8Error, string::FromUtf8Error};
 
+use axum::{response::{IntoResponse, Response}, Json};
 use openssl::error::ErrorStack;
 use reqwest::StatusCode;
+use serde_json::json;
 use tokio::time::error::Elapsed;
+use tracing::warn;
 use beam_lib::ProxyId;
 
+use crate::http_client::HttpErrorKind;
+
 #[derive(thiserror::Error, Debug)]
 pub enum SamplyBeamError {
     #[error("Invalid bind address supplied: {0}")]
@@ -15,28 +20,51 @@ pub enum SamplyBeamError {
     RequestValidationFailed(String),
     #[error("Invalid path supplied")]
     InvalidPath,
-    #[error("Invalid Client ID: {0}")]
-    InvalidClientIdString(String),
     #[error("Unable to parse JSON: {0}")]
     JsonParseError(String),
     #[error("Decryption error: {0}")]
     DecryptError(&'static str),
     #[error("Signing / encryption failed: {0}")]
     SignEncryptError(String),
+    #[cfg(feature = "vault")]
     #[error("Samply.PKI error: Vault is still sealed.")]
     VaultSealed,
+    #[cfg(feature = "vault")]
     #[error("Samply.PKI error: Unable to connect to Vault: {0}")]
     VaultUnreachable(reqwest::Error),
+    #[cfg(feature = "vault")]
+    #[error("Samply.PKI error: Vault kept failing, so no requests are sent to it for the next {0:.0?}.")]
+    VaultCircuitOpen(std::time::Duration),
+    #[cfg(feature = "vault")]
+    #[error("Samply.PKI error: Unable to communicate after {attempts} attempts in {elapsed:.1?}. Giving up.")]
+    VaultGaveUp { attempts: u32, elapsed: std::time::Duration },
+    #[cfg(feature = "vault")]
     #[error("Samply.PKI error: Vault has not been initialized, yet.")]
     VaultNotInitialized,
+    #[cfg(feature = "vault")]
     #[error("Samply.PKI error: Vault has asked with code {0} to redirect to {1}; this should not happen.")]
     VaultRedirectError(StatusCode, String),
+    #[cfg(feature = "vault")]
+    #[error("Samply.PKI error: Request to Vault was cancelled because the broker is shutting down.")]
+    VaultRequestCancelled,
+    #[cfg(feature = "vault")]
+    #[error("Samply.PKI error: Unable to authenticate to Vault: {0}")]
+    VaultAuthError(String),
+    #[cfg(feature = "vault")]
+    #[error("Samply.PKI error: Vault's response from {endpoint} has an unexpected format: {source}")]
+    VaultDeserializationError { endpoint: String, source: serde_json::Error },
     #[error("Samply.PKI error: {0}")]
     VaultOtherError(String),
+    #[error("No certificate with serial {0}")]
+    CertificateNotFound(String),
+    #[error("No certificate with fingerprint {0}")]
+    CertificateFingerprintNotFound(String),
     #[error("Unable to read config: {0}. Please check your environment and parameters.")]
     ConfigurationFailed(String),
     #[error("Internal synchronization error: {0}")]
     InternalSynchronizationError(String),
+    #[error("Unable to build HTTP request: {0}")]
+    HttpRequestBuildError(String),
     #[error("Error executing HTTP request: {0}")]
     HttpRequestError(#[from] reqwest::Error),
     // #[error("Error building HTTP request: {0}")]
@@ -49,10 +77,103 @@ pub enum SamplyBeamError {
     HttpParseError(FromUtf8Error),
     #[error("X509 certificate invalid: {0}")]
     CertificateError(#[from] CertificateInvalidReason),
+    #[error("Certificate {serial} is outside its validity period ending {not_after}")]
+    CertificateExpired { serial: String, not_after: String },
+    #[error("Certificate {0} has been revoked")]
+    CertificateRevoked(String),
     #[error("Timeout executing HTTP request: {0}")]
     HttpTimeoutError(Elapsed),
     #[error("Invalid receivers: {0:?}")]
-    InvalidReceivers(Vec<ProxyId>)
+    InvalidReceivers(Vec<ProxyId>),
+    /// An error which occurred for a message from the proxy `id`, e.g. while verifying its signature
+    #[error("{source} (sender: {id})")]
+    WithContext { id: ProxyId, source: Box<SamplyBeamError> },
+}
+
+impl SamplyBeamError {
+    /// Tells which proxy the error occurred for, keeping the error's status code
+    pub fn with_context(self, id: &ProxyId) -> Self {
+        match self {
+            Self::WithContext { .. } => self,
+            source => Self::WithContext { id: id.clone(), source: Box::new(source) },
+        }
+    }
+
+    /// The error without the proxy it occurred for, e.g. to match on its kind
+    pub fn without_context(&self) -> &Self {
+        match self {
+            Self::WithContext { source, .. } => source.without_context(),
+            e => e,
+        }
+    }
+
+    /// Why a request failed without a response, if it did
+    pub fn http_error_kind(&self) -> Option<HttpErrorKind> {
+        match self {
+            Self::HttpRequestError(e) => Some(HttpErrorKind::of(e)),
+            #[cfg(feature = "vault")]
+            Self::VaultUnreachable(e) => Some(HttpErrorKind::of(e)),
+            Self::HttpTimeoutError(_) => Some(HttpErrorKind::Timeout),
+            Self::WithContext { source, .. } => source.http_error_kind(),
+            _ => None,
+        }
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            Self::WithContext { source, .. } => source.status_code(),
+            Self::RequestValidationFailed(_)
+            | Self::InvalidPath
+            | Self::InvalidBeamId(_)
+            | Self::JsonParseError(_)
+            | Self::DecryptError(_) => StatusCode::BAD_REQUEST,
+            Self::CertificateError(_) | Self::CertificateExpired { .. } | Self::CertificateRevoked(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::InvalidReceivers(_) => StatusCode::FAILED_DEPENDENCY,
+            Self::CertificateNotFound(_) | Self::CertificateFingerprintNotFound(_) => StatusCode::NOT_FOUND,
+            #[cfg(feature = "vault")]
+            Self::VaultSealed
+            | Self::VaultUnreachable(_)
+            | Self::VaultCircuitOpen(_)
+            | Self::VaultGaveUp { .. }
+            | Self::VaultNotInitialized
+            | Self::VaultRequestCancelled => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            #[cfg(feature = "vault")]
+            Self::VaultRedirectError(..) | Self::VaultAuthError(_) | Self::VaultDeserializationError { .. } => StatusCode::BAD_GATEWAY,
+            Self::VaultOtherError(_) | Self::HttpRequestError(_) | Self::HttpProxyProblem(_) | Self::HttpParseError(_) => {
+                StatusCode::BAD_GATEWAY
+            }
+            Self::HttpTimeoutError(_) => StatusCode::GATEWAY_TIMEOUT,
+            Self::BindAddr(_)
+            | Self::WrongBrokerUri(_)
+            | Self::SignEncryptError(_)
+            | Self::ConfigurationFailed(_)
+            | Self::InternalSynchronizationError(_)
+            | Self::HttpRequestBuildError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// Errors caused by the request are explained to the client. Details of server-side errors
+/// (e.g. Vault's responses or addresses) only go to the log so that nothing internal is leaked.
+impl IntoResponse for SamplyBeamError {
+    fn into_response(self) -> Response {
+        let status = self.status_code();
+        let error = match (status, self) {
+            (_, Self::InvalidReceivers(proxies)) => return (status, Json(proxies)).into_response(),
+            (status, e) if status.is_client_error() => e.to_string(),
+            (StatusCode::SERVICE_UNAVAILABLE, e) => {
+                warn!("Responding with {status}: {e}");
+                "The broker's PKI is currently unavailable, please try again later".to_string()
+            }
+            (status, e) => {
+                warn!("Responding with {status}: {e}");
+                status.canonical_reason().unwrap_or("Internal error").to_string()
+            }
+        };
+        (status, Json(json!({ "error": error }))).into_response()
+    }
 }
 
 impl From<AddrParseError> for SamplyBeamError {
@@ -96,3 +217,65 @@ pub enum CertificateInvalidReason {
     #[error("Other problem: {0}")]
     Other(String),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn respond(e: SamplyBeamError) -> (StatusCode, serde_json::Value) {
+        let res = e.into_response();
+        let status = res.status();
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_errors_map_to_responses() {
+        let (status, body) = respond(SamplyBeamError::RequestValidationFailed("Missing signature".into())).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], "The request could not be validated: Missing signature.");
+
+        let (status, _) = respond(SamplyBeamError::CertificateError(CertificateInvalidReason::Revoked)).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        let (status, body) = respond(SamplyBeamError::CertificateRevoked("0a:1b".into())).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["error"], "Certificate 0a:1b has been revoked");
+
+        let (status, body) = respond(SamplyBeamError::CertificateNotFound("0a:1b".into())).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["error"], "No certificate with serial 0a:1b");
+        let (status, body) = respond(SamplyBeamError::CertificateFingerprintNotFound("0a1b".into())).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["error"], "No certificate with fingerprint 0a1b");
+
+        beam_lib::set_broker_id("broker.samply.de".into());
+        let proxy = ProxyId::new("proxy1.broker.samply.de").unwrap();
+        let with_context = SamplyBeamError::CertificateRevoked("0a:1b".into()).with_context(&proxy).with_context(&proxy);
+        assert!(matches!(with_context.without_context(), SamplyBeamError::CertificateRevoked(_)));
+        let (status, body) = respond(with_context).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["error"], "Certificate 0a:1b has been revoked (sender: proxy1.broker.samply.de)");
+
+        let (status, body) = respond(SamplyBeamError::InvalidReceivers(vec![])).await;
+        assert_eq!(status, StatusCode::FAILED_DEPENDENCY);
+        assert_eq!(body, json!([]));
+
+        // Server-side details stay in the log
+        let (status, body) = respond(SamplyBeamError::VaultOtherError("Token s.secret was rejected".into())).await;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert_eq!(body, json!({ "error": "Bad Gateway" }));
+        let (status, body) = respond(SamplyBeamError::ConfigurationFailed("/run/secrets/pki.secret".into())).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(!body.to_string().contains("secret"));
+    }
+
+    #[cfg(feature = "vault")]
+    #[tokio::test]
+    async fn test_unavailable_vault_maps_to_service_unavailable() {
+        let (status, body) = respond(SamplyBeamError::VaultSealed).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(body["error"].as_str().unwrap().contains("unavailable"));
+        let (status, _) = respond(SamplyBeamError::VaultAuthError("permission denied for s.secret".into())).await;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+    }
+}
@@ -41,6 +41,8 @@ pub type TaskResponse = String;
 
 pub mod crypto;
 pub mod crypto_jwt;
+#[cfg(any(test, feature = "test-util"))]
+pub mod crypto_mock;
 pub mod errors;
 pub mod serde_helpers;
 pub mod logger;
@@ -302,32 +304,27 @@ pub trait EncryptableMsg: Msg + Serialize + Sized {
     fn convert_self(self, body: Encrypted) -> Self::Output;
     fn get_plain(&self) -> &Plain;
 
-    #[allow(clippy::or_fun_call)]
     fn encrypt(
         self,
         receivers_public_keys: &Vec<RsaPublicKey>,
     ) -> Result<Self::Output, SamplyBeamError> {
+        self.encrypt_keeping_key(receivers_public_keys).map(|(msg, _)| msg)
+    }
+
+    /// Like [`EncryptableMsg::encrypt`] but also returns the symmetric key so it can be
+    /// wrapped for additional recipients later on (see [`wrap_symmetric_key`]).
+    #[allow(clippy::or_fun_call)]
+    fn encrypt_keeping_key(
+        self,
+        receivers_public_keys: &Vec<RsaPublicKey>,
+    ) -> Result<(Self::Output, SymmetricKey), SamplyBeamError> {
         // Generate Symmetric Key and Nonce
         let mut rng = rand::thread_rng();
         let symmetric_key = XChaCha20Poly1305::generate_key(&mut rng);
         let nonce = XChaCha20Poly1305::generate_nonce(&mut rng);
 
         // Encrypt symmetric key with receivers' public keys
-        let Ok(encrypted_keys) = receivers_public_keys
-            .iter()
-            .map(|key| {
-                key.encrypt(
-                    &mut rng,
-                    Oaep::new::<sha2::Sha256>(),
-                    symmetric_key.as_slice(),
-                )
-            })
-            .collect()
-        else {
-            return Err(SamplyBeamError::SignEncryptError(
-                "Encryption error: Cannot encrypt symmetric key".into(),
-            ));
-        };
+        let encrypted_keys = wrap_symmetric_key(&symmetric_key, receivers_public_keys)?;
 
         // Encrypt fields content
         let cipher = XChaCha20Poly1305::new(&symmetric_key);
@@ -344,11 +341,36 @@ pub trait EncryptableMsg: Msg + Serialize + Sized {
         let mut nonce_and_ciphertext = nonce.to_vec();
         nonce_and_ciphertext.append(&mut ciphertext);
 
-        Ok(self.convert_self(Encrypted {
+        let msg = self.convert_self(Encrypted {
             encrypted: nonce_and_ciphertext,
             encryption_keys: encrypted_keys,
-        }))
-    }
+        });
+        Ok((msg, symmetric_key))
+    }
+}
+
+/// Key used to encrypt the body of a message
+pub type SymmetricKey = chacha20poly1305::Key;
+
+/// Encrypts the symmetric key of a message for each of the receivers
+pub fn wrap_symmetric_key(
+    symmetric_key: &SymmetricKey,
+    receivers_public_keys: &[RsaPublicKey],
+) -> Result<Vec<Vec<u8>>, SamplyBeamError> {
+    let mut rng = rand::thread_rng();
+    receivers_public_keys
+        .iter()
+        .map(|key| {
+            key.encrypt(
+                &mut rng,
+                Oaep::new::<sha2::Sha256>(),
+                symmetric_key.as_slice(),
+            )
+        })
+        .collect::<Result<_, _>>()
+        .map_err(|_| SamplyBeamError::SignEncryptError(
+            "Encryption error: Cannot encrypt symmetric key".into(),
+        ))
 }
 
 pub trait Msg: Serialize {
@@ -471,6 +493,10 @@ where
     #[serde(skip)]
     pub results: HashMap<AppOrProxyId, MsgSigned<MsgTaskResult<State>>>,
     pub metadata: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sequence: Option<u64>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub probe: bool,
 }
 
 //TODO: Implement EncMsg and DecMsg for all message types
@@ -485,6 +511,8 @@ impl EncryptableMsg for MsgTaskRequest {
             expire,
             failure_strategy,
             metadata,
+            sequence,
+            probe,
             ..
         } = self;
         Self::Output {
@@ -495,6 +523,8 @@ impl EncryptableMsg for MsgTaskRequest {
             expire,
             failure_strategy,
             metadata,
+            sequence,
+            probe,
             results: Default::default(),
         }
     }
@@ -515,6 +545,8 @@ impl DecryptableMsg for MsgTaskRequest<Encrypted> {
             expire,
             failure_strategy,
             metadata,
+            sequence,
+            probe,
             ..
         } = self;
         Self::Output {
@@ -525,6 +557,8 @@ impl DecryptableMsg for MsgTaskRequest<Encrypted> {
             expire,
             failure_strategy,
             metadata,
+            sequence,
+            probe,
             results: Default::default(),
         }
     }
@@ -665,6 +699,8 @@ impl MsgTaskRequest {
             results: HashMap::new(),
             metadata,
             expire: SystemTime::now() + Duration::from_secs(3600),
+            sequence: None,
+            probe: false,
         }
     }
 }
@@ -756,6 +792,8 @@ mod tests {
             failure_strategy: failure,
             results: HashMap::new(),
             metadata: "".into(),
+            sequence: None,
+            probe: false,
         };
 
         //Setup Keypairs
@@ -787,6 +825,45 @@ mod tests {
         assert_eq!(msg, msg_p1_decr);
     }
 
+    #[test]
+    fn add_recipient_to_encrypted_task() {
+        beam_lib::set_broker_id("broker.samply.de".to_string());
+        let p1_id = AppOrProxyId::App(AppId::new("app.proxy1.broker.samply.de").unwrap());
+        let p2_id = AppOrProxyId::App(AppId::new("app.proxy2.broker.samply.de").unwrap());
+        let msg = MsgTaskRequest {
+            id: MsgId::new(),
+            from: p1_id.clone(),
+            to: vec![p1_id.clone()],
+            body: "Testbody".into(),
+            expire: SystemTime::now() + Duration::from_secs(60),
+            failure_strategy: FailureStrategy::Discard,
+            results: HashMap::new(),
+            metadata: "".into(),
+            sequence: None,
+            probe: false,
+        };
+        let mut rng = rand::thread_rng();
+        let p1_private = RsaPrivateKey::new(&mut rng, 2048).unwrap();
+        let p2_private = RsaPrivateKey::new(&mut rng, 2048).unwrap();
+
+        let (mut msg_encr, key) = msg
+            .clone()
+            .encrypt_keeping_key(&vec![RsaPublicKey::from(&p1_private)])
+            .unwrap();
+        assert!(msg_encr.clone().decrypt(&p2_id, &p2_private).is_err());
+
+        // Onboard proxy 2 without touching the ciphertext
+        let encrypted = msg_encr.body.encrypted.clone();
+        msg_encr.to.push(p2_id.clone());
+        msg_encr.body.encryption_keys.extend(wrap_symmetric_key(&key, &[RsaPublicKey::from(&p2_private)]).unwrap());
+        assert_eq!(msg_encr.body.encrypted, encrypted);
+
+        let msg_p2_decr = msg_encr.clone().decrypt(&p2_id, &p2_private).unwrap();
+        assert_eq!(msg_p2_decr.body, msg.body);
+        let msg_p1_decr = msg_encr.decrypt(&p1_id, &p1_private).unwrap();
+        assert_eq!(msg_p1_decr.body, msg.body);
+    }
+
     #[test]
     fn encrypt_decrypt_result() {
         beam_lib::set_broker_id("broker.samply.de".to_string());
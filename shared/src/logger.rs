@@ -1,4 +1,6 @@
-use tracing::{debug, dispatcher::SetGlobalDefaultError, Level};
+use std::backtrace::Backtrace;
+
+use tracing::{debug, dispatcher::SetGlobalDefaultError, error, Level};
 
 #[allow(clippy::if_same_then_else)] // The redundant if-else serves documentation purposes
 pub fn init_logger() -> Result<(), SetGlobalDefaultError> {
@@ -24,6 +26,15 @@ pub fn init_logger() -> Result<(), SetGlobalDefaultError> {
 
     let subscriber = subscriber.with_env_filter(env_filter.clone()).finish();
     tracing::subscriber::set_global_default(subscriber)?;
+    std::panic::set_hook(Box::new(|info| {
+        error!("{info}\n{}", Backtrace::force_capture());
+        // A lock poisoned by an earlier panic stays poisoned, so every later request would fail the same way
+        let poisoned = info.payload().downcast_ref::<String>().is_some_and(|msg| msg.contains("PoisonError"));
+        if !crate::middleware::panic_is_caught() || poisoned {
+            error!("Aborting as the panic cannot be recovered from");
+            std::process::abort();
+        }
+    }));
 
     debug!("Logging initialized with env_filter {env_filter}.");
     Ok(())
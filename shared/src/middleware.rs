@@ -1,20 +1,28 @@
 use std::{
-    cell::RefCell,
+    any::Any,
+    cell::{Cell, RefCell},
+    future::Future,
     net::{IpAddr, SocketAddr},
+    pin::Pin,
     sync::Arc,
+    task::{Context, Poll},
 };
 
 use axum::{
-    body::HttpBody,
-    extract::{ConnectInfo, Request},
-    http::{header::HeaderName, Method, StatusCode, Uri},
+    body::{Body, HttpBody},
+    extract::{ConnectInfo, Request, State},
+    http::{header::{self, HeaderName}, uri::Authority, HeaderValue, Method, StatusCode, Uri},
     middleware::{self, Next},
     response::{IntoResponse, Response},
+    Json,
 };
+use http_body_util::Limited;
+use serde_json::json;
 use tokio::sync::mpsc;
 use tracing::{error, info, instrument, span, warn, Level};
 
 use beam_lib::AppOrProxyId;
+use uuid::Uuid;
 
 const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
 
@@ -69,6 +77,11 @@ impl LoggingInfo {
 
 pub type ProxyLogger = mpsc::Sender<AppOrProxyId>;
 
+/// Marks requests on connections from a trusted load balancer, whose [`ConnectInfo`] is the client address
+/// from the PROXY protocol header. `X-Forwarded-For` is ignored for them as it is entirely up to the client.
+#[derive(Debug, Clone, Copy)]
+pub struct SourceFromProxyProtocol;
+
 pub async fn log(
     ConnectInfo(info): ConnectInfo<SocketAddr>,
     mut req: Request,
@@ -102,6 +115,9 @@ pub async fn log(
 }
 
 fn get_ip(req: &Request, info: &SocketAddr) -> IpAddr {
+    if req.extensions().get::<SourceFromProxyProtocol>().is_some() {
+        return info.ip();
+    }
     req.headers()
         .get(X_FORWARDED_FOR)
         .and_then(|v| v.to_str().ok())
@@ -109,3 +125,190 @@ fn get_ip(req: &Request, info: &SocketAddr) -> IpAddr {
         .and_then(|v| v.parse().ok())
         .unwrap_or(info.ip())
 }
+
+/// What to do with requests without a `Host` header, which HTTP/1.0 clients may omit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum MissingHost {
+    /// Answer with 400 Bad Request
+    Reject,
+    /// Handle the request as if it had been sent to the listener's own address
+    Synthesize,
+}
+
+#[derive(Debug, Clone)]
+pub struct HostPolicy {
+    missing: MissingHost,
+    fallback: HeaderValue,
+}
+
+impl HostPolicy {
+    pub fn new(missing: MissingHost, bind_addr: SocketAddr) -> Self {
+        let fallback = if bind_addr.ip().is_unspecified() {
+            format!("localhost:{}", bind_addr.port())
+        } else {
+            bind_addr.to_string()
+        };
+        Self {
+            missing,
+            fallback: HeaderValue::try_from(fallback).expect("Socket addresses are valid header values"),
+        }
+    }
+}
+
+/// Rejects requests with an invalid `Host` header and handles those without one according to the [`HostPolicy`].
+/// Requests of any HTTP version are accepted.
+pub async fn check_host(State(policy): State<HostPolicy>, mut req: Request, next: Next) -> Response {
+    match req.headers().get(header::HOST) {
+        Some(host) if host.to_str().ok().and_then(|host| host.parse::<Authority>().ok()).is_none() => {
+            warn!("Rejecting {} {} with invalid Host header {host:?}", req.method(), req.uri());
+            return (StatusCode::BAD_REQUEST, "Invalid Host header").into_response();
+        },
+        Some(_) => {},
+        // HTTP/2 requests carry the host in the URI instead
+        None if req.uri().authority().is_some() => {},
+        None => match policy.missing {
+            MissingHost::Reject => {
+                warn!("Rejecting {:?} request {} {} without Host header", req.version(), req.method(), req.uri());
+                return (StatusCode::BAD_REQUEST, "Missing Host header").into_response();
+            },
+            MissingHost::Synthesize => {
+                info!("Assuming Host {:?} for {:?} request {} {} without Host header", policy.fallback, req.version(), req.method(), req.uri());
+                req.headers_mut().insert(header::HOST, policy.fallback.clone());
+            },
+        },
+    }
+    next.run(req).await
+}
+
+/// Upper bound for the size of decompressed request bodies if no other limit is configured
+pub const MAX_DECOMPRESSED_SIZE: usize = 256 * 1024 * 1024;
+
+/// Marks requests whose body was compressed on the wire, see [`limit_decompressed`]
+#[derive(Clone, Copy)]
+struct Compressed;
+
+/// Remembers which requests arrive with a compressed body. Has to run before they are decompressed.
+pub async fn mark_compressed(mut req: Request, next: Next) -> Response {
+    if req.headers().contains_key(header::CONTENT_ENCODING) {
+        req.extensions_mut().insert(Compressed);
+    }
+    next.run(req).await
+}
+
+/// Limits the decompressed bodies of requests marked by [`mark_compressed`] to the given number of bytes, so that a small compressed body cannot expand without bound.
+/// Has to run after they are decompressed. Handlers reject bodies exceeding the limit with `413 Payload Too Large`.
+pub async fn limit_decompressed(State(limit): State<usize>, req: Request, next: Next) -> Response {
+    if req.extensions().get::<Compressed>().is_none() {
+        return next.run(req).await;
+    }
+    next.run(req.map(|body| Body::new(Limited::new(body, limit)))).await
+}
+
+thread_local! {
+    /// Set while a request is handled behind [`catch_panics`]
+    static CATCHING_PANICS: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Marks the request handlers whose panics are answered by a `CatchPanicLayer` with [`panic_to_json`].
+/// Has to be the layer right inside it. Panics anywhere else, e.g. in background tasks, abort the process
+/// (see [`crate::logger::init_logger`]) instead of leaving it running without them.
+pub async fn catch_panics(req: Request, next: Next) -> Response {
+    CatchingPanics(Box::pin(next.run(req))).await
+}
+
+/// Whether a panic on this thread would be answered with a response rather than abort the process
+pub fn panic_is_caught() -> bool {
+    CATCHING_PANICS.get()
+}
+
+struct CatchingPanics<F>(Pin<Box<F>>);
+
+impl<F: Future> Future for CatchingPanics<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        /// Restores the flag even if the request handler panics
+        struct Restore(bool);
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                CATCHING_PANICS.set(self.0);
+            }
+        }
+        let _restore = Restore(CATCHING_PANICS.replace(true));
+        self.0.as_mut().poll(cx)
+    }
+}
+
+/// Turns a panic caught in a handler into a JSON error response carrying an id to find the panic in the logs.
+/// The panic itself is logged including its backtrace by the panic hook set in [`crate::logger::init_logger`].
+pub fn panic_to_json(panic: Box<dyn Any + Send + 'static>) -> Response {
+    let message = panic
+        .downcast_ref::<String>()
+        .map(String::as_str)
+        .or_else(|| panic.downcast_ref::<&str>().copied())
+        .unwrap_or("(no panic message)");
+    let correlation_id = Uuid::new_v4();
+    error!("Request handler panicked (correlation id {correlation_id}): {message}");
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(json!({
+            "error": "Internal server error",
+            "correlation_id": correlation_id,
+        })),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{routing::get, Router};
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpListener};
+
+    use super::*;
+
+    /// Serves the `Host` header of each request with the given policy and returns the listener's address
+    async fn serve(missing: MissingHost) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new()
+            .route("/host", get(|req: Request| async move { format!("{:?}", req.headers()[header::HOST]) }))
+            .layer(middleware::from_fn_with_state(HostPolicy::new(missing, addr), check_host));
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        addr
+    }
+
+    /// Sends a raw request and reads the response until the server closes the connection
+    async fn send(addr: SocketAddr, request: &str) -> String {
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        tokio::time::timeout(std::time::Duration::from_secs(5), stream.read_to_string(&mut response))
+            .await
+            .expect("HTTP/1.0 connections must be closed after the response")
+            .unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_legacy_requests() {
+        // Like the broker's public listener
+        let strict = serve(MissingHost::Reject).await;
+        // Like the proxy's listener for local apps
+        let lenient = serve(MissingHost::Synthesize).await;
+
+        for addr in [strict, lenient] {
+            let response = send(addr, "GET /host HTTP/1.0\r\nHost: broker.example\r\n\r\n").await;
+            assert!(response.starts_with("HTTP/1.0 200 OK"), "{response}");
+            assert!(response.ends_with("\"broker.example\""), "{response}");
+            let response = send(addr, "GET /host HTTP/1.1\r\nHost: broker example\r\nConnection: close\r\n\r\n").await;
+            assert!(response.starts_with("HTTP/1.1 400"), "{response}");
+        }
+
+        let response = send(strict, "GET /host HTTP/1.0\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.0 400"), "{response}");
+        assert!(response.ends_with("Missing Host header"), "{response}");
+        let response = send(lenient, "GET /host HTTP/1.0\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.0 200 OK"), "{response}");
+        assert!(response.ends_with(&format!("\"{lenient}\"")), "{response}");
+    }
+}
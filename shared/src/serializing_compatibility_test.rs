@@ -53,6 +53,8 @@ fn test_msg_task() {
         },
         results: Default::default(),
         metadata: json_data.clone(),
+        sequence: Some(3),
+        probe: true,
     };
     let lib = beam_lib::TaskRequest {
         from: AppOrProxyId::new("app1.proxy1.broker.samply.de").unwrap(),
@@ -65,6 +67,8 @@ fn test_msg_task() {
             max_tries: 10,
         },
         metadata: json_data,
+        sequence: Some(3),
+        probe: true,
     };
     assert_json_eq(lib, internal);
 }
@@ -6,7 +6,7 @@ use serde::{de::DeserializeOwned, Serialize};
 use serde_json::Value;
 use tokio::sync::oneshot;
 
-use crate::{CLIENT1, APP1, APP2, CLIENT2};
+use crate::{CLIENT1, APP1, APP2, CLIENT2, PROXY1, APP_KEY};
 
 #[tokio::test]
 async fn test_full_task_cycle() -> Result<()> {
@@ -50,6 +50,61 @@ async fn test_task_claiming() -> Result<()> {
     Ok(())
 }
 
+async fn post_task_raw(id: MsgId, prefer: Option<&str>, query: &str) -> Result<reqwest::Response> {
+    use reqwest::header;
+    let task = TaskRequest {
+        id,
+        from: APP1.clone(),
+        to: vec![APP2.clone()],
+        body: (),
+        ttl: "10s".to_string(),
+        failure_strategy: beam_lib::FailureStrategy::Discard,
+        metadata: serde_json::Value::Null,
+        sequence: None,
+        probe: false,
+    };
+    let mut req = reqwest::Client::new()
+        .post(format!("{PROXY1}/v1/tasks{query}"))
+        .header(header::AUTHORIZATION, format!("ApiKey {} {APP_KEY}", APP1.clone()))
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(serde_json::to_vec(&task)?);
+    if let Some(prefer) = prefer {
+        req = req.header("Prefer", prefer);
+    }
+    Ok(req.send().await?)
+}
+
+#[tokio::test]
+async fn test_post_task_respond_async() -> Result<()> {
+    use reqwest::{header, StatusCode};
+    let id = MsgId::new();
+    let res = post_task_raw(id, Some("respond-async, wait=10"), "?wait_count=1").await?;
+    assert_eq!(res.status(), StatusCode::ACCEPTED);
+    assert_eq!(res.headers()[header::CONTENT_LOCATION], format!("/v1/tasks/{id}/status").as_str());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_post_task_sync() -> Result<()> {
+    use reqwest::StatusCode;
+    let id = MsgId::new();
+    let client = async {
+        let res = post_task_raw(id, None, "?wait_count=1&wait_time=10s").await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let results: Vec<TaskResult<()>> = serde_json::from_slice(&res.bytes().await?)?;
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].status, WorkStatus::Succeeded);
+        Ok(())
+    };
+    let server = async {
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        poll_task::<()>(id).await?;
+        put_result(id, (), None).await
+    };
+    tokio::try_join!(client, server)?;
+    Ok(())
+}
+
 pub async fn post_task<T: Serialize + 'static>(body: T) -> Result<MsgId> {
     let id = MsgId::new();
     CLIENT1.post_task(&TaskRequest {
@@ -60,6 +115,8 @@ pub async fn post_task<T: Serialize + 'static>(body: T) -> Result<MsgId> {
         ttl: "10s".to_string(),
         failure_strategy: beam_lib::FailureStrategy::Discard,
         metadata: serde_json::Value::Null,
+        sequence: None,
+        probe: false,
     }).await?;
     Ok(id)
 }
@@ -70,8 +127,8 @@ pub async fn poll_task<T: DeserializeOwned + 'static>(expected_id: MsgId) -> Res
         .into_iter()
         .find(|t| t.id == expected_id)
         .ok_or(anyhow::anyhow!("Did not find expected task"))
-        .and_then(|TaskRequest { id, from, to, body, ttl, failure_strategy, metadata }| Ok(TaskRequest {
-            id, from, to, ttl, failure_strategy, metadata,
+        .and_then(|TaskRequest { id, from, to, body, ttl, failure_strategy, metadata, sequence, probe }| Ok(TaskRequest {
+            id, from, to, ttl, failure_strategy, metadata, sequence, probe,
             body: serde_json::from_value(body)?
         }))
 }
@@ -0,0 +1,61 @@
+use std::io::{Read, Write};
+
+use anyhow::Result;
+use beam_lib::{MsgId, TaskRequest, TaskResult};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use reqwest::{header, StatusCode};
+
+use crate::{task_test, APP1, APP2, APP_KEY, PROXY1};
+
+fn gzip(data: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+/// Proxy1 talks gzip to the broker (see `WIRE_COMPRESSION` in dev/docker-compose.yml) while proxy2 does not
+#[tokio::test]
+async fn test_compressed_task_cycle() -> Result<()> {
+    // Decompresses nothing by itself even if another crate enables reqwest's gzip feature
+    let client = reqwest::Client::builder().no_gzip().build()?;
+    let auth = format!("ApiKey {} {APP_KEY}", APP1.clone());
+    let id = MsgId::new();
+    let body = "A task body that compresses well. ".repeat(20);
+    let task = TaskRequest {
+        id,
+        from: APP1.clone(),
+        to: vec![APP2.clone()],
+        body: body.clone(),
+        ttl: "10s".to_string(),
+        failure_strategy: beam_lib::FailureStrategy::Discard,
+        metadata: serde_json::Value::Null,
+        sequence: None,
+        probe: false,
+    };
+    let res = client
+        .post(format!("{PROXY1}/v1/tasks"))
+        .header(header::AUTHORIZATION, &auth)
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::CONTENT_ENCODING, "gzip")
+        .body(gzip(&serde_json::to_vec(&task)?)?)
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    assert_eq!(task_test::poll_task::<String>(id).await?.body, body);
+    task_test::put_result(id, body.clone(), None).await?;
+
+    let res = client
+        .get(format!("{PROXY1}/v1/tasks/{id}/results?wait_count=1"))
+        .header(header::AUTHORIZATION, &auth)
+        .header(header::ACCEPT_ENCODING, "gzip")
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers()[header::CONTENT_ENCODING], "gzip");
+    let mut json = Vec::new();
+    GzDecoder::new(&res.bytes().await?[..]).read_to_end(&mut json)?;
+    let results: Vec<TaskResult<String>> = serde_json::from_slice(&json)?;
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].body, body);
+    Ok(())
+}